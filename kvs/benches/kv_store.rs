@@ -30,7 +30,7 @@ pub fn bench_write_read(c: &mut Criterion) {
     ];
 
     {
-        let mut group_write = c.benchmark_group("read");
+        let mut group_write = c.benchmark_group("write");
         for mut store in stores.iter_mut() {
             group_write.bench_function(
                 store.name(),
@@ -45,7 +45,7 @@ pub fn bench_write_read(c: &mut Criterion) {
         }
     }
     {
-        let mut group_read = c.benchmark_group("write");
+        let mut group_read = c.benchmark_group("read");
         for store in stores.iter() {
             group_read.bench_function(
                 store.name(),
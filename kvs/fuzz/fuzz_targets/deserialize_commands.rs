@@ -0,0 +1,14 @@
+//! Replays arbitrary text as the log of a store, as opening it does, which has to fail on a
+//! malformed log rather than panic.
+#![no_main]
+
+use kvs::deserialize_commands;
+use kvs::encryption::Keyring;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    let _ = deserialize_commands(text, &Keyring::default());
+});
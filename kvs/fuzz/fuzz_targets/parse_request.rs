@@ -0,0 +1,16 @@
+//! Feeds arbitrary bytes to the TCP server's parsing of requests, which has to turn them down with
+//! an error rather than panic.
+#![no_main]
+
+use kvs::limits::Limits;
+use kvs::protocol::{parse_namespace_command, parse_request, parse_watch, read_request};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(request) = read_request(data, &Limits::default()) else {
+        return;
+    };
+    let _ = parse_watch(&request.words);
+    let _ = parse_namespace_command(&request.words);
+    let _ = parse_request(request.words, &request.payload);
+});
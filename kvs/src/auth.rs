@@ -0,0 +1,328 @@
+use crate::err::{PermissionDeniedSnafu, Result, UnauthenticatedSnafu};
+use crate::index::collection_prefix;
+use crate::watch::KeyPattern;
+use crate::Command;
+use serde::Deserialize;
+use snafu::{ensure, whatever};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+/// What a role may do with the keys it covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// The `[auth]` section of the server config, e.g.
+///
+/// ```toml
+/// [auth.roles.reader]
+/// read = ["*"]
+///
+/// [auth.roles.users-admin]
+/// read = ["user:*"]
+/// write = ["user:*"]
+///
+/// [[auth.users]]
+/// name = "alice"
+/// token = "s3cr3t"
+/// roles = ["reader", "users-admin"]
+/// ```
+///
+/// Permissions are key patterns: `key` covers a single key and `prefix*` every key under a
+/// prefix, so `*` covers the whole store. Writing doesn't imply reading.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub roles: HashMap<String, RoleConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct RoleConfig {
+    #[serde(default)]
+    pub read: Vec<String>,
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl AuthConfig {
+    /// Catches the mistakes that would otherwise only show up as denied requests.
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        let mut tokens = HashSet::new();
+        for user in &self.users {
+            if !names.insert(&user.name) {
+                whatever!("User {} is defined more than once", user.name);
+            }
+            if user.token.is_empty() {
+                whatever!("User {} has an empty token", user.name);
+            }
+            if !tokens.insert(&user.token) {
+                whatever!("User {} shares its token with another user", user.name);
+            }
+            for role in &user.roles {
+                if !self.roles.contains_key(role) {
+                    whatever!("User {} has undefined role {}", user.name, role);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the user owning `token`, with the permissions of all of its roles.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<User> {
+        let token = token.ok_or_else(|| {
+            UnauthenticatedSnafu {
+                reason: "no token given",
+            }
+            .build()
+        })?;
+        // Every token is compared, so the timing doesn't tell which user almost matched
+        let mut found = None;
+        for user in &self.users {
+            if constant_time_eq(user.token.as_bytes(), token.as_bytes()) {
+                found = Some(user);
+            }
+        }
+        let user = found.ok_or_else(|| {
+            UnauthenticatedSnafu {
+                reason: "invalid token",
+            }
+            .build()
+        })?;
+
+        let mut read = Vec::new();
+        let mut write = Vec::new();
+        for role in user.roles.iter().filter_map(|role| self.roles.get(role)) {
+            read.extend(role.read.iter().map(|pattern| KeyPattern::parse(pattern)));
+            write.extend(role.write.iter().map(|pattern| KeyPattern::parse(pattern)));
+        }
+
+        Ok(User {
+            name: user.name.clone(),
+            read,
+            write,
+        })
+    }
+}
+
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+
+/// An authenticated client, with the key patterns it may read and write.
+#[derive(Debug, Clone, PartialEq)]
+pub struct User {
+    pub name: String,
+    read: Vec<KeyPattern>,
+    write: Vec<KeyPattern>,
+}
+
+impl User {
+    /// The client of a server running without authentication, allowed to do anything.
+    pub fn unrestricted() -> Self {
+        let everything = vec![KeyPattern::Prefix(String::new())];
+        Self {
+            name: "anonymous".to_owned(),
+            read: everything.clone(),
+            write: everything,
+        }
+    }
+
+    pub fn authorize(&self, permission: Permission, target: &KeyPattern) -> Result<()> {
+        let granted = match permission {
+            Permission::Read => &self.read,
+            Permission::Write => &self.write,
+        };
+        ensure!(
+            granted.iter().any(|pattern| pattern.covers(target)),
+            PermissionDeniedSnafu {
+                user: &self.name,
+                permission,
+                target: target.to_string(),
+            }
+        );
+        Ok(())
+    }
+
+    /// Checks that the user may run `command`, before it gets to the engine.
+    pub fn authorize_command(&self, command: &Command) -> Result<()> {
+        let everything = KeyPattern::Prefix(String::new());
+        match command {
+            Command::Get { key }
+            | Command::LRange { key, .. }
+            | Command::HGet { key, .. }
+            | Command::SMembers { key } => {
+                self.authorize(Permission::Read, &KeyPattern::Exact(key.clone()))
+            }
+            Command::Set { key, .. }
+            | Command::Rm { key }
+            | Command::LPush { key, .. }
+            | Command::HSet { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::IncrBy { key, .. } => {
+                self.authorize(Permission::Write, &KeyPattern::Exact(key.clone()))
+            }
+            Command::Scan { prefix } => {
+                self.authorize(Permission::Read, &KeyPattern::Prefix(prefix.clone()))
+            }
+            // Indexes only cover the keys of their collection
+            Command::Find { index, .. } => match collection_prefix(index) {
+                Some(prefix) => self.authorize(Permission::Read, &KeyPattern::Prefix(prefix)),
+                None => self.authorize(Permission::Read, &everything),
+            },
+            // Scripts may only touch the keys they declare, and may read and write them
+            Command::Eval { keys, .. } | Command::EvalSha { keys, .. } => {
+                keys.iter().try_for_each(|key| {
+                    let key = KeyPattern::Exact(key.clone());
+                    self.authorize(Permission::Read, &key)?;
+                    self.authorize(Permission::Write, &key)
+                })
+            }
+            Command::Backup => self.authorize(Permission::Read, &everything),
+            Command::Restore { .. } => self.authorize(Permission::Write, &everything),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    const CONFIG: &str = r#"
+        [roles.reader]
+        read = ["*"]
+
+        [roles.users-admin]
+        read = ["user:*"]
+        write = ["user:*", "motd"]
+
+        [[users]]
+        name = "alice"
+        token = "alice-token"
+        roles = ["reader", "users-admin"]
+
+        [[users]]
+        name = "bob"
+        token = "bob-token"
+        roles = ["users-admin"]
+    "#;
+
+    fn config() -> AuthConfig {
+        let config: AuthConfig = toml::from_str(CONFIG).unwrap();
+        config.validate().unwrap();
+        config
+    }
+
+    fn get(key: &str) -> Command {
+        Command::Get {
+            key: key.to_owned(),
+        }
+    }
+
+    fn set(key: &str) -> Command {
+        Command::Set {
+            key: key.to_owned(),
+            value: "value".to_owned(),
+        }
+    }
+
+    #[test]
+    fn authenticate() {
+        let config = config();
+        assert_eq!(config.authenticate(Some("bob-token")).unwrap().name, "bob");
+        assert!(matches!(
+            config.authenticate(Some("bob-tokeN")),
+            Err(Error::Unauthenticated { .. })
+        ));
+        assert!(matches!(
+            config.authenticate(None),
+            Err(Error::Unauthenticated { .. })
+        ));
+    }
+
+    #[test]
+    fn authorize() {
+        let config = config();
+        let alice = config.authenticate(Some("alice-token")).unwrap();
+        let bob = config.authenticate(Some("bob-token")).unwrap();
+
+        assert!(alice.authorize_command(&get("order:1")).is_ok());
+        assert!(alice.authorize_command(&set("user:1")).is_ok());
+        assert!(alice.authorize_command(&set("motd")).is_ok());
+        assert!(alice.authorize_command(&Command::Backup).is_ok());
+        assert!(bob.authorize_command(&get("user:1")).is_ok());
+        assert!(bob
+            .authorize(Permission::Read, &KeyPattern::parse("user:admins*"))
+            .is_ok());
+
+        let err = bob.authorize_command(&get("order:1")).unwrap_err();
+        assert!(matches!(err, Error::PermissionDenied { .. }));
+        assert_eq!(err.to_string(), "User bob isn't allowed to read order:1");
+        for command in [
+            get("motd"),
+            set("order:1"),
+            Command::Backup,
+            Command::Restore { pairs: vec![] },
+        ] {
+            assert!(bob.authorize_command(&command).is_err(), "{:?}", command);
+        }
+        assert!(bob
+            .authorize(Permission::Read, &KeyPattern::parse("*"))
+            .is_err());
+
+        let anonymous = User::unrestricted();
+        assert!(anonymous.authorize_command(&Command::Backup).is_ok());
+        assert!(anonymous
+            .authorize_command(&Command::Restore { pairs: vec![] })
+            .is_ok());
+    }
+
+    #[test]
+    fn invalid_config() {
+        for (config, message) in [
+            (
+                "[[users]]\nname = \"alice\"\ntoken = \"t\"\nroles = [\"missing\"]",
+                "undefined role missing",
+            ),
+            (
+                "[[users]]\nname = \"alice\"\ntoken = \"t\"\n[[users]]\nname = \"bob\"\ntoken = \"t\"",
+                "shares its token",
+            ),
+            ("[[users]]\nname = \"alice\"\ntoken = \"\"", "empty token"),
+        ] {
+            let config: AuthConfig = toml::from_str(config).unwrap();
+            let err = config.validate().unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use crate::err::Result;
+use crate::KvsEngine;
+use serde::{Deserialize, Serialize};
+use snafu::{whatever, ResultExt};
+use std::io::{BufRead, Write};
+
+/// First line of every backup archive.
+pub const BACKUP_HEADER: &str = "kvs-backup v1";
+/// Prefix of the last line of every backup archive, followed by the number of records and the
+/// CRC32 of every record line.
+const BACKUP_TRAILER_PREFIX: &str = "checksum ";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct BackupRecord {
+    key: String,
+    value: String,
+}
+
+/// Writes every pair of `store` as a backup archive: a header line, one JSON record per line, and
+/// a trailer line with the record count and checksum. Returns the number of records.
+///
+/// The caller is responsible for keeping writes away from `store` until this returns, so that
+/// the archive is a consistent snapshot.
+pub fn write_backup(store: &dyn KvsEngine, writer: &mut impl Write) -> Result<usize> {
+    write_pairs(store.scan(String::new())?, writer)
+}
+
+/// Writes `pairs` as a backup archive, as [`write_backup`] does for a whole store.
+pub fn write_pairs(
+    pairs: impl IntoIterator<Item = Result<(String, String)>>,
+    writer: &mut impl Write,
+) -> Result<usize> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut count = 0;
+
+    writeln!(writer, "{}", BACKUP_HEADER).with_whatever_context(|_| "Couldn't write backup")?;
+    for pair in pairs {
+        let (key, value) = pair?;
+        let mut line = serde_json::to_vec(&BackupRecord { key, value })
+            .with_whatever_context(|_| "Couldn't serialize backup record")?;
+        line.push(b'\n');
+        hasher.update(&line);
+        writer
+            .write_all(&line)
+            .with_whatever_context(|_| "Couldn't write backup")?;
+        count += 1;
+    }
+    writeln!(
+        writer,
+        "{}{} {:08x}",
+        BACKUP_TRAILER_PREFIX,
+        count,
+        hasher.finalize()
+    )
+    .with_whatever_context(|_| "Couldn't write backup")?;
+
+    Ok(count)
+}
+
+/// Reads a whole backup archive and checks it against its trailer. Nothing is returned unless the
+/// archive is complete and its checksum matches, so a restore never applies half an archive.
+pub fn read_backup(reader: impl BufRead) -> Result<Vec<(String, String)>> {
+    let mut lines = reader.split(b'\n');
+    let header = lines
+        .next()
+        .transpose()
+        .with_whatever_context(|_| "Couldn't read backup")?;
+    if header.as_deref() != Some(BACKUP_HEADER.as_bytes()) {
+        whatever!("Not a backup archive: missing `{}` header", BACKUP_HEADER);
+    }
+
+    let mut hasher = crc32fast::Hasher::new();
+    let mut pairs = Vec::new();
+    let mut trailer = None;
+    for line in lines {
+        let line = line.with_whatever_context(|_| "Couldn't read backup")?;
+        if trailer.is_some() {
+            if line.is_empty() {
+                continue;
+            }
+            whatever!("Unexpected data after the backup trailer");
+        }
+        if let Some(rest) = line.strip_prefix(BACKUP_TRAILER_PREFIX.as_bytes()) {
+            trailer = Some(String::from_utf8_lossy(rest).into_owned());
+            continue;
+        }
+
+        hasher.update(&line);
+        hasher.update(b"\n");
+        let record: BackupRecord = serde_json::from_slice(&line).with_whatever_context(|_| {
+            format!("Couldn't parse backup record {}", pairs.len() + 1)
+        })?;
+        pairs.push((record.key, record.value));
+    }
+
+    let Some(trailer) = trailer else {
+        whatever!("Backup is truncated: missing checksum trailer");
+    };
+    let expected = format!("{} {:08x}", pairs.len(), hasher.finalize());
+    if trailer.trim() != expected {
+        whatever!(
+            "Backup checksum mismatch: trailer says `{}`, content gives `{}`",
+            trailer.trim(),
+            expected,
+        );
+    }
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemStore;
+    use std::io::Cursor;
+
+    fn backup_of(pairs: &[(&str, &str)]) -> Vec<u8> {
+        let mut store = MemStore::new();
+        for (key, value) in pairs {
+            store.set(key.to_string(), value.to_string()).unwrap();
+        }
+        let mut archive = Vec::new();
+        write_backup(&store, &mut archive).unwrap();
+        archive
+    }
+
+    #[test]
+    fn round_trip() {
+        let archive = backup_of(&[("key2", "value2"), ("key1", "value\nwith newline")]);
+        let pairs = read_backup(Cursor::new(archive)).unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("key1".to_owned(), "value\nwith newline".to_owned()),
+                ("key2".to_owned(), "value2".to_owned()),
+            ]
+        );
+
+        let empty = backup_of(&[]);
+        assert_eq!(read_backup(Cursor::new(empty)).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn fail() {
+        let archive = backup_of(&[("key1", "value1"), ("key2", "value2")]);
+        let text = String::from_utf8(archive.clone()).unwrap();
+
+        // Tampered record
+        let tampered = text.replace("value2", "value3");
+        let err = read_backup(Cursor::new(tampered)).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+
+        // Missing trailer
+        let truncated = &archive[..text.find(BACKUP_TRAILER_PREFIX).unwrap()];
+        let err = read_backup(Cursor::new(truncated)).unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+
+        // Missing header
+        let headless = text.replacen(BACKUP_HEADER, "", 1);
+        let err = read_backup(Cursor::new(headless)).unwrap_err();
+        assert!(err.to_string().contains("Not a backup archive"));
+    }
+}
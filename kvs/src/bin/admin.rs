@@ -0,0 +1,154 @@
+use admin::dump::{dump, restore};
+use admin::engine::{detect_engine, open_engine};
+use admin::migrate::migrate;
+use admin::stats::stats;
+use admin::verify::verify;
+use clap::Parser;
+use cli::data_dir::lock_data_dir;
+use cli::dump::Format;
+use cli::engine::Engine;
+use kvs::encryption::{EncryptionKey, Keyring};
+use snafu::ResultExt;
+use std::fs::File;
+use std::io::{stdout, BufReader, BufWriter};
+use std::path::PathBuf;
+
+mod admin {
+    pub mod dump;
+    pub mod engine;
+    pub mod migrate;
+    pub mod stats;
+    pub mod verify;
+}
+
+mod cli {
+    pub mod data_dir;
+    pub mod dump;
+    pub mod engine;
+}
+
+/// Offline tooling for the data directory of a `kvs-server`. The directory is locked while a
+/// command runs, so it can't be used on the directory of a running server.
+#[derive(Parser)]
+#[command(version)]
+#[command(propagate_version = true)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Commands,
+
+    /// The directory containing the database files
+    #[arg(long, default_value = ".", global = true)]
+    data_dir: PathBuf,
+
+    /// A file holding the key the database files are encrypted with
+    #[arg(long, env = "KVS_ENCRYPTION_KEY_FILE", global = true)]
+    encryption_key_file: Option<PathBuf>,
+
+    /// The key the database files are encrypted with, instead of `--encryption-key-file`
+    #[arg(
+        long,
+        env = "KVS_ENCRYPTION_KEY",
+        hide_env_values = true,
+        global = true
+    )]
+    encryption_key: Option<String>,
+
+    /// A file holding a key the database files were encrypted with before the current one; can
+    /// be repeated
+    #[arg(long = "old-encryption-key-file", global = true)]
+    old_encryption_key_files: Vec<PathBuf>,
+}
+
+#[derive(Parser)]
+enum Commands {
+    /// Copy every key from one engine to another, then switch the data directory over to the
+    /// new engine
+    Migrate {
+        /// The engine currently holding the data
+        #[arg(long)]
+        from: Engine,
+        /// The engine to move the data to
+        #[arg(long)]
+        to: Engine,
+    },
+    /// Write every key and value to a file, or to stdout
+    Dump {
+        /// The format of the dump
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        /// The file to write to [default: stdout]
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Load the keys and values of a dump, overwriting existing keys
+    Restore {
+        /// The dump to load
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// The format of the dump [default: guessed from the file extension]
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// The engine to restore into [default: the engine already in the data directory]
+        #[arg(long)]
+        engine: Option<Engine>,
+    },
+    /// Check the integrity of the database files
+    Verify,
+    /// Print the number of keys, stale records and the size on disk
+    Stats,
+    /// Print a new random encryption key, to be saved in a key file
+    GenKey,
+}
+
+fn main() -> kvs::Result<()> {
+    let cli = Cli::parse();
+    if let Commands::GenKey = cli.command {
+        println!("{}", EncryptionKey::generate());
+        return Ok(());
+    }
+    let _data_dir_lock = lock_data_dir(&cli.data_dir)?;
+    let keys = Keyring::load(
+        cli.encryption_key.as_deref(),
+        cli.encryption_key_file.as_deref(),
+        &cli.old_encryption_key_files,
+    )?;
+
+    match cli.command {
+        Commands::Migrate { from, to } => migrate(&cli.data_dir, &from, &to, &keys)?,
+        Commands::Dump { format, out } => {
+            let store = open_engine(&detect_engine(&cli.data_dir)?, &cli.data_dir, &keys)?;
+            let count = match out {
+                Some(out) => {
+                    let file = File::create(&out).with_whatever_context(|_| {
+                        format!("Couldn't create dump file {}", out.display())
+                    })?;
+                    dump(store.as_ref(), &format, BufWriter::new(file))?
+                }
+                None => dump(store.as_ref(), &format, stdout().lock())?,
+            };
+            eprintln!("Dumped {} records", count);
+        }
+        Commands::Restore {
+            input,
+            format,
+            engine,
+        } => {
+            let engine = match engine {
+                Some(engine) => engine,
+                None => detect_engine(&cli.data_dir)?,
+            };
+            let format = format.unwrap_or_else(|| Format::from_path(&input));
+            let file = File::open(&input).with_whatever_context(|_| {
+                format!("Couldn't open dump file {}", input.display())
+            })?;
+            let mut store = open_engine(&engine, &cli.data_dir, &keys)?;
+            let count = restore(store.as_mut(), &format, BufReader::new(file))?;
+            eprintln!("Restored {} records", count);
+        }
+        Commands::Verify => verify(&cli.data_dir, &detect_engine(&cli.data_dir)?, &keys)?,
+        Commands::Stats => stats(&cli.data_dir, &detect_engine(&cli.data_dir)?, &keys)?,
+        Commands::GenKey => unreachable!("handled before locking the data directory"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,37 @@
+use crate::cli::dump::{read_records, report_progress, DumpRecord, DumpWriter, Format};
+use kvs::index::is_reserved;
+use kvs::{KvsEngine, Result};
+use std::io::{Read, Write};
+
+/// Writes every pair of `store` to `writer`, in key order. Returns the number of written records.
+/// The keys kept for indexes are left out, as the server builds them again from the values.
+pub fn dump(store: &dyn KvsEngine, format: &Format, writer: impl Write) -> Result<usize> {
+    let mut writer = DumpWriter::new(format, writer);
+    let mut count = 0;
+    for pair in store.scan(String::new())? {
+        let (key, value) = pair?;
+        if is_reserved(&key) {
+            continue;
+        }
+        writer.write(&DumpRecord { key, value })?;
+        count += 1;
+        report_progress("Dumped", count);
+    }
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// Sets every record read from `reader` into `store`, overwriting existing keys. Returns the
+/// number of restored records.
+pub fn restore(store: &mut dyn KvsEngine, format: &Format, reader: impl Read) -> Result<usize> {
+    let mut count = 0;
+    for record in read_records(format, reader) {
+        let record = record?;
+        store.set(record.key, record.value)?;
+        count += 1;
+        report_progress("Restored", count);
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,34 @@
+use crate::cli::engine::Engine;
+use kvs::encryption::Keyring;
+use kvs::{KvStoreV2, KvsEngine, Result, SledStore};
+use snafu::whatever;
+use std::path::Path;
+
+pub fn open_engine(engine: &Engine, dir: &Path, keys: &Keyring) -> Result<Box<dyn KvsEngine>> {
+    match engine {
+        Engine::Kvs => Ok(Box::new(KvStoreV2::open_with_keys(dir, keys.clone())?)),
+        Engine::Sled => Ok(Box::new(SledStore::open_with_keys(dir, keys.clone())?)),
+        Engine::Mem => whatever!("The {} engine keeps no data on disk", engine),
+    }
+}
+
+/// Finds out which engine the data inside `data_dir` belongs to, from the database file that
+/// exists there.
+pub fn detect_engine(data_dir: &Path) -> Result<Engine> {
+    let engines: Vec<Engine> = [Engine::Kvs, Engine::Sled]
+        .into_iter()
+        .filter(|engine| {
+            engine
+                .db_file_name()
+                .is_some_and(|file_name| data_dir.join(file_name).exists())
+        })
+        .collect();
+    match &engines[..] {
+        [engine] => Ok(engine.clone()),
+        [] => whatever!("No database file found in {}", data_dir.display()),
+        _ => whatever!(
+            "Database files of more than one engine found in {}",
+            data_dir.display()
+        ),
+    }
+}
@@ -0,0 +1,143 @@
+use super::engine::open_engine;
+use crate::cli::engine::{check_engine_db_file, Engine};
+use kvs::encryption::Keyring;
+use kvs::{KvsEngine, Result};
+use snafu::{whatever, ResultExt};
+use std::fs;
+use std::path::Path;
+
+/// Where the destination engine is filled before being moved into the data directory.
+const STAGING_DIR_NAME: &str = ".kvs-migrate";
+/// Where the source engine's database file is moved to once the migration is done.
+const BACKUP_DIR_NAME: &str = ".kvs-migrate-backup";
+const PROGRESS_INTERVAL: usize = 10_000;
+
+/// Copies every pair from `source` into `destination`, then reads them all back to make sure
+/// nothing got lost on the way. Returns the number of migrated keys.
+fn copy_and_verify(source: &dyn KvsEngine, destination: &mut dyn KvsEngine) -> Result<usize> {
+    let mut copied: usize = 0;
+    for pair in source.scan(String::new())? {
+        let (key, value) = pair?;
+        destination.set(key, value)?;
+        copied += 1;
+        if copied.is_multiple_of(PROGRESS_INTERVAL) {
+            println!("Copied {} keys", copied);
+        }
+    }
+    println!("Copied {} keys, verifying", copied);
+
+    let mut verified: usize = 0;
+    for pair in source.scan(String::new())? {
+        let (key, value) = pair?;
+        if destination.get(key.clone())?.as_ref() != Some(&value) {
+            whatever!("Value of key {} differs after migration", key);
+        }
+        verified += 1;
+        if verified.is_multiple_of(PROGRESS_INTERVAL) {
+            println!("Verified {} keys", verified);
+        }
+    }
+    let destination_count = destination.scan(String::new())?.count();
+    if verified != copied || destination_count != copied {
+        whatever!(
+            "Copied {} keys, but verified {} and found {} in the destination",
+            copied,
+            verified,
+            destination_count,
+        );
+    }
+    println!("Verified {} keys", verified);
+
+    Ok(copied)
+}
+
+/// Moves the data inside `data_dir` from one engine to another.
+///
+/// The destination engine is filled and verified inside a staging directory first, so a failure
+/// at that point leaves the data directory untouched. The swap itself is two renames: the new
+/// database file is moved in first, then the old one is moved out to a backup directory. A crash
+/// in between leaves both files in place, which makes the server refuse to start instead of
+/// silently serving the wrong data.
+///
+/// Both engines are opened with `keys`, so encrypted data stays encrypted.
+pub fn migrate(data_dir: &Path, from: &Engine, to: &Engine, keys: &Keyring) -> Result<()> {
+    if from == to {
+        whatever!("Source and destination engines are both {}", from);
+    }
+    let (Some(from_file_name), Some(to_file_name)) = (from.db_file_name(), to.db_file_name())
+    else {
+        whatever!(
+            "Can't migrate between {} and {}: the in-memory engine keeps no data on disk",
+            from,
+            to
+        );
+    };
+
+    let from_path = data_dir.join(from_file_name);
+    let to_path = data_dir.join(to_file_name);
+    let staging_dir = data_dir.join(STAGING_DIR_NAME);
+    let backup_dir = data_dir.join(BACKUP_DIR_NAME);
+    if !from_path.exists() {
+        whatever!(
+            "No database file for engine {} at {}",
+            from,
+            from_path.display()
+        );
+    }
+    check_engine_db_file(from, data_dir)?;
+    for dir in [&staging_dir, &backup_dir] {
+        if dir.exists() {
+            whatever!(
+                "{} is left over from a previous migration; remove it first",
+                dir.display(),
+            );
+        }
+    }
+
+    println!("Migrating {} from {} to {}", data_dir.display(), from, to);
+    fs::create_dir(&staging_dir).with_whatever_context(|_| {
+        format!(
+            "Couldn't create staging directory {}",
+            staging_dir.display()
+        )
+    })?;
+    let copied = {
+        let source = open_engine(from, data_dir, keys)?;
+        let mut destination = open_engine(to, &staging_dir, keys)?;
+        copy_and_verify(source.as_ref(), destination.as_mut())
+    };
+    let copied = match copied {
+        Ok(copied) => copied,
+        Err(err) => {
+            let _ = fs::remove_dir_all(&staging_dir);
+            return Err(err);
+        }
+    };
+
+    fs::rename(staging_dir.join(to_file_name), &to_path).with_whatever_context(|_| {
+        format!("Couldn't move migrated data to {}", to_path.display())
+    })?;
+    fs::create_dir(&backup_dir).with_whatever_context(|_| {
+        format!("Couldn't create backup directory {}", backup_dir.display())
+    })?;
+    let backup_path = backup_dir.join(from_file_name);
+    fs::rename(&from_path, &backup_path).with_whatever_context(|_| {
+        format!("Couldn't move {} out of the way", from_path.display())
+    })?;
+    fs::remove_dir_all(&staging_dir).with_whatever_context(|_| {
+        format!(
+            "Couldn't remove staging directory {}",
+            staging_dir.display()
+        )
+    })?;
+
+    println!(
+        "Migrated {} keys from {} to {}; the old database file was kept at {}",
+        copied,
+        from,
+        to,
+        backup_path.display(),
+    );
+
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use super::engine::open_engine;
+use crate::cli::engine::Engine;
+use kvs::encryption::Keyring;
+use kvs::Result;
+use std::path::Path;
+
+pub fn stats(data_dir: &Path, engine: &Engine, keys: &Keyring) -> Result<()> {
+    let store = open_engine(engine, data_dir, keys)?;
+    let stats = store.stats()?;
+
+    println!("Engine: {}", engine);
+    println!("Live keys: {}", stats.live_keys);
+    if let (Some(stale_records), Some(ratio)) = (stats.stale_records, stats.stale_ratio()) {
+        println!(
+            "Stale records: {} ({:.1}% of the log)",
+            stale_records,
+            ratio * 100.0
+        );
+    }
+    println!("Disk size: {} bytes", stats.disk_size);
+    if let Some(compacted_size) = stats.compacted_size {
+        let savings = stats.disk_size.saturating_sub(compacted_size);
+        let ratio = if stats.disk_size == 0 {
+            0.0
+        } else {
+            savings as f64 / stats.disk_size as f64 * 100.0
+        };
+        println!("Size after compaction: {} bytes", compacted_size);
+        println!("Compaction savings: {} bytes ({:.1}%)", savings, ratio);
+    }
+    if let (Some(value_size), Some(stored_value_size), Some(ratio)) = (
+        stats.value_size,
+        stats.stored_value_size,
+        stats.compression_ratio(),
+    ) {
+        println!(
+            "Values: {} bytes, stored in {} bytes (compression ratio {:.2})",
+            value_size, stored_value_size, ratio
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use super::engine::open_engine;
+use crate::cli::engine::Engine;
+use kvs::encryption::Keyring;
+use kvs::{Command, Error, Result};
+use snafu::{whatever, ResultExt};
+use std::fs;
+use std::path::Path;
+
+/// Checks every record of a `kvs` log: each line has to be a `Set` or `Rm` command or a batch of
+/// them, encrypted records have to decrypt, compressed values have to decompress, and the last
+/// line has to be complete. Returns the number of valid records and the problems found.
+fn verify_kvs_log(file_path: &Path, keys: &Keyring) -> Result<(usize, Vec<String>)> {
+    let content = fs::read(file_path).with_whatever_context(|_| {
+        format!("Couldn't read content of file at {}", file_path.display())
+    })?;
+    let mut records = 0;
+    let mut problems = Vec::new();
+
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        problems.push("the last record is incomplete, it may have been cut off".to_owned());
+    }
+    for (index, line) in content.split(|byte| *byte == b'\n').enumerate() {
+        let line_number = index + 1;
+        let Ok(line) = std::str::from_utf8(line) else {
+            problems.push(format!("line {} is not valid UTF-8", line_number));
+            continue;
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line = match kvs::decrypt_log_line(line, keys) {
+            Ok(line) => line,
+            // Not a problem of the log, which would show on every line
+            Err(err @ Error::WrongEncryptionKey { .. }) => return Err(err),
+            Err(err) => {
+                problems.push(format!("line {} can't be decrypted: {}", line_number, err));
+                continue;
+            }
+        };
+        match serde_json::from_str::<Command>(&line) {
+            Ok(Command::Set { .. }) => match kvs::decode_log_line(&line, keys) {
+                Ok(_) => records += 1,
+                Err(err) => {
+                    problems.push(format!(
+                        "line {} has an unreadable value: {}",
+                        line_number, err
+                    ));
+                }
+            },
+            Ok(Command::Rm { .. }) => records += 1,
+            // The engine refuses to open a log holding any other command
+            Ok(Command::Get { .. }) => {
+                problems.push(format!("line {} is a Get command", line_number));
+            }
+            Ok(Command::Scan { .. }) => {
+                problems.push(format!("line {} is a Scan command", line_number));
+            }
+            Ok(Command::Backup) => {
+                problems.push(format!("line {} is a Backup command", line_number));
+            }
+            Ok(Command::Restore { .. }) => {
+                problems.push(format!("line {} is a Restore command", line_number));
+            }
+            // Typed values are logged as the `Set` of their encoding
+            Ok(command) => {
+                problems.push(format!(
+                    "line {} is a {} command",
+                    line_number,
+                    command.name().to_uppercase()
+                ));
+            }
+            // A batch of writes, logged as a single line
+            Err(err) => match kvs::decode_log_line(&line, keys) {
+                Ok(commands) => records += commands.len(),
+                Err(_) => {
+                    problems.push(format!(
+                        "line {} is not a valid command: {}",
+                        line_number, err
+                    ));
+                }
+            },
+        }
+    }
+
+    Ok((records, problems))
+}
+
+/// Checks that every value of a `sled` database can be read back.
+fn verify_sled(data_dir: &Path, keys: &Keyring) -> Result<(usize, Vec<String>)> {
+    let store = open_engine(&Engine::Sled, data_dir, keys)?;
+    let mut records = 0;
+    let mut problems = Vec::new();
+    for pair in store.scan(String::new())? {
+        match pair {
+            Ok(_) => records += 1,
+            Err(err) => problems.push(err.to_string()),
+        }
+    }
+
+    Ok((records, problems))
+}
+
+pub fn verify(data_dir: &Path, engine: &Engine, keys: &Keyring) -> Result<()> {
+    let (records, problems) = match engine {
+        Engine::Kvs => verify_kvs_log(&data_dir.join(kvs::DEFAULT_FILE_NAME_KVS), keys)?,
+        Engine::Sled => verify_sled(data_dir, keys)?,
+        Engine::Mem => whatever!("The {} engine keeps no data on disk", engine),
+    };
+
+    for problem in problems.iter() {
+        println!("Problem: {}", problem);
+    }
+    println!("Checked {} records of engine {}", records, engine);
+    if !problems.is_empty() {
+        whatever!(
+            "Found {} problems in {}",
+            problems.len(),
+            data_dir.display()
+        );
+    }
+    println!("No problems found");
+
+    Ok(())
+}
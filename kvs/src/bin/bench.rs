@@ -0,0 +1,390 @@
+use clap::{Parser, ValueEnum};
+use cli::parse_addr::parse_addr;
+use kvs::client::{AsyncKvsClient, ClientConfig, Protocol};
+use kvs::{Command, Result};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use snafu::{whatever, ResultExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod cli {
+    pub mod parse_addr;
+}
+
+/// Sets sent at once while preloading the keys.
+const PRELOAD_BATCH: usize = 1000;
+
+/// Drives a running server with a mix of gets and sets, then reports the throughput and the
+/// latency percentiles of each.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// The address of the server
+    #[arg(long, default_value_t = String::from("127.0.0.1:4004"))]
+    addr: String,
+
+    /// The protocol of the server: `tcp` for `kvs-server`, `http` for `server`
+    #[arg(long, value_enum, default_value_t = Transport::Tcp)]
+    protocol: Transport,
+
+    /// The token to authenticate with, for servers requiring authentication
+    #[arg(long, env = "KVS_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Requests in flight at the same time
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    /// Seconds to run for
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+
+    /// The share of gets among the requests, from 0 (only sets) to 1 (only gets)
+    #[arg(long, default_value_t = 0.9)]
+    read_ratio: f64,
+
+    /// How many distinct keys to use
+    #[arg(long, default_value_t = 10_000)]
+    keys: usize,
+
+    /// How keys are picked
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// The exponent of the Zipf distribution: the higher, the more requests go to the first keys
+    #[arg(long, default_value_t = 0.99)]
+    zipf_exponent: f64,
+
+    /// The size in bytes of the values set, `N` or a `MIN-MAX` range
+    #[arg(long, default_value = "100", value_parser = parse_size_range)]
+    value_size: (usize, usize),
+
+    /// Don't set every key before starting, so that gets may miss
+    #[arg(long)]
+    no_preload: bool,
+
+    /// Ask the server to compress its responses
+    #[arg(long)]
+    compress: bool,
+
+    /// Seed of the random choices, for runs that can be repeated
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum Transport {
+    Tcp,
+    Http,
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Debug)]
+enum Distribution {
+    /// Every key is as likely
+    Uniform,
+    /// A few keys get most requests, as with real traffic
+    Zipf,
+}
+
+/// Picks key indexes in `0..keys` along a distribution.
+enum KeyPicker {
+    Uniform {
+        keys: usize,
+    },
+    /// The cumulative probabilities of the keys, searched for a uniform sample.
+    Zipf {
+        cdf: Vec<f64>,
+    },
+}
+
+impl KeyPicker {
+    fn new(distribution: Distribution, keys: usize, exponent: f64) -> Self {
+        match distribution {
+            Distribution::Uniform => KeyPicker::Uniform { keys },
+            Distribution::Zipf => {
+                let mut total = 0.0;
+                let mut cdf: Vec<f64> = (1..=keys)
+                    .map(|rank| {
+                        total += 1.0 / (rank as f64).powf(exponent);
+                        total
+                    })
+                    .collect();
+                for probability in &mut cdf {
+                    *probability /= total;
+                }
+                KeyPicker::Zipf { cdf }
+            }
+        }
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            KeyPicker::Uniform { keys } => rng.gen_range(0..*keys),
+            KeyPicker::Zipf { cdf } => {
+                let sample: f64 = rng.gen();
+                cdf.partition_point(|probability| *probability < sample)
+                    .min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+/// What a worker saw.
+#[derive(Default)]
+struct Latencies {
+    gets: Vec<Duration>,
+    sets: Vec<Duration>,
+    errors: usize,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    parse_addr(&cli.addr)?;
+    if cli.keys == 0 || cli.concurrency == 0 {
+        whatever!("--keys and --concurrency must be at least 1");
+    }
+    if !(0.0..=1.0).contains(&cli.read_ratio) {
+        whatever!("--read-ratio must be between 0 and 1");
+    }
+    let client = AsyncKvsClient::new(ClientConfig {
+        protocol: match cli.protocol {
+            Transport::Tcp => Protocol::Tcp,
+            Transport::Http => Protocol::Http,
+        },
+        token: cli.token.clone(),
+        // A failed request is counted rather than hidden behind retries
+        retries: 0,
+        max_connections: cli.concurrency,
+        compression: cli.compress,
+        ..ClientConfig::new(cli.addr.clone())
+    })?;
+    let runtime =
+        tokio::runtime::Runtime::new().with_whatever_context(|_| "Unable to start the runtime")?;
+    runtime.block_on(run(cli, client))
+}
+
+async fn run(cli: Cli, client: AsyncKvsClient) -> Result<()> {
+    let seed = cli.seed.unwrap_or_else(rand::random);
+    let mut rng = StdRng::seed_from_u64(seed);
+    if !cli.no_preload {
+        let started = Instant::now();
+        preload(&client, &cli, &mut rng).await?;
+        eprintln!(
+            "Preloaded {} keys in {:.2} s",
+            cli.keys,
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    let picker = Arc::new(KeyPicker::new(
+        cli.distribution,
+        cli.keys,
+        cli.zipf_exponent,
+    ));
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(cli.duration);
+    let workers: Vec<_> = (0..cli.concurrency)
+        .map(|worker| {
+            let client = client.clone();
+            let picker = picker.clone();
+            let rng = StdRng::seed_from_u64(seed.wrapping_add(worker as u64 + 1));
+            let (read_ratio, value_size) = (cli.read_ratio, cli.value_size);
+            tokio::spawn(async move {
+                work(client, &picker, rng, read_ratio, value_size, deadline).await
+            })
+        })
+        .collect();
+    let mut latencies = Latencies::default();
+    for worker in workers {
+        let worker = worker
+            .await
+            .with_whatever_context(|_| "A worker panicked")?;
+        latencies.gets.extend(worker.gets);
+        latencies.sets.extend(worker.sets);
+        latencies.errors += worker.errors;
+    }
+    let elapsed = started.elapsed();
+
+    let count = latencies.gets.len() + latencies.sets.len();
+    println!(
+        "{} requests in {:.2} s: {:.0} requests/s, {} errors (seed {})",
+        count,
+        elapsed.as_secs_f64(),
+        count as f64 / elapsed.as_secs_f64(),
+        latencies.errors,
+        seed
+    );
+    println!(
+        "{:<6}{:>10}{:>12}{:>12}{:>12}{:>12}",
+        "", "count", "p50", "p99", "p999", "max"
+    );
+    let mut all = [latencies.gets.as_slice(), latencies.sets.as_slice()].concat();
+    for (name, samples) in [
+        ("get", &mut latencies.gets),
+        ("set", &mut latencies.sets),
+        ("all", &mut all),
+    ] {
+        samples.sort_unstable();
+        println!(
+            "{:<6}{:>10}{:>12}{:>12}{:>12}{:>12}",
+            name,
+            samples.len(),
+            format_latency(percentile(samples, 0.5)),
+            format_latency(percentile(samples, 0.99)),
+            format_latency(percentile(samples, 0.999)),
+            format_latency(samples.last().copied()),
+        );
+    }
+    Ok(())
+}
+
+/// Sets every key, so that gets find them.
+async fn preload(client: &AsyncKvsClient, cli: &Cli, rng: &mut StdRng) -> Result<()> {
+    let indexes: Vec<_> = (0..cli.keys).collect();
+    for chunk in indexes.chunks(PRELOAD_BATCH) {
+        let commands: Vec<_> = chunk
+            .iter()
+            .map(|index| Command::Set {
+                key: key(*index),
+                value: value(rng, cli.value_size),
+            })
+            .collect();
+        for response in client.pipeline(&commands).await {
+            response?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends requests one after the other until `deadline`.
+async fn work(
+    client: AsyncKvsClient,
+    picker: &KeyPicker,
+    mut rng: StdRng,
+    read_ratio: f64,
+    value_size: (usize, usize),
+    deadline: Instant,
+) -> Latencies {
+    let mut latencies = Latencies::default();
+    while Instant::now() < deadline {
+        let key = key(picker.pick(&mut rng));
+        let read = rng.gen_bool(read_ratio);
+        let started = Instant::now();
+        let result = if read {
+            client.get(&key).await.map(|_| ())
+        } else {
+            let value = value(&mut rng, value_size);
+            client.set(&key, &value).await
+        };
+        let elapsed = started.elapsed();
+        match (result, read) {
+            (Ok(()), true) => latencies.gets.push(elapsed),
+            (Ok(()), false) => latencies.sets.push(elapsed),
+            (Err(_), _) => latencies.errors += 1,
+        }
+    }
+    latencies
+}
+
+fn key(index: usize) -> String {
+    format!("key{:08}", index)
+}
+
+/// A value without whitespace, which the TCP protocol can't carry.
+fn value(rng: &mut impl Rng, (min, max): (usize, usize)) -> String {
+    let len = rng.gen_range(min..=max);
+    Alphanumeric.sample_string(rng, len)
+}
+
+/// The latency below which `quantile` of the sorted `samples` fall.
+fn percentile(samples: &[Duration], quantile: f64) -> Option<Duration> {
+    if samples.is_empty() {
+        return None;
+    }
+    let index = ((samples.len() as f64 * quantile).ceil() as usize).clamp(1, samples.len()) - 1;
+    Some(samples[index])
+}
+
+fn format_latency(latency: Option<Duration>) -> String {
+    match latency {
+        Some(latency) => format!("{:.3} ms", latency.as_secs_f64() * 1000.0),
+        None => "-".to_owned(),
+    }
+}
+
+fn parse_size_range(range: &str) -> std::result::Result<(usize, usize), String> {
+    let parse = |size: &str| {
+        size.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid size {}", size))
+    };
+    let (min, max) = match range.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(range)?, parse(range)?),
+    };
+    if min == 0 || min > max {
+        return Err(format!(
+            "Invalid size range {}, expected 1 <= MIN <= MAX",
+            range
+        ));
+    }
+    Ok((min, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zipf_prefers_first_keys() {
+        let picker = KeyPicker::new(Distribution::Zipf, 1000, 0.99);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = vec![0; 1000];
+        for _ in 0..100_000 {
+            counts[picker.pick(&mut rng)] += 1;
+        }
+        assert!(counts[0] > counts[1]);
+        assert!(counts[1] > counts[10]);
+        assert!(counts[10] > counts[500]);
+        // About 1 / H(1000, 0.99), i.e. 13%
+        assert!((10_000..16_000).contains(&counts[0]), "{}", counts[0]);
+    }
+
+    #[test]
+    fn uniform_covers_keys() {
+        let picker = KeyPicker::new(Distribution::Uniform, 10, 0.99);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = [0; 10];
+        for _ in 0..10_000 {
+            counts[picker.pick(&mut rng)] += 1;
+        }
+        assert!(counts.iter().all(|count| (800..1200).contains(count)));
+    }
+
+    #[test]
+    fn percentiles() {
+        let samples: Vec<_> = (1..=1000).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&samples, 0.5), Some(Duration::from_millis(500)));
+        assert_eq!(percentile(&samples, 0.99), Some(Duration::from_millis(990)));
+        assert_eq!(
+            percentile(&samples, 0.999),
+            Some(Duration::from_millis(999))
+        );
+        assert_eq!(
+            percentile(&samples[..1], 0.999),
+            Some(Duration::from_millis(1))
+        );
+        assert_eq!(percentile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn size_ranges() {
+        assert_eq!(parse_size_range("100"), Ok((100, 100)));
+        assert_eq!(parse_size_range("10-1000"), Ok((10, 1000)));
+        assert!(parse_size_range("0").is_err());
+        assert!(parse_size_range("10-5").is_err());
+        assert!(parse_size_range("big").is_err());
+    }
+}
@@ -0,0 +1,568 @@
+use super::dump::{read_records, report_progress, DumpRecord, DumpWriter, Format};
+use super::parse_addr::parse_addr;
+use super::repl;
+use clap::Parser;
+use kvs::backup::read_backup;
+use kvs::client::{ClientConfig, KvsClient, Protocol};
+use kvs::namespace::NamespaceConfig;
+use kvs::watch::Position;
+use kvs::{Command, Error, Result};
+use serde::Deserialize;
+use snafu::{whatever, ResultExt};
+use std::fs::{self, File};
+use std::io::{stdout, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(version)]
+#[command(propagate_version = true)]
+pub struct Cli {
+    #[clap(subcommand)]
+    command: Commands,
+
+    /// The address of the server, or of several separated by commas to spread the keys over
+    /// them [default: 127.0.0.1:4004]
+    #[arg(long, global = true)]
+    addr: Option<String>,
+
+    /// With several servers, how many of them hold every key [default: 1]
+    #[arg(long, global = true)]
+    replicas: Option<usize>,
+
+    /// A TOML file listing the servers, as `nodes = ["host:port", ...]`, and `replicas`
+    #[arg(long, env = "KVS_CLIENT_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// The token to authenticate with, for servers requiring authentication
+    #[arg(long, env = "KVS_TOKEN", hide_env_values = true, global = true)]
+    token: Option<String>,
+
+    /// Connect over TLS, trusting the server certificates signed by this PEM CA certificate
+    #[arg(long, env = "KVS_CA", global = true)]
+    ca: Option<PathBuf>,
+
+    /// A PEM client certificate, for servers requiring one
+    #[arg(long, env = "KVS_CERT", requires_all = ["ca", "cert_key"], global = true)]
+    cert: Option<PathBuf>,
+
+    /// The PEM private key of `--cert`
+    #[arg(long, env = "KVS_CERT_KEY", requires = "cert", global = true)]
+    cert_key: Option<PathBuf>,
+
+    /// Seconds to wait for the response of the server
+    #[arg(long, default_value_t = 30, global = true)]
+    timeout: u64,
+
+    /// How many times to try again a request that failed on the way, or that the server refused
+    /// for now
+    #[arg(long, default_value_t = 3, global = true)]
+    retries: u32,
+
+    /// Ask the server to compress its responses, for servers started with `--compression`
+    #[arg(long, global = true)]
+    compress: bool,
+
+    /// The namespace to run the commands in [default: the default namespace]
+    #[arg(long, env = "KVS_NAMESPACE", global = true)]
+    namespace: Option<String>,
+}
+
+#[derive(Parser)]
+enum Commands {
+    /// Get a value from the store
+    Get {
+        /// The key to be retrieved
+        key: String,
+    },
+    /// Set a value in the store
+    Set {
+        /// The key to be set
+        key: String,
+        /// The value to set
+        value: String,
+    },
+    /// Remove a value from the store
+    Rm {
+        /// The key to be removed
+        key: String,
+    },
+    /// Print the pairs whose key starts with a prefix, in key order
+    Scan {
+        /// The prefix of the keys to print; all of them without it
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+    /// Print the pairs whose JSON value holds a value at the path of an index, in key order
+    Find {
+        /// The index, as `<collection>.<name>` declared in the config file of the server
+        index: String,
+        /// The value to look for; numbers and booleans as they are written in JSON
+        value: String,
+    },
+    /// Push values at the front of a list, printing its length
+    Lpush {
+        /// The key of the list
+        key: String,
+        /// The values to push, one after the other
+        #[arg(required = true)]
+        values: Vec<String>,
+    },
+    /// Print the elements of a list, one per line
+    Lrange {
+        /// The key of the list
+        key: String,
+        /// The index of the first element; negative ones count from the end
+        #[arg(default_value_t = 0, allow_negative_numbers = true)]
+        start: i64,
+        /// The index of the last element; -1 is the last one
+        #[arg(default_value_t = -1, allow_negative_numbers = true)]
+        stop: i64,
+    },
+    /// Set a field of a hash
+    Hset {
+        /// The key of the hash
+        key: String,
+        /// The field to set
+        field: String,
+        /// The value to set
+        value: String,
+    },
+    /// Get a field of a hash
+    Hget {
+        /// The key of the hash
+        key: String,
+        /// The field to get
+        field: String,
+    },
+    /// Add members to a set, printing how many were new
+    Sadd {
+        /// The key of the set
+        key: String,
+        /// The members to add
+        #[arg(required = true)]
+        members: Vec<String>,
+    },
+    /// Print the members of a set, one per line
+    Smembers {
+        /// The key of the set
+        key: String,
+    },
+    /// Add to the integer held by a key, printing the result
+    Incrby {
+        /// The key of the counter, which starts at 0
+        key: String,
+        /// The amount to add, which may be negative
+        #[arg(allow_negative_numbers = true)]
+        delta: i64,
+    },
+    /// Run a Lua script on the server, printing what it returned as JSON
+    Eval {
+        /// The script, which reads and writes keys through `kvs.get`, `kvs.set` and `kvs.remove`
+        #[arg(required_unless_present = "file", conflicts_with = "file")]
+        script: Option<String>,
+        /// The file to read the script from
+        #[arg(long)]
+        file: Option<PathBuf>,
+        /// A key the script may touch, given to it in `KEYS`; may be repeated
+        #[arg(long = "key")]
+        keys: Vec<String>,
+        /// An argument given to the script in `ARGV`; may be repeated
+        #[arg(long = "arg", allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+    /// Save a consistent snapshot of the whole store to a file
+    Backup {
+        /// The file to write the backup archive to
+        #[arg(long)]
+        out: PathBuf,
+    },
+    /// Load a backup archive into an empty store
+    Restore {
+        /// The backup archive to load
+        #[arg(long = "in")]
+        input: PathBuf,
+    },
+    /// Set every pair of a JSONL or CSV file, as written by `export` or `kvs-admin dump`,
+    /// overwriting existing keys
+    Import {
+        /// The file to load
+        file: PathBuf,
+        /// The format of the file [default: guessed from the file extension]
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// How many sets to send at once
+        #[arg(long, default_value_t = 1000)]
+        batch_size: usize,
+        /// Skip this many records first, to resume an import that failed
+        #[arg(long, default_value_t = 0)]
+        skip: usize,
+    },
+    /// Write the pairs whose key starts with a prefix to a file, or to stdout
+    Export {
+        /// The prefix of the keys to export; all of them without it
+        #[arg(long, default_value = "")]
+        prefix: String,
+        /// The format of the export [default: guessed from the extension of `--out`]
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+        /// The file to write to [default: stdout]
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Print every change of a key, or of the keys under a prefix, as one JSON line each
+    Watch {
+        /// The key to watch, or a prefix followed by `*`
+        pattern: String,
+        /// Resume after the change at this position, `<epoch>-<seq>` as printed with every event
+        #[arg(long, value_parser = parse_position)]
+        from: Option<Position>,
+    },
+    /// Check which servers are up, exiting with 1 if one isn't
+    Health,
+    /// Create, drop or list the namespaces of the servers
+    #[clap(subcommand)]
+    Namespace(NamespaceCommands),
+    /// Run commands interactively, with line editing and history, in one session
+    Repl {
+        /// Run the commands of this file instead, stopping at the first failing one
+        #[arg(long)]
+        script: Option<PathBuf>,
+    },
+}
+
+#[derive(Parser)]
+enum NamespaceCommands {
+    /// Create an empty namespace on every server
+    Create {
+        /// The name of the namespace, made of letters, digits, `-` and `_`
+        name: String,
+        /// The most keys the namespace may hold
+        #[arg(long)]
+        max_keys: Option<u64>,
+        /// The most bytes of keys and values the namespace may hold
+        #[arg(long)]
+        max_bytes: Option<u64>,
+        /// Keep the namespace in this directory of the server [default: under its data directory]
+        #[arg(long)]
+        dir: Option<PathBuf>,
+    },
+    /// Drop a namespace and its keys on every server
+    Drop {
+        /// The name of the namespace
+        name: String,
+    },
+    /// Print the namespaces of the server, one JSON object per line
+    List,
+}
+
+/// The servers to spread the keys over, as read from `--config`.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ClientFile {
+    #[serde(default)]
+    nodes: Vec<String>,
+    replicas: Option<usize>,
+}
+
+impl Cli {
+    /// The servers, from `--addr`, then the config file, then the default.
+    fn addr_and_replicas(&self) -> Result<(String, usize)> {
+        let file = match &self.config {
+            Some(path) => {
+                let content = fs::read_to_string(path).with_whatever_context(|_| {
+                    format!("Couldn't read config file at {}", path.display())
+                })?;
+                toml::from_str(&content).with_whatever_context(|err| {
+                    format!("Couldn't parse config file at {}: {}", path.display(), err)
+                })?
+            }
+            None => ClientFile::default(),
+        };
+        let addr = match &self.addr {
+            Some(addr) => addr.clone(),
+            None if !file.nodes.is_empty() => file.nodes.join(","),
+            None => "127.0.0.1:4004".to_owned(),
+        };
+        for node in addr.split(',') {
+            parse_addr(node.trim())?;
+        }
+        let replicas = self.replicas.or(file.replicas).unwrap_or(1);
+        if replicas == 0 {
+            whatever!("There must be at least 1 replica");
+        }
+        Ok((addr, replicas))
+    }
+
+    fn client(&self, protocol: Protocol) -> Result<KvsClient> {
+        let (addr, replicas) = self.addr_and_replicas()?;
+        let tls = match &self.ca {
+            Some(ca) => {
+                let identity = self.cert.as_deref().zip(self.cert_key.as_deref());
+                Some(kvs::tls::client_config(ca, identity)?)
+            }
+            None => None,
+        };
+        KvsClient::new(ClientConfig {
+            protocol,
+            token: self.token.clone(),
+            tls,
+            timeout: Duration::from_secs(self.timeout),
+            retries: self.retries,
+            replicas,
+            compression: self.compress,
+            namespace: self.namespace.clone(),
+            ..ClientConfig::new(addr)
+        })
+    }
+}
+
+/// Parses the arguments and runs the command against a server speaking `protocol`, exiting with
+/// 1 when the server refuses it.
+pub fn run(protocol: Protocol) -> Result<()> {
+    let cli = Cli::parse();
+    let client = cli.client(protocol)?;
+    if let Err(err) = run_command(&client, cli.command) {
+        eprintln!("{}", err);
+        exit(1);
+    }
+    Ok(())
+}
+
+fn run_command(client: &KvsClient, command: Commands) -> Result<()> {
+    match command {
+        Commands::Get { key } => match client.get(&key)? {
+            Some(value) => println!("{}", value),
+            // A missing key is an answer too
+            None => println!("Key not found"),
+        },
+        Commands::Set { key, value } => client.set(&key, &value)?,
+        Commands::Rm { key } => match client.remove(&key) {
+            Err(Error::KeyNotFound { .. }) => {
+                eprintln!("Key not found");
+                exit(1);
+            }
+            result => result?,
+        },
+        Commands::Scan { prefix } => {
+            for (key, value) in client.scan(&prefix)? {
+                println!("{} = {}", key, value);
+            }
+        }
+        Commands::Find { index, value } => {
+            for (key, value) in client.find(&index, &value)? {
+                println!("{} = {}", key, value);
+            }
+        }
+        Commands::Lpush { key, values } => {
+            let values: Vec<_> = values.iter().map(String::as_str).collect();
+            println!("{}", client.lpush(&key, &values)?);
+        }
+        Commands::Lrange { key, start, stop } => {
+            for value in client.lrange(&key, start, stop)? {
+                println!("{}", value);
+            }
+        }
+        Commands::Hset { key, field, value } => {
+            client.hset(&key, &field, &value)?;
+        }
+        Commands::Hget { key, field } => match client.hget(&key, &field)? {
+            Some(value) => println!("{}", value),
+            None => println!("Field not found"),
+        },
+        Commands::Sadd { key, members } => {
+            let members: Vec<_> = members.iter().map(String::as_str).collect();
+            println!("{}", client.sadd(&key, &members)?);
+        }
+        Commands::Smembers { key } => {
+            for member in client.smembers(&key)? {
+                println!("{}", member);
+            }
+        }
+        Commands::Incrby { key, delta } => println!("{}", client.incr_by(&key, delta)?),
+        Commands::Eval {
+            script,
+            file,
+            keys,
+            args,
+        } => {
+            let script = match (script, file) {
+                (Some(script), _) => script,
+                (None, Some(file)) => fs::read_to_string(&file).with_whatever_context(|_| {
+                    format!("Unable to read script from {}", file.display())
+                })?,
+                (None, None) => whatever!("A script or a --file is needed"),
+            };
+            let keys: Vec<_> = keys.iter().map(String::as_str).collect();
+            let args: Vec<_> = args.iter().map(String::as_str).collect();
+            println!("{}", client.eval(&script, &keys, &args)?);
+        }
+        Commands::Backup { out } => {
+            // The client verifies the checksum before returning the archive
+            let archive = client.backup()?;
+            let count = read_backup(archive.as_slice())?.len();
+            fs::write(&out, &archive).with_whatever_context(|_| {
+                format!("Unable to write backup to {}", out.display())
+            })?;
+            println!("Backed up {} keys to {}", count, out.display());
+        }
+        Commands::Restore { input } => {
+            let archive = fs::read(&input).with_whatever_context(|_| {
+                format!("Unable to read backup from {}", input.display())
+            })?;
+            let count = client.restore(&archive)?;
+            println!("Restored {} keys", count);
+        }
+        Commands::Import {
+            file,
+            format,
+            batch_size,
+            skip,
+        } => {
+            let format = format.unwrap_or_else(|| Format::from_path(&file));
+            let count = import(client, &file, &format, batch_size.max(1), skip)?;
+            eprintln!("Imported {} records", count);
+        }
+        Commands::Export {
+            prefix,
+            format,
+            out,
+        } => {
+            let format = format.unwrap_or_else(|| match &out {
+                Some(out) => Format::from_path(out),
+                None => Format::Jsonl,
+            });
+            let pairs = client.scan(&prefix)?;
+            let count = match out {
+                Some(out) => {
+                    let file = File::create(&out).with_whatever_context(|_| {
+                        format!("Couldn't create export file {}", out.display())
+                    })?;
+                    export(pairs, &format, BufWriter::new(file))?
+                }
+                None => export(pairs, &format, stdout().lock())?,
+            };
+            eprintln!("Exported {} records", count);
+        }
+        Commands::Watch { pattern, from } => {
+            let events = client.watch(&pattern, from)?;
+            match events.started_at() {
+                Some(position) => eprintln!("Watching {} from position {}", pattern, position),
+                None => eprintln!("Watching {}", pattern),
+            }
+            for event in events {
+                let event = serde_json::to_string(&event?)
+                    .with_whatever_context(|_| "Unable to serialize event")?;
+                println!("{}", event);
+            }
+        }
+        Commands::Health => {
+            let mut down = false;
+            for (addr, result) in client.health_check() {
+                match result {
+                    Ok(()) => println!("{} up", addr),
+                    Err(err) => {
+                        println!("{} down: {}", addr, err);
+                        down = true;
+                    }
+                }
+            }
+            if down {
+                exit(1);
+            }
+        }
+        Commands::Namespace(command) => run_namespace_command(client, command)?,
+        Commands::Repl { script } => repl::run(client, script)?,
+    }
+    Ok(())
+}
+
+fn run_namespace_command(client: &KvsClient, command: NamespaceCommands) -> Result<()> {
+    match command {
+        NamespaceCommands::Create {
+            name,
+            max_keys,
+            max_bytes,
+            dir,
+        } => {
+            let config = NamespaceConfig {
+                dir,
+                max_keys,
+                max_bytes,
+            };
+            client.create_namespace(&name, &config)?;
+        }
+        NamespaceCommands::Drop { name } => client.drop_namespace(&name)?,
+        NamespaceCommands::List => {
+            for info in client.namespaces()? {
+                let info = serde_json::to_string(&info)
+                    .with_whatever_context(|_| "Unable to serialize namespace")?;
+                println!("{}", info);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sets the records of `file` after the first `skip` ones, `batch_size` at a time. Returns the
+/// number of records imported, counting the skipped ones. On failure, tells how many to skip to
+/// resume: every record before the failing batch is in, and setting a record again is harmless.
+fn import(
+    client: &KvsClient,
+    file: &Path,
+    format: &Format,
+    batch_size: usize,
+    skip: usize,
+) -> Result<usize> {
+    let reader = File::open(file)
+        .with_whatever_context(|_| format!("Couldn't open import file {}", file.display()))?;
+    let mut records = read_records(format, BufReader::new(reader)).skip(skip);
+    let mut count = skip;
+    loop {
+        let batch = records
+            .by_ref()
+            .take(batch_size)
+            .map(|record| record.map(|DumpRecord { key, value }| Command::Set { key, value }))
+            .collect::<Result<Vec<_>>>();
+        let result = batch.and_then(|batch| {
+            for response in client.pipeline(&batch) {
+                response?;
+            }
+            Ok(batch.len())
+        });
+        match result {
+            Ok(0) => return Ok(count),
+            Ok(len) => {
+                for _ in 0..len {
+                    count += 1;
+                    report_progress("Imported", count);
+                }
+            }
+            Err(err) => {
+                eprintln!(
+                    "Imported {} records before failing, resume with --skip {}",
+                    count, count
+                );
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Writes `pairs` to `writer`. Returns the number of written records.
+fn export(pairs: Vec<(String, String)>, format: &Format, writer: impl Write) -> Result<usize> {
+    let mut writer = DumpWriter::new(format, writer);
+    let mut count = 0;
+    for (key, value) in pairs {
+        writer.write(&DumpRecord { key, value })?;
+        count += 1;
+        report_progress("Exported", count);
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+fn parse_position(position: &str) -> std::result::Result<Position, String> {
+    position.parse().map_err(|err: Error| err.to_string())
+}
@@ -0,0 +1,40 @@
+use kvs::Result;
+use snafu::{whatever, ResultExt};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::path::Path;
+
+pub const LOCK_FILE_NAME: &str = "kvs.lock";
+
+/// Keeps the data directory locked for as long as it is alive. The lock is released by the OS
+/// when the process exits, even if it gets killed.
+pub struct DataDirLock {
+    _file: File,
+}
+
+/// Creates `data_dir` if needed, then takes an exclusive lock on it so that no other server or
+/// admin command can open the same database files at the same time.
+pub fn lock_data_dir(data_dir: &Path) -> Result<DataDirLock> {
+    fs::create_dir_all(data_dir).with_whatever_context(|_| {
+        format!("Couldn't create data directory {}", data_dir.display())
+    })?;
+
+    let lock_path = data_dir.join(LOCK_FILE_NAME);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_whatever_context(|_| format!("Couldn't open lock file {}", lock_path.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok(DataDirLock { _file: file }),
+        Err(TryLockError::WouldBlock) => whatever!(
+            "Data directory {} is already in use by another process",
+            data_dir.display(),
+        ),
+        Err(TryLockError::Error(err)) => whatever!(
+            "Couldn't lock data directory {}: {}",
+            data_dir.display(),
+            err,
+        ),
+    }
+}
@@ -0,0 +1,107 @@
+use clap::ValueEnum;
+use kvs::Result;
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+const PROGRESS_INTERVAL: usize = 10_000;
+
+#[derive(ValueEnum, PartialEq, Eq, Debug, Clone)]
+pub enum Format {
+    /// One JSON object per line, e.g. `{"key":"key1","value":"value1"}`
+    Jsonl,
+    /// A `key,value` header, then one record per line
+    Csv,
+}
+
+impl Format {
+    /// Guesses the format of a dump from its file extension, defaulting to JSONL.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Jsonl,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DumpRecord {
+    pub key: String,
+    pub value: String,
+}
+
+/// Writes records one at a time, in either format.
+pub enum DumpWriter<W: Write> {
+    Jsonl(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> DumpWriter<W> {
+    pub fn new(format: &Format, writer: W) -> Self {
+        match format {
+            Format::Jsonl => DumpWriter::Jsonl(writer),
+            Format::Csv => DumpWriter::Csv(Box::new(csv::Writer::from_writer(writer))),
+        }
+    }
+
+    pub fn write(&mut self, record: &DumpRecord) -> Result<()> {
+        match self {
+            DumpWriter::Jsonl(writer) => {
+                serde_json::to_writer(&mut *writer, record)
+                    .with_whatever_context(|_| "Couldn't write JSONL record")?;
+                writeln!(writer).with_whatever_context(|_| "Couldn't write JSONL record")
+            }
+            DumpWriter::Csv(writer) => writer
+                .serialize(record)
+                .with_whatever_context(|_| "Couldn't write CSV record"),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        match self {
+            DumpWriter::Jsonl(writer) => writer.flush(),
+            DumpWriter::Csv(writer) => writer.flush(),
+        }
+        .with_whatever_context(|_| "Couldn't flush dump")
+    }
+}
+
+/// Reads the records of a dump lazily, so that dumps bigger than memory can be loaded.
+pub fn read_records<'a>(
+    format: &Format,
+    reader: impl Read + 'a,
+) -> Box<dyn Iterator<Item = Result<DumpRecord>> + 'a> {
+    match format {
+        Format::Jsonl => Box::new(
+            BufReader::new(reader)
+                .lines()
+                .enumerate()
+                .filter(|(_, line)| !matches!(line, Ok(line) if line.trim().is_empty()))
+                .map(|(index, line)| {
+                    let line = line.with_whatever_context(|_| "Couldn't read dump")?;
+                    serde_json::from_str(&line).with_whatever_context(|_| {
+                        format!("Couldn't parse JSONL record at line {}", index + 1)
+                    })
+                }),
+        ),
+        Format::Csv => Box::new(
+            csv::Reader::from_reader(reader)
+                .into_deserialize()
+                .enumerate()
+                .map(|(index, record)| {
+                    // Line 1 is the header
+                    record.with_whatever_context(|_| {
+                        format!("Couldn't parse CSV record at line {}", index + 2)
+                    })
+                }),
+        ),
+    }
+}
+
+/// Progress goes to stderr, as the dump itself may be written to stdout.
+pub fn report_progress(action: &str, count: usize) {
+    if count.is_multiple_of(PROGRESS_INTERVAL) {
+        eprintln!("{} {} records", action, count);
+    }
+}
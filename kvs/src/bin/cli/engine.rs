@@ -2,10 +2,12 @@ use kvs::err::Result;
 use std::collections::HashMap;
 use std::path::Path;
 use clap_derive::ValueEnum;
+use serde::Deserialize;
 use snafu::whatever;
 use std::fmt::Display;
 
-#[derive(ValueEnum, PartialEq, Eq, Hash, Default, Debug, Clone)]
+#[derive(ValueEnum, Deserialize, PartialEq, Eq, Hash, Default, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
 pub enum Engine {
     /// A custom key-value store
     #[default]
@@ -26,16 +28,30 @@ impl Display for Engine {
     }
 }
 
-/// Checks for the existence of other engines' database files. For example, if we are using
-/// `kvs`, then `sled` database file should not exist and vice versa.
-pub fn check_engine_db_file(engine: &Engine) -> Result<()> {
-    let engine_db_files: HashMap<Engine, _> = HashMap::from([
-        (Engine::Kvs, Path::new(kvs::DEFAULT_FILE_NAME_KVS).exists()),
-        (
-            Engine::Sled,
-            Path::new(kvs::DEFAULT_FILE_NAME_SLED).exists(),
-        ),
-    ]);
+impl Engine {
+    /// Name of the file (or directory, for `sled`) that the engine keeps its data in. The
+    /// in-memory engine keeps nothing on disk.
+    pub fn db_file_name(&self) -> Option<&'static str> {
+        match self {
+            Engine::Kvs => Some(kvs::DEFAULT_FILE_NAME_KVS),
+            Engine::Sled => Some(kvs::DEFAULT_FILE_NAME_SLED),
+            Engine::Mem => None,
+        }
+    }
+}
+
+/// Checks for the existence of other engines' database files within `data_dir`. For example, if
+/// we are using `kvs`, then `sled` database file should not exist and vice versa.
+pub fn check_engine_db_file(engine: &Engine, data_dir: &Path) -> Result<()> {
+    let engine_db_files: HashMap<Engine, _> = [Engine::Kvs, Engine::Sled]
+        .into_iter()
+        .map(|engine_checking| {
+            let db_file_exists = engine_checking
+                .db_file_name()
+                .is_some_and(|file_name| data_dir.join(file_name).exists());
+            (engine_checking, db_file_exists)
+        })
+        .collect();
     for (engine_checking, db_file_exists) in engine_db_files {
         if engine_checking != *engine && db_file_exists {
             whatever!(
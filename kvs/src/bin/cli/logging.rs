@@ -0,0 +1,30 @@
+use clap_derive::ValueEnum;
+use serde::Deserialize;
+use std::io::IsTerminal;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+#[derive(ValueEnum, Deserialize, PartialEq, Eq, Default, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines, prefixed by the spans they happened in
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the spans they happened in
+    Json,
+}
+
+/// Sends the logs to stderr, filtered by `RUST_LOG` and defaulting to `trace`. The `log` records
+/// of the library end up in the same output, within the span of the request being handled. Every
+/// span logs how long it took when it closes, e.g. `evaluate_command` within `request{id=3}`.
+pub fn init_logging(format: &LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("trace"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_span_events(FmtSpan::CLOSE);
+    match format {
+        LogFormat::Text => builder.with_ansi(std::io::stderr().is_terminal()).init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
@@ -0,0 +1,78 @@
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use kvs::metrics::Metrics;
+use kvs::namespace::Namespaces;
+use kvs::Result;
+use log::{error, info};
+use snafu::{whatever, ResultExt};
+use std::sync::Arc;
+use std::thread;
+
+type MetricsState = (Arc<Metrics>, Arc<Namespaces>);
+
+/// Serves the metrics of the server, its engine and its namespaces at `/metrics`, for
+/// Prometheus to scrape.
+pub fn router(metrics: Arc<Metrics>, namespaces: Arc<Namespaces>) -> Router {
+    Router::new()
+        .route("/metrics", get(render))
+        .with_state((metrics, namespaces))
+}
+
+async fn render(State((metrics, namespaces)): State<MetricsState>) -> Result<impl IntoResponse> {
+    let stats = match namespaces.default_namespace()?.store().read() {
+        Ok(store) => store.stats()?,
+        Err(_) => whatever!("Unable to acquire read lock on state"),
+    };
+    let namespaces: Vec<_> = namespaces
+        .list()?
+        .iter()
+        .map(|namespace| namespace.info())
+        .collect();
+    Ok((
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(&stats, &namespaces),
+    ))
+}
+
+/// Serves [`router`] at `addr` from a thread of its own, for servers not running an async
+/// runtime already.
+pub fn spawn_metrics_server(
+    addr: &str,
+    metrics: Arc<Metrics>,
+    namespaces: Arc<Namespaces>,
+) -> Result<()> {
+    // Bound here so that a taken address is reported before the server starts
+    let listener = std::net::TcpListener::bind(addr)
+        .with_whatever_context(|_| format!("Couldn't bind metrics address {}", addr))?;
+    listener
+        .set_nonblocking(true)
+        .with_whatever_context(|_| format!("Couldn't set up metrics address {}", addr))?;
+    info!("Serving metrics at http://{}/metrics", addr);
+
+    thread::spawn(move || {
+        let runtime = match tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+        {
+            Ok(runtime) => runtime,
+            Err(err) => {
+                error!("Couldn't start the metrics server: {}", err);
+                return;
+            }
+        };
+        runtime.block_on(async move {
+            let result = match tokio::net::TcpListener::from_std(listener) {
+                Ok(listener) => axum::serve(listener, router(metrics, namespaces)).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                error!("Metrics server stopped: {}", err);
+            }
+        });
+    });
+
+    Ok(())
+}
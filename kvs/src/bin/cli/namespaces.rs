@@ -0,0 +1,170 @@
+use crate::cli::data_dir::lock_data_dir;
+use crate::cli::engine::{check_engine_db_file, Engine};
+use crate::cli::server::ServerConfig;
+use kvs::compression::Compression;
+use kvs::encryption::Keyring;
+use kvs::eviction::Storage;
+use kvs::index::{IndexDef, IndexedStore};
+use kvs::namespace::{Namespaces, Opener};
+use kvs::{KvStoreV2, KvsEngine, MemStore, Result, SledStore};
+use log::{error, info, warn};
+use std::any::Any;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+/// Keys indexed at once while building indexes.
+const BACKFILL_BATCH: usize = 1000;
+
+/// Opens the engine kept in the data directory as the default namespace, then the other
+/// namespaces of the server. Every namespace uses the engine, the keys, the compression and the
+/// indexes the server was started with, and its directory is locked like the data directory is.
+pub fn open_namespaces(config: &ServerConfig) -> Result<Arc<Namespaces>> {
+    let keys = config.keyring()?;
+    let snapshots = config.snapshots();
+    match config.snapshot_interval {
+        Some(0) if snapshots => info!("Saving snapshots of the data on shutdown"),
+        Some(secs) if snapshots => info!(
+            "Saving snapshots of the data every {} seconds and on shutdown",
+            secs
+        ),
+        Some(_) => warn!(
+            "The {} engine keeps its data in files, not in snapshots",
+            config.engine
+        ),
+        None => {}
+    }
+    if keys.is_enabled() {
+        info!("Encrypting data files");
+        if config.engine == Engine::Mem && !snapshots {
+            warn!(
+                "The {} engine keeps no data files to encrypt",
+                config.engine
+            );
+        }
+    }
+    let indexes = config.indexes.definitions()?;
+    let default = open_engine(
+        &config.engine,
+        &config.data_dir,
+        &keys,
+        config.compression,
+        &indexes,
+        snapshots,
+    )?;
+
+    let engine = config.engine.clone();
+    let compression = config.compression;
+    let opener: Opener = Box::new(move |dir: &Path| {
+        // The in-memory engine has no directory to lock, unless it keeps snapshots there
+        let lock: Box<dyn Any + Send + Sync> = match engine {
+            Engine::Mem if !snapshots => Box::new(()),
+            _ => {
+                let lock = lock_data_dir(dir)?;
+                check_engine_db_file(&engine, dir)?;
+                Box::new(lock)
+            }
+        };
+        Ok((
+            open_engine(&engine, dir, &keys, compression, &indexes, snapshots)?,
+            lock,
+        ))
+    });
+    // The kvs engine keeps every value in memory besides its log
+    let storage = Storage::new(
+        &config.storage,
+        matches!(config.engine, Engine::Kvs | Engine::Mem),
+        config.engine.db_file_name().is_some(),
+    );
+    match storage.limit() {
+        Some((what, max)) => info!(
+            "Keeping at most {} bytes in {}, evicting with {}",
+            max,
+            what,
+            storage.policy()
+        ),
+        None if config.storage.max_memory.is_some() || config.storage.max_disk.is_some() => {
+            warn!(
+                "The {} engine is bound by none of the storage limits given",
+                config.engine
+            )
+        }
+        None => {}
+    }
+    let namespaces = Namespaces::open_with_storage(&config.data_dir, default, opener, storage)?;
+    info!("Namespaces: {}", namespaces.list()?.len());
+    if let Some((what, max)) = namespaces.storage().limit() {
+        if namespaces.storage().used() > max {
+            warn!(
+                "The namespaces already take {} bytes of {}, more than the {} allowed",
+                namespaces.storage().used(),
+                what,
+                max
+            );
+        }
+    }
+    Ok(Arc::new(namespaces))
+}
+
+fn open_engine(
+    engine: &Engine,
+    dir: &Path,
+    keys: &Keyring,
+    compression: Compression,
+    indexes: &[IndexDef],
+    snapshots: bool,
+) -> Result<Arc<RwLock<dyn KvsEngine>>> {
+    match engine {
+        Engine::Kvs => indexed(
+            KvStoreV2::open_with_keys(dir, keys.clone())?.with_compression(compression),
+            indexes,
+            dir,
+        ),
+        Engine::Sled => indexed(SledStore::open_with_keys(dir, keys.clone())?, indexes, dir),
+        Engine::Mem if snapshots => indexed(
+            MemStore::open_with_snapshots(dir, keys.clone())?,
+            indexes,
+            dir,
+        ),
+        Engine::Mem => indexed(MemStore::new(), indexes, dir),
+    }
+}
+
+/// Wraps `store` with the indexes of the server, even without any, so that the entries of the
+/// indexes it no longer has are dropped. New indexes are built in the background, a batch of keys
+/// at a time so that commands get the store in between; the thread stops early when the store is
+/// closed, and the build resumes where it was the next time it is opened.
+fn indexed<E: KvsEngine + 'static>(
+    store: E,
+    indexes: &[IndexDef],
+    dir: &Path,
+) -> Result<Arc<RwLock<dyn KvsEngine>>> {
+    let store = Arc::new(RwLock::new(IndexedStore::open(store, indexes.to_vec())?));
+    if store.read().map_or(true, |store| store.is_built()) {
+        return Ok(store);
+    }
+
+    info!("Building indexes in {}", dir.display());
+    let weak = Arc::downgrade(&store);
+    let dir = dir.to_owned();
+    thread::spawn(move || loop {
+        let Some(store) = weak.upgrade() else {
+            return;
+        };
+        let Ok(mut store) = store.write() else {
+            return;
+        };
+        match store.backfill(BACKFILL_BATCH) {
+            Ok(false) => {}
+            Ok(true) => {
+                info!("Built indexes in {}", dir.display());
+                return;
+            }
+            Err(err) => {
+                error!("Couldn't build indexes in {}: {}", dir.display(), err);
+                return;
+            }
+        }
+    });
+    Ok(store)
+}
@@ -0,0 +1,525 @@
+use kvs::client::KvsClient;
+use kvs::{Command, CommandResponse, Error, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use snafu::{whatever, ResultExt};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Instant;
+
+/// Everything that can start a line, offered by tab completion.
+const COMMANDS: &[&str] = &[
+    "get", "set", "rm", "scan", "find", "lpush", "lrange", "hset", "hget", "sadd", "smembers",
+    "incrby", "backup", "restore", "batch", "end", "\\timing", "help", "exit",
+];
+
+const HELP: &str = "\
+get <key>                 print the value of a key
+set <key> <value>         set a key; the rest of the line is the value
+rm <key>                  remove a key
+scan [<prefix>]           print the pairs under a prefix, or all of them
+find <index> <value>      print the pairs an index holds a value under
+lpush <key> <value>...    push values at the front of a list
+lrange <key> [<start> <stop>]
+                          print the elements of a list, all of them by default
+hset <key> <field> <value>
+                          set a field of a hash
+hget <key> <field>        print a field of a hash
+sadd <key> <member>...    add members to a set
+smembers <key>            print the members of a set
+incrby <key> <delta>      add to the integer held by a key
+backup <file>             save a backup archive of the store to a file
+restore <file>            load a backup archive into an empty store
+batch ... end             send the commands on keys in between at once
+\\timing                   toggle printing how long every command took
+help                      print this help
+exit                      leave (as does Ctrl-D)
+
+Words are separated by spaces. Quote them to keep spaces; within double quotes, \\n, \\t, \\\" and
+\\\\ are escapes, so that multi-line values fit on one line. Lines starting with # are comments.
+Watches stream until interrupted, so they are left to `kvs-client watch`.";
+
+/// A line of the REPL.
+#[derive(Debug, PartialEq)]
+enum Input {
+    Command(Command),
+    Backup { out: PathBuf },
+    Restore { input: PathBuf },
+    Batch,
+    End,
+    Timing,
+    Help,
+    Exit,
+}
+
+/// What is kept from one line to the next.
+struct Session<'a> {
+    client: &'a KvsClient,
+    timing: bool,
+    /// The commands of the batch being entered, if any.
+    batch: Option<Vec<Command>>,
+}
+
+/// Reads commands from the terminal, with line editing and history, until `exit` or Ctrl-D. With
+/// `script`, runs the commands of that file instead, stopping at the first failing one.
+pub fn run(client: &KvsClient, script: Option<PathBuf>) -> Result<()> {
+    let mut session = Session {
+        client,
+        timing: false,
+        batch: None,
+    };
+    match script {
+        Some(script) => run_script(&mut session, &script),
+        None => run_interactive(&mut session),
+    }
+}
+
+fn run_script(session: &mut Session, script: &Path) -> Result<()> {
+    let content = fs::read_to_string(script)
+        .with_whatever_context(|_| format!("Unable to read script {}", script.display()))?;
+    for (index, line) in content.lines().enumerate() {
+        match session.run_line(line) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            Err(err) => {
+                eprintln!("{}:{}: {}", script.display(), index + 1, err);
+                exit(1);
+            }
+        }
+    }
+    if session.batch.is_some() {
+        eprintln!("{}: batch without end", script.display());
+        exit(1);
+    }
+    Ok(())
+}
+
+fn run_interactive(session: &mut Session) -> Result<()> {
+    let mut editor: Editor<ReplHelper, DefaultHistory> =
+        Editor::new().with_whatever_context(|_| "Unable to set up the terminal")?;
+    editor.set_helper(Some(ReplHelper));
+    let history = std::env::var_os("HOME").map(|home| Path::new(&home).join(".kvs_history"));
+    if let Some(history) = &history {
+        // There is none yet on the first run
+        let _ = editor.load_history(history);
+    }
+    loop {
+        let prompt = match session.batch {
+            Some(_) => "batch> ",
+            None => "kvs> ",
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                match session.run_line(&line) {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => eprintln!("{}", err),
+                }
+            }
+            // Ctrl-C drops the line being edited, and the batch being entered
+            Err(ReadlineError::Interrupted) => session.batch = None,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => whatever!("Unable to read the line: {}", err),
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            eprintln!(
+                "Unable to save the history to {}: {}",
+                history.display(),
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Session<'_> {
+    /// Runs a line. Returns whether to keep going.
+    fn run_line(&mut self, line: &str) -> Result<bool> {
+        let Some(input) = parse_line(line)? else {
+            return Ok(true);
+        };
+        if let Some(batch) = &mut self.batch {
+            match input {
+                Input::Command(command) => batch.push(command),
+                Input::End => {
+                    let commands = self.batch.take().unwrap_or_default();
+                    self.timed(|session| {
+                        let responses = session.client.pipeline(&commands);
+                        for (command, response) in commands.iter().zip(responses) {
+                            println!("{}", format_response(command, response)?);
+                        }
+                        Ok(())
+                    })?;
+                }
+                _ => whatever!("Only commands on keys can be batched; end the batch first"),
+            }
+            return Ok(true);
+        }
+        match input {
+            Input::Command(command) => self.timed(|session| {
+                let response = session.client.execute(&command);
+                println!("{}", format_response(&command, response)?);
+                Ok(())
+            })?,
+            Input::Backup { out } => self.timed(|session| {
+                let archive = session.client.backup()?;
+                fs::write(&out, &archive).with_whatever_context(|_| {
+                    format!("Unable to write backup to {}", out.display())
+                })?;
+                println!("Backed up to {}", out.display());
+                Ok(())
+            })?,
+            Input::Restore { input } => self.timed(|session| {
+                let archive = fs::read(&input).with_whatever_context(|_| {
+                    format!("Unable to read backup from {}", input.display())
+                })?;
+                println!("Restored {} keys", session.client.restore(&archive)?);
+                Ok(())
+            })?,
+            Input::Batch => self.batch = Some(Vec::new()),
+            Input::End => whatever!("No batch to end"),
+            Input::Timing => {
+                self.timing = !self.timing;
+                println!("Timing is {}", if self.timing { "on" } else { "off" });
+            }
+            Input::Help => println!("{}", HELP),
+            Input::Exit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Runs `f`, then prints how long it took when timing is on, whether it failed or not.
+    fn timed(&mut self, f: impl FnOnce(&Self) -> Result<()>) -> Result<()> {
+        let started = Instant::now();
+        let result = f(self);
+        if self.timing {
+            println!("Time: {:.3} ms", started.elapsed().as_secs_f64() * 1000.0);
+        }
+        result
+    }
+}
+
+/// Parses a line, or returns `None` for a blank line or a comment.
+fn parse_line(line: &str) -> Result<Option<Input>> {
+    if line.trim_start().starts_with('#') {
+        return Ok(None);
+    }
+    let words = split_words(line)?;
+    let Some((name, args)) = words.split_first() else {
+        return Ok(None);
+    };
+    let input = match (name.to_lowercase().as_str(), args) {
+        ("get", [key]) => Input::Command(Command::Get { key: key.clone() }),
+        // Spaces between the words of an unquoted value are kept as single ones
+        ("set", [key, value @ ..]) if !value.is_empty() => Input::Command(Command::Set {
+            key: key.clone(),
+            value: value.join(" "),
+        }),
+        ("rm", [key]) => Input::Command(Command::Rm { key: key.clone() }),
+        ("scan", []) => Input::Command(Command::Scan {
+            prefix: String::new(),
+        }),
+        ("scan", [prefix]) => Input::Command(Command::Scan {
+            prefix: prefix.clone(),
+        }),
+        ("find", [index, value]) => Input::Command(Command::Find {
+            index: index.clone(),
+            value: value.clone(),
+        }),
+        ("lpush", [key, values @ ..]) if !values.is_empty() => Input::Command(Command::LPush {
+            key: key.clone(),
+            values: values.to_vec(),
+        }),
+        ("lrange", [key]) => Input::Command(Command::LRange {
+            key: key.clone(),
+            start: 0,
+            stop: -1,
+        }),
+        ("lrange", [key, start, stop]) => Input::Command(Command::LRange {
+            key: key.clone(),
+            start: parse_integer(start)?,
+            stop: parse_integer(stop)?,
+        }),
+        ("hset", [key, field, value]) => Input::Command(Command::HSet {
+            key: key.clone(),
+            field: field.clone(),
+            value: value.clone(),
+        }),
+        ("hget", [key, field]) => Input::Command(Command::HGet {
+            key: key.clone(),
+            field: field.clone(),
+        }),
+        ("sadd", [key, members @ ..]) if !members.is_empty() => Input::Command(Command::SAdd {
+            key: key.clone(),
+            members: members.to_vec(),
+        }),
+        ("smembers", [key]) => Input::Command(Command::SMembers { key: key.clone() }),
+        ("incrby", [key, delta]) => Input::Command(Command::IncrBy {
+            key: key.clone(),
+            delta: parse_integer(delta)?,
+        }),
+        ("backup", [out]) => Input::Backup { out: out.into() },
+        ("restore", [input]) => Input::Restore {
+            input: input.into(),
+        },
+        ("batch", []) => Input::Batch,
+        ("end", []) => Input::End,
+        ("\\timing", []) => Input::Timing,
+        ("help", []) => Input::Help,
+        ("exit" | "quit", []) => Input::Exit,
+        (name, _) if COMMANDS.contains(&name) || name == "quit" => {
+            whatever!("Wrong arguments for {}, see help", name)
+        }
+        (name, _) => whatever!("Unknown command {}, see help", name),
+    };
+    Ok(Some(input))
+}
+
+fn parse_integer(word: &str) -> Result<i64> {
+    match word.parse() {
+        Ok(integer) => Ok(integer),
+        Err(_) => whatever!("{} isn't an integer", word),
+    }
+}
+
+/// Splits a line into words as a shell does: words are separated by whitespace, and quotes
+/// keep whitespace within a word. Within double quotes, backslashes start escapes.
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    // `None` between words, so that `""` still makes an (empty) word
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+    while let Some(char) = chars.next() {
+        match char {
+            '"' | '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match (chars.next(), char) {
+                        (None, _) => whatever!("Unterminated quote {} in {}", char, line),
+                        (Some(end), _) if end == char => break,
+                        (Some('\\'), '"') => match chars.next() {
+                            Some('n') => word.push('\n'),
+                            Some('t') => word.push('\t'),
+                            Some(escaped @ ('"' | '\\')) => word.push(escaped),
+                            Some(escaped) => whatever!("Unknown escape \\{} in {}", escaped, line),
+                            None => whatever!("Unterminated quote {} in {}", char, line),
+                        },
+                        (Some(quoted), _) => word.push(quoted),
+                    }
+                }
+            }
+            char if char.is_whitespace() => words.extend(word.take()),
+            char => word.get_or_insert_with(String::new).push(char),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// What the REPL prints for the response to `command`. A missing key is an answer, not an error.
+fn format_response(command: &Command, response: Result<CommandResponse>) -> Result<String> {
+    match response {
+        Ok(CommandResponse::Get { value: Some(value) }) => Ok(format_value(&value)),
+        Ok(CommandResponse::Get { value: None }) | Err(Error::KeyNotFound { .. }) => {
+            Ok("Key not found".to_owned())
+        }
+        Ok(CommandResponse::Set | CommandResponse::Rm { .. }) => Ok("OK".to_owned()),
+        Ok(CommandResponse::Scan { pairs } | CommandResponse::Find { pairs }) => {
+            let mut output = String::new();
+            for (key, value) in &pairs {
+                output.push_str(&format!("{} = {}\n", key, format_value(value)));
+            }
+            output.push_str(&format!("({} keys)", pairs.len()));
+            Ok(output)
+        }
+        Ok(CommandResponse::HSet { .. }) => Ok("OK".to_owned()),
+        Ok(CommandResponse::HGet { value: Some(value) }) => Ok(format_value(&value)),
+        Ok(CommandResponse::HGet { value: None }) => Ok("Field not found".to_owned()),
+        Ok(CommandResponse::LPush { len: count } | CommandResponse::SAdd { added: count }) => {
+            Ok(count.to_string())
+        }
+        Ok(CommandResponse::IncrBy { value }) => Ok(value.to_string()),
+        Ok(
+            CommandResponse::LRange { values: elements }
+            | CommandResponse::SMembers { members: elements },
+        ) => {
+            let mut output = String::new();
+            for (index, element) in elements.iter().enumerate() {
+                output.push_str(&format!("{}) {}\n", index + 1, format_value(element)));
+            }
+            output.push_str(&format!("({} elements)", elements.len()));
+            Ok(output)
+        }
+        Ok(response) => whatever!("Unexpected response to {}: {:?}", command.name(), response),
+        Err(err) => Err(err),
+    }
+}
+
+/// A value as is when it fits on a line, otherwise its line count followed by its lines, each
+/// behind a margin so that they can't be mistaken for the next output.
+fn format_value(value: &str) -> String {
+    if !value.contains('\n') {
+        return value.to_owned();
+    }
+    let mut output = format!("({} lines)", value.lines().count());
+    for line in value.lines() {
+        output.push_str("\n| ");
+        output.push_str(line);
+    }
+    output
+}
+
+/// Completes the names of the commands.
+#[derive(Helper, Hinter, Highlighter, Validator)]
+struct ReplHelper;
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let typed = &line[..pos];
+        // Only the first word is a command name
+        if typed.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(typed))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let test_table = vec![
+            ("get key1", vec!["get", "key1"]),
+            ("  set   key1  a b  ", vec!["set", "key1", "a", "b"]),
+            ("set key1 \"a  b\"", vec!["set", "key1", "a  b"]),
+            ("set key1 'a \\n b'", vec!["set", "key1", "a \\n b"]),
+            (
+                "set key1 \"a\\nb \\\"c\\\"\"",
+                vec!["set", "key1", "a\nb \"c\""],
+            ),
+            ("set key1 \"\"", vec!["set", "key1", ""]),
+            ("set key1 x\"y z\"", vec!["set", "key1", "xy z"]),
+        ];
+        for (input, expected) in test_table {
+            assert_eq!(split_words(input).unwrap(), expected, "{}", input);
+        }
+        assert!(split_words("set key1 \"value").is_err());
+        assert!(split_words("set key1 \"\\x\"").is_err());
+    }
+
+    #[test]
+    fn parse() {
+        let test_table = vec![
+            ("", None),
+            ("# comment", None),
+            (
+                "SET key1 hello  world",
+                Some(Input::Command(Command::Set {
+                    key: "key1".to_owned(),
+                    value: "hello world".to_owned(),
+                })),
+            ),
+            (
+                "scan",
+                Some(Input::Command(Command::Scan {
+                    prefix: String::new(),
+                })),
+            ),
+            (
+                "backup out.kvs",
+                Some(Input::Backup {
+                    out: "out.kvs".into(),
+                }),
+            ),
+            (
+                "lrange list1",
+                Some(Input::Command(Command::LRange {
+                    key: "list1".to_owned(),
+                    start: 0,
+                    stop: -1,
+                })),
+            ),
+            (
+                "incrby counter1 -2",
+                Some(Input::Command(Command::IncrBy {
+                    key: "counter1".to_owned(),
+                    delta: -2,
+                })),
+            ),
+            (
+                "find users.by_email a@example.com",
+                Some(Input::Command(Command::Find {
+                    index: "users.by_email".to_owned(),
+                    value: "a@example.com".to_owned(),
+                })),
+            ),
+            ("\\timing", Some(Input::Timing)),
+            ("quit", Some(Input::Exit)),
+        ];
+        for (input, expected) in test_table {
+            assert_eq!(parse_line(input).unwrap(), expected, "{}", input);
+        }
+        let err = parse_line("get").unwrap_err();
+        assert_eq!(err.to_string(), "Wrong arguments for get, see help");
+        let err = parse_line("sadd set1").unwrap_err();
+        assert_eq!(err.to_string(), "Wrong arguments for sadd, see help");
+        let err = parse_line("incrby counter1 one").unwrap_err();
+        assert_eq!(err.to_string(), "one isn't an integer");
+        let err = parse_line("put key1 value1").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown command put, see help");
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(format_value("value1"), "value1");
+        assert_eq!(format_value("line1\nline2"), "(2 lines)\n| line1\n| line2");
+
+        let scan = Command::Scan {
+            prefix: String::new(),
+        };
+        let pairs = vec![
+            ("key1".to_owned(), "value1".to_owned()),
+            ("key2".to_owned(), "a\nb".to_owned()),
+        ];
+        assert_eq!(
+            format_response(&scan, Ok(CommandResponse::Scan { pairs })).unwrap(),
+            "key1 = value1\nkey2 = (2 lines)\n| a\n| b\n(2 keys)"
+        );
+        let rm = Command::Rm {
+            key: "key1".to_owned(),
+        };
+        let missing = Err(Error::KeyNotFound {
+            key: "key1".to_owned(),
+        });
+        assert_eq!(format_response(&rm, missing).unwrap(), "Key not found");
+
+        let smembers = Command::SMembers {
+            key: "set1".to_owned(),
+        };
+        let members = vec!["a".to_owned(), "b".to_owned()];
+        assert_eq!(
+            format_response(&smembers, Ok(CommandResponse::SMembers { members })).unwrap(),
+            "1) a\n2) b\n(2 elements)"
+        );
+    }
+}
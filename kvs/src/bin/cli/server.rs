@@ -1,15 +1,308 @@
-use clap::Parser;
+use crate::cli::logging::LogFormat;
 use crate::Engine;
+use clap::Parser;
+use kvs::auth::AuthConfig;
+use kvs::compression::Compression;
+use kvs::encryption::Keyring;
+use kvs::eviction::{EvictionPolicy, StorageLimits};
+use kvs::index::IndexConfig;
+use kvs::limits::Limits;
+use kvs::Result;
+use serde::Deserialize;
+use snafu::{whatever, ResultExt};
+use std::fs;
+use std::path::PathBuf;
 
+/// Every setting can also be given through an environment variable or a TOML config file.
+/// Command line arguments take precedence over environment variables, which take precedence over
+/// the config file.
 #[derive(Parser)]
 #[command(version)]
 #[command(propagate_version = true)]
 pub struct Server {
-    /// The address of the server
-    #[arg(long, default_value_t = String::from("127.0.0.1:4004"))]
-    pub addr: String,
+    /// The address of the server [default: 127.0.0.1:4004]
+    #[arg(long, env = "KVS_ADDR")]
+    pub addr: Option<String>,
+
+    /// The underlying engine to use [default: kvs]
+    #[arg(long, env = "KVS_ENGINE")]
+    pub engine: Option<Engine>,
+
+    /// The directory to keep the database files in [default: current directory]
+    #[arg(long, env = "KVS_DATA_DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// A PEM certificate chain to serve TLS with, instead of plain text
+    #[arg(long, env = "KVS_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The PEM private key of `--tls-cert`
+    #[arg(long, env = "KVS_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// A PEM CA certificate; clients must then present a certificate signed by it
+    #[arg(long, env = "KVS_TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// The address to serve Prometheus metrics at, under `/metrics`
+    #[arg(long, env = "KVS_METRICS_ADDR")]
+    pub metrics_addr: Option<String>,
+
+    /// How to compress the values of the kvs engine and the responses to clients asking for it:
+    /// none, lz4 or zstd. HTTP responses use zstd either way [default: none]
+    #[arg(long, env = "KVS_COMPRESSION")]
+    pub compression: Option<Compression>,
+
+    /// Seconds between snapshots of the data of the mem engine, which then also takes one on
+    /// shutdown and loads the last one on startup; 0 takes one on shutdown only [default: no
+    /// snapshots]
+    #[arg(long, env = "KVS_SNAPSHOT_INTERVAL")]
+    pub snapshot_interval: Option<u64>,
+
+    /// A file holding the key to encrypt the data files with, as 64 hexadecimal characters, e.g.
+    /// from `kvs-admin gen-key` [default: no encryption]
+    #[arg(long, env = "KVS_ENCRYPTION_KEY_FILE", help_heading = "Encryption")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// The key to encrypt the data files with, instead of `--encryption-key-file`. Not settable
+    /// from the config file
+    #[arg(
+        long,
+        env = "KVS_ENCRYPTION_KEY",
+        hide_env_values = true,
+        help_heading = "Encryption"
+    )]
+    pub encryption_key: Option<String>,
+
+    /// A file holding a key the data files were encrypted with before the current one. Data is
+    /// encrypted again with the current key when the server starts; can be repeated
+    #[arg(long = "old-encryption-key-file", help_heading = "Encryption")]
+    pub old_encryption_key_files: Vec<PathBuf>,
+
+    /// The format of the logs written to stderr [default: text]
+    #[arg(long, env = "KVS_LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// Connections open at the same time, watches included [default: 1024]
+    #[arg(long, env = "KVS_MAX_CONNECTIONS", help_heading = "Limits")]
+    pub max_connections: Option<usize>,
+
+    /// Requests per second allowed to every client IP address [default: unlimited]
+    #[arg(long, env = "KVS_RATE_LIMIT", help_heading = "Limits")]
+    pub rate_limit: Option<f64>,
 
-    /// The underlying engine to use
-    #[arg(long, default_value_t)]
+    /// Requests a client may send in a row before `--rate-limit` kicks in [default: one second
+    /// worth]
+    #[arg(long, env = "KVS_RATE_BURST", help_heading = "Limits")]
+    pub rate_burst: Option<u32>,
+
+    /// Bytes a key may take [default: 65536]
+    #[arg(long, env = "KVS_MAX_KEY_SIZE", help_heading = "Limits")]
+    pub max_key_size: Option<usize>,
+
+    /// Bytes a value may take [default: 1048576]
+    #[arg(long, env = "KVS_MAX_VALUE_SIZE", help_heading = "Limits")]
+    pub max_value_size: Option<usize>,
+
+    /// Bytes a whole request may take, e.g. a backup to restore [default: 67108864]
+    #[arg(long, env = "KVS_MAX_REQUEST_SIZE", help_heading = "Limits")]
+    pub max_request_size: Option<usize>,
+
+    /// Seconds reading a request or writing a response may stall [default: 30]
+    #[arg(long, env = "KVS_TIMEOUT", help_heading = "Limits")]
+    pub timeout: Option<u64>,
+
+    /// Bytes of keys and values the namespaces may keep in memory together, for the kvs and mem
+    /// engines [default: unlimited]
+    #[arg(long, env = "KVS_MAX_MEMORY", help_heading = "Limits")]
+    pub max_memory: Option<u64>,
+
+    /// Bytes of keys and values the namespaces may keep on disk together, for the kvs and sled
+    /// engines [default: unlimited]
+    #[arg(long, env = "KVS_MAX_DISK", help_heading = "Limits")]
+    pub max_disk: Option<u64>,
+
+    /// What to evict when a write would go past `--max-memory` or `--max-disk`: noeviction,
+    /// allkeys-lru or random [default: noeviction]
+    #[arg(long, env = "KVS_EVICTION_POLICY", help_heading = "Limits")]
+    pub eviction_policy: Option<EvictionPolicy>,
+
+    /// A TOML file containing any of the settings above, e.g. `engine = "sled"`
+    #[arg(long, env = "KVS_CONFIG")]
+    pub config: Option<PathBuf>,
+}
+
+/// Settings of the server after merging the command line, the environment and the config file.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: String,
     pub engine: Engine,
-}
\ No newline at end of file
+    pub data_dir: PathBuf,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub metrics_addr: Option<String>,
+    pub compression: Compression,
+    /// Only used by the mem engine.
+    pub snapshot_interval: Option<u64>,
+    pub encryption_key_file: Option<PathBuf>,
+    /// Keys are better kept out of config files, which get shared and checked in.
+    #[serde(skip)]
+    pub encryption_key: Option<String>,
+    pub old_encryption_key_files: Vec<PathBuf>,
+    pub log_format: LogFormat,
+    /// Only enforced by `kvs-server`.
+    pub limits: Limits,
+    pub storage: StorageLimits,
+    /// Users and the roles they have. Without it, anyone reaching the server may do anything.
+    /// Only settable from the config file.
+    pub auth: Option<AuthConfig>,
+    /// The indexes of every namespace. Only settable from the config file.
+    pub indexes: IndexConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: String::from("127.0.0.1:4004"),
+            engine: Engine::default(),
+            data_dir: PathBuf::from("."),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            metrics_addr: None,
+            compression: Compression::default(),
+            snapshot_interval: None,
+            encryption_key_file: None,
+            encryption_key: None,
+            old_encryption_key_files: Vec::new(),
+            log_format: LogFormat::default(),
+            limits: Limits::default(),
+            storage: StorageLimits::default(),
+            auth: None,
+            indexes: IndexConfig::default(),
+        }
+    }
+}
+
+impl Server {
+    pub fn into_config(self) -> Result<ServerConfig> {
+        let mut config = match &self.config {
+            Some(path) => {
+                let content = fs::read_to_string(path).with_whatever_context(|_| {
+                    format!("Couldn't read config file at {}", path.display())
+                })?;
+                toml::from_str(&content).with_whatever_context(|err| {
+                    format!("Couldn't parse config file at {}: {}", path.display(), err)
+                })?
+            }
+            None => ServerConfig::default(),
+        };
+
+        if let Some(addr) = self.addr {
+            config.addr = addr;
+        }
+        if let Some(engine) = self.engine {
+            config.engine = engine;
+        }
+        if let Some(data_dir) = self.data_dir {
+            config.data_dir = data_dir;
+        }
+        if self.tls_cert.is_some() {
+            config.tls_cert = self.tls_cert;
+            config.tls_key = self.tls_key;
+        }
+        if self.tls_client_ca.is_some() {
+            config.tls_client_ca = self.tls_client_ca;
+        }
+        if self.metrics_addr.is_some() {
+            config.metrics_addr = self.metrics_addr;
+        }
+        if let Some(compression) = self.compression {
+            config.compression = compression;
+        }
+        if self.snapshot_interval.is_some() {
+            config.snapshot_interval = self.snapshot_interval;
+        }
+        if self.encryption_key_file.is_some() {
+            config.encryption_key_file = self.encryption_key_file;
+        }
+        config.encryption_key = self.encryption_key;
+        if !self.old_encryption_key_files.is_empty() {
+            config.old_encryption_key_files = self.old_encryption_key_files;
+        }
+        if let Some(log_format) = self.log_format {
+            config.log_format = log_format;
+        }
+        if let Some(max_connections) = self.max_connections {
+            config.limits.max_connections = max_connections;
+        }
+        if self.rate_limit.is_some() {
+            config.limits.rate_limit = self.rate_limit;
+        }
+        if self.rate_burst.is_some() {
+            config.limits.rate_burst = self.rate_burst;
+        }
+        if let Some(max_key_size) = self.max_key_size {
+            config.limits.max_key_size = max_key_size;
+        }
+        if let Some(max_value_size) = self.max_value_size {
+            config.limits.max_value_size = max_value_size;
+        }
+        if let Some(max_request_size) = self.max_request_size {
+            config.limits.max_request_size = max_request_size;
+        }
+        if let Some(timeout) = self.timeout {
+            config.limits.timeout = timeout;
+        }
+        if self.max_memory.is_some() {
+            config.storage.max_memory = self.max_memory;
+        }
+        if self.max_disk.is_some() {
+            config.storage.max_disk = self.max_disk;
+        }
+        if let Some(eviction_policy) = self.eviction_policy {
+            config.storage.eviction_policy = eviction_policy;
+        }
+        config.limits.validate()?;
+        if let Some(auth) = &config.auth {
+            auth.validate()?;
+        }
+        config.indexes.definitions()?;
+
+        Ok(config)
+    }
+}
+
+impl ServerConfig {
+    /// The TLS settings to serve with, or `None` to serve plain text.
+    pub fn tls(&self) -> Result<Option<rustls::ServerConfig>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(kvs::tls::server_config(
+                cert,
+                key,
+                self.tls_client_ca.as_deref(),
+            )?)),
+            (None, None) if self.tls_client_ca.is_some() => {
+                whatever!("tls_client_ca needs tls_cert and tls_key too")
+            }
+            (None, None) => Ok(None),
+            _ => whatever!("tls_cert and tls_key must be given together"),
+        }
+    }
+
+    /// Whether the engine keeps snapshots of its data, which only the mem engine does.
+    pub fn snapshots(&self) -> bool {
+        self.engine == Engine::Mem && self.snapshot_interval.is_some()
+    }
+
+    /// The keys to encrypt the data files with, which encrypt nothing unless some were given.
+    pub fn keyring(&self) -> Result<Keyring> {
+        Keyring::load(
+            self.encryption_key.as_deref(),
+            self.encryption_key_file.as_deref(),
+            &self.old_encryption_key_files,
+        )
+    }
+}
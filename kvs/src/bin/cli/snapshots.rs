@@ -0,0 +1,105 @@
+use kvs::namespace::Namespaces;
+use kvs::Result;
+use log::{error, info, warn};
+use snafu::ResultExt;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Writes a snapshot of every namespace that changed since its last one. The store of a namespace
+/// is only locked while the snapshot is taken, not while it is written.
+pub fn save_snapshots(namespaces: &Namespaces) {
+    let namespaces = match namespaces.list() {
+        Ok(namespaces) => namespaces,
+        Err(err) => {
+            error!("Couldn't list namespaces to snapshot: {}", err);
+            return;
+        }
+    };
+    for namespace in namespaces {
+        let snapshot = match namespace.store().read() {
+            Ok(store) => store.snapshot(),
+            Err(_) => {
+                error!(
+                    "Unable to acquire read lock on namespace {}",
+                    namespace.name()
+                );
+                continue;
+            }
+        };
+        let Some(snapshot) = snapshot else {
+            continue;
+        };
+        let started = Instant::now();
+        match snapshot.write() {
+            Ok(count) => info!(
+                "Saved {} pairs of namespace {} to {} in {:?}",
+                count,
+                namespace.name(),
+                snapshot.path().display(),
+                started.elapsed()
+            ),
+            Err(err) => error!(
+                "Couldn't save snapshot of namespace {}: {}",
+                namespace.name(),
+                err
+            ),
+        }
+    }
+}
+
+/// Saves snapshots every `interval` on a thread of its own, and once more when the server is
+/// asked to stop with Ctrl-C or `SIGTERM`, before exiting. Without an interval, snapshots are
+/// only saved on shutdown.
+pub fn spawn_snapshots(namespaces: Arc<Namespaces>, interval: Option<Duration>) -> Result<()> {
+    if let Some(interval) = interval {
+        let namespaces = namespaces.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            save_snapshots(&namespaces);
+        });
+    }
+
+    // A runtime of its own, as `kvs-server` has none
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .with_whatever_context(|_| "Couldn't start the shutdown handler")?;
+    thread::spawn(move || {
+        runtime.block_on(shutdown_signal());
+        info!("Shutting down, saving snapshots");
+        save_snapshots(&namespaces);
+        process::exit(0);
+    });
+    Ok(())
+}
+
+/// Completes once Ctrl-C or `SIGTERM` is received.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            warn!("Couldn't listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                warn!("Couldn't listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
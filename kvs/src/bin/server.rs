@@ -1,73 +1,200 @@
+use axum::extract::DefaultBodyLimit;
+use axum::middleware;
 use axum::routing::{get, post};
 use axum::Router;
 use clap::{Parser};
+use cli::data_dir::lock_data_dir;
 use cli::engine::{check_engine_db_file, Engine};
+use cli::logging::init_logging;
+use cli::metrics::{router as metrics_router, spawn_metrics_server};
+use cli::namespaces::open_namespaces;
 use cli::parse_addr::parse_addr;
 use cli::server::Server;
-use env_logger::Env;
-use kvs::{KvStoreV2, KvsEngine, MemStore, Result, SledStore};
-use log::{error, info};
+use cli::snapshots::spawn_snapshots;
+use kvs::compression::MIN_SIZE;
+use kvs::limits::Limits;
+use kvs::metrics::Metrics;
+use kvs::Result;
+use log::{error, info, warn};
 use server::app_state::AppState;
 use server::handlers;
-use std::env;
-use std::sync::{Arc, RwLock};
+use server::request::trace_request;
+use server::tls::serve_tls;
+use std::sync::Arc;
+use std::time::Duration;
+use tower_http::compression::predicate::{NotForContentType, Predicate, SizeAbove};
+use tower_http::compression::CompressionLayer;
 
 mod server {
     pub mod app_state;
+    pub mod auth;
     pub mod handlers;
+    pub mod namespace;
+    pub mod request;
+    pub mod tls;
 }
 
 mod cli {
+    pub mod data_dir;
     pub mod parse_addr;
 
     pub mod engine;
+    pub mod logging;
+    pub mod metrics;
+    pub mod namespaces;
     pub mod server;
+    pub mod snapshots;
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
+    let config = Server::parse().into_config()?;
+    init_logging(&config.log_format);
     info!("Logger initialized!");
     info!("Current binary version: {:?}", env!("CARGO_PKG_VERSION"));
 
-    let cli = Server::parse();
     // TODO: validate engine by Clap instead of hard-coding
-    parse_addr(&cli.addr)?;
-    info!("Started server at: {:?}", cli.addr);
+    parse_addr(&config.addr)?;
+    info!("Started server at: {:?}", config.addr);
     info!("Chosen engine: {:?}", {
-        match cli.engine {
+        match config.engine {
             Engine::Kvs => "kvs",
             Engine::Sled => "sled",
             Engine::Mem => "in-memory",
         }
     });
+    info!("Data directory: {:?}", &config.data_dir);
+    match &config.auth {
+        Some(auth) => info!("Authentication enabled for {} users", auth.users.len()),
+        None => warn!("Authentication disabled: anyone reaching the server may do anything"),
+    }
+    let default_limits = Limits {
+        max_request_size: config.limits.max_request_size,
+        ..Limits::default()
+    };
+    if config.limits != default_limits {
+        warn!("Limits other than max_request_size are only enforced by kvs-server");
+    }
+    let _data_dir_lock = lock_data_dir(&config.data_dir)?;
 
-    if let Err(err) = check_engine_db_file(&cli.engine) {
+    if let Err(err) = check_engine_db_file(&config.engine, &config.data_dir) {
         error!(
             "Database file of engines other than {} already exists",
-            cli.engine,
+            config.engine,
         );
         return Err(err);
     }
 
-    let current_dir = env::current_dir().unwrap();
+    let tls = config.tls()?.map(Arc::new);
+    let namespaces = open_namespaces(&config)?;
+    if config.snapshots() {
+        let interval = config.snapshot_interval.filter(|&secs| secs > 0);
+        spawn_snapshots(namespaces.clone(), interval.map(Duration::from_secs))?;
+    }
+    let metrics = Arc::new(Metrics::new());
     let shared_state = AppState {
-        store: match cli.engine {
-            Engine::Kvs => Arc::new(RwLock::new(KvStoreV2::open(current_dir.as_path())?)),
-            Engine::Sled => Arc::new(RwLock::new(SledStore::open(current_dir.as_path())?)),
-            Engine::Mem => Arc::new(RwLock::new(MemStore::new())),
-        },
+        namespaces: namespaces.clone(),
+        auth: config.auth.map(Arc::new),
+        metrics: metrics.clone(),
     };
 
-    let app = Router::new()
-        .route("/v1/get/{key}", get(handlers::get))
-        .route("/v1/set/{key}/{value}", post(handlers::set))
-        .route("/v1/rm/{key}", post(handlers::remove))
+    if let Some(metrics_addr) = &config.metrics_addr {
+        spawn_metrics_server(metrics_addr, metrics.clone(), namespaces.clone())?;
+    }
+
+    let max_request_size = config.limits.max_request_size;
+    let mut app = command_routes("/v1", max_request_size)
+        // The keys of a namespace as resources of their own, besides the same commands as v1
+        .merge(command_routes("/v2/ns/{ns}", max_request_size))
+        .route("/v2/ns/{ns}/keys", get(handlers::scan))
+        .route(
+            "/v2/ns/{ns}/keys/{key}",
+            get(handlers::get)
+                .put(handlers::put)
+                .delete(handlers::remove),
+        )
+        .route("/v2/ns", get(handlers::list_namespaces))
+        .route(
+            "/v2/ns/{ns}",
+            get(handlers::namespace_info)
+                .put(handlers::create_namespace)
+                .delete(handlers::drop_namespace),
+        )
         .fallback(handlers::not_found)
+        .route_layer(middleware::from_fn_with_state(
+            shared_state.clone(),
+            trace_request,
+        ))
         .with_state(shared_state);
-    let listener = tokio::net::TcpListener::bind(cli.addr).await.unwrap();
+    if !config.compression.is_none() {
+        // Over HTTP, the client picks among the content codings we know, which is only zstd.
+        // Events are left alone, as they must reach the client as soon as they happen
+        info!("Compressing responses with zstd");
+        let predicate = SizeAbove::new(MIN_SIZE as u16)
+            .and(NotForContentType::IMAGES)
+            .and(NotForContentType::SSE);
+        app = app.layer(CompressionLayer::new().compress_when(predicate));
+    }
+    let app = app.merge(metrics_router(metrics, namespaces));
+    let listener = tokio::net::TcpListener::bind(config.addr).await.unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    match tls {
+        Some(tls) => {
+            info!("Serving over TLS");
+            serve_tls(listener, app, tls).await;
+        }
+        None => axum::serve(listener, app).await.unwrap(),
+    }
 
     Ok(())
 }
+
+/// The routes of the commands on keys, under `prefix`.
+fn command_routes(prefix: &str, max_request_size: usize) -> Router<AppState> {
+    Router::new()
+        .route(&format!("{}/get/{{key}}", prefix), get(handlers::get))
+        .route(
+            &format!("{}/set/{{key}}/{{value}}", prefix),
+            post(handlers::set),
+        )
+        .route(&format!("{}/rm/{{key}}", prefix), post(handlers::remove))
+        .route(&format!("{}/scan", prefix), get(handlers::scan))
+        .route(&format!("{}/lpush/{{key}}", prefix), post(handlers::lpush))
+        .route(&format!("{}/lrange/{{key}}", prefix), get(handlers::lrange))
+        .route(
+            &format!("{}/hset/{{key}}/{{field}}/{{value}}", prefix),
+            post(handlers::hset),
+        )
+        .route(
+            &format!("{}/hget/{{key}}/{{field}}", prefix),
+            get(handlers::hget),
+        )
+        .route(&format!("{}/sadd/{{key}}", prefix), post(handlers::sadd))
+        .route(
+            &format!("{}/smembers/{{key}}", prefix),
+            get(handlers::smembers),
+        )
+        .route(
+            &format!("{}/incrby/{{key}}/{{delta}}", prefix),
+            post(handlers::incr_by),
+        )
+        .route(
+            &format!("{}/find/{{index}}/{{value}}", prefix),
+            get(handlers::find),
+        )
+        .route(&format!("{}/eval", prefix), post(handlers::eval))
+        .route(
+            &format!("{}/evalsha/{{hash}}", prefix),
+            post(handlers::eval_sha),
+        )
+        .route(&format!("{}/backup", prefix), get(handlers::backup))
+        // Archives are far bigger than the 2 MiB axum allows by default
+        .route(
+            &format!("{}/restore", prefix),
+            post(handlers::restore).layer(DefaultBodyLimit::max(max_request_size)),
+        )
+        .route(
+            &format!("{}/watch/{{pattern}}", prefix),
+            get(handlers::watch),
+        )
+}
@@ -1,9 +1,12 @@
-use std::sync::{Arc, RwLock};
-use kvs::KvsEngine;
+use kvs::auth::AuthConfig;
+use kvs::metrics::Metrics;
+use kvs::namespace::Namespaces;
+use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
-    // TODO: use dashmap (https://docs.rs/dashmap/latest/dashmap/struct.DashMap.html)
-    //       to make it thread-safe instead of hand-rolling it
-    pub store: Arc<RwLock<dyn KvsEngine>>,
+    pub namespaces: Arc<Namespaces>,
+    /// `None` when the server runs without authentication.
+    pub auth: Option<Arc<AuthConfig>>,
+    pub metrics: Arc<Metrics>,
 }
@@ -0,0 +1,29 @@
+use super::app_state::AppState;
+use axum::extract::FromRequestParts;
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use kvs::auth::User;
+use kvs::Error;
+
+/// The user behind a request, authenticated through an `Authorization: Bearer <token>` header.
+pub struct AuthUser(pub User);
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(auth) = &state.auth else {
+            return Ok(AuthUser(User::unrestricted()));
+        };
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        Ok(AuthUser(auth.authenticate(token)?))
+    }
+}
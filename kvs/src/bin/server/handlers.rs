@@ -1,61 +1,523 @@
 use super::app_state::AppState;
-use axum::extract::{Path, State};
-use axum::http::StatusCode;
-use kvs::Result;
+use super::auth::AuthUser;
+use super::namespace::Ns;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use kvs::auth::Permission;
+use kvs::backup::{read_backup, write_backup};
+use kvs::namespace::{NamespaceConfig, NamespaceInfo};
+use kvs::watch::{KeyPattern, Position};
+use kvs::{Command, CommandResponse, Result};
 use log::{info, warn};
+use serde::Deserialize;
 use snafu::whatever;
-use std::ops::{Deref, DerefMut};
+use std::ops::Deref;
+
+// The parameters of the routes are taken by name, so that the same handlers serve the routes
+// of `/v1/...` and those of `/v2/ns/{ns}/...`, which have an `ns` parameter more. The user is
+// authenticated before the namespace is looked up, so that only users can tell which exist.
+
+#[derive(Deserialize)]
+pub struct KeyPath {
+    key: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetPath {
+    key: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+pub struct FieldPath {
+    key: String,
+    field: String,
+}
+
+#[derive(Deserialize)]
+pub struct HSetPath {
+    key: String,
+    field: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+pub struct IncrByPath {
+    key: String,
+    delta: i64,
+}
+
+#[derive(Deserialize)]
+pub struct FindPath {
+    index: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+pub struct PatternPath {
+    pattern: String,
+}
 
 pub async fn get(
-    State(state): State<AppState>,
-    Path(key): Path<String>,
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
 ) -> Result<(StatusCode, String)> {
-    if let Ok(state_lock) = state.store.read() {
-        let state = state_lock.deref();
-        let value_opt = state.get(key.clone())?;
-        if let Some(value) = value_opt {
+    let command = Command::Get { key: key.clone() };
+    user.authorize_command(&command)?;
+    match ns.evaluate_read(&command)? {
+        CommandResponse::Get { value: Some(value) } => {
             info!("Found value for key {}", key);
             Ok((StatusCode::OK, value))
-        } else {
+        }
+        CommandResponse::Get { value: None } => {
             warn!("Couldn't find value for key {}", key);
             Ok((StatusCode::NOT_FOUND, "Key not found".to_owned()))
         }
-    } else {
-        whatever!("Unable to acquire write lock on state");
+        response => refusal(response),
     }
 }
 
 pub async fn set(
-    State(state): State<AppState>,
-    Path((key, value)): Path<(String, String)>,
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(SetPath { key, value }): Path<SetPath>,
+) -> Result<(StatusCode, ())> {
+    let command = Command::Set {
+        key: key.clone(),
+        value,
+    };
+    user.authorize_command(&command)?;
+    ns.evaluate(&command)?;
+    info!("Set value for key {}", key);
+    Ok((StatusCode::OK, ()))
+}
+
+/// Sets the value of a key to the body, which may hold characters a path can't.
+pub async fn put(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
+    value: String,
 ) -> Result<(StatusCode, ())> {
-    if let Ok(mut state_lock) = state.store.write() {
-        let state = state_lock.deref_mut();
-        state.set(key.clone(), value.to_owned())?;
-        info!("Set value for key {}", key);
-        Ok((StatusCode::OK, ()))
+    let command = Command::Set {
+        key: key.clone(),
+        value,
+    };
+    user.authorize_command(&command)?;
+    ns.evaluate(&command)?;
+    info!("Set value for key {}", key);
+    Ok((StatusCode::OK, ()))
+}
+
+pub async fn remove(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
+) -> Result<(StatusCode, String)> {
+    let command = Command::Rm { key: key.clone() };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::Rm { value: Some(_) } => {
+            info!("Removed value for key {}", key);
+            Ok((StatusCode::OK, "".to_owned()))
+        }
+        CommandResponse::Rm { value: None } => {
+            Ok((StatusCode::NOT_FOUND, "Key not found".to_owned()))
+        }
+        response => whatever!("Unexpected response to rm: {:?}", response),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ScanQuery {
+    #[serde(default)]
+    prefix: String,
+}
+
+/// Lists the pairs under `?prefix=`, or the whole store without it, as a JSON array of
+/// `[key, value]` in key order.
+pub async fn scan(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Query(query): Query<ScanQuery>,
+) -> Result<Json<Vec<(String, String)>>> {
+    let command = Command::Scan {
+        prefix: query.prefix.clone(),
+    };
+    user.authorize_command(&command)?;
+    let command_response = ns.evaluate_read(&command)?;
+    let CommandResponse::Scan { pairs } = command_response else {
+        whatever!("Unexpected response to scan: {:?}", command_response);
+    };
+    info!("Scanned {} keys under {:?}", pairs.len(), query.prefix);
+    Ok(Json(pairs))
+}
+
+/// Lists the pairs whose value an index holds under a value, as a JSON array of `[key, value]`
+/// in key order.
+pub async fn find(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(FindPath { index, value }): Path<FindPath>,
+) -> Result<Json<Vec<(String, String)>>> {
+    let command = Command::Find { index, value };
+    user.authorize_command(&command)?;
+    let command_response = ns.evaluate_read(&command)?;
+    let CommandResponse::Find { pairs } = command_response else {
+        whatever!("Unexpected response to find: {:?}", command_response);
+    };
+    info!("Found {} keys", pairs.len());
+    Ok(Json(pairs))
+}
+
+/// Pushes the values of the JSON array in the body at the front of a list, responding with its
+/// length.
+pub async fn lpush(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
+    Json(values): Json<Vec<String>>,
+) -> Result<(StatusCode, String)> {
+    let command = Command::LPush { key, values };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::LPush { len } => Ok((StatusCode::OK, len.to_string())),
+        response => refusal(response),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RangeQuery {
+    #[serde(default)]
+    start: i64,
+    #[serde(default = "last_index")]
+    stop: i64,
+}
+
+fn last_index() -> i64 {
+    -1
+}
+
+/// Lists the elements of a list from `?start=` to `?stop=` included, all of them by default, as
+/// a JSON array.
+pub async fn lrange(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
+    Query(query): Query<RangeQuery>,
+) -> Result<Response> {
+    let command = Command::LRange {
+        key,
+        start: query.start,
+        stop: query.stop,
+    };
+    user.authorize_command(&command)?;
+    match ns.evaluate_read(&command)? {
+        CommandResponse::LRange { values } => Ok(Json(values).into_response()),
+        response => Ok(refusal(response)?.into_response()),
+    }
+}
+
+/// Sets a field of a hash, responding with 1 when the field is new and 0 otherwise.
+pub async fn hset(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(HSetPath { key, field, value }): Path<HSetPath>,
+) -> Result<(StatusCode, String)> {
+    let command = Command::HSet { key, field, value };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::HSet { created } => Ok((StatusCode::OK, u8::from(created).to_string())),
+        response => refusal(response),
+    }
+}
+
+pub async fn hget(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(FieldPath { key, field }): Path<FieldPath>,
+) -> Result<(StatusCode, String)> {
+    let command = Command::HGet { key, field };
+    user.authorize_command(&command)?;
+    match ns.evaluate_read(&command)? {
+        CommandResponse::HGet { value: Some(value) } => Ok((StatusCode::OK, value)),
+        CommandResponse::HGet { value: None } => {
+            Ok((StatusCode::NOT_FOUND, "Field not found".to_owned()))
+        }
+        response => refusal(response),
+    }
+}
+
+/// Adds the members of the JSON array in the body to a set, responding with how many were new.
+pub async fn sadd(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
+    Json(members): Json<Vec<String>>,
+) -> Result<(StatusCode, String)> {
+    let command = Command::SAdd { key, members };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::SAdd { added } => Ok((StatusCode::OK, added.to_string())),
+        response => refusal(response),
+    }
+}
+
+/// Lists the members of a set as a JSON array, in order.
+pub async fn smembers(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(KeyPath { key }): Path<KeyPath>,
+) -> Result<Response> {
+    let command = Command::SMembers { key };
+    user.authorize_command(&command)?;
+    match ns.evaluate_read(&command)? {
+        CommandResponse::SMembers { members } => Ok(Json(members).into_response()),
+        response => Ok(refusal(response)?.into_response()),
+    }
+}
+
+pub async fn incr_by(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(IncrByPath { key, delta }): Path<IncrByPath>,
+) -> Result<(StatusCode, String)> {
+    let command = Command::IncrBy { key, delta };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::IncrBy { value } => Ok((StatusCode::OK, value.to_string())),
+        response => refusal(response),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EvalBody {
+    script: String,
+    #[serde(default)]
+    keys: Vec<String>,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EvalShaBody {
+    #[serde(default)]
+    keys: Vec<String>,
+    #[serde(default)]
+    args: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct HashPath {
+    hash: String,
+}
+
+/// Runs the script of a `{"script": ..., "keys": [...], "args": [...]}` body, responding with
+/// what it returned as JSON.
+pub async fn eval(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Json(EvalBody { script, keys, args }): Json<EvalBody>,
+) -> Result<Response> {
+    let command = Command::Eval { script, keys, args };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::Eval { result } => Ok(Json(result).into_response()),
+        response => Ok(refusal(response)?.into_response()),
+    }
+}
+
+/// Runs the script cached under a hash with the `{"keys": [...], "args": [...]}` of the body,
+/// answering `404 Not Found` when no script is cached under it.
+pub async fn eval_sha(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(HashPath { hash }): Path<HashPath>,
+    Json(EvalShaBody { keys, args }): Json<EvalShaBody>,
+) -> Result<Response> {
+    let command = Command::EvalSha { hash, keys, args };
+    user.authorize_command(&command)?;
+    match ns.evaluate(&command)? {
+        CommandResponse::Eval { result } => Ok(Json(result).into_response()),
+        response => Ok(refusal(response)?.into_response()),
+    }
+}
+
+/// Answers a response that isn't the one the command expects: `409 Conflict` when the key holds
+/// a value of another type.
+fn refusal(response: CommandResponse) -> Result<(StatusCode, String)> {
+    match response {
+        CommandResponse::WrongType(wrong_type) => {
+            warn!("{}", wrong_type);
+            Ok((StatusCode::CONFLICT, wrong_type.to_string()))
+        }
+        response => whatever!("Unexpected response: {:?}", response),
+    }
+}
+
+/// Takes the snapshot under the read lock only, so writes resume as soon as the archive is
+/// built in memory.
+pub async fn backup(AuthUser(user): AuthUser, Ns(ns): Ns) -> Result<(StatusCode, Vec<u8>)> {
+    user.authorize_command(&Command::Backup)?;
+    if let Ok(state_lock) = ns.store().read() {
+        let mut archive = Vec::new();
+        let count = write_backup(state_lock.deref(), &mut archive)?;
+        info!("Backed up {} keys", count);
+        Ok((StatusCode::OK, archive))
     } else {
-        whatever!("Unable to acquire write lock on state");
+        whatever!("Unable to acquire read lock on state");
     }
 }
 
-pub async fn remove(
-    State(state): State<AppState>,
-    Path(key): Path<String>,
-) -> Result<(StatusCode, String)> {
-    if let Ok(mut state_lock) = state.store.write() {
-        let state = state_lock.deref_mut();
-        match state.remove(key.clone()) {
-            Ok(Some(_)) => {
-                info!("Removed value for key {}", key);
-                Ok((StatusCode::OK, "".to_owned()))
+pub async fn restore(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    body: Bytes,
+) -> Result<(StatusCode, String)> {
+    user.authorize_command(&Command::Restore { pairs: Vec::new() })?;
+    let pairs = match read_backup(body.as_ref()) {
+        Ok(pairs) => pairs,
+        Err(err) => {
+            warn!("Rejected backup archive: {}", err);
+            return Ok((StatusCode::BAD_REQUEST, err.to_string()));
+        }
+    };
+    let command_response = ns.evaluate(&Command::Restore { pairs })?;
+    let CommandResponse::Restore { count } = command_response else {
+        whatever!("Unexpected response to restore: {:?}", command_response);
+    };
+    info!("Restored {} keys", count);
+    Ok((StatusCode::OK, count.to_string()))
+}
+
+#[derive(Deserialize)]
+pub struct WatchQuery {
+    from: Option<String>,
+}
+
+/// Streams the changes of the keys matching `pattern` as Server-Sent Events. The id of every
+/// event is its position, so a reconnecting `EventSource` resumes through the `Last-Event-ID`
+/// header on its own; `?from=<epoch>-<seq>` does the same explicitly.
+pub async fn watch(
+    AuthUser(user): AuthUser,
+    Ns(ns): Ns,
+    Path(PatternPath { pattern }): Path<PatternPath>,
+    Query(query): Query<WatchQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    let key_pattern = KeyPattern::parse(&pattern);
+    user.authorize(Permission::Read, &key_pattern)?;
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    let after: Option<Position> = match query.from.or(last_event_id).map(|from| from.parse()) {
+        Some(Ok(position)) => Some(position),
+        Some(Err(err)) => return Ok((StatusCode::BAD_REQUEST, err.to_string()).into_response()),
+        None => None,
+    };
+    let watcher = if let Ok(state_lock) = ns.store().read() {
+        match state_lock.watch(key_pattern, after) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                warn!("Rejected watch of {}: {}", pattern, err);
+                return Ok((StatusCode::BAD_REQUEST, err.to_string()).into_response());
             }
-            Ok(None) => Ok((StatusCode::NOT_FOUND, "Key not found".to_owned())),
-            Err(_) => whatever!("Unable to remove value for key {}", key),
         }
     } else {
-        whatever!("Unable to acquire write lock on state");
-    }
+        whatever!("Unable to acquire read lock on state");
+    };
+    info!("Watching {} from {}", pattern, watcher.started_at());
+
+    // The watcher is polled by the response stream itself, and dropped with it when the client
+    // hangs up
+    let events = futures_util::stream::unfold(watcher, move |mut watcher| {
+        let pattern = pattern.clone();
+        async move {
+            match watcher.recv().await {
+                Ok(Some(event)) => {
+                    let sse_event = SseEvent::default()
+                        .id(event.position().to_string())
+                        .json_data(&event);
+                    Some((sse_event, watcher))
+                }
+                Ok(None) => None,
+                Err(err) => {
+                    // The client reconnects with the last id it got and resumes from there
+                    info!("Stopped watching {}: {}", pattern, err);
+                    None
+                }
+            }
+        }
+    });
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Lists every namespace, its settings and what it holds, as a JSON array.
+pub async fn list_namespaces(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<NamespaceInfo>>> {
+    user.authorize(Permission::Read, &KeyPattern::Prefix(String::new()))?;
+    let infos = state
+        .namespaces
+        .list()?
+        .iter()
+        .map(|namespace| namespace.info())
+        .collect();
+    Ok(Json(infos))
+}
+
+/// The settings of a namespace and what it holds, as JSON.
+pub async fn namespace_info(AuthUser(user): AuthUser, Ns(ns): Ns) -> Result<Json<NamespaceInfo>> {
+    user.authorize(Permission::Read, &KeyPattern::Prefix(String::new()))?;
+    Ok(Json(ns.info()))
+}
+
+#[derive(Deserialize)]
+pub struct NamespacePath {
+    ns: String,
+}
+
+/// Creates a namespace, set up by the JSON of a [`NamespaceConfig`] in the body if there is one.
+pub async fn create_namespace(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(NamespacePath { ns }): Path<NamespacePath>,
+    body: Bytes,
+) -> Result<(StatusCode, String)> {
+    user.authorize(Permission::Write, &KeyPattern::Prefix(String::new()))?;
+    let config = if body.is_empty() {
+        NamespaceConfig::default()
+    } else {
+        match serde_json::from_slice(&body) {
+            Ok(config) => config,
+            Err(err) => return Ok((StatusCode::BAD_REQUEST, err.to_string())),
+        }
+    };
+    state.namespaces.create(&ns, config)?;
+    info!("Created namespace {}", ns);
+    Ok((StatusCode::CREATED, "".to_owned()))
+}
+
+/// Drops a namespace, deleting its data unless it was given a directory of its own.
+pub async fn drop_namespace(
+    State(state): State<AppState>,
+    AuthUser(user): AuthUser,
+    Path(NamespacePath { ns }): Path<NamespacePath>,
+) -> Result<(StatusCode, ())> {
+    user.authorize(Permission::Write, &KeyPattern::Prefix(String::new()))?;
+    state.namespaces.remove(&ns)?;
+    info!("Dropped namespace {}", ns);
+    Ok((StatusCode::OK, ()))
 }
 
 pub async fn not_found() -> &'static str {
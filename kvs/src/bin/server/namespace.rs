@@ -0,0 +1,33 @@
+use super::app_state::AppState;
+use axum::extract::rejection::RawPathParamsRejection;
+use axum::extract::{FromRequestParts, RawPathParams};
+use axum::http::request::Parts;
+use kvs::namespace::{Namespace, DEFAULT_NAMESPACE};
+use kvs::Error;
+use snafu::whatever;
+use std::sync::Arc;
+
+/// The namespace a request runs in: the `{ns}` of the `/v2/ns/{ns}/...` routes, the default
+/// namespace for the `/v1/...` ones.
+pub struct Ns(pub Arc<Namespace>);
+
+impl FromRequestParts<AppState> for Ns {
+    type Rejection = Error;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let name = match RawPathParams::from_request_parts(parts, state).await {
+            Ok(params) => params
+                .iter()
+                .find(|(param, _)| *param == "ns")
+                .map(|(_, name)| name.to_owned()),
+            // Routes without parameters, e.g. `/v1/scan`
+            Err(RawPathParamsRejection::MissingPathParams(_)) => None,
+            Err(err) => whatever!("Invalid path: {}", err),
+        };
+        let name = name.as_deref().unwrap_or(DEFAULT_NAMESPACE);
+        Ok(Ns(state.namespaces.get(name)?))
+    }
+}
@@ -0,0 +1,95 @@
+use super::app_state::AppState;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{HeaderName, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use kvs::err::ERROR_CODE_HEADER;
+use kvs::metrics::Outcome;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use tracing::{info_span, Instrument};
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The ID of the next request not bringing its own.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Runs the request within a span carrying its ID, then counts it in the metrics. The ID is the
+/// `X-Request-Id` header of the request when there is one, and is sent back in the same header.
+pub async fn trace_request(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let id = request
+        .headers()
+        .get(&REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .unwrap_or_else(|| NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed).to_string());
+    let command = request
+        .extensions()
+        .get::<MatchedPath>()
+        .and_then(|path| command_name(request.method(), path.as_str()));
+    let span = info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+    );
+
+    let mut response = next.run(request).instrument(span).await;
+    if let Some(command) = command {
+        let outcome = match response.status() {
+            status if status.is_success() => Outcome::Ok,
+            // Unless it is an error, e.g. a missing namespace rather than a missing key
+            StatusCode::NOT_FOUND if !response.headers().contains_key(ERROR_CODE_HEADER) => {
+                Outcome::NotFound
+            }
+            _ => Outcome::Error,
+        };
+        state.metrics.observe(command, outcome, started.elapsed());
+    }
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+/// The command served by a route, e.g. `get` for `/v1/get/{key}` and for a `GET` of
+/// `/v2/ns/{ns}/keys/{key}`. The routes managing namespaces all count as `namespace`.
+fn command_name(method: &Method, route: &str) -> Option<&'static str> {
+    if route == "/v2/ns" || route == "/v2/ns/{ns}" {
+        return Some("namespace");
+    }
+    let route = route
+        .strip_prefix("/v1/")
+        .or_else(|| route.strip_prefix("/v2/ns/{ns}/"))?;
+    match route.split('/').next()? {
+        "keys" if route == "keys" => Some("scan"),
+        "keys" => match *method {
+            Method::PUT => Some("set"),
+            Method::DELETE => Some("rm"),
+            _ => Some("get"),
+        },
+        "get" => Some("get"),
+        "set" => Some("set"),
+        "rm" => Some("rm"),
+        "backup" => Some("backup"),
+        "restore" => Some("restore"),
+        "lpush" => Some("lpush"),
+        "lrange" => Some("lrange"),
+        "hset" => Some("hset"),
+        "hget" => Some("hget"),
+        "sadd" => Some("sadd"),
+        "smembers" => Some("smembers"),
+        "incrby" => Some("incrby"),
+        "find" => Some("find"),
+        "eval" => Some("eval"),
+        "evalsha" => Some("evalsha"),
+        "scan" => Some("scan"),
+        "watch" => Some("watch"),
+        _ => None,
+    }
+}
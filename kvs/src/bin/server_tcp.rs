@@ -1,66 +1,130 @@
 use clap::Parser;
+use cli::data_dir::lock_data_dir;
 use cli::engine::{check_engine_db_file, Engine};
+use cli::logging::init_logging;
+use cli::metrics::spawn_metrics_server;
+use cli::namespaces::open_namespaces;
 use cli::parse_addr::parse_addr;
 use cli::server::Server;
-use env_logger::Env;
-use kvs::{
-    evaluate_command, Command, CommandResponse, KvStoreV2, KvsEngine, MemStore, Result, SledStore,
-};
+use cli::snapshots::spawn_snapshots;
+use kvs::auth::{AuthConfig, Permission, User};
+use kvs::client::ServerErrorKind;
+use kvs::compression::Compression;
+use kvs::limits::{ConnectionLimiter, ConnectionPermit, Limits, RateLimiter};
+use kvs::metrics::{Metrics, Outcome};
+use kvs::namespace::{NamespaceCommand, Namespaces, DEFAULT_NAMESPACE};
+use kvs::protocol::{parse_namespace_command, parse_request, parse_watch, read_request, Request};
+use kvs::watch::{KeyPattern, Watcher};
+use kvs::{Command, CommandResponse, Result};
 use log::{error, info, warn};
+use rustls::{ServerConnection, StreamOwned};
 use snafu::{whatever, ResultExt, Whatever};
-use std::env;
-use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write};
-use std::net::{Shutdown, TcpListener, TcpStream};
-use std::ops::DerefMut;
+use std::io::{BufWriter, Cursor, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::io::AsyncWriteExt;
+use tracing::{info_span, instrument};
+
+/// How long a watch connection may stay silent before an empty line is sent, so that watchers
+/// which hung up are noticed even when their keys never change.
+const WATCH_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// The ID of the next request, carried by the span of every log line about it.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
 
 mod cli {
+    pub mod data_dir;
     pub mod engine;
+    pub mod logging;
+    pub mod metrics;
+    pub mod namespaces;
     pub mod parse_addr;
     pub mod server;
+    pub mod snapshots;
 }
 
-/// Turns the incoming stream into readable words represented as a vector of
-/// strings.
-fn tokenize<T: Read>(stream: T) -> Result<Vec<String>> {
-    let buf_reader = BufReader::new(stream);
-    let words = buf_reader
-        .split(b' ')
-        .map(|vec_result| {
-            let vec: Vec<u8> = vec_result.with_whatever_context::<_, &str, kvs::Error>(|_| {
-                "Failed to parse stream to u8 vector"
-            })?;
-            let word: String = String::from_utf8(vec)
-                .with_whatever_context::<_, &str, kvs::Error>(|_| {
-                    "Failed to parse u8 vector to UTF-8"
-                })?;
-            Ok::<String, kvs::Error>(word.trim().to_owned())
-        })
-        .filter(|result| match result {
-            Ok(word) => !word.is_empty(),
-            Err(_) => true,
-        })
-        .collect::<Result<Vec<String>>>()?;
-
-    Ok(words)
+/// Writes `response`, or `Z <algorithm> ` followed by `response` compressed with `compression`
+/// when the client accepts it and compressing makes the response smaller.
+fn write_response<T: Write>(
+    stream: &mut T,
+    response: &[u8],
+    compression: Compression,
+) -> Result<()> {
+    let compressed = compression.compress_if_smaller(response)?;
+    let mut buf_writer = BufWriter::new(stream);
+    match compressed {
+        Some(compressed) => {
+            write!(buf_writer, "Z {} ", compression).and_then(|_| buf_writer.write_all(&compressed))
+        }
+        None => buf_writer.write_all(response),
+    }
+    .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+    buf_writer
+        .flush()
+        .with_whatever_context(|e| format!("Error happened flushing {}", e))
 }
 
-fn parse(words: Vec<String>) -> Result<Command> {
-    match &words[..] {
-        [command_str, key] if command_str.to_uppercase() == "GET" => {
-            Ok(Command::Get { key: key.clone() })
+/// Runs a namespace command, responding `OK` to creations and drops, and `OK` followed by a JSON
+/// array of every namespace, its settings and what it holds to `NAMESPACE LIST`.
+fn run_namespace_command(
+    command: NamespaceCommand,
+    user: &User,
+    namespaces: &Namespaces,
+) -> Result<String> {
+    let everything = KeyPattern::Prefix(String::new());
+    match command {
+        NamespaceCommand::Create { name, config } => {
+            user.authorize(Permission::Write, &everything)?;
+            namespaces.create(&name, config)?;
+            info!("Created namespace {}", name);
+            Ok("OK".to_owned())
+        }
+        NamespaceCommand::Drop { name } => {
+            user.authorize(Permission::Write, &everything)?;
+            namespaces.remove(&name)?;
+            info!("Dropped namespace {}", name);
+            Ok("OK".to_owned())
         }
-        [command_str, key, value] if command_str.to_uppercase() == "SET" => Ok(Command::Set {
-            key: key.clone(),
-            value: value.clone(),
-        }),
-        [command_str, key] if command_str.to_uppercase() == "RM" => {
-            Ok(Command::Rm { key: key.clone() })
+        NamespaceCommand::List => {
+            user.authorize(Permission::Read, &everything)?;
+            let infos: Vec<_> = namespaces
+                .list()?
+                .iter()
+                .map(|namespace| namespace.info())
+                .collect();
+            let infos = serde_json::to_string(&infos)
+                .with_whatever_context(|_| "Couldn't serialize the namespaces")?;
+            Ok(format!("OK {}", infos))
         }
-        _ => whatever!("Invalid command"),
     }
 }
 
+/// Writes `OK <epoch>-<seq>` with the position the watch started at, then one JSON event per
+/// line until the client hangs up. If the watcher is dropped, e.g. for falling behind, a last
+/// `ERR <code> <message>` line says why.
+fn stream_events<T: Write>(stream: &mut T, mut watcher: Watcher) -> Result<()> {
+    writeln!(stream, "OK {}", watcher.started_at())
+        .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+    loop {
+        let line = match watcher.recv_timeout(WATCH_HEARTBEAT) {
+            Ok(Some(event)) => serde_json::to_string(&event)
+                .with_whatever_context(|_| format!("Couldn't serialize event {:?}", event))?,
+            Ok(None) => String::new(),
+            Err(err) => {
+                writeln!(stream, "ERR {} {}", err.server_kind().code(), err)
+                    .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+                return Err(err);
+            }
+        };
+        writeln!(stream, "{}", line)
+            .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+    }
+}
+
+#[instrument(skip_all)]
 fn respond<T: Write>(stream: &mut T, command_response: CommandResponse) -> Result<()> {
     match command_response {
         CommandResponse::Get { value } => {
@@ -71,7 +135,7 @@ fn respond<T: Write>(stream: &mut T, command_response: CommandResponse) -> Resul
                     .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
             } else {
                 buf_writer
-                    .write("ERR Key not found".as_bytes())
+                    .write("ERR NOT_FOUND Key not found".as_bytes())
                     .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
             }
             buf_writer
@@ -79,11 +143,73 @@ fn respond<T: Write>(stream: &mut T, command_response: CommandResponse) -> Resul
                 .with_whatever_context(|e| format!("Error happened flushing {}", e))?;
         }
         CommandResponse::Set {} => {}
+        CommandResponse::Scan { pairs } | CommandResponse::Find { pairs } => {
+            // Values may hold any character, so the pairs go as a JSON array of `[key, value]`
+            let pairs = serde_json::to_string(&pairs)
+                .with_whatever_context(|_| "Couldn't serialize the scanned pairs")?;
+            write!(stream, "OK {}", pairs)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::Backup { archive } => {
+            let mut buf_writer = BufWriter::new(stream);
+            buf_writer
+                .write_all(b"OK ")
+                .and_then(|_| buf_writer.write_all(&archive))
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+            buf_writer
+                .flush()
+                .with_whatever_context(|e| format!("Error happened flushing {}", e))?;
+        }
+        CommandResponse::Restore { count } => {
+            write!(stream, "OK {}", count)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::LPush { len: count } | CommandResponse::SAdd { added: count } => {
+            write!(stream, "OK {}", count)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::HSet { created } => {
+            write!(stream, "OK {}", u8::from(created))
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::IncrBy { value } => {
+            write!(stream, "OK {}", value)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::Eval { result } => {
+            write!(stream, "OK {}", result)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::HGet { value: Some(value) } => {
+            write!(stream, "OK {}", value)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::HGet { value: None } => {
+            write!(stream, "ERR NOT_FOUND Field not found")
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::LRange { values: elements }
+        | CommandResponse::SMembers { members: elements } => {
+            // Elements may hold any character, so they go as a JSON array too
+            let elements = serde_json::to_string(&elements)
+                .with_whatever_context(|_| "Couldn't serialize the elements")?;
+            write!(stream, "OK {}", elements)
+                .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
+        CommandResponse::WrongType(wrong_type) => {
+            write!(
+                stream,
+                "ERR {} {}",
+                ServerErrorKind::WrongType.code(),
+                wrong_type
+            )
+            .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
+        }
         CommandResponse::Rm { value } => {
             if value.is_none() {
                 let mut buf_writer = BufWriter::new(stream);
                 buf_writer
-                    .write("ERR Key not found".as_bytes())
+                    .write("ERR NOT_FOUND Key not found".as_bytes())
                     .with_whatever_context(|e| format!("Error happened writing to stream {}", e))?;
                 buf_writer
                     .flush()
@@ -98,81 +224,25 @@ fn respond<T: Write>(stream: &mut T, command_response: CommandResponse) -> Resul
 mod tests {
     use super::*;
 
-    mod tokenize {
+    mod write_response {
         use super::*;
 
         #[test]
         fn success() {
-            let test_table = vec![
-                (
-                    "word1 word2 word3",
-                    vec![
-                        "word1".to_string(),
-                        "word2".to_string(),
-                        "word3".to_string(),
-                    ],
-                ),
-                (
-                    "  word1   word2  word3    ",
-                    vec![
-                        "word1".to_string(),
-                        "word2".to_string(),
-                        "word3".to_string(),
-                    ],
-                ),
-                (
-                    "word1\nword2 word3",
-                    vec!["word1\nword2".to_string(), "word3".to_string()],
-                ),
-            ];
-
-            for (input, expected) in test_table {
-                let stream = Cursor::new(input.as_bytes());
-                let got = tokenize(stream).unwrap();
-                assert_eq!(got, expected);
-            }
-        }
-    }
-
-    mod parse {
-        use super::*;
+            let response = format!("OK {}", "value1".repeat(100));
+            let mut stream = Cursor::new(Vec::new());
+            write_response(&mut stream, response.as_bytes(), Compression::Zstd).unwrap();
+            let written = stream.into_inner();
+            let compressed = written.strip_prefix(b"Z zstd ").unwrap();
+            assert_eq!(
+                Compression::Zstd.decompress(compressed).unwrap(),
+                response.as_bytes()
+            );
 
-        #[test]
-        fn success() {
-            let test_table = vec![
-                (
-                    "GET key1".to_string(),
-                    Command::Get {
-                        key: "key1".to_string(),
-                    },
-                ),
-                (
-                    "SET key1 value1".to_string(),
-                    Command::Set {
-                        key: "key1".to_string(),
-                        value: "value1".to_string(),
-                    },
-                ),
-                (
-                    "RM key1".to_string(),
-                    Command::Rm {
-                        key: "key1".to_string(),
-                    },
-                ),
-                (
-                    "   GET   spaced-key-command    ".to_string(),
-                    Command::Get {
-                        key: "spaced-key-command".to_string(),
-                    },
-                ),
-            ];
-
-            for (input, expected) in test_table {
-                let input_stream = Cursor::new(input.as_bytes());
-                let words = tokenize(input_stream).unwrap();
-                let got = parse(words).unwrap();
-                assert_eq!(got, expected);
-            }
+            // Short responses aren't worth it
+            let mut stream = Cursor::new(Vec::new());
+            write_response(&mut stream, b"OK value1", Compression::Zstd).unwrap();
+            assert_eq!(stream.into_inner(), b"OK value1");
         }
     }
 
@@ -195,55 +265,280 @@ mod tests {
 }
 
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
+    let config = Server::parse().into_config()?;
+    init_logging(&config.log_format);
     info!("Logger initialized!");
     info!("Current binary version: {:?}", env!("CARGO_PKG_VERSION"));
 
-    let cli = Server::parse();
     // TODO: validate engine by Clap instead of hard-coding
-    parse_addr(&cli.addr)?;
-    info!("Started server at: {:?}", cli.addr);
+    parse_addr(&config.addr)?;
+    info!("Started server at: {:?}", config.addr);
     info!("Chosen engine: {:?}", {
-        match cli.engine {
+        match config.engine {
             Engine::Kvs => "kvs",
             Engine::Sled => "sled",
             Engine::Mem => "in-memory",
         }
     });
-    let current_dir = env::current_dir().unwrap();
-    info!("Current directory: {:?}", &current_dir);
+    info!("Data directory: {:?}", &config.data_dir);
+    match &config.auth {
+        Some(auth) => info!("Authentication enabled for {} users", auth.users.len()),
+        None => warn!("Authentication disabled: anyone reaching the server may do anything"),
+    }
+    let _data_dir_lock = lock_data_dir(&config.data_dir)?;
 
-    if let Err(err) = check_engine_db_file(&cli.engine) {
+    if let Err(err) = check_engine_db_file(&config.engine, &config.data_dir) {
         error!(
             "Database file of engines other than {} already exists",
-            cli.engine,
+            config.engine,
         );
         return Err(err);
     }
 
-    let mut store: Box<dyn KvsEngine> = match cli.engine {
-        Engine::Kvs => Box::new(KvStoreV2::open(current_dir.as_path())?),
-        Engine::Sled => Box::new(SledStore::open(current_dir.as_path())?),
-        Engine::Mem => Box::new(MemStore::new()),
-    };
+    // Shared with the metrics server, which reads the engine stats
+    let namespaces = open_namespaces(&config)?;
+    if config.snapshots() {
+        let interval = config.snapshot_interval.filter(|&secs| secs > 0);
+        spawn_snapshots(namespaces.clone(), interval.map(Duration::from_secs))?;
+    }
+    let metrics = Arc::new(Metrics::new());
+    if let Some(metrics_addr) = &config.metrics_addr {
+        spawn_metrics_server(metrics_addr, metrics.clone(), namespaces.clone())?;
+    }
 
-    let listener = TcpListener::bind(cli.addr).unwrap();
+    let tls = config.tls()?.map(Arc::new);
+    if tls.is_some() {
+        info!("Serving over TLS");
+    }
+    info!("Limits: {:?}", config.limits);
+    info!("Compression: {}", config.compression);
+    let context = Arc::new(Context {
+        namespaces,
+        auth: config.auth,
+        compression: config.compression,
+        metrics,
+        rate_limiter: config.limits.rate_limiter(),
+        connections: ConnectionLimiter::new(config.limits.max_connections),
+        limits: config.limits,
+    });
+
+    let listener = TcpListener::bind(config.addr).unwrap();
     for stream in listener.incoming() {
-        let mut stream = stream.with_whatever_context(|err| {
+        let stream = stream.with_whatever_context(|err| {
             format!("Failed to accept incoming connection: {}", err)
         })?;
-        let words = tokenize(&stream)?;
-        info!("Received words: {:?}", words);
-        let command = parse(words)?;
-        info!("Parsed command: {:?}", command);
-        let command_response = evaluate_command(&command, store.deref_mut())?;
-        info!("Response: {:?}", command_response);
-        respond(&mut stream, command_response)?;
-        info!("Sent response");
-        // stream.shutdown(Shutdown::Both).with_whatever_context(
-        //     |err| "Unable to shut down stream",
-        // )?;
+        // Without timeouts, a client that stops sending would block every other one
+        let timeout = Some(context.limits.timeout());
+        let peer = stream.set_read_timeout(timeout).and_then(|_| {
+            stream.set_write_timeout(timeout)?;
+            stream.peer_addr()
+        });
+        let peer = match peer {
+            Ok(peer) => peer.ip(),
+            Err(err) => {
+                warn!("Failed to set up incoming connection: {}", err);
+                continue;
+            }
+        };
+        // Counted from here on, so that connections still sending their request or shaking
+        // hands count as well
+        let permit = context.connections.acquire();
+        match &tls {
+            Some(tls) => match ServerConnection::new(tls.clone()) {
+                Ok(connection) => {
+                    dispatch(StreamOwned::new(connection, stream), peer, permit, &context)
+                }
+                Err(err) => error!("Failed to set up TLS connection: {}", err),
+            },
+            None => dispatch(stream, peer, permit, &context),
+        }
     }
 
     Ok(())
 }
+
+/// Everything a connection is served with.
+struct Context {
+    namespaces: Arc<Namespaces>,
+    /// `None` when the server runs without authentication.
+    auth: Option<AuthConfig>,
+    /// How responses are compressed for the clients accepting it.
+    compression: Compression,
+    metrics: Arc<Metrics>,
+    limits: Limits,
+    /// `None` when requests aren't rate limited.
+    rate_limiter: Option<RateLimiter>,
+    connections: ConnectionLimiter,
+}
+
+/// A client connection, either plain or over TLS.
+trait Connection: Read + Write + Send + 'static {
+    /// Tells the client that the response is complete.
+    fn finish(&mut self) -> std::io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Connection for StreamOwned<ServerConnection, TcpStream> {
+    fn finish(&mut self) -> std::io::Result<()> {
+        // Without it, the client can't tell a complete response from a truncated one
+        self.conn.send_close_notify();
+        self.flush()
+    }
+}
+
+/// Serves a connection on a thread of its own. A connection over the limit is refused from the
+/// accept loop instead, so that a flood of connections can't spawn threads without end; reading
+/// its request first takes at most the timeout.
+fn dispatch<C: Connection>(
+    stream: C,
+    peer: IpAddr,
+    permit: Result<ConnectionPermit>,
+    context: &Arc<Context>,
+) {
+    match permit {
+        Ok(permit) => {
+            let context = context.clone();
+            thread::spawn(move || serve(stream, peer, Ok(permit), &context));
+        }
+        Err(err) => serve(stream, peer, Err(err), context),
+    }
+}
+
+/// Handles the request of a connection, then closes it, unless the request was `WATCH`.
+fn serve<C: Connection>(
+    mut stream: C,
+    peer: IpAddr,
+    permit: Result<ConnectionPermit>,
+    context: &Context,
+) {
+    let span = info_span!(
+        "request",
+        id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed),
+        %peer
+    );
+    let _entered = span.enter();
+    match handle_request(&mut stream, peer, permit, context) {
+        Ok(Some((pattern, watcher, _permit))) => {
+            // Watch connections stay open, and keep their thread and permit until they close
+            info!("Watching {:?}", pattern);
+            if let Err(err) = stream_events(&mut stream, watcher) {
+                info!("Stopped watching {:?}: {}", pattern, err);
+            }
+            return;
+        }
+        Ok(None) => {}
+        Err(err) => {
+            error!("Failed to handle request: {}", err);
+            if let Err(err) = write!(stream, "ERR {} {}", err.server_kind().code(), err) {
+                warn!("Unable to send error response: {}", err);
+            }
+        }
+    }
+    if let Err(err) = stream.finish() {
+        warn!("Unable to finish response: {}", err);
+    }
+}
+
+/// Handles a single request. Errors are sent back to the client by the caller, so that a bad
+/// request doesn't take the whole server down. A `WATCH` request is answered by the caller too,
+/// with the returned watcher, which keeps counting as an open connection until the permit is
+/// dropped. The store of the namespace is only locked while the command runs.
+fn handle_request<C: Connection>(
+    stream: &mut C,
+    peer: IpAddr,
+    permit: Result<ConnectionPermit>,
+    context: &Context,
+) -> Result<Option<(KeyPattern, Watcher, ConnectionPermit)>> {
+    let started = Instant::now();
+    let metrics = &context.metrics;
+    // Requests failing before their command is known are counted as `unknown` ones
+    let observe_unknown = |_: &kvs::Error| {
+        metrics.observe("unknown", Outcome::Error, started.elapsed());
+    };
+    // The request is read even when it gets refused, so that the client reads the refusal
+    // instead of a reset connection
+    let Request {
+        token,
+        accepts,
+        namespace,
+        words,
+        payload,
+    } = read_request(&mut *stream, &context.limits).inspect_err(observe_unknown)?;
+    let permit = permit.inspect_err(observe_unknown)?;
+    if let Some(rate_limiter) = &context.rate_limiter {
+        rate_limiter.check(peer).inspect_err(observe_unknown)?;
+    }
+    let user = match &context.auth {
+        Some(auth) => auth
+            .authenticate(token.as_deref())
+            .inspect_err(observe_unknown)?,
+        None => User::unrestricted(),
+    };
+    info!("Received words from {}: {:?}", user.name, words);
+    if let Some(command) = parse_namespace_command(&words).inspect_err(observe_unknown)? {
+        let response = run_namespace_command(command, &user, &context.namespaces);
+        let outcome = match response {
+            Ok(_) => Outcome::Ok,
+            Err(_) => Outcome::Error,
+        };
+        metrics.observe("namespace", outcome, started.elapsed());
+        write_response(stream, response?.as_bytes(), Compression::None)?;
+        return Ok(None);
+    }
+    let namespace = context
+        .namespaces
+        .get(namespace.as_deref().unwrap_or(DEFAULT_NAMESPACE))
+        .inspect_err(observe_unknown)?;
+    if let Some((pattern, after)) = parse_watch(&words).inspect_err(observe_unknown)? {
+        let watcher = user.authorize(Permission::Read, &pattern).and_then(|_| {
+            match namespace.store().read() {
+                Ok(store) => store.watch(pattern.clone(), after),
+                Err(_) => whatever!("Unable to acquire read lock on state"),
+            }
+        });
+        let outcome = match watcher {
+            Ok(_) => Outcome::Ok,
+            Err(_) => Outcome::Error,
+        };
+        metrics.observe("watch", outcome, started.elapsed());
+        return Ok(Some((pattern, watcher?, permit)));
+    }
+    let command = parse_request(words, &payload).inspect_err(observe_unknown)?;
+    match &command {
+        Command::Restore { pairs } => info!("Parsed command: Restore of {} pairs", pairs.len()),
+        _ => info!("Parsed command: {:?}", command),
+    }
+    let command_response = context
+        .limits
+        .check_command(&command)
+        .and_then(|_| user.authorize_command(&command))
+        .and_then(|_| namespace.evaluate(&command));
+    metrics.observe(
+        command.name(),
+        Outcome::of(&command_response),
+        started.elapsed(),
+    );
+    let command_response = command_response?;
+    match &command_response {
+        CommandResponse::Backup { archive } => {
+            info!("Response: Backup of {} bytes", archive.len())
+        }
+        _ => info!("Response: {:?}", command_response),
+    }
+    let compression = if accepts.contains(&context.compression) {
+        context.compression
+    } else {
+        Compression::None
+    };
+    let mut response = Vec::new();
+    respond(&mut response, command_response)?;
+    write_response(stream, &response, compression)?;
+    info!("Sent response");
+
+    Ok(None)
+}
@@ -0,0 +1,1032 @@
+//! Clients of `kvs-server` (TCP) and `server` (HTTP).
+//!
+//! ```no_run
+//! use kvs::client::{ClientConfig, KvsClient, Protocol};
+//!
+//! let client = KvsClient::new(ClientConfig {
+//!     protocol: Protocol::Tcp,
+//!     ..ClientConfig::new("127.0.0.1:4004")
+//! })?;
+//! client.set("key1", "value1")?;
+//! assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+//! # Ok::<(), kvs::Error>(())
+//! ```
+//!
+//! The TCP protocol closes the connection after every response, so TCP connections can't be
+//! reused: there is no pool as such, only a bound on how many connections are open at once. HTTP
+//! connections are kept alive and reused.
+//!
+//! Given several servers, separated by commas in [`ClientConfig::addr`], the client spreads the
+//! keys over them as [`crate::routing`] decides, keeping every key on
+//! [`ClientConfig::replicas`] of them. A server that can't be reached is marked down and left
+//! alone for [`ClientConfig::down_for`], its keys being read from their other replicas meanwhile.
+//! Writes go to every replica that is up, and a replica that was down misses them: nothing
+//! repairs it once it is back.
+//!
+//! Commands run in the default namespace of the servers, unless [`ClientConfig::namespace`] names
+//! another one.
+
+use crate::backup::{read_backup, write_pairs};
+use crate::err::Result;
+use crate::namespace::{NamespaceCommand, NamespaceConfig, NamespaceInfo};
+use crate::routing::route;
+use crate::script::script_hash;
+use crate::watch::{Event, Position};
+use crate::{Command, CommandResponse, Error};
+use futures_util::future::join_all;
+use snafu::{whatever, ResultExt};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::Semaphore;
+
+mod http;
+mod tcp;
+
+pub use crate::err::ServerErrorKind;
+
+/// The protocol spoken by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// The line protocol of `kvs-server`.
+    #[default]
+    Tcp,
+    /// The REST API of `server`.
+    Http,
+}
+
+/// How to reach the server and how hard to try.
+#[derive(Clone)]
+pub struct ClientConfig {
+    /// `host:port` of the server. HTTP servers may also be given as a URL. Several servers are
+    /// separated by commas.
+    pub addr: String,
+    pub protocol: Protocol,
+    /// The token to authenticate with, for servers requiring authentication.
+    pub token: Option<String>,
+    /// Connect over TLS with these settings, see [`crate::tls::client_config`].
+    pub tls: Option<rustls::ClientConfig>,
+    pub connect_timeout: Duration,
+    /// How long a whole request may take, connecting included. Watches aren't bound by it.
+    pub timeout: Duration,
+    /// How many times a failed request is tried again. Only requests that failed before reaching
+    /// the server or that the server refused for now are retried, as well as reads whatever the
+    /// failure.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every following one.
+    pub backoff: Duration,
+    /// Connections open at the same time, to every server.
+    pub max_connections: usize,
+    /// With several servers, how many of them hold every key.
+    pub replicas: usize,
+    /// How long a server that couldn't be reached is left alone before being tried again.
+    pub down_for: Duration,
+    /// Ask the servers to compress their responses, which those set up with compression do for
+    /// the large ones. Servers older than compression refuse the requests of TCP clients asking.
+    pub compression: bool,
+    /// The namespace to run the commands in, instead of the default one.
+    pub namespace: Option<String>,
+}
+
+impl ClientConfig {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            protocol: Protocol::default(),
+            token: None,
+            tls: None,
+            connect_timeout: Duration::from_secs(5),
+            timeout: Duration::from_secs(30),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            max_connections: 16,
+            replicas: 1,
+            down_for: Duration::from_secs(5),
+            compression: false,
+            namespace: None,
+        }
+    }
+
+    /// The addresses of the servers.
+    pub fn addrs(&self) -> Vec<String> {
+        self.addr
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+enum Transport {
+    Tcp(tcp::TcpTransport),
+    Http(http::HttpTransport),
+}
+
+/// A server, and whether it answered lately.
+struct Node {
+    addr: String,
+    transport: Transport,
+    connections: Semaphore,
+    /// When the server last failed to answer, unless it answered since.
+    down_since: Mutex<Option<Instant>>,
+}
+
+impl Node {
+    fn is_down(&self, down_for: Duration) -> bool {
+        let down_since = self.down_since.lock().unwrap();
+        down_since.is_some_and(|since| since.elapsed() < down_for)
+    }
+
+    /// Marks the server up or down after a request to it ended with `result`.
+    fn record<T>(&self, result: &Result<T>) {
+        let mut down_since = self.down_since.lock().unwrap();
+        match result {
+            Err(err) if is_unreachable(err) => *down_since = Some(Instant::now()),
+            _ => *down_since = None,
+        }
+    }
+}
+
+struct Inner {
+    nodes: Vec<Node>,
+    config: ClientConfig,
+}
+
+/// A client for async code. Cloning it is cheap, and the clones share their connections.
+#[derive(Clone)]
+pub struct AsyncKvsClient {
+    inner: Arc<Inner>,
+}
+
+impl AsyncKvsClient {
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        let addrs = config.addrs();
+        if addrs.is_empty() {
+            whatever!("No server address in {:?}", config.addr);
+        }
+        let nodes = addrs
+            .into_iter()
+            .map(|addr| {
+                let config = ClientConfig {
+                    addr: addr.clone(),
+                    ..config.clone()
+                };
+                let transport = match config.protocol {
+                    Protocol::Tcp => Transport::Tcp(tcp::TcpTransport::new(&config)?),
+                    Protocol::Http => Transport::Http(http::HttpTransport::new(&config)?),
+                };
+                Ok(Node {
+                    addr,
+                    transport,
+                    connections: Semaphore::new(config.max_connections.max(1)),
+                    down_since: Mutex::new(None),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            inner: Arc::new(Inner { nodes, config }),
+        })
+    }
+
+    pub async fn get(&self, key: &str) -> Result<Option<String>> {
+        let command = Command::Get {
+            key: key.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::Get { value } => Ok(value),
+            response => unexpected(response),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str) -> Result<()> {
+        let command = Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::Set => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Fails with [`Error::KeyNotFound`] when there is no such key.
+    pub async fn remove(&self, key: &str) -> Result<()> {
+        let command = Command::Rm {
+            key: key.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::Rm { .. } => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Lists the pairs whose key starts with `prefix`, in key order. An empty prefix lists the
+    /// whole store.
+    pub async fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let command = Command::Scan {
+            prefix: prefix.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::Scan { pairs } => Ok(pairs),
+            response => unexpected(response),
+        }
+    }
+
+    /// Lists the pairs whose JSON value holds `value` at the path of `index`, named
+    /// `<collection>.<name>` as declared on the server, in key order. Fails while the server is
+    /// still building the index.
+    pub async fn find(&self, index: &str, value: &str) -> Result<Vec<(String, String)>> {
+        let command = Command::Find {
+            index: index.to_owned(),
+            value: value.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::Find { pairs } => Ok(pairs),
+            response => unexpected(response),
+        }
+    }
+
+    /// Pushes `values` one after the other at the front of the list at `key`. Returns the length
+    /// of the list. Fails with [`ServerErrorKind::WrongType`] when `key` holds something else, as
+    /// do the other commands on typed values.
+    pub async fn lpush(&self, key: &str, values: &[&str]) -> Result<usize> {
+        let command = Command::LPush {
+            key: key.to_owned(),
+            values: values.iter().map(|value| value.to_string()).collect(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::LPush { len } => Ok(len),
+            response => unexpected(response),
+        }
+    }
+
+    /// Lists the elements of the list at `key` from `start` to `stop` included. Negative indexes
+    /// count from the end, so `0` to `-1` lists them all.
+    pub async fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>> {
+        let command = Command::LRange {
+            key: key.to_owned(),
+            start,
+            stop,
+        };
+        match self.execute(&command).await? {
+            CommandResponse::LRange { values } => Ok(values),
+            response => unexpected(response),
+        }
+    }
+
+    /// Sets a field of the hash at `key`. Returns whether the field is new.
+    pub async fn hset(&self, key: &str, field: &str, value: &str) -> Result<bool> {
+        let command = Command::HSet {
+            key: key.to_owned(),
+            field: field.to_owned(),
+            value: value.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::HSet { created } => Ok(created),
+            response => unexpected(response),
+        }
+    }
+
+    pub async fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        let command = Command::HGet {
+            key: key.to_owned(),
+            field: field.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::HGet { value } => Ok(value),
+            response => unexpected(response),
+        }
+    }
+
+    /// Adds `members` to the set at `key`. Returns how many of them weren't in it yet.
+    pub async fn sadd(&self, key: &str, members: &[&str]) -> Result<usize> {
+        let command = Command::SAdd {
+            key: key.to_owned(),
+            members: members.iter().map(|member| member.to_string()).collect(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::SAdd { added } => Ok(added),
+            response => unexpected(response),
+        }
+    }
+
+    /// Lists the members of the set at `key`, in order.
+    pub async fn smembers(&self, key: &str) -> Result<Vec<String>> {
+        let command = Command::SMembers {
+            key: key.to_owned(),
+        };
+        match self.execute(&command).await? {
+            CommandResponse::SMembers { members } => Ok(members),
+            response => unexpected(response),
+        }
+    }
+
+    /// Adds `delta` to the integer at `key`, a missing key counting as 0. Returns the new value.
+    /// As it isn't idempotent, it is never retried once it may have reached the server.
+    pub async fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        let command = Command::IncrBy {
+            key: key.to_owned(),
+            delta,
+        };
+        match self.execute(&command).await? {
+            CommandResponse::IncrBy { value } => Ok(value),
+            response => unexpected(response),
+        }
+    }
+
+    /// Runs a Lua script on the server, see [`crate::script`], returning what it returned. The
+    /// script may only touch `keys`, which must be on the same servers when there are several.
+    /// Only the hash of the script is sent at first, and the whole script when the server doesn't
+    /// have it cached yet. Never retried once it may have reached the server.
+    pub async fn eval(
+        &self,
+        script: &str,
+        keys: &[&str],
+        args: &[&str],
+    ) -> Result<serde_json::Value> {
+        let keys: Vec<_> = keys.iter().map(|key| key.to_string()).collect();
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        let by_hash = Command::EvalSha {
+            hash: script_hash(script),
+            keys: keys.clone(),
+            args: args.clone(),
+        };
+        let response = match self.execute(&by_hash).await {
+            Err(Error::Server {
+                kind: ServerErrorKind::NoScript,
+                ..
+            }) => {
+                let command = Command::Eval {
+                    script: script.to_owned(),
+                    keys,
+                    args,
+                };
+                self.execute(&command).await?
+            }
+            response => response?,
+        };
+        match response {
+            CommandResponse::Eval { result } => Ok(result),
+            response => unexpected(response),
+        }
+    }
+
+    /// Fetches a backup archive of the whole store, checked before it is returned.
+    pub async fn backup(&self) -> Result<Vec<u8>> {
+        match self.execute(&Command::Backup).await? {
+            CommandResponse::Backup { archive } => {
+                read_backup(archive.as_slice())?;
+                Ok(archive)
+            }
+            response => unexpected(response),
+        }
+    }
+
+    /// Loads a backup archive into the store, which must be empty. Returns the number of keys.
+    pub async fn restore(&self, archive: &[u8]) -> Result<usize> {
+        let command = Command::Restore {
+            pairs: read_backup(archive)?,
+        };
+        match self.execute(&command).await? {
+            CommandResponse::Restore { count } => Ok(count),
+            response => unexpected(response),
+        }
+    }
+
+    /// Runs any command, retrying it as configured. As the protocols don't send removed values
+    /// back, a successful `Rm` responds with `Rm { value: None }`, and a missing key fails with
+    /// [`Error::KeyNotFound`].
+    ///
+    /// With several servers, reads go to the first replica of the key that answers and writes to
+    /// all of them, succeeding if one did. Scripts run like writes, on the replicas their keys
+    /// share. Scans and finds ask every server. Backups and restores are left
+    /// to be run against every server on its own.
+    pub async fn execute(&self, command: &Command) -> Result<CommandResponse> {
+        let nodes = &self.inner.nodes;
+        if let [node] = nodes.as_slice() {
+            return self.execute_on(node, command, false).await;
+        }
+        match command {
+            Command::Get { key }
+            | Command::LRange { key, .. }
+            | Command::HGet { key, .. }
+            | Command::SMembers { key } => self.read(key, command).await,
+            Command::Set { key, .. }
+            | Command::Rm { key }
+            | Command::LPush { key, .. }
+            | Command::HSet { key, .. }
+            | Command::SAdd { key, .. }
+            | Command::IncrBy { key, .. } => self.write(key, command).await,
+            Command::Scan { .. } | Command::Find { .. } => self.scan_all(command).await,
+            Command::Eval { keys, .. } | Command::EvalSha { keys, .. } => {
+                let Some(key) = keys.first() else {
+                    whatever!("A script needs a key to know which server to run on");
+                };
+                let servers = |key: &str| {
+                    let mut servers = route(key, &self.inner.config.addrs());
+                    servers.truncate(self.inner.config.replicas.clamp(1, nodes.len()));
+                    servers.sort();
+                    servers
+                };
+                if keys[1..].iter().any(|other| servers(other) != servers(key)) {
+                    whatever!("The keys of a script must be on the same servers");
+                }
+                self.write(key, command).await
+            }
+            Command::Backup | Command::Restore { .. } => whatever!(
+                "{} only works against a single server, not {}",
+                command.name(),
+                self.inner.config.addr
+            ),
+        }
+    }
+
+    /// Creates a namespace on every server. A directory given in `config` is a directory of each
+    /// server.
+    pub async fn create_namespace(&self, name: &str, config: &NamespaceConfig) -> Result<()> {
+        let command = NamespaceCommand::Create {
+            name: name.to_owned(),
+            config: config.clone(),
+        };
+        self.run_namespace_command_everywhere(&command).await
+    }
+
+    /// Drops a namespace on every server, deleting its data unless it was given a directory of
+    /// its own.
+    pub async fn drop_namespace(&self, name: &str) -> Result<()> {
+        let command = NamespaceCommand::Drop {
+            name: name.to_owned(),
+        };
+        self.run_namespace_command_everywhere(&command).await
+    }
+
+    /// Lists the namespaces of the server, the default one included, with their settings and
+    /// what they hold.
+    pub async fn namespaces(&self) -> Result<Vec<NamespaceInfo>> {
+        let [node] = self.inner.nodes.as_slice() else {
+            whatever!(
+                "Listing namespaces only works against a single server, not {}",
+                self.inner.config.addr
+            );
+        };
+        node.run_namespace_command(&NamespaceCommand::List, &self.inner.config)
+            .await
+    }
+
+    /// Runs `command` on every server at once, failing as the first server that failed did.
+    async fn run_namespace_command_everywhere(&self, command: &NamespaceCommand) -> Result<()> {
+        let config = &self.inner.config;
+        let results = join_all(
+            self.inner
+                .nodes
+                .iter()
+                .map(|node| node.run_namespace_command(command, config)),
+        )
+        .await;
+        for result in results {
+            result?;
+        }
+        Ok(())
+    }
+
+    /// Asks every server whether it is up, marking it up or down for the requests that follow.
+    /// A server refusing the request (e.g. for want of permission) is up.
+    pub async fn health_check(&self) -> Vec<(String, Result<()>)> {
+        let command = Command::Get {
+            key: HEALTH_CHECK_KEY.to_owned(),
+        };
+        let checks = self.inner.nodes.iter().map(|node| async {
+            let result = match node.execute(&command, &self.inner.config).await {
+                Err(err) if is_unreachable(&err) => Err(err),
+                _ => Ok(()),
+            };
+            (node.addr.clone(), result)
+        });
+        join_all(checks).await
+    }
+
+    /// Runs `command` on `node`, retrying it as configured. With `failover`, an unreachable
+    /// server isn't retried, as another one can take the command.
+    async fn execute_on(
+        &self,
+        node: &Node,
+        command: &Command,
+        failover: bool,
+    ) -> Result<CommandResponse> {
+        let config = &self.inner.config;
+        let mut attempt = 0;
+        loop {
+            match node.execute(command, config).await {
+                Err(err)
+                    if attempt < config.retries
+                        && is_retryable(&err, command)
+                        && !(failover && is_unreachable(&err)) => {}
+                result => return result,
+            }
+            tokio::time::sleep(config.backoff * 2u32.saturating_pow(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    /// Runs all of `commands` concurrently, over up to `max_connections` connections at once, and
+    /// returns their responses in the order of the commands. Neither protocol can carry several
+    /// requests in flight on one connection, so this isn't pipelining in the wire sense: over TCP
+    /// every command still opens a connection of its own, over HTTP the commands share the
+    /// kept-alive connections. There is no ordering between the commands either, so a `Get`
+    /// following a `Set` of the same key may or may not see it.
+    pub async fn pipeline(&self, commands: &[Command]) -> Vec<Result<CommandResponse>> {
+        join_all(commands.iter().map(|command| self.execute(command))).await
+    }
+
+    /// Streams every later change of `pattern`: a key, or a prefix followed by `*`. With `from`,
+    /// the changes following that position (as found in [`Event::position`]) are replayed first.
+    ///
+    /// With several servers, a key is watched on the first of its replicas that answers, which
+    /// sees every write unless it was down. Prefixes span every server, so they can only be
+    /// watched on a single one.
+    pub async fn watch(&self, pattern: &str, from: Option<Position>) -> Result<Watch> {
+        let nodes = match self.inner.nodes.as_slice() {
+            [node] => vec![node],
+            _ if pattern.ends_with('*') => whatever!(
+                "Watching a prefix only works against a single server, not {}",
+                self.inner.config.addr
+            ),
+            _ => self.replicas(pattern),
+        };
+        let mut last_err = None;
+        for node in nodes {
+            let result = match &node.transport {
+                Transport::Tcp(transport) => transport.watch(pattern, from).await,
+                Transport::Http(transport) => transport.watch(pattern, from).await,
+            };
+            node.record(&result);
+            match result {
+                Err(err) if is_unreachable(&err) => last_err = Some(err),
+                result => return result,
+            }
+        }
+        Err(last_err.expect("a key has at least one replica"))
+    }
+
+    /// The servers holding `key`, in the order to try them: the ones marked down come last, so
+    /// that they are only tried when no other one answered.
+    fn replicas(&self, key: &str) -> Vec<&Node> {
+        let nodes = &self.inner.nodes;
+        let config = &self.inner.config;
+        let mut replicas: Vec<_> = route(key, &config.addrs())
+            .into_iter()
+            .take(config.replicas.clamp(1, nodes.len()))
+            .map(|index| &nodes[index])
+            .collect();
+        // Stable, so that the order among the servers up is kept
+        replicas.sort_by_key(|node| node.is_down(config.down_for));
+        replicas
+    }
+
+    /// Reads `key` from the first of its replicas that answers.
+    async fn read(&self, key: &str, command: &Command) -> Result<CommandResponse> {
+        let mut last_err = None;
+        for node in self.replicas(key) {
+            match self.execute_on(node, command, true).await {
+                Err(err) if is_unreachable(&err) => last_err = Some(err),
+                result => return result,
+            }
+        }
+        Err(last_err.expect("a key has at least one replica"))
+    }
+
+    /// Writes `key` to every replica up, or to all of them when none is. Succeeds if a replica
+    /// did, and otherwise fails as the replicas that answered did.
+    async fn write(&self, key: &str, command: &Command) -> Result<CommandResponse> {
+        let down_for = self.inner.config.down_for;
+        let replicas = self.replicas(key);
+        let up: Vec<_> = replicas
+            .iter()
+            .filter(|node| !node.is_down(down_for))
+            .collect();
+        let targets = if up.is_empty() {
+            replicas.iter().collect()
+        } else {
+            up
+        };
+        let results = join_all(
+            targets
+                .into_iter()
+                .map(|node| self.execute_on(node, command, false)),
+        )
+        .await;
+        let mut failure = None;
+        for result in results {
+            match result {
+                Ok(response) => return Ok(response),
+                Err(err) => match &failure {
+                    // An answer explains the failure better than a server down
+                    Some(failure) if !is_unreachable(failure) => {}
+                    _ => failure = Some(err),
+                },
+            }
+        }
+        Err(failure.expect("a key has at least one replica"))
+    }
+
+    /// Runs a scan or a find on every server and merges their pairs. A key found on several
+    /// servers takes the value of the replica reads go to first. Up to `replicas - 1` servers may
+    /// be unreachable, as every key is still on another one then.
+    async fn scan_all(&self, command: &Command) -> Result<CommandResponse> {
+        let config = &self.inner.config;
+        let nodes = &self.inner.nodes;
+        let results = join_all(
+            nodes
+                .iter()
+                .map(|node| self.execute_on(node, command, false)),
+        )
+        .await;
+        let addrs = config.addrs();
+        let mut merged = BTreeMap::new();
+        let mut unreachable = Vec::new();
+        for (index, result) in results.into_iter().enumerate() {
+            let pairs = match result {
+                Ok(CommandResponse::Scan { pairs } | CommandResponse::Find { pairs }) => pairs,
+                Ok(response) => return unexpected(response),
+                Err(err) if is_unreachable(&err) => {
+                    unreachable.push(err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            for (key, value) in pairs {
+                let rank = route(&key, &addrs).iter().position(|node| *node == index);
+                match merged.get(&key) {
+                    Some((best, _)) if *best <= rank => {}
+                    _ => {
+                        merged.insert(key, (rank, value));
+                    }
+                }
+            }
+        }
+        if unreachable.len() >= config.replicas.clamp(1, nodes.len()) {
+            return Err(unreachable.remove(0));
+        }
+        let pairs = merged
+            .into_iter()
+            .map(|(key, (_, value))| (key, value))
+            .collect();
+        match command {
+            Command::Find { .. } => Ok(CommandResponse::Find { pairs }),
+            _ => Ok(CommandResponse::Scan { pairs }),
+        }
+    }
+}
+
+impl Node {
+    /// Runs `command` once, marking the server up or down depending on how it went.
+    async fn execute(&self, command: &Command, config: &ClientConfig) -> Result<CommandResponse> {
+        // Only fails once the semaphore is closed, which never happens
+        let _permit = self
+            .connections
+            .acquire()
+            .await
+            .with_whatever_context(|_| "Connection pool closed")?;
+        let response = match &self.transport {
+            Transport::Tcp(transport) => {
+                tokio::time::timeout(config.timeout, transport.execute(command)).await
+            }
+            // reqwest enforces the timeout itself
+            Transport::Http(transport) => Ok(transport.execute(command).await),
+        };
+        let result = response.unwrap_or_else(|_| {
+            Err(Error::Timeout {
+                addr: self.addr.clone(),
+            })
+        });
+        self.record(&result);
+        result
+    }
+}
+
+impl Node {
+    /// Runs a namespace command once, returning the namespaces listed by
+    /// [`NamespaceCommand::List`] and nothing for the other commands.
+    async fn run_namespace_command(
+        &self,
+        command: &NamespaceCommand,
+        config: &ClientConfig,
+    ) -> Result<Vec<NamespaceInfo>> {
+        let _permit = self
+            .connections
+            .acquire()
+            .await
+            .with_whatever_context(|_| "Connection pool closed")?;
+        let response = match &self.transport {
+            Transport::Tcp(transport) => {
+                tokio::time::timeout(config.timeout, transport.run_namespace_command(command)).await
+            }
+            Transport::Http(transport) => Ok(transport.run_namespace_command(command).await),
+        };
+        let result = response.unwrap_or_else(|_| {
+            Err(Error::Timeout {
+                addr: self.addr.clone(),
+            })
+        });
+        self.record(&result);
+        result
+    }
+}
+
+/// A key that health checks read, which needn't exist.
+const HEALTH_CHECK_KEY: &str = "kvs:health-check";
+
+/// Whether `err` means that the server couldn't be reached, or stopped answering.
+fn is_unreachable(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Connect { .. } | Error::Timeout { .. } | Error::Connection { .. }
+    )
+}
+
+/// Whether `command` may be sent again after failing with `err`.
+fn is_retryable(err: &Error, command: &Command) -> bool {
+    match err {
+        // Nothing reached the server
+        Error::Connect { .. } => true,
+        Error::Server { kind, .. } => kind.is_transient(),
+        // The server may have run the command, so only reads are sent again: replaying a write
+        // could undo a newer write of another client
+        Error::Timeout { .. } | Error::Connection { .. } => {
+            matches!(
+                command,
+                Command::Get { .. }
+                    | Command::Scan { .. }
+                    | Command::Backup
+                    | Command::LRange { .. }
+                    | Command::HGet { .. }
+                    | Command::SMembers { .. }
+                    | Command::Find { .. }
+            )
+        }
+        _ => false,
+    }
+}
+
+fn unexpected<T>(response: CommandResponse) -> Result<T> {
+    Err(Error::UnexpectedResponse {
+        response: format!("{:?}", response),
+    })
+}
+
+/// Encodes the pairs of a `Restore` command back into the archive sent over the wire.
+fn restore_archive(pairs: &[(String, String)]) -> Result<Vec<u8>> {
+    let mut archive = Vec::new();
+    write_pairs(pairs.iter().cloned().map(Ok), &mut archive)?;
+    Ok(archive)
+}
+
+/// The changes streamed by [`AsyncKvsClient::watch`].
+pub struct Watch {
+    started_at: Option<Position>,
+    events: WatchEvents,
+}
+
+enum WatchEvents {
+    Tcp(tcp::TcpEvents),
+    Http(http::HttpEvents),
+}
+
+impl Watch {
+    /// The position of the last change before the watch started, when the server tells it.
+    pub fn started_at(&self) -> Option<Position> {
+        self.started_at
+    }
+
+    /// Waits for the next change. Returns `None` once the server closed the watch.
+    pub async fn next(&mut self) -> Result<Option<Event>> {
+        match &mut self.events {
+            WatchEvents::Tcp(events) => events.next().await,
+            WatchEvents::Http(events) => events.next().await,
+        }
+    }
+}
+
+/// A client for blocking code, running an [`AsyncKvsClient`] on a runtime of its own. It can't be
+/// used from within an async runtime.
+pub struct KvsClient {
+    runtime: Runtime,
+    client: AsyncKvsClient,
+}
+
+impl KvsClient {
+    pub fn new(config: ClientConfig) -> Result<Self> {
+        // A worker thread keeps driving the idle HTTP connections between calls, so that one
+        // closed by the server leaves the pool instead of failing the next request
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .with_whatever_context(|_| "Unable to start the client runtime")?;
+        Ok(Self {
+            runtime,
+            client: AsyncKvsClient::new(config)?,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.get(key))
+    }
+
+    pub fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.runtime.block_on(self.client.set(key, value))
+    }
+
+    /// Fails with [`Error::KeyNotFound`] when there is no such key.
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.runtime.block_on(self.client.remove(key))
+    }
+
+    /// See [`AsyncKvsClient::scan`].
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        self.runtime.block_on(self.client.scan(prefix))
+    }
+
+    /// See [`AsyncKvsClient::find`].
+    pub fn find(&self, index: &str, value: &str) -> Result<Vec<(String, String)>> {
+        self.runtime.block_on(self.client.find(index, value))
+    }
+
+    /// See [`AsyncKvsClient::lpush`].
+    pub fn lpush(&self, key: &str, values: &[&str]) -> Result<usize> {
+        self.runtime.block_on(self.client.lpush(key, values))
+    }
+
+    /// See [`AsyncKvsClient::lrange`].
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>> {
+        self.runtime.block_on(self.client.lrange(key, start, stop))
+    }
+
+    /// See [`AsyncKvsClient::hset`].
+    pub fn hset(&self, key: &str, field: &str, value: &str) -> Result<bool> {
+        self.runtime.block_on(self.client.hset(key, field, value))
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        self.runtime.block_on(self.client.hget(key, field))
+    }
+
+    /// See [`AsyncKvsClient::sadd`].
+    pub fn sadd(&self, key: &str, members: &[&str]) -> Result<usize> {
+        self.runtime.block_on(self.client.sadd(key, members))
+    }
+
+    /// See [`AsyncKvsClient::smembers`].
+    pub fn smembers(&self, key: &str) -> Result<Vec<String>> {
+        self.runtime.block_on(self.client.smembers(key))
+    }
+
+    /// See [`AsyncKvsClient::incr_by`].
+    pub fn incr_by(&self, key: &str, delta: i64) -> Result<i64> {
+        self.runtime.block_on(self.client.incr_by(key, delta))
+    }
+
+    /// See [`AsyncKvsClient::eval`].
+    pub fn eval(&self, script: &str, keys: &[&str], args: &[&str]) -> Result<serde_json::Value> {
+        self.runtime.block_on(self.client.eval(script, keys, args))
+    }
+
+    /// See [`AsyncKvsClient::backup`].
+    pub fn backup(&self) -> Result<Vec<u8>> {
+        self.runtime.block_on(self.client.backup())
+    }
+
+    /// See [`AsyncKvsClient::restore`].
+    pub fn restore(&self, archive: &[u8]) -> Result<usize> {
+        self.runtime.block_on(self.client.restore(archive))
+    }
+
+    /// See [`AsyncKvsClient::create_namespace`].
+    pub fn create_namespace(&self, name: &str, config: &NamespaceConfig) -> Result<()> {
+        self.runtime
+            .block_on(self.client.create_namespace(name, config))
+    }
+
+    /// See [`AsyncKvsClient::drop_namespace`].
+    pub fn drop_namespace(&self, name: &str) -> Result<()> {
+        self.runtime.block_on(self.client.drop_namespace(name))
+    }
+
+    /// See [`AsyncKvsClient::namespaces`].
+    pub fn namespaces(&self) -> Result<Vec<NamespaceInfo>> {
+        self.runtime.block_on(self.client.namespaces())
+    }
+
+    /// See [`AsyncKvsClient::execute`].
+    pub fn execute(&self, command: &Command) -> Result<CommandResponse> {
+        self.runtime.block_on(self.client.execute(command))
+    }
+
+    /// See [`AsyncKvsClient::health_check`].
+    pub fn health_check(&self) -> Vec<(String, Result<()>)> {
+        self.runtime.block_on(self.client.health_check())
+    }
+
+    /// See [`AsyncKvsClient::pipeline`].
+    pub fn pipeline(&self, commands: &[Command]) -> Vec<Result<CommandResponse>> {
+        self.runtime.block_on(self.client.pipeline(commands))
+    }
+
+    /// See [`AsyncKvsClient::watch`].
+    pub fn watch(&self, pattern: &str, from: Option<Position>) -> Result<WatchIter<'_>> {
+        let watch = self.runtime.block_on(self.client.watch(pattern, from))?;
+        Ok(WatchIter {
+            runtime: &self.runtime,
+            watch,
+        })
+    }
+}
+
+/// The changes streamed by [`KvsClient::watch`], ending once the server closed the watch.
+pub struct WatchIter<'a> {
+    runtime: &'a Runtime,
+    watch: Watch,
+}
+
+impl WatchIter<'_> {
+    /// See [`Watch::started_at`].
+    pub fn started_at(&self) -> Option<Position> {
+        self.watch.started_at()
+    }
+}
+
+impl Iterator for WatchIter<'_> {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.runtime.block_on(self.watch.next()).transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retryable() {
+        let get = Command::Get {
+            key: "key1".to_owned(),
+        };
+        let set = Command::Set {
+            key: "key1".to_owned(),
+            value: "value1".to_owned(),
+        };
+        let rm = Command::Rm {
+            key: "key1".to_owned(),
+        };
+        let lost = Error::Connection {
+            addr: "127.0.0.1:4004".to_owned(),
+            message: "reset".to_owned(),
+        };
+        let refused = Error::Connect {
+            addr: "127.0.0.1:4004".to_owned(),
+            message: "refused".to_owned(),
+        };
+        let rate_limited = Error::Server {
+            kind: ServerErrorKind::RateLimited,
+            message: "Rate limit exceeded".to_owned(),
+        };
+        let denied = Error::Server {
+            kind: ServerErrorKind::PermissionDenied,
+            message: "User bob isn't allowed to read key1".to_owned(),
+        };
+
+        assert!(is_retryable(&lost, &get));
+        assert!(!is_retryable(&lost, &set));
+        assert!(!is_retryable(&lost, &rm));
+        assert!(is_retryable(&refused, &rm));
+        assert!(is_retryable(&rate_limited, &rm));
+        assert!(!is_retryable(&denied, &get));
+
+        let smembers = Command::SMembers {
+            key: "set1".to_owned(),
+        };
+        let incr_by = Command::IncrBy {
+            key: "counter1".to_owned(),
+            delta: 1,
+        };
+        assert!(is_retryable(&lost, &smembers));
+        assert!(!is_retryable(&lost, &incr_by));
+    }
+
+    #[test]
+    fn addrs() {
+        assert_eq!(
+            ClientConfig::new("127.0.0.1:4004").addrs(),
+            vec!["127.0.0.1:4004"]
+        );
+        assert_eq!(
+            ClientConfig::new(" 10.0.0.1:4004, 10.0.0.2:4004,").addrs(),
+            vec!["10.0.0.1:4004", "10.0.0.2:4004"]
+        );
+        assert!(ClientConfig::new("").addrs().is_empty());
+        assert!(AsyncKvsClient::new(ClientConfig::new(",")).is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use super::{restore_archive, ClientConfig, ServerErrorKind, Watch, WatchEvents};
+use crate::err::{Result, ERROR_CODE_HEADER};
+use crate::namespace::{NamespaceCommand, NamespaceInfo};
+use crate::watch::{Event, Position};
+use crate::{Command, CommandResponse, Error};
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+use reqwest::{RequestBuilder, Response, StatusCode, Url};
+use serde::Serialize;
+use snafu::{whatever, ResultExt};
+use std::error::Error as _;
+use std::time::Duration;
+
+/// Speaks the REST API of `server`, reusing its connections.
+pub(super) struct HttpTransport {
+    addr: String,
+    base: Url,
+    client: reqwest::Client,
+    timeout: Duration,
+    /// The segments the paths of the commands start with: `v1`, or `v2/ns/<namespace>`.
+    prefix: Vec<String>,
+    namespace: Option<String>,
+}
+
+impl HttpTransport {
+    pub(super) fn new(config: &ClientConfig) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        if let Some(token) = &config.token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token))
+                .with_whatever_context(|_| "Token contains invalid characters")?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        let mut builder = reqwest::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(config.connect_timeout)
+            .pool_max_idle_per_host(config.max_connections);
+        if let Some(tls) = &config.tls {
+            builder = builder.use_preconfigured_tls(tls.clone());
+        }
+        if !config.compression {
+            builder = builder.no_zstd();
+        }
+        let client = builder
+            .build()
+            .with_whatever_context(|_| "Unable to build HTTP client")?;
+
+        let addr = &config.addr;
+        let base = if addr.starts_with("http://") || addr.starts_with("https://") {
+            addr.clone()
+        } else if config.tls.is_some() {
+            format!("https://{}", addr)
+        } else {
+            format!("http://{}", addr)
+        };
+        let base = Url::parse(&base).with_whatever_context(|_| format!("Invalid URL {}", base))?;
+        if base.cannot_be_a_base() {
+            whatever!("Invalid URL {}", base);
+        }
+        Ok(Self {
+            addr: addr.clone(),
+            base,
+            client,
+            timeout: config.timeout,
+            prefix: match &config.namespace {
+                Some(namespace) => vec!["v2".to_owned(), "ns".to_owned(), namespace.clone()],
+                None => vec!["v1".to_owned()],
+            },
+            namespace: config.namespace.clone(),
+        })
+    }
+
+    pub(super) async fn execute(&self, command: &Command) -> Result<CommandResponse> {
+        let request = match command {
+            // The keys of a namespace are resources of their own
+            Command::Get { key } if self.namespace.is_some() => {
+                self.client.get(self.url(&["keys", key]))
+            }
+            Command::Set { key, value } if self.namespace.is_some() => self
+                .client
+                .put(self.url(&["keys", key]))
+                .body(value.clone()),
+            Command::Rm { key } if self.namespace.is_some() => {
+                self.client.delete(self.url(&["keys", key]))
+            }
+            Command::Scan { prefix } if self.namespace.is_some() => self
+                .client
+                .get(self.url(&["keys"]))
+                .query(&[("prefix", prefix)]),
+            Command::Get { key } => self.client.get(self.url(&["get", key])),
+            Command::Set { key, value } => self.client.post(self.url(&["set", key, value])),
+            Command::Rm { key } => self.client.post(self.url(&["rm", key])),
+            Command::Scan { prefix } => self
+                .client
+                .get(self.url(&["scan"]))
+                .query(&[("prefix", prefix)]),
+            Command::Backup => self.client.get(self.url(&["backup"])),
+            Command::Restore { pairs } => self
+                .client
+                .post(self.url(&["restore"]))
+                .body(restore_archive(pairs)?),
+            Command::LPush { key, values } => self.post_json(self.url(&["lpush", key]), values)?,
+            Command::LRange { key, start, stop } => self
+                .client
+                .get(self.url(&["lrange", key]))
+                .query(&[("start", start), ("stop", stop)]),
+            Command::HSet { key, field, value } => {
+                self.client.post(self.url(&["hset", key, field, value]))
+            }
+            Command::HGet { key, field } => self.client.get(self.url(&["hget", key, field])),
+            Command::SAdd { key, members } => self.post_json(self.url(&["sadd", key]), members)?,
+            Command::SMembers { key } => self.client.get(self.url(&["smembers", key])),
+            Command::IncrBy { key, delta } => {
+                self.client
+                    .post(self.url(&["incrby", key, &delta.to_string()]))
+            }
+            Command::Find { index, value } => self.client.get(self.url(&["find", index, value])),
+            Command::Eval { script, keys, args } => self.post_json(
+                self.url(&["eval"]),
+                &serde_json::json!({ "script": script, "keys": keys, "args": args }),
+            )?,
+            Command::EvalSha { hash, keys, args } => self.post_json(
+                self.url(&["evalsha", hash]),
+                &serde_json::json!({ "keys": keys, "args": args }),
+            )?,
+        };
+        let response = self.send(request.timeout(self.timeout)).await?;
+        let status = response.status();
+        // A missing namespace is `404 Not Found` too, but comes as an error
+        let missing =
+            status == StatusCode::NOT_FOUND && !response.headers().contains_key(ERROR_CODE_HEADER);
+
+        match command {
+            Command::Get { .. } if missing => return Ok(CommandResponse::Get { value: None }),
+            Command::HGet { .. } if missing => return Ok(CommandResponse::HGet { value: None }),
+            Command::Rm { key } if missing => return Err(Error::KeyNotFound { key: key.clone() }),
+            _ if !status.is_success() => return Err(self.error(response).await),
+            _ => {}
+        }
+        let body = response.bytes().await.map_err(|err| self.lost(err))?;
+        let unexpected = || Error::UnexpectedResponse {
+            response: String::from_utf8_lossy(&body).into_owned(),
+        };
+        let number = || {
+            std::str::from_utf8(&body)
+                .ok()
+                .and_then(|number| number.trim().parse::<i64>().ok())
+                .ok_or_else(unexpected)
+        };
+        match command {
+            Command::Get { .. } => Ok(CommandResponse::Get {
+                value: Some(String::from_utf8_lossy(&body).into_owned()),
+            }),
+            Command::Set { .. } => Ok(CommandResponse::Set),
+            Command::Rm { .. } => Ok(CommandResponse::Rm { value: None }),
+            Command::Scan { .. } => Ok(CommandResponse::Scan {
+                pairs: serde_json::from_slice(&body).map_err(|_| Error::UnexpectedResponse {
+                    response: String::from_utf8_lossy(&body).into_owned(),
+                })?,
+            }),
+            Command::Backup => Ok(CommandResponse::Backup {
+                archive: body.to_vec(),
+            }),
+            Command::Restore { .. } => {
+                let body = String::from_utf8_lossy(&body);
+                match body.trim().parse() {
+                    Ok(count) => Ok(CommandResponse::Restore { count }),
+                    Err(_) => Err(Error::UnexpectedResponse {
+                        response: body.into_owned(),
+                    }),
+                }
+            }
+            Command::LPush { .. } => Ok(CommandResponse::LPush {
+                len: number()? as usize,
+            }),
+            Command::LRange { .. } => Ok(CommandResponse::LRange {
+                values: serde_json::from_slice(&body).map_err(|_| unexpected())?,
+            }),
+            Command::HSet { .. } => Ok(CommandResponse::HSet {
+                created: number()? == 1,
+            }),
+            Command::HGet { .. } => Ok(CommandResponse::HGet {
+                value: Some(String::from_utf8_lossy(&body).into_owned()),
+            }),
+            Command::SAdd { .. } => Ok(CommandResponse::SAdd {
+                added: number()? as usize,
+            }),
+            Command::SMembers { .. } => Ok(CommandResponse::SMembers {
+                members: serde_json::from_slice(&body).map_err(|_| unexpected())?,
+            }),
+            Command::IncrBy { .. } => Ok(CommandResponse::IncrBy { value: number()? }),
+            Command::Find { .. } => Ok(CommandResponse::Find {
+                pairs: serde_json::from_slice(&body).map_err(|_| unexpected())?,
+            }),
+            Command::Eval { .. } | Command::EvalSha { .. } => Ok(CommandResponse::Eval {
+                result: serde_json::from_slice(&body).map_err(|_| unexpected())?,
+            }),
+        }
+    }
+
+    /// Runs a namespace command, returning the namespaces listed by [`NamespaceCommand::List`].
+    pub(super) async fn run_namespace_command(
+        &self,
+        command: &NamespaceCommand,
+    ) -> Result<Vec<NamespaceInfo>> {
+        let request = match command {
+            NamespaceCommand::Create { name, config } => {
+                let body = serde_json::to_vec(config).with_whatever_context(|_| {
+                    format!("Couldn't serialize the settings of namespace {}", name)
+                })?;
+                self.client
+                    .put(self.root_url(&["v2", "ns", name]))
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(body)
+            }
+            NamespaceCommand::Drop { name } => {
+                self.client.delete(self.root_url(&["v2", "ns", name]))
+            }
+            NamespaceCommand::List => self.client.get(self.root_url(&["v2", "ns"])),
+        };
+        let response = self.send(request.timeout(self.timeout)).await?;
+        if !response.status().is_success() {
+            return Err(self.error(response).await);
+        }
+        match command {
+            NamespaceCommand::List => {
+                let body = response.bytes().await.map_err(|err| self.lost(err))?;
+                serde_json::from_slice(&body).map_err(|_| Error::UnexpectedResponse {
+                    response: String::from_utf8_lossy(&body).into_owned(),
+                })
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// A POST of `values` as a JSON array.
+    fn post_json(&self, url: Url, body: &impl Serialize) -> Result<RequestBuilder> {
+        let body = serde_json::to_vec(body)
+            .with_whatever_context(|_| "Couldn't serialize the request body")?;
+        Ok(self
+            .client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body))
+    }
+
+    pub(super) async fn watch(&self, pattern: &str, from: Option<Position>) -> Result<Watch> {
+        let mut url = self.url(&["watch", pattern]);
+        if let Some(from) = from {
+            url.query_pairs_mut().append_pair("from", &from.to_string());
+        }
+        let response = self.send(self.client.get(url)).await?;
+        if !response.status().is_success() {
+            return Err(self.error(response).await);
+        }
+        Ok(Watch {
+            // Server-Sent Events don't carry it
+            started_at: None,
+            events: WatchEvents::Http(HttpEvents {
+                addr: self.addr.clone(),
+                response,
+                buffer: Vec::new(),
+            }),
+        })
+    }
+
+    /// `/v1/`, or `/v2/ns/<namespace>/` given a namespace, followed by `segments`.
+    fn url(&self, segments: &[&str]) -> Url {
+        let prefix = self.prefix.iter().map(String::as_str);
+        self.root_url(&prefix.chain(segments.iter().copied()).collect::<Vec<_>>())
+    }
+
+    /// The URL of `segments`, which are percent-encoded so that keys can hold any character.
+    fn root_url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        let mut path = url.path().trim_end_matches('/').to_owned();
+        for segment in segments {
+            path.push('/');
+            path.push_str(&encode_segment(segment));
+        }
+        url.set_path(&path);
+        url
+    }
+
+    async fn send(&self, request: RequestBuilder) -> Result<Response> {
+        request.send().await.map_err(|err| {
+            if err.is_connect() || never_sent(&err) {
+                Error::Connect {
+                    addr: self.addr.clone(),
+                    message: err.to_string(),
+                }
+            } else {
+                self.lost(err)
+            }
+        })
+    }
+
+    /// Turns an error response into an [`Error::Server`]. Errors come as JSON strings with their
+    /// code in a header, other refusals (e.g. an invalid archive) as plain text.
+    async fn error(&self, response: Response) -> Error {
+        let code = response
+            .headers()
+            .get(ERROR_CODE_HEADER)
+            .and_then(|code| code.to_str().ok())
+            .and_then(ServerErrorKind::from_code);
+        let kind = code.unwrap_or_else(|| match response.status() {
+            StatusCode::UNAUTHORIZED => ServerErrorKind::Unauthenticated,
+            StatusCode::FORBIDDEN => ServerErrorKind::PermissionDenied,
+            StatusCode::TOO_MANY_REQUESTS => ServerErrorKind::RateLimited,
+            StatusCode::SERVICE_UNAVAILABLE => ServerErrorKind::TooManyConnections,
+            StatusCode::PAYLOAD_TOO_LARGE => ServerErrorKind::TooLarge,
+            StatusCode::CONFLICT => ServerErrorKind::WrongType,
+            StatusCode::INSUFFICIENT_STORAGE => ServerErrorKind::QuotaExceeded,
+            _ => ServerErrorKind::Other,
+        });
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(err) => return self.lost(err),
+        };
+        let message = serde_json::from_str::<String>(&body).unwrap_or(body);
+        Error::Server { kind, message }
+    }
+
+    fn lost(&self, err: reqwest::Error) -> Error {
+        if err.is_timeout() {
+            Error::Timeout {
+                addr: self.addr.clone(),
+            }
+        } else {
+            Error::Connection {
+                addr: self.addr.clone(),
+                message: err.to_string(),
+            }
+        }
+    }
+}
+
+/// Percent-encodes everything but unreserved characters. `Url` would encode the rest itself, but
+/// drops tabs and newlines instead of encoding them.
+fn encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+/// Whether `err` comes from a request that was given up on before it was written, e.g. because
+/// the pooled connection it was meant for turned out to be closed.
+fn never_sent(err: &reqwest::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<hyper::Error>() {
+            return err.is_canceled();
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// The events of a watch, as Server-Sent Events whose data is the JSON of the event.
+pub(super) struct HttpEvents {
+    addr: String,
+    response: Response,
+    /// What was received of the current line.
+    buffer: Vec<u8>,
+}
+
+impl HttpEvents {
+    pub(super) async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                // Ids, comments (heartbeats) and blank lines are of no use: the data is the event
+                if let Some(data) = line.trim_end().strip_prefix("data:") {
+                    let data = data.trim_start();
+                    return serde_json::from_str(data)
+                        .map(Some)
+                        .with_whatever_context(|_| format!("Invalid event {}", data));
+                }
+            }
+            let chunk = self
+                .response
+                .chunk()
+                .await
+                .map_err(|err| Error::Connection {
+                    addr: self.addr.clone(),
+                    message: err.to_string(),
+                })?;
+            match chunk {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
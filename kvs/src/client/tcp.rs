@@ -0,0 +1,624 @@
+use super::{ClientConfig, ServerErrorKind, Watch, WatchEvents};
+use crate::compression::Compression;
+use crate::err::Result;
+use crate::namespace::{NamespaceCommand, NamespaceInfo};
+use crate::protocol::{check_word, encode, encode_namespace_command};
+use crate::watch::{Event, Position};
+use crate::{Command, CommandResponse, Error};
+use rustls::pki_types::ServerName;
+use snafu::ResultExt;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// A connection to the server, either plain or over TLS.
+enum Connection {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    /// Tells the server that the request is complete, while keeping the response readable: a
+    /// half-close of a plain stream, a `close_notify` over TLS.
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Speaks the line protocol of `kvs-server`, one connection per request.
+pub(super) struct TcpTransport {
+    addr: String,
+    token: Option<String>,
+    tls: Option<(TlsConnector, ServerName<'static>)>,
+    connect_timeout: Duration,
+    compression: bool,
+    namespace: Option<String>,
+}
+
+impl TcpTransport {
+    pub(super) fn new(config: &ClientConfig) -> Result<Self> {
+        if let Some(token) = &config.token {
+            check_word("Token", token)?;
+        }
+        if let Some(namespace) = &config.namespace {
+            check_word("Namespace", namespace)?;
+        }
+        let tls = match &config.tls {
+            Some(tls) => {
+                let host = config
+                    .addr
+                    .rsplit_once(':')
+                    .map_or(config.addr.as_str(), |(host, _)| host)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                let server_name = ServerName::try_from(host.to_owned())
+                    .with_whatever_context(|_| format!("Invalid server name {}", host))?;
+                Some((TlsConnector::from(Arc::new(tls.clone())), server_name))
+            }
+            None => None,
+        };
+        Ok(Self {
+            addr: config.addr.clone(),
+            token: config.token.clone(),
+            tls,
+            connect_timeout: config.connect_timeout,
+            compression: config.compression,
+            namespace: config.namespace.clone(),
+        })
+    }
+
+    pub(super) async fn execute(&self, command: &Command) -> Result<CommandResponse> {
+        let mut request = if self.compression {
+            format!("COMPRESS {}\n", accepted_compressions()).into_bytes()
+        } else {
+            Vec::new()
+        };
+        request.extend(encode(command)?);
+        let mut connection = self.send(&request).await?;
+        let mut response = Vec::new();
+        connection
+            .read_to_end(&mut response)
+            .await
+            .map_err(|err| self.lost(err))?;
+        decode(command, &decompress(response)?)
+    }
+
+    /// Runs a `NAMESPACE` command, returning the namespaces listed by `NAMESPACE LIST`.
+    pub(super) async fn run_namespace_command(
+        &self,
+        command: &NamespaceCommand,
+    ) -> Result<Vec<NamespaceInfo>> {
+        let request = encode_namespace_command(command)?;
+        let mut connection = self.send(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        connection
+            .read_to_end(&mut response)
+            .await
+            .map_err(|err| self.lost(err))?;
+        decode_namespace_response(command, &response)
+    }
+
+    pub(super) async fn watch(&self, pattern: &str, from: Option<Position>) -> Result<Watch> {
+        check_word("Pattern", pattern)?;
+        let request = match from {
+            Some(from) => format!("WATCH {} FROM {}", pattern, from),
+            None => format!("WATCH {}", pattern),
+        };
+        let connection = self.send(request.as_bytes()).await?;
+        let mut lines = BufReader::new(connection).lines();
+        let response = lines.next_line().await.map_err(|err| self.lost(err))?;
+        let started_at = match response
+            .as_deref()
+            .and_then(|response| response.split_once(' '))
+        {
+            Some(("OK", seq)) => seq.parse().ok(),
+            Some(("ERR", message)) => return Err(server_error(message)),
+            _ => {
+                return Err(Error::UnexpectedResponse {
+                    response: response.unwrap_or_default(),
+                })
+            }
+        };
+        Ok(Watch {
+            started_at,
+            events: WatchEvents::Tcp(TcpEvents {
+                addr: self.addr.clone(),
+                lines,
+            }),
+        })
+    }
+
+    /// Connects, then writes the `AUTH` and `SELECT` lines and `request`.
+    async fn send(&self, request: &[u8]) -> Result<Connection> {
+        let mut connection = self.connect().await?;
+        if let Some(token) = &self.token {
+            connection
+                .write_all(format!("AUTH {}\n", token).as_bytes())
+                .await
+                .map_err(|err| self.lost(err))?;
+        }
+        if let Some(namespace) = &self.namespace {
+            connection
+                .write_all(format!("SELECT {}\n", namespace).as_bytes())
+                .await
+                .map_err(|err| self.lost(err))?;
+        }
+        connection
+            .write_all(request)
+            .await
+            .map_err(|err| self.lost(err))?;
+        connection.shutdown().await.map_err(|err| self.lost(err))?;
+        Ok(connection)
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        let connect = async {
+            let stream = TcpStream::connect(&self.addr).await?;
+            match &self.tls {
+                Some((connector, server_name)) => {
+                    let stream = connector.connect(server_name.clone(), stream).await?;
+                    Ok::<_, std::io::Error>(Connection::Tls(Box::new(stream)))
+                }
+                None => Ok(Connection::Plain(stream)),
+            }
+        };
+        match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(Ok(connection)) => Ok(connection),
+            Ok(Err(err)) => Err(Error::Connect {
+                addr: self.addr.clone(),
+                message: err.to_string(),
+            }),
+            Err(_) => Err(Error::Connect {
+                addr: self.addr.clone(),
+                message: "timed out".to_owned(),
+            }),
+        }
+    }
+
+    fn lost(&self, err: std::io::Error) -> Error {
+        Error::Connection {
+            addr: self.addr.clone(),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// The events of a watch, one JSON line each.
+pub(super) struct TcpEvents {
+    addr: String,
+    lines: tokio::io::Lines<BufReader<Connection>>,
+}
+
+impl TcpEvents {
+    pub(super) async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            let line = self
+                .lines
+                .next_line()
+                .await
+                .map_err(|err| Error::Connection {
+                    addr: self.addr.clone(),
+                    message: err.to_string(),
+                })?;
+            match line {
+                // Empty lines are heartbeats
+                Some(line) if line.is_empty() => continue,
+                Some(line) => {
+                    // The server gave up on the watch, e.g. because we fell behind
+                    if let Some(error) = line.strip_prefix("ERR ") {
+                        return Err(server_error(error));
+                    }
+                    return serde_json::from_str(&line)
+                        .map(Some)
+                        .with_whatever_context(|_| format!("Invalid event {}", line));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+/// The compressions we can read responses in, as listed by the `COMPRESS` line.
+fn accepted_compressions() -> String {
+    Compression::ALGORITHMS
+        .iter()
+        .map(Compression::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Undoes the compression of a response starting with `Z <algorithm> `.
+fn decompress(response: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(rest) = response.strip_prefix(b"Z ") else {
+        return Ok(response);
+    };
+    let Some(index) = rest.iter().position(|byte| *byte == b' ') else {
+        return Err(Error::UnexpectedResponse {
+            response: String::from_utf8_lossy(&response).into_owned(),
+        });
+    };
+    let compression: Compression = String::from_utf8_lossy(&rest[..index]).parse()?;
+    compression.decompress(&rest[index + 1..])
+}
+
+fn decode_namespace_response(
+    command: &NamespaceCommand,
+    response: &[u8],
+) -> Result<Vec<NamespaceInfo>> {
+    if let Some(error) = response.strip_prefix(b"ERR ") {
+        return Err(server_error(&String::from_utf8_lossy(error)));
+    }
+    let unexpected = || Error::UnexpectedResponse {
+        response: String::from_utf8_lossy(response).into_owned(),
+    };
+    match command {
+        NamespaceCommand::List => parse_json(response).ok_or_else(unexpected),
+        _ if response == b"OK" => Ok(Vec::new()),
+        _ => Err(unexpected()),
+    }
+}
+
+fn decode(command: &Command, response: &[u8]) -> Result<CommandResponse> {
+    if let Some(error) = response.strip_prefix(b"ERR ") {
+        let err = server_error(&String::from_utf8_lossy(error));
+        return match (command, err) {
+            (
+                Command::Get { .. },
+                Error::Server {
+                    kind: ServerErrorKind::NotFound,
+                    ..
+                },
+            ) => Ok(CommandResponse::Get { value: None }),
+            (
+                Command::HGet { .. },
+                Error::Server {
+                    kind: ServerErrorKind::NotFound,
+                    ..
+                },
+            ) => Ok(CommandResponse::HGet { value: None }),
+            (
+                Command::Rm { key },
+                Error::Server {
+                    kind: ServerErrorKind::NotFound,
+                    ..
+                },
+            ) => Err(Error::KeyNotFound { key: key.clone() }),
+            (_, err) => Err(err),
+        };
+    }
+    let unexpected = || Error::UnexpectedResponse {
+        response: String::from_utf8_lossy(response).into_owned(),
+    };
+    match command {
+        Command::Get { .. } => {
+            let value = response.strip_prefix(b"OK ").ok_or_else(unexpected)?;
+            let value = String::from_utf8(value.to_vec()).map_err(|_| unexpected())?;
+            Ok(CommandResponse::Get { value: Some(value) })
+        }
+        Command::Set { .. } if response.is_empty() => Ok(CommandResponse::Set),
+        Command::Rm { .. } if response.is_empty() => Ok(CommandResponse::Rm { value: None }),
+        Command::Scan { .. } => {
+            let pairs = response
+                .strip_prefix(b"OK ")
+                .and_then(|pairs| serde_json::from_slice(pairs).ok())
+                .ok_or_else(unexpected)?;
+            Ok(CommandResponse::Scan { pairs })
+        }
+        Command::Backup => {
+            let archive = response.strip_prefix(b"OK ").ok_or_else(unexpected)?;
+            Ok(CommandResponse::Backup {
+                archive: archive.to_vec(),
+            })
+        }
+        Command::Restore { .. } => {
+            let count = response
+                .strip_prefix(b"OK ")
+                .and_then(|count| std::str::from_utf8(count).ok())
+                .and_then(|count| count.trim().parse().ok())
+                .ok_or_else(unexpected)?;
+            Ok(CommandResponse::Restore { count })
+        }
+        Command::LPush { .. } => Ok(CommandResponse::LPush {
+            len: parse_ok(response).ok_or_else(unexpected)?,
+        }),
+        Command::LRange { .. } => Ok(CommandResponse::LRange {
+            values: parse_json(response).ok_or_else(unexpected)?,
+        }),
+        Command::HSet { .. } => Ok(CommandResponse::HSet {
+            created: parse_ok::<u8>(response).ok_or_else(unexpected)? == 1,
+        }),
+        Command::HGet { .. } => {
+            let value = response.strip_prefix(b"OK ").ok_or_else(unexpected)?;
+            let value = String::from_utf8(value.to_vec()).map_err(|_| unexpected())?;
+            Ok(CommandResponse::HGet { value: Some(value) })
+        }
+        Command::SAdd { .. } => Ok(CommandResponse::SAdd {
+            added: parse_ok(response).ok_or_else(unexpected)?,
+        }),
+        Command::SMembers { .. } => Ok(CommandResponse::SMembers {
+            members: parse_json(response).ok_or_else(unexpected)?,
+        }),
+        Command::IncrBy { .. } => Ok(CommandResponse::IncrBy {
+            value: parse_ok(response).ok_or_else(unexpected)?,
+        }),
+        Command::Find { .. } => Ok(CommandResponse::Find {
+            pairs: parse_json(response).ok_or_else(unexpected)?,
+        }),
+        Command::Eval { .. } | Command::EvalSha { .. } => Ok(CommandResponse::Eval {
+            result: parse_json(response).ok_or_else(unexpected)?,
+        }),
+        _ => Err(unexpected()),
+    }
+}
+
+/// Parses the number of an `OK <number>` response.
+fn parse_ok<T: std::str::FromStr>(response: &[u8]) -> Option<T> {
+    let number = std::str::from_utf8(response.strip_prefix(b"OK ")?).ok()?;
+    number.trim().parse().ok()
+}
+
+/// Parses the JSON of an `OK <json>` response.
+fn parse_json<T: serde::de::DeserializeOwned>(response: &[u8]) -> Option<T> {
+    serde_json::from_slice(response.strip_prefix(b"OK ")?).ok()
+}
+
+/// Reads the `<CODE> <message>` following `ERR`.
+fn server_error(error: &str) -> Error {
+    let (kind, message) = error
+        .split_once(' ')
+        .and_then(|(code, message)| Some((ServerErrorKind::from_code(code)?, message)))
+        .unwrap_or((ServerErrorKind::Other, error));
+    Error::Server {
+        kind,
+        message: message.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::{NamespaceConfig, Usage};
+
+    #[test]
+    fn encode_decode() {
+        let get = Command::Get {
+            key: "key1".to_owned(),
+        };
+        assert_eq!(encode(&get).unwrap(), b"GET key1");
+        assert!(matches!(
+            decode(&get, b"OK value1"),
+            Ok(CommandResponse::Get { value: Some(value) }) if value == "value1"
+        ));
+        assert!(matches!(
+            decode(&get, b"ERR NOT_FOUND Key not found"),
+            Ok(CommandResponse::Get { value: None })
+        ));
+
+        let rm = Command::Rm {
+            key: "key1".to_owned(),
+        };
+        assert!(matches!(
+            decode(&rm, b""),
+            Ok(CommandResponse::Rm { value: None })
+        ));
+        assert!(matches!(
+            decode(&rm, b"ERR NOT_FOUND Key not found"),
+            Err(Error::KeyNotFound { .. })
+        ));
+        assert!(matches!(
+            decode(
+                &rm,
+                b"ERR RATE_LIMITED Rate limit exceeded, retry in 500 ms"
+            ),
+            Err(Error::Server {
+                kind: ServerErrorKind::RateLimited,
+                ..
+            })
+        ));
+
+        let set = Command::Set {
+            key: "key1".to_owned(),
+            value: "two words".to_owned(),
+        };
+        assert!(encode(&set).is_err());
+        let restore = Command::Restore { pairs: Vec::new() };
+        assert!(encode(&restore).unwrap().starts_with(b"RESTORE\n"));
+        assert!(matches!(
+            decode(&restore, b"OK 3"),
+            Ok(CommandResponse::Restore { count: 3 })
+        ));
+
+        let lpush = Command::LPush {
+            key: "key1".to_owned(),
+            values: vec!["a".to_owned(), "b".to_owned()],
+        };
+        assert_eq!(encode(&lpush).unwrap(), b"LPUSH key1 a b");
+        assert!(matches!(
+            decode(&lpush, b"OK 2"),
+            Ok(CommandResponse::LPush { len: 2 })
+        ));
+        assert!(matches!(
+            decode(&lpush, b"ERR WRONGTYPE Key key1 holds a set, not a list"),
+            Err(Error::Server {
+                kind: ServerErrorKind::WrongType,
+                ..
+            })
+        ));
+        let no_values = Command::SAdd {
+            key: "key1".to_owned(),
+            members: Vec::new(),
+        };
+        assert!(encode(&no_values).is_err());
+        let lrange = Command::LRange {
+            key: "key1".to_owned(),
+            start: 0,
+            stop: -1,
+        };
+        assert_eq!(encode(&lrange).unwrap(), b"LRANGE key1 0 -1");
+        assert!(matches!(
+            decode(&lrange, b"OK [\"b\",\"a\"]"),
+            Ok(CommandResponse::LRange { values }) if values == ["b", "a"]
+        ));
+        let hget = Command::HGet {
+            key: "key1".to_owned(),
+            field: "field1".to_owned(),
+        };
+        assert!(matches!(
+            decode(&hget, b"ERR NOT_FOUND Field not found"),
+            Ok(CommandResponse::HGet { value: None })
+        ));
+        let incr_by = Command::IncrBy {
+            key: "key1".to_owned(),
+            delta: -5,
+        };
+        assert_eq!(encode(&incr_by).unwrap(), b"INCRBY key1 -5");
+        assert!(matches!(
+            decode(&incr_by, b"OK -4"),
+            Ok(CommandResponse::IncrBy { value: -4 })
+        ));
+        let find = Command::Find {
+            index: "users.by_email".to_owned(),
+            value: "a@b.c".to_owned(),
+        };
+        assert_eq!(encode(&find).unwrap(), b"FIND users.by_email a@b.c");
+        assert!(matches!(
+            decode(&find, b"OK [[\"users:1\",\"{}\"]]"),
+            Ok(CommandResponse::Find { pairs }) if pairs[0].0 == "users:1"
+        ));
+        let eval = Command::Eval {
+            script: "return ARGV[1]".to_owned(),
+            keys: vec!["key1".to_owned()],
+            args: vec!["arg1".to_owned()],
+        };
+        assert_eq!(encode(&eval).unwrap(), b"EVAL 1 key1 arg1\nreturn ARGV[1]");
+        let eval_sha = Command::EvalSha {
+            hash: "0a1b".to_owned(),
+            keys: Vec::new(),
+            args: vec!["arg1".to_owned()],
+        };
+        assert_eq!(encode(&eval_sha).unwrap(), b"EVALSHA 0a1b 0 arg1");
+        assert!(matches!(
+            decode(&eval_sha, b"OK {\"a\":[1,null]}"),
+            Ok(CommandResponse::Eval { result }) if result["a"][0] == 1
+        ));
+    }
+
+    #[test]
+    fn decompress_response() {
+        let response = format!("OK {}", "value1".repeat(100));
+        let mut compressed = b"Z lz4 ".to_vec();
+        compressed.extend(Compression::Lz4.compress(response.as_bytes()).unwrap());
+        assert_eq!(decompress(compressed).unwrap(), response.as_bytes());
+        assert_eq!(decompress(b"OK value1".to_vec()).unwrap(), b"OK value1");
+        assert!(decompress(b"Z gzip data".to_vec()).is_err());
+        assert!(decompress(b"Z zstd".to_vec()).is_err());
+        assert_eq!(accepted_compressions(), "zstd,lz4");
+    }
+
+    #[test]
+    fn namespace_commands() {
+        let create = NamespaceCommand::Create {
+            name: "team1".to_owned(),
+            config: NamespaceConfig {
+                dir: Some("/data/team1".into()),
+                max_keys: Some(10),
+                max_bytes: None,
+            },
+        };
+        assert_eq!(
+            encode_namespace_command(&create).unwrap(),
+            "NAMESPACE CREATE team1 MAX_KEYS 10 DIR /data/team1"
+        );
+        assert!(decode_namespace_response(&create, b"OK")
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            decode_namespace_response(&create, b"ERR ERROR Namespace team1 already exists"),
+            Err(Error::Server {
+                kind: ServerErrorKind::Other,
+                ..
+            })
+        ));
+        let drop = NamespaceCommand::Drop {
+            name: "team 1".to_owned(),
+        };
+        assert!(encode_namespace_command(&drop).is_err());
+
+        let list = NamespaceCommand::List;
+        assert_eq!(encode_namespace_command(&list).unwrap(), "NAMESPACE LIST");
+        let infos = decode_namespace_response(
+            &list,
+            br#"OK [{"name":"default","keys":1,"bytes":10},{"name":"team1","max_keys":10,"keys":0,"bytes":0}]"#,
+        )
+        .unwrap();
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[0].usage, Usage { keys: 1, bytes: 10 });
+        assert_eq!(infos[1].config.max_keys, Some(10));
+    }
+
+    #[test]
+    fn server_error_codes() {
+        for kind in [
+            ServerErrorKind::Unauthenticated,
+            ServerErrorKind::PermissionDenied,
+            ServerErrorKind::RateLimited,
+            ServerErrorKind::TooManyConnections,
+            ServerErrorKind::TooLarge,
+            ServerErrorKind::WrongType,
+            ServerErrorKind::NoSuchNamespace,
+            ServerErrorKind::QuotaExceeded,
+            ServerErrorKind::Other,
+        ] {
+            let err = server_error(&format!("{} Some message", kind.code()));
+            assert!(
+                matches!(&err, Error::Server { kind: decoded, message } if *decoded == kind && message == "Some message"),
+                "{:?}",
+                err
+            );
+        }
+        // Without a code, the whole line is the message
+        assert!(matches!(
+            server_error("Invalid command"),
+            Error::Server { kind: ServerErrorKind::Other, message } if message == "Invalid command"
+        ));
+    }
+}
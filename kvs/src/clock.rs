@@ -0,0 +1,20 @@
+//! Where the time comes from. The code measuring time asks a [`Clock`] rather than
+//! [`Instant::now`], so that the simulation harness can run it on simulated time instead.
+
+use std::fmt;
+use std::time::Instant;
+
+/// A source of the current time.
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// The time of the system, as most of the code runs on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
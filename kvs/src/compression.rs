@@ -0,0 +1,178 @@
+//! Compression of the values kept by [`crate::KvStoreV2`] and of the responses sent by the
+//! servers.
+//!
+//! ```
+//! use kvs::compression::Compression;
+//!
+//! let value = "{\"name\":\"kvs\"}".repeat(100);
+//! let compressed = Compression::Zstd.compress_if_smaller(value.as_bytes())?.unwrap();
+//! assert!(compressed.len() < value.len());
+//! assert_eq!(Compression::Zstd.decompress(&compressed)?, value.as_bytes());
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::err::{Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use snafu::whatever;
+use std::fmt;
+use std::str::FromStr;
+
+/// Bytes below which compressing isn't worth the CPU: the savings would be a few bytes at best.
+pub const MIN_SIZE: usize = 256;
+
+/// The most an lz4 block expands to, as a match of 255 bytes takes at least one.
+const LZ4_MAX_RATIO: usize = 255;
+
+/// A compression algorithm, or none.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    /// Fast, for a modest ratio.
+    Lz4,
+    /// Slower, for a better ratio.
+    Zstd,
+}
+
+impl Compression {
+    /// The algorithms a client can ask for, from the most to the least preferred.
+    pub const ALGORITHMS: [Compression; 2] = [Compression::Zstd, Compression::Lz4];
+
+    pub fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(bytes)),
+            Compression::Zstd => {
+                zstd::encode_all(bytes, 0).with_whatever_context(|_| "Couldn't compress with zstd")
+            }
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Lz4 => {
+                let (size, block) = lz4_flex::block::uncompressed_size(bytes)
+                    .with_whatever_context(|_| "Couldn't decompress with lz4")?;
+                // The size is allocated upfront, so one that no block of this length could
+                // decompress to is refused instead of exhausting the memory
+                if size > block.len().saturating_mul(LZ4_MAX_RATIO) {
+                    whatever!(
+                        "Couldn't decompress with lz4: {} bytes can't hold {}",
+                        block.len(),
+                        size
+                    );
+                }
+                lz4_flex::decompress_size_prepended(bytes)
+                    .with_whatever_context(|_| "Couldn't decompress with lz4")
+            }
+            Compression::Zstd => {
+                zstd::decode_all(bytes).with_whatever_context(|_| "Couldn't decompress with zstd")
+            }
+        }
+    }
+
+    /// Compresses `bytes` unless they are too short to be worth it or don't get any shorter, in
+    /// which case they are better kept as they are.
+    pub fn compress_if_smaller(self, bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.is_none() || bytes.len() < MIN_SIZE {
+            return Ok(None);
+        }
+        let compressed = self.compress(bytes)?;
+        Ok(Some(compressed).filter(|compressed| compressed.len() < bytes.len()))
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = crate::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => whatever!("Unknown compression {}: expected none, lz4 or zstd", name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let value = "{\"user\":{\"name\":\"kvs\",\"tags\":[\"a\",\"b\"]}}".repeat(50);
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let compressed = compression.compress(value.as_bytes()).unwrap();
+            assert_eq!(
+                compression.decompress(&compressed).unwrap(),
+                value.as_bytes()
+            );
+        }
+        assert!(Compression::Lz4.decompress(b"garbage").is_err());
+        assert!(Compression::Zstd.decompress(b"garbage").is_err());
+    }
+
+    #[test]
+    fn lz4_size_too_large() {
+        // Claims 4 GiB in a few bytes
+        let mut bytes = u32::MAX.to_le_bytes().to_vec();
+        bytes.extend(b"\x1f\x00\x01\x00");
+        assert!(Compression::Lz4.decompress(&bytes).is_err());
+        let compressed = Compression::Lz4.compress(&[0; 100_000]).unwrap();
+        assert_eq!(
+            Compression::Lz4.decompress(&compressed).unwrap(),
+            [0; 100_000]
+        );
+    }
+
+    #[test]
+    fn compress_if_smaller() {
+        let value = "a".repeat(MIN_SIZE);
+        assert!(Compression::Zstd
+            .compress_if_smaller(value.as_bytes())
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            Compression::None
+                .compress_if_smaller(value.as_bytes())
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            Compression::Zstd.compress_if_smaller(b"short").unwrap(),
+            None
+        );
+        // Random bytes don't compress
+        let random: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+        assert_eq!(Compression::Lz4.compress_if_smaller(&random).unwrap(), None);
+    }
+
+    #[test]
+    fn names() {
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            assert_eq!(
+                compression.to_string().parse::<Compression>().unwrap(),
+                compression
+            );
+        }
+        assert_eq!("ZSTD".parse::<Compression>().unwrap(), Compression::Zstd);
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
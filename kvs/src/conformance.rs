@@ -0,0 +1,854 @@
+//! Engine-agnostic conformance suite for [`KvsEngine`] implementations.
+//!
+//! Every check is a plain function generic over the engine, taking a closure that opens the
+//! engine inside a given directory. [`engine_conformance_tests!`](crate::engine_conformance_tests)
+//! wraps all of them into `#[test]` functions, so an engine living outside of this crate can be
+//! checked with:
+//!
+//! ```ignore
+//! kvs::engine_conformance_tests!(my_engine, Durability::Persistent, |path| MyEngine::open(path));
+//! ```
+
+use crate::err::Result;
+use crate::value::{ValueType, WrongType, TYPE_TAG};
+use crate::watch::{Change, Event, KeyPattern};
+use crate::{evaluate_command, Command, CommandResponse, KvsEngine, WriteOp};
+use proptest::prelude::*;
+use proptest::test_runner::{Config, TestCaseError, TestRunner};
+use snafu::whatever;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Barrier, RwLock};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Whether the data written by an engine survives dropping it and opening the same directory
+/// again. Reopening checks are skipped for volatile engines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    Persistent,
+    Volatile,
+}
+
+fn temp_dir() -> TempDir {
+    TempDir::new().expect("unable to create temporary working directory")
+}
+
+fn write_lock<E>(store: &RwLock<E>) -> std::sync::RwLockWriteGuard<'_, E> {
+    store.write().expect("store lock poisoned")
+}
+
+fn read_lock<E>(store: &RwLock<E>) -> std::sync::RwLockReadGuard<'_, E> {
+    store.read().expect("store lock poisoned")
+}
+
+/// Should get previously stored value.
+pub fn get_stored_value<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Should overwrite existent value.
+pub fn overwrite_value<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let mut store = open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        store.set("key1".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Should get `None` when getting a non-existent key.
+pub fn get_non_existent_value<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+/// Should return the removed value, and `None` afterwards.
+pub fn remove_key<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.remove("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Removing a key that was never set (or was already removed) is not an error, but yields `None`.
+pub fn remove_non_existent_key<E, F>(open: F, _durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    assert_eq!(store.remove("key1".to_owned())?, None);
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.remove("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.remove("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+/// Overwrites and removes a small set of keys many times over, so that engines with a log get to
+/// compact it, then checks that only the latest values survive.
+pub fn compaction<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    const ROUNDS: usize = 10;
+    const KEYS: usize = 50;
+
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    for round in 0..ROUNDS {
+        for key_id in 0..KEYS {
+            store.set(format!("key{}", key_id), format!("{}", round))?;
+        }
+        for key_id in (0..KEYS).step_by(10) {
+            store.remove(format!("key{}", key_id))?;
+        }
+    }
+
+    let check = |store: &E| -> Result<()> {
+        for key_id in 0..KEYS {
+            let expected = if key_id % 10 == 0 {
+                None
+            } else {
+                Some(format!("{}", ROUNDS - 1))
+            };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+/// Sets keys from many threads at once, sharing the engine the same way the servers do.
+pub fn concurrent_set<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine + 'static,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: usize = 20;
+    const KEYS_PER_THREAD: usize = 10;
+
+    let temp_dir = temp_dir();
+    let store = Arc::new(RwLock::new(open(temp_dir.path())?));
+    let barrier = Arc::new(Barrier::new(THREADS));
+    let mut handles = Vec::new();
+    for thread_id in 0..THREADS {
+        let store = store.clone();
+        let barrier = barrier.clone();
+        handles.push(thread::spawn(move || {
+            // Start every thread's writes at the same time.
+            barrier.wait();
+            for i in 0..KEYS_PER_THREAD {
+                let key_id = thread_id * KEYS_PER_THREAD + i;
+                write_lock(&store)
+                    .set(format!("key{}", key_id), format!("value{}", key_id))
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().expect("writer thread panicked");
+    }
+
+    let check = |store: &E| -> Result<()> {
+        for key_id in 0..THREADS * KEYS_PER_THREAD {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+        Ok(())
+    };
+    check(&read_lock(&store))?;
+
+    if durability == Durability::Persistent {
+        let Ok(store) = Arc::try_unwrap(store) else {
+            whatever!("Store is still shared after all threads finished");
+        };
+        drop(store);
+        let store = open(temp_dir.path())?;
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+/// Reads keys from many threads at once while another thread keeps overwriting unrelated keys.
+pub fn concurrent_get<E, F>(open: F, _durability: Durability) -> Result<()>
+where
+    E: KvsEngine + 'static,
+    F: Fn(&Path) -> Result<E>,
+{
+    const THREADS: usize = 20;
+    const KEYS: usize = 50;
+
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+    for key_id in 0..KEYS {
+        store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+    }
+    let store = Arc::new(RwLock::new(store));
+
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..KEYS {
+                write_lock(&store)
+                    .set(format!("other{}", i % 10), format!("{}", i))
+                    .unwrap();
+            }
+        })
+    };
+    let mut readers = Vec::new();
+    for thread_id in 0..THREADS {
+        let store = store.clone();
+        readers.push(thread::spawn(move || {
+            for i in 0..KEYS {
+                let key_id = (i + thread_id) % KEYS;
+                assert_eq!(
+                    read_lock(&store).get(format!("key{}", key_id)).unwrap(),
+                    Some(format!("value{}", key_id))
+                );
+            }
+        }));
+    }
+
+    writer.join().expect("writer thread panicked");
+    for reader in readers {
+        reader.join().expect("reader thread panicked");
+    }
+
+    Ok(())
+}
+
+/// Stores values of a few megabytes as well as keys and values with whitespace, newlines and
+/// non-ASCII characters.
+pub fn large_values<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let entries: Vec<(String, String)> = vec![
+        ("large1".to_owned(), "a".repeat(1 << 20)),
+        ("large2".to_owned(), "0123456789".repeat(300_000)),
+        ("k".repeat(4096), "long key".to_owned()),
+        (
+            "key with spaces".to_owned(),
+            "value\nwith\nnewlines".to_owned(),
+        ),
+        ("khóa".to_owned(), "giá trị 🦀".to_owned()),
+        ("empty".to_owned(), String::new()),
+    ];
+
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+    for (key, value) in entries.iter() {
+        store.set(key.clone(), value.clone())?;
+    }
+
+    let check = |store: &E| -> Result<()> {
+        for (key, value) in entries.iter() {
+            assert_eq!(store.get(key.clone())?.as_ref(), Some(value));
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+/// Should stream the changes of watched keys with increasing sequence numbers, skip removals of
+/// missing keys, and replay the changes following a given position, but not one from before the
+/// store was reopened.
+pub fn watch<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+    let timeout = Duration::from_secs(5);
+
+    let key_watcher = store.watch(KeyPattern::parse("user:1"), None)?;
+    let mut prefix_watcher = store.watch(KeyPattern::parse("user:*"), None)?;
+    store.set("user:1".to_owned(), "value1".to_owned())?;
+    store.set("order:1".to_owned(), "value1".to_owned())?;
+    store.remove("user:2".to_owned())?;
+    store.set("user:2".to_owned(), "value2".to_owned())?;
+    store.remove("user:1".to_owned())?;
+
+    let mut events = Vec::new();
+    while events.len() < 3 {
+        let Some(event) = prefix_watcher.recv_timeout(timeout)? else {
+            whatever!("Timed out waiting for change {}", events.len() + 1);
+        };
+        events.push(event);
+    }
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (event.key.as_str(), &event.change))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                "user:1",
+                &Change::Set {
+                    value: "value1".to_owned()
+                }
+            ),
+            (
+                "user:2",
+                &Change::Set {
+                    value: "value2".to_owned()
+                }
+            ),
+            ("user:1", &Change::Rm),
+        ]
+    );
+    assert!(events.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+    assert_eq!(prefix_watcher.recv_timeout(Duration::ZERO)?, None);
+
+    let key_events: Vec<Event> = key_watcher.take(2).collect();
+    assert_eq!(key_events, vec![events[0].clone(), events[2].clone()]);
+
+    // Resuming right after the first change replays the other two
+    let resumed = store.watch(KeyPattern::parse("user:*"), Some(events[0].position()))?;
+    assert_eq!(resumed.take(2).collect::<Vec<_>>(), events[1..]);
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        // The sequence numbers started over, so the old positions mean nothing anymore
+        assert!(store
+            .watch(KeyPattern::parse("user:*"), Some(events[0].position()))
+            .is_err());
+    }
+
+    Ok(())
+}
+
+/// Should only yield the pairs under the given prefix, in key order.
+pub fn scan_prefix<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    for key in ["user:2", "order:1", "user:1", "user:3", "users", "order:2"] {
+        store.set(key.to_owned(), format!("value of {}", key))?;
+    }
+    store.remove("user:3".to_owned())?;
+
+    let check = |store: &E| -> Result<()> {
+        let users = store
+            .scan("user:".to_owned())?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            users,
+            vec![
+                ("user:1".to_owned(), "value of user:1".to_owned()),
+                ("user:2".to_owned(), "value of user:2".to_owned()),
+            ]
+        );
+        let keys = store
+            .scan(String::new())?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, ["order:1", "order:2", "user:1", "user:2", "users"]);
+        assert_eq!(store.scan("missing".to_owned())?.count(), 0);
+        Ok(())
+    };
+    check(&store)?;
+
+    if durability == Durability::Persistent {
+        drop(store);
+        let store = open(temp_dir.path())?;
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+/// Should apply the writes of a batch in order, publishing a change for each of them but the
+/// removals of missing keys.
+pub fn write_batch<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+    let set = |key: &str, value: &str| WriteOp::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    let rm = |key: &str| WriteOp::Rm {
+        key: key.to_owned(),
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut watcher = store.watch(KeyPattern::parse("*"), None)?;
+    store.write_batch(Vec::new())?;
+    store.write_batch(vec![
+        set("key2", "value2"),
+        set("key3", "value3"),
+        rm("key1"),
+        rm("missing"),
+        set("key2", "value4"),
+        rm("key3"),
+    ])?;
+
+    let mut events = Vec::new();
+    while events.len() < 5 {
+        let Some(event) = watcher.recv_timeout(Duration::from_secs(5))? else {
+            whatever!("Timed out waiting for change {}", events.len() + 1);
+        };
+        events.push(event);
+    }
+    assert_eq!(
+        events
+            .iter()
+            .map(|event| (event.key.as_str(), &event.change))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                "key2",
+                &Change::Set {
+                    value: "value2".to_owned()
+                }
+            ),
+            (
+                "key3",
+                &Change::Set {
+                    value: "value3".to_owned()
+                }
+            ),
+            ("key1", &Change::Rm),
+            (
+                "key2",
+                &Change::Set {
+                    value: "value4".to_owned()
+                }
+            ),
+            ("key3", &Change::Rm),
+        ]
+    );
+    assert_eq!(watcher.recv_timeout(Duration::ZERO)?, None);
+
+    let check = |store: &E| -> Result<()> {
+        let pairs = store.scan(String::new())?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs, vec![("key2".to_owned(), "value4".to_owned())]);
+        Ok(())
+    };
+    check(&store)?;
+
+    if durability == Durability::Persistent {
+        drop(watcher);
+        drop(store);
+        let store = open(temp_dir.path())?;
+        check(&store)?;
+    }
+
+    Ok(())
+}
+
+/// Should keep typed values through the commands working on them, telling the types apart.
+pub fn typed_values<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let temp_dir = temp_dir();
+    let mut store = open(temp_dir.path())?;
+
+    let run = |store: &mut E, command: Command| evaluate_command(&command, store);
+    let lpush = Command::LPush {
+        key: "list1".to_owned(),
+        values: vec!["a".to_owned(), "b".to_owned()],
+    };
+    assert_eq!(run(&mut store, lpush)?, CommandResponse::LPush { len: 2 });
+    let hset = Command::HSet {
+        key: "hash1".to_owned(),
+        field: "field1".to_owned(),
+        value: "value1".to_owned(),
+    };
+    assert_eq!(
+        run(&mut store, hset.clone())?,
+        CommandResponse::HSet { created: true }
+    );
+    assert_eq!(
+        run(&mut store, hset)?,
+        CommandResponse::HSet { created: false }
+    );
+    let sadd = Command::SAdd {
+        key: "set1".to_owned(),
+        members: vec!["b".to_owned(), "a".to_owned(), "b".to_owned()],
+    };
+    assert_eq!(run(&mut store, sadd)?, CommandResponse::SAdd { added: 2 });
+    for delta in [5, -2] {
+        let incr_by = Command::IncrBy {
+            key: "counter1".to_owned(),
+            delta,
+        };
+        run(&mut store, incr_by)?;
+    }
+    // Strings that look like typed values stay strings
+    let tagged = format!("{}{{\"type\":\"set\",\"value\":[]}}", TYPE_TAG);
+    let set = Command::Set {
+        key: "string1".to_owned(),
+        value: tagged.clone(),
+    };
+    run(&mut store, set)?;
+
+    let check = |store: &mut E| -> Result<()> {
+        let lrange = Command::LRange {
+            key: "list1".to_owned(),
+            start: 0,
+            stop: -1,
+        };
+        assert_eq!(
+            run(store, lrange)?,
+            CommandResponse::LRange {
+                values: vec!["b".to_owned(), "a".to_owned()]
+            }
+        );
+        let hget = Command::HGet {
+            key: "hash1".to_owned(),
+            field: "field1".to_owned(),
+        };
+        assert_eq!(
+            run(store, hget)?,
+            CommandResponse::HGet {
+                value: Some("value1".to_owned())
+            }
+        );
+        let smembers = Command::SMembers {
+            key: "set1".to_owned(),
+        };
+        assert_eq!(
+            run(store, smembers)?,
+            CommandResponse::SMembers {
+                members: vec!["a".to_owned(), "b".to_owned()]
+            }
+        );
+        let get = |key: &str| Command::Get {
+            key: key.to_owned(),
+        };
+        assert_eq!(
+            run(store, get("counter1"))?,
+            CommandResponse::Get {
+                value: Some("3".to_owned())
+            }
+        );
+        assert_eq!(
+            run(store, get("string1"))?,
+            CommandResponse::Get {
+                value: Some(tagged.clone())
+            }
+        );
+        assert_eq!(
+            run(store, get("list1"))?,
+            CommandResponse::WrongType(WrongType {
+                key: "list1".to_owned(),
+                expected: ValueType::String,
+                found: ValueType::List,
+            })
+        );
+        let sadd = Command::SAdd {
+            key: "hash1".to_owned(),
+            members: vec!["a".to_owned()],
+        };
+        assert!(matches!(
+            run(store, sadd)?,
+            CommandResponse::WrongType(WrongType {
+                found: ValueType::Hash,
+                ..
+            })
+        ));
+        let incr_by = Command::IncrBy {
+            key: "string1".to_owned(),
+            delta: 1,
+        };
+        assert!(run(store, incr_by).is_err());
+        Ok(())
+    };
+    check(&mut store)?;
+
+    if durability == Durability::Persistent {
+        drop(store);
+        store = open(temp_dir.path())?;
+        check(&mut store)?;
+    }
+
+    // Removed values come back as they are read, not as they are stored
+    let rm = |key: &str| Command::Rm {
+        key: key.to_owned(),
+    };
+    assert_eq!(
+        run(&mut store, rm("list1"))?,
+        CommandResponse::Rm {
+            value: Some("[\"b\",\"a\"]".to_owned())
+        }
+    );
+    assert_eq!(
+        run(&mut store, rm("string1"))?,
+        CommandResponse::Rm {
+            value: Some(tagged)
+        }
+    );
+
+    Ok(())
+}
+
+/// A single step of [`model_check`].
+#[derive(Debug, Clone)]
+pub enum Operation {
+    Set {
+        key: String,
+        value: String,
+    },
+    Get {
+        key: String,
+    },
+    Remove {
+        key: String,
+    },
+    /// Scans the keys under `prefix`, checked against the model in key order.
+    Scan {
+        prefix: String,
+    },
+    /// Drops the engine and opens the same directory again. A no-op for volatile engines.
+    Reopen,
+}
+
+fn operation_strategy() -> impl Strategy<Value = Operation> {
+    // A tiny key space makes overwrites and removals of existing keys likely.
+    let key = "[a-e]{1,2}";
+    prop_oneof![
+        4 => (key, any::<String>()).prop_map(|(key, value)| Operation::Set { key, value }),
+        3 => key.prop_map(|key| Operation::Get { key }),
+        2 => key.prop_map(|key| Operation::Remove { key }),
+        1 => "[a-e]?".prop_map(|prefix| Operation::Scan { prefix }),
+        1 => Just(Operation::Reopen),
+    ]
+}
+
+fn check_operations<E, F>(
+    open: &F,
+    durability: Durability,
+    operations: &[Operation],
+) -> std::result::Result<(), TestCaseError>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let to_case_error = |err: crate::Error| TestCaseError::fail(err.to_string());
+
+    let temp_dir = temp_dir();
+    let mut store = Some(open(temp_dir.path()).map_err(to_case_error)?);
+    let mut oracle: HashMap<String, String> = HashMap::new();
+
+    for operation in operations {
+        let engine = store.as_mut().expect("store is always reopened");
+        match operation {
+            Operation::Set { key, value } => {
+                engine
+                    .set(key.clone(), value.clone())
+                    .map_err(to_case_error)?;
+                oracle.insert(key.clone(), value.clone());
+            }
+            Operation::Get { key } => {
+                let got = engine.get(key.clone()).map_err(to_case_error)?;
+                prop_assert_eq!(got.as_ref(), oracle.get(key), "get {}", key);
+            }
+            Operation::Remove { key } => {
+                let got = engine.remove(key.clone()).map_err(to_case_error)?;
+                prop_assert_eq!(got, oracle.remove(key), "remove {}", key);
+            }
+            Operation::Scan { prefix } => {
+                let got = engine
+                    .scan(prefix.clone())
+                    .map_err(to_case_error)?
+                    .collect::<Result<Vec<_>>>()
+                    .map_err(to_case_error)?;
+                let mut expected: Vec<(String, String)> = oracle
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(prefix.as_str()))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect();
+                expected.sort();
+                prop_assert_eq!(got, expected, "scan {}", prefix);
+            }
+            Operation::Reopen => {
+                if durability == Durability::Persistent {
+                    drop(store.take());
+                    store = Some(open(temp_dir.path()).map_err(to_case_error)?);
+                }
+            }
+        }
+    }
+
+    let engine = store.as_ref().expect("store is always reopened");
+    for (key, value) in oracle.iter() {
+        let got = engine.get(key.clone()).map_err(to_case_error)?;
+        prop_assert_eq!(got.as_ref(), Some(value), "final get {}", key);
+    }
+
+    Ok(())
+}
+
+/// Runs random sequences of [`Operation`]s against the engine and a `HashMap` at the same time,
+/// and fails on the first (shrunk) sequence where the two disagree.
+pub fn model_check<E, F>(open: F, durability: Durability) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&Path) -> Result<E>,
+{
+    let mut runner = TestRunner::new(Config {
+        cases: 32,
+        failure_persistence: None,
+        ..Config::default()
+    });
+    let strategy = prop::collection::vec(operation_strategy(), 1..48);
+    if let Err(err) = runner.run(&strategy, |operations| {
+        check_operations(&open, durability, &operations)
+    }) {
+        whatever!("Engine diverged from the model: {}", err);
+    }
+
+    Ok(())
+}
+
+/// Generates one `#[test]` per conformance check for an engine.
+///
+/// ```ignore
+/// kvs::engine_conformance_tests!(
+///     kv_store,
+///     kvs::conformance::Durability::Persistent,
+///     |path: &std::path::Path| kvs::KvStoreV2::open(path)
+/// );
+/// ```
+#[macro_export]
+macro_rules! engine_conformance_tests {
+    ($module:ident, $durability:expr, $open:expr) => {
+        mod $module {
+            #[allow(unused_imports)]
+            use super::*;
+
+            $crate::engine_conformance_tests!(
+                @tests $durability, $open;
+                get_stored_value,
+                overwrite_value,
+                get_non_existent_value,
+                remove_key,
+                remove_non_existent_key,
+                compaction,
+                concurrent_set,
+                concurrent_get,
+                large_values,
+                scan_prefix,
+                write_batch,
+                typed_values,
+                watch,
+                model_check,
+            );
+        }
+    };
+    (@tests $durability:expr, $open:expr; $($check:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $check() -> $crate::Result<()> {
+                $crate::conformance::$check($open, $durability)
+            }
+        )*
+    };
+}
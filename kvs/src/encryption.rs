@@ -0,0 +1,251 @@
+//! Encryption at rest of the data kept by [`crate::KvStoreV2`] and [`crate::SledStore`].
+//!
+//! Data is sealed with ChaCha20-Poly1305 under a 256-bit key, given as 64 hexadecimal characters.
+//! Every sealed piece of data starts with the ID of the key it was sealed with, so that data
+//! sealed with an old key is still read during a key rotation, and data sealed with a key that
+//! wasn't given fails with [`Error::WrongEncryptionKey`] instead of reading as garbage.
+//!
+//! ```
+//! use kvs::encryption::{EncryptionKey, Keyring};
+//!
+//! let old = EncryptionKey::from_hex(&EncryptionKey::generate())?;
+//! let new = EncryptionKey::from_hex(&EncryptionKey::generate())?;
+//! let sealed = Keyring::new(old.clone(), Vec::new()).encrypt(b"alice")?;
+//!
+//! let keyring = Keyring::new(new, vec![old]);
+//! assert_eq!(keyring.decrypt(&sealed)?, b"alice");
+//! assert!(!keyring.is_current(&sealed));
+//! assert!(Keyring::default().decrypt(&sealed).is_err());
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::err::{Result, ResultExt, WrongEncryptionKeySnafu};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use snafu::whatever;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const KEY_SIZE: usize = 32;
+const KEY_ID_SIZE: usize = 4;
+const NONCE_SIZE: usize = 12;
+
+/// A key to seal data with.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    id: [u8; KEY_ID_SIZE],
+    cipher: ChaCha20Poly1305,
+}
+
+impl EncryptionKey {
+    /// A new random key, as 64 hexadecimal characters.
+    pub fn generate() -> String {
+        hex::encode(ChaCha20Poly1305::generate_key(&mut OsRng))
+    }
+
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let bytes = match hex::decode(key.trim()) {
+            Ok(bytes) if bytes.len() == KEY_SIZE => bytes,
+            _ => whatever!(
+                "Invalid encryption key: expected {} hexadecimal characters",
+                KEY_SIZE * 2
+            ),
+        };
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&bytes));
+        // The tag of nothing under a fixed nonce tells keys apart without telling anything
+        // about them
+        let tag = cipher
+            .encrypt(&Nonce::default(), &b""[..])
+            .expect("sealing nothing can't fail");
+        let mut id = [0; KEY_ID_SIZE];
+        id.copy_from_slice(&tag[..KEY_ID_SIZE]);
+        Ok(Self { id, cipher })
+    }
+
+    /// Reads the key held by the file at `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let key = fs::read_to_string(path).with_whatever_context(|_| {
+            format!("Couldn't read encryption key file at {}", path.display())
+        })?;
+        Self::from_hex(&key)
+            .with_whatever_context(|_| format!("Invalid encryption key in {}", path.display()))
+    }
+
+    /// The ID of the key, as 8 hexadecimal characters.
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({})", self.id())
+    }
+}
+
+/// The key to seal data with, and the older ones data may still be sealed with. The default
+/// keyring has no key at all: it leaves data as it is, and refuses to read sealed data.
+#[derive(Clone, Debug, Default)]
+pub struct Keyring {
+    current: Option<EncryptionKey>,
+    old: Vec<EncryptionKey>,
+}
+
+impl Keyring {
+    pub fn new(current: EncryptionKey, old: Vec<EncryptionKey>) -> Self {
+        Self {
+            current: Some(current),
+            old,
+        }
+    }
+
+    /// Builds the keyring of a server or tool: the current key comes from `key`, as hexadecimal
+    /// characters, or else from the file at `key_file`. Without either, data isn't encrypted.
+    pub fn load(
+        key: Option<&str>,
+        key_file: Option<&Path>,
+        old_key_files: &[PathBuf],
+    ) -> Result<Self> {
+        let current = match (key, key_file) {
+            (Some(key), _) => EncryptionKey::from_hex(key)?,
+            (None, Some(key_file)) => EncryptionKey::read(key_file)?,
+            (None, None) if old_key_files.is_empty() => return Ok(Self::default()),
+            (None, None) => whatever!("Old encryption keys need a current one"),
+        };
+        let old = old_key_files
+            .iter()
+            .map(|path| EncryptionKey::read(path))
+            .collect::<Result<_>>()?;
+        Ok(Self::new(current, old))
+    }
+
+    /// Whether data is sealed when written.
+    pub fn is_enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Seals `plaintext` with the current key.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let Some(key) = &self.current else {
+            whatever!("No encryption key given");
+        };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = match key.cipher.encrypt(&nonce, plaintext) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => whatever!("Couldn't encrypt {} bytes", plaintext.len()),
+        };
+        let mut sealed = Vec::with_capacity(KEY_ID_SIZE + NONCE_SIZE + ciphertext.len());
+        sealed.extend_from_slice(&key.id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    /// Opens data sealed by [`Keyring::encrypt`], with whichever key it was sealed with.
+    pub fn decrypt(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < KEY_ID_SIZE + NONCE_SIZE {
+            whatever!("Encrypted data is {} bytes long, too short", sealed.len());
+        }
+        let (id, rest) = sealed.split_at(KEY_ID_SIZE);
+        let (nonce, ciphertext) = rest.split_at(NONCE_SIZE);
+        let Some(key) = self
+            .current
+            .iter()
+            .chain(&self.old)
+            .find(|key| key.id == id)
+        else {
+            return WrongEncryptionKeySnafu {
+                key_id: hex::encode(id),
+            }
+            .fail();
+        };
+        match key.cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            Ok(plaintext) => Ok(plaintext),
+            Err(_) => whatever!(
+                "Data encrypted with key {} is corrupted or was tampered with",
+                key.id()
+            ),
+        }
+    }
+
+    /// Whether `sealed` was sealed with the current key, rather than with an old one.
+    pub fn is_current(&self, sealed: &[u8]) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|key| sealed.starts_with(&key.id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    fn key() -> EncryptionKey {
+        EncryptionKey::from_hex(&EncryptionKey::generate()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let keyring = Keyring::new(key(), Vec::new());
+        let sealed = keyring.encrypt(b"alice").unwrap();
+        assert!(!sealed.windows(5).any(|window| window == b"alice"));
+        assert_eq!(keyring.decrypt(&sealed).unwrap(), b"alice");
+        // Nonces are random, so the same plaintext seals differently every time
+        assert_ne!(keyring.encrypt(b"alice").unwrap(), sealed);
+    }
+
+    #[test]
+    fn wrong_key() {
+        let sealed = Keyring::new(key(), Vec::new()).encrypt(b"alice").unwrap();
+        for keyring in [Keyring::new(key(), vec![key()]), Keyring::default()] {
+            let err = keyring.decrypt(&sealed).unwrap_err();
+            assert!(
+                matches!(&err, Error::WrongEncryptionKey { key_id } if key_id.len() == 8),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let keyring = Keyring::new(key(), Vec::new());
+        let mut sealed = keyring.encrypt(b"alice").unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        let err = keyring.decrypt(&sealed).unwrap_err();
+        assert!(err.to_string().contains("corrupted"), "{}", err);
+        assert!(keyring.decrypt(b"short").is_err());
+    }
+
+    #[test]
+    fn rotation() {
+        let (old, new) = (key(), key());
+        let sealed = Keyring::new(old.clone(), Vec::new())
+            .encrypt(b"alice")
+            .unwrap();
+        let keyring = Keyring::new(new, vec![old]);
+        assert!(!keyring.is_current(&sealed));
+        assert_eq!(keyring.decrypt(&sealed).unwrap(), b"alice");
+        assert!(keyring.is_current(&keyring.encrypt(b"alice").unwrap()));
+    }
+
+    #[test]
+    fn keys() {
+        let hex = EncryptionKey::generate();
+        assert_eq!(hex.len(), 64);
+        // The same key always gets the same ID
+        assert_eq!(
+            EncryptionKey::from_hex(&hex).unwrap().id(),
+            EncryptionKey::from_hex(&format!("{}\n", hex)).unwrap().id()
+        );
+        assert_ne!(key().id(), key().id());
+        assert!(EncryptionKey::from_hex("abcd").is_err());
+        assert!(EncryptionKey::from_hex(&"zz".repeat(32)).is_err());
+
+        assert!(!Keyring::load(None, None, &[]).unwrap().is_enabled());
+        assert!(Keyring::load(None, None, &[PathBuf::from("old.key")]).is_err());
+        assert!(Keyring::load(Some(&hex), None, &[]).unwrap().is_enabled());
+    }
+}
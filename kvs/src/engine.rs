@@ -1,26 +1,274 @@
+use crate::backup::write_backup;
 use crate::{Command, CommandResponse};
+use snafu::whatever;
 // use std::ops::DerefMut;
 use crate::err::Result;
+use crate::script::{evaluate_script, Outcome};
+use crate::snapshot::Snapshot;
+use crate::value::{list_range, Value, ValueType, WrongType};
+use crate::watch::{KeyPattern, Position, Watcher};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::time::Duration;
+
+/// Key-value pairs yielded by [`KvsEngine::scan`].
+pub type ScanIter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// A snapshot of an engine's bookkeeping, used by the admin tooling.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EngineStats {
+    /// Number of keys holding a value.
+    pub live_keys: usize,
+    /// Number of records on disk that were superseded by later ones, for engines keeping a log.
+    pub stale_records: Option<usize>,
+    /// Bytes taken on disk by the database file(s).
+    pub disk_size: u64,
+    /// Bytes the database file would take right after compaction, for engines keeping a log.
+    pub compacted_size: Option<u64>,
+    /// Number of compactions run since the engine was opened, for engines keeping a log.
+    pub compactions: Option<u64>,
+    /// Time spent compacting since the engine was opened, for engines keeping a log.
+    pub compaction_time: Option<Duration>,
+    /// Bytes taken by the live values, for engines that may compress them.
+    pub value_size: Option<u64>,
+    /// Bytes the live values take as they are stored, for engines that may compress them.
+    pub stored_value_size: Option<u64>,
+}
+
+impl EngineStats {
+    /// Share of the records on disk that are stale, between 0 and 1.
+    pub fn stale_ratio(&self) -> Option<f64> {
+        let stale_records = self.stale_records?;
+        let total = self.live_keys + stale_records;
+        if total == 0 {
+            Some(0.0)
+        } else {
+            Some(stale_records as f64 / total as f64)
+        }
+    }
+
+    /// How many times smaller the live values are once stored, 1 when none is compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        let (value_size, stored_value_size) = (self.value_size?, self.stored_value_size?);
+        if stored_value_size == 0 {
+            Some(1.0)
+        } else {
+            Some(value_size as f64 / stored_value_size as f64)
+        }
+    }
+}
+
+/// A write of a batch, see [`KvsEngine::write_batch`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+impl WriteOp {
+    pub fn key(&self) -> &str {
+        match self {
+            WriteOp::Set { key, .. } | WriteOp::Rm { key } => key,
+        }
+    }
+
+    /// The value the key holds once written, `None` for a removal.
+    pub fn value(&self) -> Option<&str> {
+        match self {
+            WriteOp::Set { value, .. } => Some(value),
+            WriteOp::Rm { .. } => None,
+        }
+    }
+}
 
 pub trait KvsEngine: Send + Sync {
     fn set(&mut self, key: String, value: String) -> Result<()>;
     fn get(&self, key: String) -> Result<Option<String>>;
     fn remove(&mut self, key: String) -> Result<Option<String>>;
+    /// Applies the writes of `batch` in order, all of them or none: a batch cut short by a crash
+    /// is not there once the engine is opened again. Removing a missing key is not an error.
+    fn write_batch(&mut self, batch: Vec<WriteOp>) -> Result<()>;
+    /// Iterates over every pair whose key starts with `prefix`, in ascending key order. An empty
+    /// prefix iterates over the whole store.
+    fn scan(&self, prefix: String) -> Result<ScanIter<'_>>;
+    fn stats(&self) -> Result<EngineStats>;
+    /// Streams every later change of the keys matching `pattern`. With `after`, the changes
+    /// following that position are replayed first; a position from before the engine was opened
+    /// is refused.
+    fn watch(&self, pattern: KeyPattern, after: Option<Position>) -> Result<Watcher>;
+    /// The pairs whose value holds `value` at the path of the index `index`, in key order. Only
+    /// engines keeping indexes, see [`crate::index::IndexedStore`], have any.
+    fn find(&self, index: &str, _value: &str) -> Result<Vec<(String, String)>> {
+        whatever!("Index {} doesn't exist", index)
+    }
+    /// The pairs of the store as they are now, to be written in the background without holding
+    /// the store, for the engines keeping them in memory with snapshots, see [`crate::snapshot`].
+    /// `None` when nothing changed since the last snapshot written.
+    fn snapshot(&self) -> Option<Snapshot> {
+        None
+    }
 
     fn name(&self) -> &'static str;
 }
 
+/// Runs `command` against `store`. Commands on typed values read the value, change it and write
+/// it back, which the caller makes atomic by holding the store exclusively meanwhile.
+#[tracing::instrument(skip_all, fields(command = command.name()))]
 pub fn evaluate_command(command: &Command, store: &mut dyn KvsEngine) -> Result<CommandResponse> {
     match command {
-        Command::Get { key } => Ok(CommandResponse::Get {
-            value: store.get(key.clone())?,
-        }),
         Command::Set { key, value } => {
-            store.set(key.clone(), value.clone())?;
+            store.set(key.clone(), Value::String(value.clone()).encode()?)?;
             Ok(CommandResponse::Set {})
         }
-        Command::Rm { key } => Ok(CommandResponse::Rm {
-            value: store.remove(key.clone())?,
+        Command::Rm { key } => {
+            let value = match store.remove(key.clone())? {
+                Some(value) => Some(Value::decode(value)?.into_text()?),
+                None => None,
+            };
+            Ok(CommandResponse::Rm { value })
+        }
+        Command::Backup => {
+            let mut archive = Vec::new();
+            write_backup(store, &mut archive)?;
+            Ok(CommandResponse::Backup { archive })
+        }
+        // Backups hold the values as stored, so they are restored as they are
+        Command::Restore { pairs } => {
+            if store.scan(String::new())?.next().is_some() {
+                whatever!("Backups can only be restored into an empty store");
+            }
+            for (key, value) in pairs {
+                store.set(key.clone(), value.clone())?;
+            }
+            Ok(CommandResponse::Restore { count: pairs.len() })
+        }
+        Command::LPush { key, values } => {
+            let mut list = match read_value(store, key)? {
+                None => VecDeque::new(),
+                Some(Value::List(list)) => list,
+                Some(value) => return Ok(wrong_type(key, ValueType::List, &value)),
+            };
+            for value in values {
+                list.push_front(value.clone());
+            }
+            let len = list.len();
+            store.set(key.clone(), Value::List(list).encode()?)?;
+            Ok(CommandResponse::LPush { len })
+        }
+        Command::HSet { key, field, value } => {
+            let mut hash = match read_value(store, key)? {
+                None => BTreeMap::new(),
+                Some(Value::Hash(hash)) => hash,
+                Some(value) => return Ok(wrong_type(key, ValueType::Hash, &value)),
+            };
+            let created = hash.insert(field.clone(), value.clone()).is_none();
+            store.set(key.clone(), Value::Hash(hash).encode()?)?;
+            Ok(CommandResponse::HSet { created })
+        }
+        Command::SAdd { key, members } => {
+            let mut set = match read_value(store, key)? {
+                None => BTreeSet::new(),
+                Some(Value::Set(set)) => set,
+                Some(value) => return Ok(wrong_type(key, ValueType::Set, &value)),
+            };
+            let len = set.len();
+            set.extend(members.iter().cloned());
+            let added = set.len() - len;
+            store.set(key.clone(), Value::Set(set).encode()?)?;
+            Ok(CommandResponse::SAdd { added })
+        }
+        Command::IncrBy { key, delta } => {
+            let current = match read_value(store, key)? {
+                None => 0,
+                Some(Value::String(value)) => match value.parse::<i64>() {
+                    Ok(current) => current,
+                    Err(_) => whatever!("Value of {} isn't an integer", key),
+                },
+                Some(value) => return Ok(wrong_type(key, ValueType::String, &value)),
+            };
+            let Some(value) = current.checked_add(*delta) else {
+                whatever!("Adding {} to {} would overflow", delta, key);
+            };
+            store.set(key.clone(), value.to_string())?;
+            Ok(CommandResponse::IncrBy { value })
+        }
+        // Scripts write once they returned, so that a failing one writes nothing
+        Command::Eval { .. } | Command::EvalSha { .. } => {
+            let Outcome { result, writes } = evaluate_script(command, store)?;
+            store.write_batch(writes)?;
+            Ok(CommandResponse::Eval { result })
+        }
+        Command::Get { .. }
+        | Command::Scan { .. }
+        | Command::LRange { .. }
+        | Command::HGet { .. }
+        | Command::SMembers { .. }
+        | Command::Find { .. } => evaluate_read_command(command, store),
+    }
+}
+
+/// Runs a command that only reads, so that it needs the store shared only. Fails for any other
+/// command.
+pub fn evaluate_read_command(command: &Command, store: &dyn KvsEngine) -> Result<CommandResponse> {
+    match command {
+        Command::Get { key } => match read_value(store, key)? {
+            None => Ok(CommandResponse::Get { value: None }),
+            Some(Value::String(value)) => Ok(CommandResponse::Get { value: Some(value) }),
+            Some(value) => Ok(wrong_type(key, ValueType::String, &value)),
+        },
+        Command::Scan { prefix } => Ok(CommandResponse::Scan {
+            pairs: store
+                .scan(prefix.clone())?
+                .map(|pair| {
+                    let (key, value) = pair?;
+                    Ok((key, Value::decode(value)?.into_text()?))
+                })
+                .collect::<Result<_>>()?,
+        }),
+        Command::LRange { key, start, stop } => match read_value(store, key)? {
+            None => Ok(CommandResponse::LRange { values: Vec::new() }),
+            Some(Value::List(list)) => Ok(CommandResponse::LRange {
+                values: list
+                    .range(list_range(list.len(), *start, *stop))
+                    .cloned()
+                    .collect(),
+            }),
+            Some(value) => Ok(wrong_type(key, ValueType::List, &value)),
+        },
+        Command::HGet { key, field } => match read_value(store, key)? {
+            None => Ok(CommandResponse::HGet { value: None }),
+            Some(Value::Hash(mut hash)) => Ok(CommandResponse::HGet {
+                value: hash.remove(field),
+            }),
+            Some(value) => Ok(wrong_type(key, ValueType::Hash, &value)),
+        },
+        Command::SMembers { key } => match read_value(store, key)? {
+            None => Ok(CommandResponse::SMembers {
+                members: Vec::new(),
+            }),
+            Some(Value::Set(set)) => Ok(CommandResponse::SMembers {
+                members: set.into_iter().collect(),
+            }),
+            Some(value) => Ok(wrong_type(key, ValueType::Set, &value)),
+        },
+        Command::Find { index, value } => Ok(CommandResponse::Find {
+            pairs: store
+                .find(index, value)?
+                .into_iter()
+                .map(|(key, value)| Ok((key, Value::decode(value)?.into_text()?)))
+                .collect::<Result<_>>()?,
         }),
+        _ => whatever!("{} isn't a read-only command", command.name()),
     }
 }
+
+fn read_value(store: &dyn KvsEngine, key: &str) -> Result<Option<Value>> {
+    store.get(key.to_owned())?.map(Value::decode).transpose()
+}
+
+fn wrong_type(key: &str, expected: ValueType, found: &Value) -> CommandResponse {
+    CommandResponse::WrongType(WrongType {
+        key: key.to_owned(),
+        expected,
+        found: found.value_type(),
+    })
+}
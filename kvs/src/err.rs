@@ -1,3 +1,4 @@
+use crate::auth::Permission;
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::Json;
@@ -33,11 +34,116 @@ pub enum Error {
     #[snafu(display("Couldn't write to file at {path}"))]
     FileWrite { path: String, err_str: String },
 
+    #[snafu(display("Authentication failed: {reason}"), visibility(pub(crate)))]
+    Unauthenticated { reason: String },
+
+    #[snafu(
+        display("User {user} isn't allowed to {permission} {target}"),
+        visibility(pub(crate))
+    )]
+    PermissionDenied {
+        user: String,
+        permission: Permission,
+        target: String,
+    },
+
+    #[snafu(
+        display("Too many connections, the server allows {max} at a time"),
+        visibility(pub(crate))
+    )]
+    TooManyConnections { max: usize },
+
+    #[snafu(
+        display("Rate limit exceeded, retry in {retry_after_ms} ms"),
+        visibility(pub(crate))
+    )]
+    RateLimited { retry_after_ms: u64 },
+
+    #[snafu(
+        display("{what} is {size} bytes long, more than the {max} allowed"),
+        visibility(pub(crate))
+    )]
+    TooLarge {
+        what: String,
+        size: usize,
+        max: usize,
+    },
+
+    /// Data at rest was encrypted with a key that wasn't given, or was encrypted while no key
+    /// was given at all.
+    #[snafu(
+        display("Data was encrypted with key {key_id}, which isn't one of the keys given"),
+        visibility(pub(crate))
+    )]
+    WrongEncryptionKey { key_id: String },
+
+    #[snafu(display("Namespace {name} doesn't exist"), visibility(pub(crate)))]
+    NamespaceNotFound { name: String },
+
+    #[snafu(
+        display("Namespace {namespace} would go over its quota of {max} {what}"),
+        visibility(pub(crate))
+    )]
+    QuotaExceeded {
+        namespace: String,
+        what: &'static str,
+        max: u64,
+    },
+
+    /// The write would take the data of the server past its `max_memory` or `max_disk`, and the
+    /// eviction policy couldn't make room for it.
+    #[snafu(
+        display("Out of memory: the write would go over the {max} bytes of {what} allowed"),
+        visibility(pub(crate))
+    )]
+    OutOfMemory { what: &'static str, max: u64 },
+
+    /// A script run by `EVAL` failed, or went over one of its limits.
+    #[snafu(display("Script failed: {message}"), visibility(pub(crate)))]
+    Script { message: String },
+
+    #[snafu(
+        display("No script is cached under {hash}, it has to be sent with EVAL"),
+        visibility(pub(crate))
+    )]
+    NoScript { hash: String },
+
+    #[snafu(
+        display("Unable to connect to server at {addr}: {message}"),
+        visibility(pub(crate))
+    )]
+    Connect { addr: String, message: String },
+
+    #[snafu(
+        display("Timed out waiting for server at {addr}"),
+        visibility(pub(crate))
+    )]
+    Timeout { addr: String },
+
+    #[snafu(
+        display("Lost connection to server at {addr}: {message}"),
+        visibility(pub(crate))
+    )]
+    Connection { addr: String, message: String },
+
+    /// An error the server sent back instead of a response.
+    #[snafu(display("{message}"), visibility(pub(crate)))]
+    Server {
+        kind: ServerErrorKind,
+        message: String,
+    },
+
+    #[snafu(
+        display("Unexpected response from server: {response}"),
+        visibility(pub(crate))
+    )]
+    UnexpectedResponse { response: String },
+
     #[snafu(whatever, display("{message}"))]
     Whatever {
         message: String,
-        #[snafu(source(from(Box<dyn std::error::Error>, Some)))]
-        source: Option<Box<dyn std::error::Error>>,
+        #[snafu(source(from(Box<dyn std::error::Error + Send + Sync>, Some)))]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
     },
 
     // IoError(std::io::Error),
@@ -45,7 +151,122 @@ pub enum Error {
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, Json(self.to_string())).into_response()
+        let status = match self {
+            Error::Unauthenticated { .. } => StatusCode::UNAUTHORIZED,
+            Error::PermissionDenied { .. } => StatusCode::FORBIDDEN,
+            Error::TooManyConnections { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::NamespaceNotFound { .. } => StatusCode::NOT_FOUND,
+            Error::QuotaExceeded { .. } | Error::OutOfMemory { .. } => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            Error::Script { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::NoScript { .. } => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let code = [(ERROR_CODE_HEADER, self.server_kind().code())];
+        (status, code, Json(self.to_string())).into_response()
+    }
+}
+
+impl Error {
+    /// The kind of the error as sent to clients, which they can rely on more than on the message.
+    pub fn server_kind(&self) -> ServerErrorKind {
+        match self {
+            Error::KeyNotFound { .. } => ServerErrorKind::NotFound,
+            Error::Unauthenticated { .. } => ServerErrorKind::Unauthenticated,
+            Error::PermissionDenied { .. } => ServerErrorKind::PermissionDenied,
+            Error::RateLimited { .. } => ServerErrorKind::RateLimited,
+            Error::TooManyConnections { .. } => ServerErrorKind::TooManyConnections,
+            Error::TooLarge { .. } => ServerErrorKind::TooLarge,
+            Error::NamespaceNotFound { .. } => ServerErrorKind::NoSuchNamespace,
+            Error::QuotaExceeded { .. } => ServerErrorKind::QuotaExceeded,
+            Error::OutOfMemory { .. } => ServerErrorKind::OutOfMemory,
+            Error::Script { .. } => ServerErrorKind::Script,
+            Error::NoScript { .. } => ServerErrorKind::NoScript,
+            Error::Server { kind, .. } => *kind,
+            _ => ServerErrorKind::Other,
+        }
+    }
+}
+
+/// The header of the HTTP error responses holding the [`ServerErrorKind::code`] of the error, so
+/// that clients can tell e.g. a missing namespace from a missing key, both being `404 Not Found`.
+pub const ERROR_CODE_HEADER: &str = "x-error-code";
+
+/// What made the server refuse a request. The TCP protocol sends it as the code following `ERR`,
+/// e.g. `ERR RATE_LIMITED Rate limit exceeded, retry in 500 ms`, and the HTTP API as the status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerErrorKind {
+    NotFound,
+    Unauthenticated,
+    PermissionDenied,
+    RateLimited,
+    TooManyConnections,
+    TooLarge,
+    /// The key holds a value of another type than the command works on.
+    WrongType,
+    /// The namespace the request was sent to doesn't exist.
+    NoSuchNamespace,
+    /// The write would take the namespace over one of its quotas.
+    QuotaExceeded,
+    /// The write would take the server past its memory or disk limit, and nothing could be
+    /// evicted to make room for it.
+    OutOfMemory,
+    /// The script failed, or went over one of its limits.
+    Script,
+    /// No script is cached under the hash, so it has to be sent again.
+    NoScript,
+    /// Anything else, e.g. an invalid command or a failing engine.
+    Other,
+}
+
+impl ServerErrorKind {
+    const ALL: [ServerErrorKind; 13] = [
+        ServerErrorKind::NotFound,
+        ServerErrorKind::Unauthenticated,
+        ServerErrorKind::PermissionDenied,
+        ServerErrorKind::RateLimited,
+        ServerErrorKind::TooManyConnections,
+        ServerErrorKind::TooLarge,
+        ServerErrorKind::WrongType,
+        ServerErrorKind::NoSuchNamespace,
+        ServerErrorKind::QuotaExceeded,
+        ServerErrorKind::OutOfMemory,
+        ServerErrorKind::Script,
+        ServerErrorKind::NoScript,
+        ServerErrorKind::Other,
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerErrorKind::NotFound => "NOT_FOUND",
+            ServerErrorKind::Unauthenticated => "UNAUTHENTICATED",
+            ServerErrorKind::PermissionDenied => "FORBIDDEN",
+            ServerErrorKind::RateLimited => "RATE_LIMITED",
+            ServerErrorKind::TooManyConnections => "BUSY",
+            ServerErrorKind::TooLarge => "TOO_LARGE",
+            ServerErrorKind::WrongType => "WRONGTYPE",
+            ServerErrorKind::NoSuchNamespace => "NO_NAMESPACE",
+            ServerErrorKind::QuotaExceeded => "QUOTA_EXCEEDED",
+            ServerErrorKind::OutOfMemory => "OOM",
+            ServerErrorKind::Script => "SCRIPT",
+            ServerErrorKind::NoScript => "NOSCRIPT",
+            ServerErrorKind::Other => "ERROR",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.code() == code)
+    }
+
+    /// Whether the server refused the request before running it, asking to come back later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            ServerErrorKind::RateLimited | ServerErrorKind::TooManyConnections
+        )
     }
 }
 
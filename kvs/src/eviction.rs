@@ -0,0 +1,316 @@
+//! Bounds on the data a server keeps, and what to do when a write would go past them.
+//!
+//! The data of every namespace counts against the same [`Storage`]: the bytes of the keys and
+//! values, as the engines are given them. `max_memory` bounds it for the engines keeping their
+//! values in memory, `max_disk` for those keeping them in files; the log of the kvs engine may
+//! take more on disk for a while, until it is compacted. A write that would go past a limit first
+//! evicts keys of its namespace as the [`EvictionPolicy`] says, then fails with
+//! [`crate::Error::OutOfMemory`] when nothing can be evicted.
+//!
+//! ```
+//! use kvs::eviction::{EvictionPolicy, Storage, StorageLimits};
+//!
+//! let limits = StorageLimits {
+//!     max_memory: Some(64 * 1024 * 1024),
+//!     max_disk: None,
+//!     eviction_policy: EvictionPolicy::AllKeysLru,
+//! };
+//! // The in-memory engine keeps no files, so only `max_memory` applies to it
+//! let storage = Storage::new(&limits, true, false);
+//! assert_eq!(storage.limit(), Some(("memory", 64 * 1024 * 1024)));
+//! ```
+
+use crate::err::Result;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use snafu::whatever;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// What to evict when a write would go past a limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(try_from = "String")]
+pub enum EvictionPolicy {
+    /// Evict nothing: the write fails.
+    #[default]
+    #[serde(rename = "noeviction")]
+    NoEviction,
+    /// Evict the keys read or written the longest time ago.
+    #[serde(rename = "allkeys-lru")]
+    AllKeysLru,
+    /// Evict keys picked at random.
+    #[serde(rename = "random")]
+    Random,
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvictionPolicy::NoEviction => write!(f, "noeviction"),
+            EvictionPolicy::AllKeysLru => write!(f, "allkeys-lru"),
+            EvictionPolicy::Random => write!(f, "random"),
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = crate::Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "random" => Ok(EvictionPolicy::Random),
+            "volatile-ttl" => whatever!(
+                "Eviction policy volatile-ttl isn't supported: keys don't expire yet, so there \
+                 would be none to evict"
+            ),
+            _ => whatever!(
+                "Unknown eviction policy {}: expected noeviction, allkeys-lru or random",
+                name
+            ),
+        }
+    }
+}
+
+/// Config files are read as the command line is, so that they are refused the same way.
+impl TryFrom<String> for EvictionPolicy {
+    type Error = crate::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        name.parse()
+    }
+}
+
+/// The limits of a server, as set in the `[storage]` section of its config file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageLimits {
+    /// Bytes of keys and values kept in memory, for the engines keeping their values there.
+    pub max_memory: Option<u64>,
+    /// Bytes of keys and values kept in files, for the engines keeping their values there.
+    pub max_disk: Option<u64>,
+    pub eviction_policy: EvictionPolicy,
+}
+
+/// The bytes the namespaces of a server hold together, and the limit they are held to.
+#[derive(Debug, Default)]
+pub struct Storage {
+    limit: Option<(&'static str, u64)>,
+    policy: EvictionPolicy,
+    used: AtomicU64,
+}
+
+impl Storage {
+    /// Storage without a limit.
+    pub fn unbounded() -> Self {
+        Self::default()
+    }
+
+    /// The storage of an engine keeping its values in memory and/or in files, held to the
+    /// tightest of the `limits` that apply to it.
+    pub fn new(limits: &StorageLimits, in_memory: bool, on_disk: bool) -> Self {
+        let memory = limits
+            .max_memory
+            .filter(|_| in_memory)
+            .map(|max| ("memory", max));
+        let disk = limits.max_disk.filter(|_| on_disk).map(|max| ("disk", max));
+        let limit = match (memory, disk) {
+            (Some(memory), Some(disk)) => Some(if disk.1 < memory.1 { disk } else { memory }),
+            (memory, disk) => memory.or(disk),
+        };
+        Self {
+            limit,
+            policy: limits.eviction_policy,
+            used: AtomicU64::new(0),
+        }
+    }
+
+    /// What is limited, `memory` or `disk`, and the bytes allowed.
+    pub fn limit(&self) -> Option<(&'static str, u64)> {
+        self.limit
+    }
+
+    pub fn policy(&self) -> EvictionPolicy {
+        self.policy
+    }
+
+    /// Bytes held by all the namespaces.
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether keys have to be tracked to be evicted.
+    pub(crate) fn evicts(&self) -> bool {
+        self.limit.is_some()
+            && matches!(
+                self.policy,
+                EvictionPolicy::AllKeysLru | EvictionPolicy::Random
+            )
+    }
+
+    /// Whether `growth` more bytes would go past the limit. Namespaces check it on their own, so
+    /// writes running at the same time in several of them may go past it by as much as they add.
+    pub(crate) fn would_exceed(&self, growth: u64) -> bool {
+        match self.limit {
+            Some((_, max)) => self.used().saturating_add(growth) > max,
+            None => false,
+        }
+    }
+
+    /// Accounts for a namespace going from `before` to `after` bytes.
+    pub(crate) fn update(&self, before: u64, after: u64) {
+        if after > before {
+            self.used.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            // Never below 0, even if the namespaces got out of step
+            let _ = self
+                .used
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    Some(used.saturating_sub(before - after))
+                });
+        }
+    }
+}
+
+/// The keys of a namespace, from the least to the most recently used, to pick the ones to evict.
+#[derive(Debug, Default)]
+pub(crate) struct KeyTracker {
+    ticks: HashMap<String, u64>,
+    by_tick: BTreeMap<u64, String>,
+    next_tick: u64,
+}
+
+impl KeyTracker {
+    /// Marks `key` as just used.
+    pub(crate) fn touch(&mut self, key: &str) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        match self.ticks.get_mut(key) {
+            Some(previous) => {
+                self.by_tick.remove(previous);
+                *previous = tick;
+            }
+            None => {
+                self.ticks.insert(key.to_owned(), tick);
+            }
+        }
+        self.by_tick.insert(tick, key.to_owned());
+    }
+
+    pub(crate) fn forget(&mut self, key: &str) {
+        if let Some(tick) = self.ticks.remove(key) {
+            self.by_tick.remove(&tick);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ticks.clear();
+        self.by_tick.clear();
+    }
+
+    /// The key to evict under `policy`, other than those of `keep`.
+    pub(crate) fn victim(&self, policy: EvictionPolicy, keep: &[&str]) -> Option<String> {
+        let evictable = |key: &&String| !keep.contains(&key.as_str());
+        match policy {
+            EvictionPolicy::AllKeysLru => self.by_tick.values().find(evictable).cloned(),
+            EvictionPolicy::Random => {
+                let (first, _) = self.by_tick.first_key_value()?;
+                let (last, _) = self.by_tick.last_key_value()?;
+                let start = rand::thread_rng().gen_range(*first..=*last);
+                let candidates = self
+                    .by_tick
+                    .range(start..)
+                    .chain(self.by_tick.range(..start));
+                candidates.map(|(_, key)| key).find(evictable).cloned()
+            }
+            EvictionPolicy::NoEviction => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies() {
+        for policy in [
+            EvictionPolicy::NoEviction,
+            EvictionPolicy::AllKeysLru,
+            EvictionPolicy::Random,
+        ] {
+            assert_eq!(
+                policy.to_string().parse::<EvictionPolicy>().unwrap(),
+                policy
+            );
+            let json = serde_json::to_string(&policy).unwrap();
+            assert_eq!(json, format!("\"{}\"", policy));
+            assert_eq!(
+                serde_json::from_str::<EvictionPolicy>(&json).unwrap(),
+                policy
+            );
+        }
+        assert!("lru".parse::<EvictionPolicy>().is_err());
+        let err = "volatile-ttl".parse::<EvictionPolicy>().unwrap_err();
+        assert!(err.to_string().contains("keys don't expire"));
+        let err = serde_json::from_str::<EvictionPolicy>("\"volatile-ttl\"").unwrap_err();
+        assert!(err.to_string().contains("keys don't expire"));
+    }
+
+    #[test]
+    fn limits() {
+        let limits = StorageLimits {
+            max_memory: Some(100),
+            max_disk: Some(50),
+            eviction_policy: EvictionPolicy::Random,
+        };
+        assert_eq!(
+            Storage::new(&limits, true, false).limit(),
+            Some(("memory", 100))
+        );
+        assert_eq!(
+            Storage::new(&limits, false, true).limit(),
+            Some(("disk", 50))
+        );
+        assert_eq!(
+            Storage::new(&limits, true, true).limit(),
+            Some(("disk", 50))
+        );
+        assert!(!Storage::new(&StorageLimits::default(), true, true).evicts());
+
+        let storage = Storage::new(&limits, true, false);
+        assert!(storage.evicts());
+        storage.update(0, 90);
+        assert!(!storage.would_exceed(10));
+        assert!(storage.would_exceed(11));
+        storage.update(90, 30);
+        storage.update(200, 0);
+        assert_eq!(storage.used(), 0);
+    }
+
+    #[test]
+    fn victims() {
+        let mut tracker = KeyTracker::default();
+        for key in ["key1", "key2", "key3"] {
+            tracker.touch(key);
+        }
+        tracker.touch("key1");
+        let lru = EvictionPolicy::AllKeysLru;
+        assert_eq!(tracker.victim(lru, &[]).as_deref(), Some("key2"));
+        assert_eq!(tracker.victim(lru, &["key2"]).as_deref(), Some("key3"));
+        tracker.forget("key2");
+        assert_eq!(tracker.victim(lru, &[]).as_deref(), Some("key3"));
+        assert_eq!(tracker.victim(EvictionPolicy::NoEviction, &[]), None);
+
+        for _ in 0..10 {
+            let victim = tracker.victim(EvictionPolicy::Random, &["key1"]);
+            assert_eq!(victim.as_deref(), Some("key3"));
+        }
+        assert_eq!(tracker.victim(lru, &["key1", "key3"]), None);
+        tracker.clear();
+        assert_eq!(tracker.victim(EvictionPolicy::Random, &[]), None);
+    }
+}
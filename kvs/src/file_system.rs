@@ -0,0 +1,70 @@
+//! The file access of [`crate::KvStoreV2`], behind a trait so that the simulation harness can run
+//! the store on a simulated disk that fails on purpose.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// The few operations on files the store needs.
+pub trait FileSystem: Send + Sync + fmt::Debug {
+    fn exists(&self, path: &Path) -> bool;
+    /// Creates an empty file unless there is one already.
+    fn create(&self, path: &Path) -> io::Result<()>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Appends `bytes` to the file. When it fails, part of them may have been written.
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+    /// Replaces the content of the file, creating it if needed.
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+    /// Returns once what was written to the file is on the disk.
+    fn sync(&self, path: &Path) -> io::Result<()>;
+    /// Cuts the file down to its first `size` bytes.
+    fn truncate(&self, path: &Path, size: u64) -> io::Result<()>;
+    /// Puts the file at `from` in place of the one at `to`, in one step.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Bytes the file holds.
+    fn size(&self, path: &Path) -> io::Result<u64>;
+}
+
+/// The file system of the operating system.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OsFileSystem;
+
+impl FileSystem for OsFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create(&self, path: &Path) -> io::Result<()> {
+        OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        OpenOptions::new().append(true).open(path)?.write_all(bytes)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        fs::write(path, bytes)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        OpenOptions::new().write(true).open(path)?.sync_all()
+    }
+
+    fn truncate(&self, path: &Path, size: u64) -> io::Result<()> {
+        OpenOptions::new().write(true).open(path)?.set_len(size)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(fs::metadata(path)?.len())
+    }
+}
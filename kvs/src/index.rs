@@ -0,0 +1,698 @@
+//! Secondary indexes on JSON values, to look keys up by a field of their value.
+//!
+//! An index belongs to a collection, the keys starting with `<collection>:`, and is declared
+//! with a [`JsonPath`] into their values, e.g. `users.by_email = "$.email"` in the `[indexes]`
+//! section of the server config file. Values that aren't JSON, or don't hold a string, a number
+//! or a boolean at the path, or an array of them, aren't indexed.
+//!
+//! [`IndexedStore`] keeps the entries of its indexes as keys of the engine it wraps, starting
+//! with [`RESERVED_PREFIX`] and hidden from the commands, and writes them in the same
+//! [`KvsEngine::write_batch`] as the values they index. An index declared on a store already
+//! holding values is backfilled a batch of keys at a time, each batch recording how far the
+//! backfill got, so that it resumes from there when the store is opened again.
+//!
+//! ```
+//! use kvs::index::{IndexDef, IndexedStore};
+//! use kvs::{KvsEngine, MemStore};
+//!
+//! let index = IndexDef::new("users", "by_email", "$.email")?;
+//! let mut store = IndexedStore::open(MemStore::new(), vec![index])?;
+//! store.set("users:1".to_owned(), r#"{"email": "a@b.c"}"#.to_owned())?;
+//! assert!(store.backfill(1000)?);
+//! let found = store.find("users.by_email", "a@b.c")?;
+//! assert_eq!(found[0].0, "users:1");
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::engine::WriteOp;
+use crate::err::{Result, ResultExt};
+use crate::snapshot::Snapshot;
+use crate::value::TYPE_TAG;
+use crate::watch::{KeyPattern, Position, Watcher};
+use crate::{EngineStats, KvsEngine, ScanIter};
+use serde::{Deserialize, Serialize};
+use snafu::whatever;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+/// The character starting the keys kept for indexes. Keys starting with it can't be written by
+/// commands, and scans and watches skip them.
+pub const RESERVED_PREFIX: char = '\0';
+
+/// The key recording the indexes of a store and how far their backfill got.
+const CATALOG_KEY: &str = "\0indexes";
+
+/// The start of the keys of index entries, which go on with the name of the index, the indexed
+/// value and the key holding it, separated by [`RESERVED_PREFIX`].
+const ENTRY_PREFIX: &str = "\0index\0";
+
+/// Keys removed at once when dropping the entries of an index.
+const DROP_BATCH: usize = 1000;
+
+/// Whether `key` is kept for indexes.
+pub fn is_reserved(key: &str) -> bool {
+    key.starts_with(RESERVED_PREFIX)
+}
+
+/// The prefix of the keys an index named `<collection>.<name>` covers, if `index` is named so.
+pub fn collection_prefix(index: &str) -> Option<String> {
+    let (collection, _) = index.split_once('.')?;
+    Some(format!("{}:", collection))
+}
+
+/// The indexes of a server, as declared in its config file: indexes by collection, e.g.
+/// `users.by_email = "$.email"` within `[indexes]`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct IndexConfig(BTreeMap<String, BTreeMap<String, String>>);
+
+impl IndexConfig {
+    /// The declared indexes, failing on an invalid name or path.
+    pub fn definitions(&self) -> Result<Vec<IndexDef>> {
+        let mut definitions = Vec::new();
+        for (collection, indexes) in &self.0 {
+            for (name, path) in indexes {
+                definitions.push(IndexDef::new(collection, name, path)?);
+            }
+        }
+        Ok(definitions)
+    }
+}
+
+/// A path into a JSON value: `$` followed by `.field` and `[index]` steps, e.g.
+/// `$.addresses[0].city`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    source: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Step {
+    Field(String),
+    Index(usize),
+}
+
+impl FromStr for JsonPath {
+    type Err = crate::Error;
+
+    fn from_str(source: &str) -> Result<Self> {
+        let Some(mut rest) = source.strip_prefix('$') else {
+            whatever!("JSON path {} doesn't start with $", source);
+        };
+        let mut steps = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    whatever!("JSON path {} has an empty field name", source);
+                }
+                steps.push(Step::Field(after[..end].to_owned()));
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let Some((index, after)) = after.split_once(']') else {
+                    whatever!("JSON path {} has an unclosed [", source);
+                };
+                let Ok(index) = index.parse() else {
+                    whatever!("JSON path {} has an invalid index {}", source, index);
+                };
+                steps.push(Step::Index(index));
+                rest = after;
+            } else {
+                whatever!("JSON path {} has an unexpected {}", source, rest);
+            }
+        }
+        Ok(JsonPath {
+            source: source.to_owned(),
+            steps,
+        })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl JsonPath {
+    fn select<'a>(&self, value: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
+        self.steps.iter().try_fold(value, |value, step| match step {
+            Step::Field(field) => value.get(field),
+            Step::Index(index) => value.get(index),
+        })
+    }
+}
+
+/// An index of the keys of a collection by the value at a path of their JSON values.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexDef {
+    name: String,
+    prefix: String,
+    path: JsonPath,
+}
+
+impl IndexDef {
+    /// The index `name` of the keys under `<collection>:`, by the value at `path`.
+    pub fn new(collection: &str, name: &str, path: &str) -> Result<Self> {
+        for part in [collection, name] {
+            if part.is_empty() || part.contains(['.', RESERVED_PREFIX]) {
+                whatever!("Invalid index name {}.{}", collection, name);
+            }
+        }
+        Ok(IndexDef {
+            name: format!("{}.{}", collection, name),
+            prefix: format!("{}:", collection),
+            path: path.parse()?,
+        })
+    }
+
+    /// The name commands find keys with, `<collection>.<name>`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &JsonPath {
+        &self.path
+    }
+
+    fn covers(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix)
+    }
+
+    /// The values `stored` is indexed under. Numbers and booleans are indexed as their JSON, and
+    /// every element of an array on its own. Values holding [`RESERVED_PREFIX`] aren't indexed,
+    /// as it separates the parts of the entry keys.
+    fn values(&self, stored: Option<&str>) -> BTreeSet<String> {
+        let Some(stored) = stored.filter(|stored| !stored.starts_with(TYPE_TAG)) else {
+            return BTreeSet::new();
+        };
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(stored) else {
+            return BTreeSet::new();
+        };
+        let scalars = match self.path.select(&json) {
+            Some(serde_json::Value::Array(elements)) => elements.iter().collect(),
+            Some(value) => vec![value],
+            None => Vec::new(),
+        };
+        scalars
+            .into_iter()
+            .filter_map(|scalar| match scalar {
+                serde_json::Value::String(value) => Some(value.clone()),
+                serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+                    Some(scalar.to_string())
+                }
+                _ => None,
+            })
+            .filter(|value| !value.contains(RESERVED_PREFIX))
+            .collect()
+    }
+}
+
+fn index_prefix(index: &str) -> String {
+    format!("{}{}{}", ENTRY_PREFIX, index, RESERVED_PREFIX)
+}
+
+fn entries_prefix(index: &str, value: &str) -> String {
+    format!("{}{}{}", index_prefix(index), value, RESERVED_PREFIX)
+}
+
+fn entry_key(index: &str, value: &str, key: &str) -> String {
+    format!("{}{}", entries_prefix(index, value), key)
+}
+
+/// Whether an index can be used yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Status {
+    /// Backfilling the keys of the collection that were there before the index, up to `after`.
+    Building {
+        after: Option<String>,
+    },
+    Ready,
+}
+
+/// An index as recorded under [`CATALOG_KEY`].
+#[derive(Debug, Serialize, Deserialize)]
+struct Stored {
+    path: String,
+    #[serde(flatten)]
+    status: Status,
+}
+
+/// An engine keeping secondary indexes of the values of another one, see the [module
+/// docs](self).
+pub struct IndexedStore<E> {
+    store: E,
+    indexes: BTreeMap<String, (IndexDef, Status)>,
+}
+
+impl<E: KvsEngine> IndexedStore<E> {
+    /// Wraps `store` with the indexes of `definitions`. The entries of the indexes it kept that
+    /// are no longer defined, or are defined on another path, are removed; new indexes start
+    /// being built, which [`IndexedStore::backfill`] goes on with.
+    pub fn open(mut store: E, definitions: Vec<IndexDef>) -> Result<Self> {
+        let catalog: BTreeMap<String, Stored> = match store.get(CATALOG_KEY.to_owned())? {
+            Some(json) => serde_json::from_str(&json)
+                .with_whatever_context(|_| format!("Invalid index catalog {}", json))?,
+            None => BTreeMap::new(),
+        };
+        let mut indexes = BTreeMap::new();
+        let mut changed = false;
+        for (name, stored) in catalog {
+            match definitions
+                .iter()
+                .find(|definition| definition.name == name)
+            {
+                Some(definition) if definition.path.source == stored.path => {
+                    indexes.insert(name, (definition.clone(), stored.status));
+                }
+                _ => {
+                    drop_entries(&mut store, &name)?;
+                    changed = true;
+                }
+            }
+        }
+        for definition in definitions {
+            if !indexes.contains_key(&definition.name) {
+                let status = Status::Building { after: None };
+                indexes.insert(definition.name.clone(), (definition, status));
+                changed = true;
+            }
+        }
+
+        let mut store = IndexedStore { store, indexes };
+        if changed {
+            let catalog = store.catalog()?;
+            store.store.write_batch(vec![catalog])?;
+        }
+        Ok(store)
+    }
+
+    /// Whether every index is built, so that it can be searched.
+    pub fn is_built(&self) -> bool {
+        self.indexes
+            .values()
+            .all(|(_, status)| *status == Status::Ready)
+    }
+
+    /// Indexes the next `limit` keys of an index being built, along with recording how far it
+    /// got. Returns whether every index is built.
+    pub fn backfill(&mut self, limit: usize) -> Result<bool> {
+        let limit = limit.max(1);
+        let building = self
+            .indexes
+            .iter()
+            .find_map(|(name, (definition, status))| match status {
+                Status::Building { after } => {
+                    Some((name.clone(), definition.clone(), after.clone()))
+                }
+                Status::Ready => None,
+            });
+        let Some((name, definition, after)) = building else {
+            return Ok(true);
+        };
+
+        let pairs = self
+            .store
+            .scan(definition.prefix.clone())?
+            .filter(|pair| match (pair, &after) {
+                (Ok((key, _)), Some(after)) => key > after,
+                _ => true,
+            })
+            .take(limit)
+            .collect::<Result<Vec<_>>>()?;
+        let mut batch = Vec::new();
+        for (key, value) in &pairs {
+            for value in definition.values(Some(value)) {
+                batch.push(WriteOp::Set {
+                    key: entry_key(&name, &value, key),
+                    value: String::new(),
+                });
+            }
+        }
+        let status = match pairs.last() {
+            Some((key, _)) if pairs.len() == limit => Status::Building {
+                after: Some(key.clone()),
+            },
+            _ => Status::Ready,
+        };
+
+        let (_, current) = self.indexes.get_mut(&name).expect("index being built");
+        let previous = std::mem::replace(current, status);
+        let result = self.catalog().and_then(|catalog| {
+            batch.push(catalog);
+            self.store.write_batch(batch)
+        });
+        if let Err(err) = result {
+            self.indexes.get_mut(&name).expect("index being built").1 = previous;
+            return Err(err);
+        }
+        Ok(self.is_built())
+    }
+
+    /// The write recording the indexes and their status.
+    fn catalog(&self) -> Result<WriteOp> {
+        let catalog: BTreeMap<&String, Stored> = self
+            .indexes
+            .iter()
+            .map(|(name, (definition, status))| {
+                let stored = Stored {
+                    path: definition.path.source.clone(),
+                    status: status.clone(),
+                };
+                (name, stored)
+            })
+            .collect();
+        let value = serde_json::to_string(&catalog)
+            .with_whatever_context(|_| "Couldn't serialize the index catalog")?;
+        Ok(WriteOp::Set {
+            key: CATALOG_KEY.to_owned(),
+            value,
+        })
+    }
+
+    fn is_indexed(&self, key: &str) -> bool {
+        self.indexes
+            .values()
+            .any(|(definition, _)| definition.covers(key))
+    }
+
+    /// `batch` along with the writes of the index entries it changes.
+    fn with_entries(&self, batch: Vec<WriteOp>) -> Result<Vec<WriteOp>> {
+        let mut writes = Vec::new();
+        // The values of the keys written earlier in the batch
+        let mut pending: HashMap<String, Option<String>> = HashMap::new();
+        for op in batch {
+            let key = op.key();
+            check_key(key)?;
+            if self.is_indexed(key) {
+                let before = match pending.get(key) {
+                    Some(before) => before.clone(),
+                    None => self.store.get(key.to_owned())?,
+                };
+                for (name, (definition, _)) in &self.indexes {
+                    if !definition.covers(key) {
+                        continue;
+                    }
+                    let old = definition.values(before.as_deref());
+                    let new = definition.values(op.value());
+                    for value in old.difference(&new) {
+                        writes.push(WriteOp::Rm {
+                            key: entry_key(name, value, key),
+                        });
+                    }
+                    for value in new.difference(&old) {
+                        writes.push(WriteOp::Set {
+                            key: entry_key(name, value, key),
+                            value: String::new(),
+                        });
+                    }
+                }
+                pending.insert(key.to_owned(), op.value().map(str::to_owned));
+            }
+            writes.push(op);
+        }
+        Ok(writes)
+    }
+}
+
+/// Refuses the keys kept for indexes.
+fn check_key(key: &str) -> Result<()> {
+    if is_reserved(key) {
+        whatever!("Keys starting with a NUL character are kept for indexes");
+    }
+    Ok(())
+}
+
+/// Removes the entries of the index `name` from `store`.
+fn drop_entries(store: &mut impl KvsEngine, name: &str) -> Result<()> {
+    let keys = store
+        .scan(index_prefix(name))?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    for keys in keys.chunks(DROP_BATCH) {
+        let batch = keys
+            .iter()
+            .map(|key| WriteOp::Rm { key: key.clone() })
+            .collect();
+        store.write_batch(batch)?;
+    }
+    Ok(())
+}
+
+impl<E: KvsEngine> KvsEngine for IndexedStore<E> {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.write_batch(vec![WriteOp::Set { key, value }])
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if is_reserved(&key) {
+            return Ok(None);
+        }
+        self.store.get(key)
+    }
+
+    fn remove(&mut self, key: String) -> Result<Option<String>> {
+        check_key(&key)?;
+        if !self.is_indexed(&key) {
+            return self.store.remove(key);
+        }
+        let Some(value) = self.store.get(key.clone())? else {
+            return Ok(None);
+        };
+        self.write_batch(vec![WriteOp::Rm { key }])?;
+        Ok(Some(value))
+    }
+
+    /// Writes that change no index entry go to the engine as they are.
+    fn write_batch(&mut self, batch: Vec<WriteOp>) -> Result<()> {
+        let mut writes = self.with_entries(batch)?;
+        if writes.len() > 1 {
+            return self.store.write_batch(writes);
+        }
+        match writes.pop() {
+            Some(WriteOp::Set { key, value }) => self.store.set(key, value),
+            Some(WriteOp::Rm { key }) => self.store.remove(key).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    fn scan(&self, prefix: String) -> Result<ScanIter<'_>> {
+        Ok(Box::new(self.store.scan(prefix)?.filter(
+            |pair| !matches!(pair, Ok((key, _)) if is_reserved(key)),
+        )))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.store.stats()
+    }
+
+    fn watch(&self, pattern: KeyPattern, after: Option<Position>) -> Result<Watcher> {
+        self.store.watch(pattern, after)
+    }
+
+    /// Index entries are kept as keys of the store, so they are in its snapshots too.
+    fn snapshot(&self) -> Option<Snapshot> {
+        self.store.snapshot()
+    }
+
+    fn find(&self, index: &str, value: &str) -> Result<Vec<(String, String)>> {
+        let Some((_, status)) = self.indexes.get(index) else {
+            whatever!("Index {} doesn't exist", index);
+        };
+        if let Status::Building { after } = status {
+            match after {
+                Some(after) => whatever!("Index {} is still being built, up to {}", index, after),
+                None => whatever!("Index {} is still being built", index),
+            }
+        }
+        if value.contains(RESERVED_PREFIX) {
+            return Ok(Vec::new());
+        }
+        let prefix = entries_prefix(index, value);
+        let mut pairs = Vec::new();
+        for entry in self.store.scan(prefix.clone())? {
+            let (entry, _) = entry?;
+            let key = &entry[prefix.len()..];
+            if let Some(value) = self.store.get(key.to_owned())? {
+                pairs.push((key.to_owned(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn name(&self) -> &'static str {
+        self.store.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStoreV2, MemStore};
+
+    fn by_email() -> IndexDef {
+        IndexDef::new("users", "by_email", "$.email").unwrap()
+    }
+
+    fn keys(pairs: Vec<(String, String)>) -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn path() {
+        let path: JsonPath = "$.addresses[1].city".parse().unwrap();
+        assert_eq!(path.to_string(), "$.addresses[1].city");
+        let json = serde_json::json!({"addresses": [{"city": "Lyon"}, {"city": "Paris"}]});
+        assert_eq!(path.select(&json), Some(&serde_json::json!("Paris")));
+        assert_eq!("$".parse::<JsonPath>().unwrap().select(&json), Some(&json));
+        for invalid in ["email", "$.", "$..email", "$[x]", "$[1", "$email"] {
+            assert!(invalid.parse::<JsonPath>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn definition() {
+        let index = by_email();
+        assert_eq!(index.name(), "users.by_email");
+        assert!(index.covers("users:1"));
+        assert!(!index.covers("users1"));
+        assert!(IndexDef::new("users", "", "$.email").is_err());
+        assert!(IndexDef::new("my.users", "by_email", "$.email").is_err());
+        assert_eq!(
+            collection_prefix("users.by_email"),
+            Some("users:".to_owned())
+        );
+        assert_eq!(collection_prefix("users"), None);
+    }
+
+    #[test]
+    fn values() {
+        let index = IndexDef::new("users", "by_tag", "$.tags").unwrap();
+        let values = |stored: &str| index.values(Some(stored)).into_iter().collect::<Vec<_>>();
+        assert_eq!(values(r#"{"tags": ["b", "a", "b"]}"#), ["a", "b"]);
+        assert_eq!(values(r#"{"tags": 12}"#), ["12"]);
+        assert_eq!(values(r#"{"tags": [true, null, {}]}"#), ["true"]);
+        assert!(values(r#"{"tags": "a\u0000b"}"#).is_empty());
+        assert!(values(r#"{"other": "a"}"#).is_empty());
+        assert!(values("not json").is_empty());
+        assert!(index.values(None).is_empty());
+    }
+
+    #[test]
+    fn maintained() -> Result<()> {
+        let mut store = IndexedStore::open(MemStore::new(), vec![by_email()])?;
+        assert!(store.backfill(10)?);
+        store.set("users:1".to_owned(), r#"{"email": "a@b.c"}"#.to_owned())?;
+        store.set("users:2".to_owned(), r#"{"email": "a@b.c"}"#.to_owned())?;
+        store.set("orders:1".to_owned(), r#"{"email": "a@b.c"}"#.to_owned())?;
+        assert_eq!(
+            keys(store.find("users.by_email", "a@b.c")?),
+            ["users:1", "users:2"]
+        );
+
+        store.set("users:1".to_owned(), r#"{"email": "d@e.f"}"#.to_owned())?;
+        store.remove("users:2".to_owned())?;
+        assert!(store.find("users.by_email", "a@b.c")?.is_empty());
+        assert_eq!(
+            store.find("users.by_email", "d@e.f")?,
+            vec![("users:1".to_owned(), r#"{"email": "d@e.f"}"#.to_owned())]
+        );
+
+        // A batch sees the writes before it
+        store.write_batch(vec![
+            WriteOp::Set {
+                key: "users:3".to_owned(),
+                value: r#"{"email": "g@h.i"}"#.to_owned(),
+            },
+            WriteOp::Set {
+                key: "users:3".to_owned(),
+                value: r#"{"email": "j@k.l"}"#.to_owned(),
+            },
+        ])?;
+        assert!(store.find("users.by_email", "g@h.i")?.is_empty());
+        assert_eq!(keys(store.find("users.by_email", "j@k.l")?), ["users:3"]);
+        assert!(store.find("users.by_name", "a").is_err());
+
+        // The entries are hidden and out of reach of the commands
+        assert_eq!(
+            keys(store.scan(String::new())?.collect::<Result<_>>()?),
+            ["orders:1", "users:1", "users:3"]
+        );
+        assert_eq!(store.get(CATALOG_KEY.to_owned())?, None);
+        assert!(store.set("\0key".to_owned(), "value".to_owned()).is_err());
+        assert!(store.remove(CATALOG_KEY.to_owned()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn backfill() -> Result<()> {
+        let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+        let mut store = KvStoreV2::open(temp_dir.path())?;
+        for i in 0..5 {
+            store.set(
+                format!("users:{}", i),
+                format!(r#"{{"email": "{}@b.c"}}"#, i % 2),
+            )?;
+        }
+
+        let mut store = IndexedStore::open(store, vec![by_email()])?;
+        assert!(!store.is_built());
+        let err = store.find("users.by_email", "0@b.c").unwrap_err();
+        assert_eq!(err.to_string(), "Index users.by_email is still being built");
+        assert!(!store.backfill(2)?);
+        assert!(!store.backfill(2)?);
+        let err = store.find("users.by_email", "0@b.c").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Index users.by_email is still being built, up to users:3"
+        );
+        // Written while building, behind the cursor
+        store.set("users:0".to_owned(), r#"{"email": "2@b.c"}"#.to_owned())?;
+        drop(store);
+
+        // Goes on from the cursor it recorded
+        let store = KvStoreV2::open(temp_dir.path())?;
+        let mut store = IndexedStore::open(store, vec![by_email()])?;
+        assert!(store.backfill(2)?);
+        assert_eq!(
+            keys(store.find("users.by_email", "0@b.c")?),
+            ["users:2", "users:4"]
+        );
+        assert_eq!(
+            keys(store.find("users.by_email", "1@b.c")?),
+            ["users:1", "users:3"]
+        );
+        assert_eq!(keys(store.find("users.by_email", "2@b.c")?), ["users:0"]);
+        Ok(())
+    }
+
+    #[test]
+    fn dropped() -> Result<()> {
+        let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+        let store = KvStoreV2::open(temp_dir.path())?;
+        let mut store = IndexedStore::open(store, vec![by_email()])?;
+        store.set(
+            "users:1".to_owned(),
+            r#"{"email": "a@b.c", "name": "a"}"#.to_owned(),
+        )?;
+        drop(store);
+
+        // Changing the path of an index builds it again
+        let by_name = IndexDef::new("users", "by_email", "$.name")?;
+        let mut store = IndexedStore::open(KvStoreV2::open(temp_dir.path())?, vec![by_name])?;
+        assert!(store.backfill(10)?);
+        assert!(store.find("users.by_email", "a@b.c")?.is_empty());
+        assert_eq!(keys(store.find("users.by_email", "a")?), ["users:1"]);
+        drop(store);
+
+        let store = IndexedStore::open(KvStoreV2::open(temp_dir.path())?, Vec::new())?;
+        assert!(store.is_built());
+        let store = store.store;
+        let entries = store.scan(ENTRY_PREFIX.to_owned())?.count();
+        assert_eq!(entries, 0);
+        Ok(())
+    }
+}
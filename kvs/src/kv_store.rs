@@ -1,33 +1,120 @@
-use crate::engine::KvsEngine;
+use crate::compression::Compression;
+use crate::encryption::Keyring;
+use crate::engine::{EngineStats, KvsEngine, ScanIter, WriteOp};
 use crate::err::{Result, ResultExt};
+use crate::file_system::{FileSystem, OsFileSystem};
+use crate::value::WrongType;
+use crate::watch::{Change, ChangeFeed, KeyPattern, Position, Watcher};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 use snafu::whatever;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::fs;
-use std::fs::{read_to_string, OpenOptions};
-use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub const DEFAULT_FILE_NAME: &str = "kvs.db";
 
-/// Make sure that the file exists and is writable.
-pub fn initialize(file_path: &Path) -> Result<()> {
-    if !file_path.exists() {
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(file_path)
-            .with_whatever_context(|_| format!("Couldn't open file at {}", file_path.display()))?;
-    }
-    Ok(())
-}
-
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Command {
-    Get { key: String },
-    Set { key: String, value: String },
-    Rm { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Rm {
+        key: String,
+    },
+    /// Lists the pairs whose key starts with `prefix`, in key order.
+    Scan {
+        prefix: String,
+    },
+    /// Takes a snapshot of the whole store as a backup archive.
+    Backup,
+    /// Loads the pairs of a verified backup archive into an empty store.
+    Restore {
+        pairs: Vec<(String, String)>,
+    },
+    /// Pushes `values` one after the other at the front of a list.
+    LPush {
+        key: String,
+        values: Vec<String>,
+    },
+    /// Lists the elements of a list from `start` to `stop` included, see
+    /// [`crate::value::list_range`].
+    LRange {
+        key: String,
+        start: i64,
+        stop: i64,
+    },
+    HSet {
+        key: String,
+        field: String,
+        value: String,
+    },
+    HGet {
+        key: String,
+        field: String,
+    },
+    SAdd {
+        key: String,
+        members: Vec<String>,
+    },
+    /// Lists the members of a set, in order.
+    SMembers {
+        key: String,
+    },
+    /// Adds `delta` to the integer held by a string, a missing key counting as 0.
+    IncrBy {
+        key: String,
+        delta: i64,
+    },
+    /// Lists the pairs whose value `index` indexes under `value`, in key order.
+    Find {
+        index: String,
+        value: String,
+    },
+    /// Runs a Lua script, which may read and write the `keys` it declares, see [`crate::script`].
+    Eval {
+        script: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    },
+    /// Runs the script cached under `hash` by an earlier `Eval`.
+    EvalSha {
+        hash: String,
+        keys: Vec<String>,
+        args: Vec<String>,
+    },
+}
+
+impl Command {
+    /// The lowercase name of the command, as used in logs and metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get { .. } => "get",
+            Command::Set { .. } => "set",
+            Command::Rm { .. } => "rm",
+            Command::Scan { .. } => "scan",
+            Command::Backup => "backup",
+            Command::Restore { .. } => "restore",
+            Command::LPush { .. } => "lpush",
+            Command::LRange { .. } => "lrange",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::SAdd { .. } => "sadd",
+            Command::SMembers { .. } => "smembers",
+            Command::IncrBy { .. } => "incrby",
+            Command::Find { .. } => "find",
+            Command::Eval { .. } => "eval",
+            Command::EvalSha { .. } => "evalsha",
+        }
+    }
 }
 
 // TODO: move `Command` and `CommandResponse` to a more correct place
@@ -36,26 +123,185 @@ pub enum CommandResponse {
     Get { value: Option<String> },
     Set,
     Rm { value: Option<String> },
+    Scan { pairs: Vec<(String, String)> },
+    Backup { archive: Vec<u8> },
+    Restore { count: usize },
+    /// The length of the list once pushed to.
+    LPush { len: usize },
+    LRange { values: Vec<String> },
+    /// Whether the field is new to the hash.
+    HSet { created: bool },
+    HGet { value: Option<String> },
+    /// How many of the members weren't in the set yet.
+    SAdd { added: usize },
+    SMembers { members: Vec<String> },
+    IncrBy { value: i64 },
+    Find { pairs: Vec<(String, String)> },
+    /// What the script returned, as JSON.
+    Eval { result: serde_json::Value },
+    /// The key holds a value of another type than the command works on.
+    WrongType(WrongType),
+}
+
+/// A line of the log: a command, and how the value of a `Set` is stored. Compressed values are
+/// stored as the base64 of the compressed bytes, and the lines of values stored as they are don't
+/// mention compression at all, so that logs written before compression existed still read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Record {
+    #[serde(flatten)]
+    command: Command,
+    #[serde(default, skip_serializing_if = "Compression::is_none")]
+    compression: Compression,
+}
+
+impl Record {
+    /// Compresses the value of a `Set` with `compression`, unless that doesn't make it smaller.
+    fn encode(command: Command, compression: Compression) -> Result<Self> {
+        if let Command::Set { key, value } = &command {
+            if let Some(compressed) = compression.compress_if_smaller(value.as_bytes())? {
+                // base64 takes a third more room, which may undo the savings
+                let stored = BASE64.encode(compressed);
+                if stored.len() < value.len() {
+                    return Ok(Record {
+                        command: Command::Set {
+                            key: key.clone(),
+                            value: stored,
+                        },
+                        compression,
+                    });
+                }
+            }
+        }
+        Ok(Record {
+            command,
+            compression: Compression::None,
+        })
+    }
+
+    /// The command with its value as it was before being stored.
+    fn decode(self) -> Result<Command> {
+        match self.command {
+            Command::Set { key, value } if !self.compression.is_none() => {
+                let compressed = BASE64.decode(&value).with_whatever_context(|_| {
+                    format!("Couldn't decode the stored value of {}", key)
+                })?;
+                let value = String::from_utf8(self.compression.decompress(&compressed)?)
+                    .with_whatever_context(|_| {
+                        format!("Decompressed value of {} isn't valid UTF-8", key)
+                    })?;
+                Ok(Command::Set { key, value })
+            }
+            command => Ok(command),
+        }
+    }
+}
+
+/// A line of the log holding the records of a [`KvsEngine::write_batch`], so that a batch cut off
+/// by a crash is read as a cut-off line rather than as some of its records.
+#[derive(Debug, Serialize, Deserialize)]
+struct Batch<'a> {
+    batch: Cow<'a, [Record]>,
 }
 
-pub fn deserialize_commands(text: &String) -> Result<Vec<Command>> {
+/// The records of the JSON of a line of the log: a single record, or those of a batch.
+fn parse_line(line: &str) -> Result<Vec<Record>> {
+    if let Ok(Batch { batch }) = serde_json::from_str(line) {
+        return Ok(batch.into_owned());
+    }
+    let record = serde_json::from_str(line)
+        .with_whatever_context(|_| format!("Couldn't deserialize command {}", line))?;
+    Ok(vec![record])
+}
+
+/// A line of the log encrypted with the keys of a [`Keyring`]: the base64 of the sealed JSON of
+/// the record, whose value was compressed before being encrypted.
+#[derive(Debug, Serialize, Deserialize)]
+struct Sealed {
+    encrypted: String,
+}
+
+impl Sealed {
+    fn seal(record: &str, keys: &Keyring) -> Result<String> {
+        let sealed = Sealed {
+            encrypted: BASE64.encode(keys.encrypt(record.as_bytes())?),
+        };
+        serde_json::to_string(&sealed)
+            .with_whatever_context(|_| "Couldn't serialize encrypted record")
+    }
+
+    fn decode(&self) -> Result<Vec<u8>> {
+        BASE64.decode(&self.encrypted).with_whatever_context(|_| {
+            format!("Couldn't decode encrypted record {}", self.encrypted)
+        })
+    }
+}
+
+/// Reads the JSON of the record in a line of the log, decrypting it with `keys` if it was
+/// encrypted. Lines that weren't are returned as they are.
+pub fn decrypt_log_line(line: &str, keys: &Keyring) -> Result<String> {
+    Ok(open_line(line, keys)?.0)
+}
+
+/// The JSON of the record in `line`, and whether it is stored as the current key would store it:
+/// encrypted with that key, or not encrypted when there is none.
+fn open_line(line: &str, keys: &Keyring) -> Result<(String, bool)> {
+    let Ok(sealed) = serde_json::from_str::<Sealed>(line) else {
+        return Ok((line.to_owned(), !keys.is_enabled()));
+    };
+    let sealed = sealed.decode()?;
+    let record = String::from_utf8(keys.decrypt(&sealed)?)
+        .with_whatever_context(|_| "Decrypted record isn't valid UTF-8")?;
+    Ok((record, keys.is_current(&sealed)))
+}
+
+/// Reads a line of the log into the commands it records, their values decompressed: one, or
+/// those of a batch. Unlike opening the log, it doesn't refuse the commands that don't belong
+/// there.
+pub fn decode_log_line(line: &str, keys: &Keyring) -> Result<Vec<Command>> {
+    let line = decrypt_log_line(line, keys)?;
+    parse_line(&line)?.into_iter().map(Record::decode).collect()
+}
+
+/// Only `Set` and `Rm` make it to the log.
+fn check_logged(command: &Command, action: &str) -> Result<()> {
+    match command {
+        Command::Set { .. } | Command::Rm { .. } => Ok(()),
+        Command::Get { key: _ } => whatever!("Get command should not be {}", action),
+        _ => whatever!("{:?} command should not be {}", command, action),
+    }
+}
+
+/// The records of the log, and whether any of them should be written again: because it was
+/// encrypted with an old key, or isn't encrypted the way `keys` would encrypt it.
+fn deserialize_records(text: &str, keys: &Keyring) -> Result<(Vec<Record>, bool)> {
     let lines = text.trim().lines();
-    let mut commands = Vec::new();
+    let mut records = Vec::new();
+    let mut stale = false;
 
     for line in lines {
         let line = line.trim();
         if line.is_empty() {
             continue;
         }
-        let command: Command = serde_json::from_str(line)
-            .with_whatever_context(|_| format!("Couldn't deserialize command {}", line))?;
-        if let Command::Get { key: _ } = command {
-            whatever!("Get command should not be deserialized");
+        let (line, current) = open_line(line, keys)?;
+        stale |= !current;
+        for record in parse_line(&line)? {
+            check_logged(&record.command, "deserialized")?;
+            records.push(record);
         }
-        commands.push(command);
     }
 
-    Ok(commands)
+    Ok((records, stale))
+}
+
+/// Replays the text of a log into the commands it records, their values decompressed, as opening
+/// the store does. Fails on any line that isn't a record of the log, without panicking.
+pub fn deserialize_commands(text: &str, keys: &Keyring) -> Result<Vec<Command>> {
+    deserialize_records(text, keys)?
+        .0
+        .into_iter()
+        .map(Record::decode)
+        .collect()
 }
 
 pub fn apply_command(command: &Command, map: &mut HashMap<String, String>) -> Result<()> {
@@ -73,34 +319,96 @@ pub fn apply_command(command: &Command, map: &mut HashMap<String, String>) -> Re
 }
 
 pub fn serialize_command(command: &Command) -> Result<String> {
-    if let Command::Get { key: _ } = command {
-        whatever!("Get command should not be serialized");
-    }
+    check_logged(command, "serialized")?;
     let command_str = serde_json::to_string(command)
         .with_whatever_context(|_| format!("Couldn't serialize command {:?}", command))?;
     Ok(command_str)
 }
 
-pub fn serialize_commands(commands: &[Command]) -> Result<String> {
+fn serialize_record(record: &Record) -> Result<String> {
+    if record.compression.is_none() {
+        return serialize_command(&record.command);
+    }
+    check_logged(&record.command, "serialized")?;
+    serde_json::to_string(record)
+        .with_whatever_context(|_| format!("Couldn't serialize record {:?}", record))
+}
+
+/// Serializes a record into a line of the log, encrypted with the current key of `keys` if any.
+fn serialize_line(record: &Record, keys: &Keyring) -> Result<String> {
+    let line = serialize_record(record)?;
+    if keys.is_enabled() {
+        Sealed::seal(&line, keys)
+    } else {
+        Ok(line)
+    }
+}
+
+/// Serializes the records of a batch into a single line of the log, encrypted like any other.
+fn serialize_batch(records: &[Record], keys: &Keyring) -> Result<String> {
+    for record in records {
+        check_logged(&record.command, "serialized")?;
+    }
+    let batch = Batch {
+        batch: Cow::Borrowed(records),
+    };
+    let line = serde_json::to_string(&batch)
+        .with_whatever_context(|_| format!("Couldn't serialize batch {:?}", batch))?;
+    if keys.is_enabled() {
+        Sealed::seal(&line, keys)
+    } else {
+        Ok(line)
+    }
+}
+
+fn serialize_records(records: &[Record], keys: &Keyring) -> Result<String> {
     let mut output = String::new();
-    for command in commands {
-        let command_str = serialize_command(command)?;
-        output.push_str(&command_str);
+    for record in records {
+        output.push_str(&serialize_line(record, keys)?);
         output.push('\n');
     }
 
     Ok(output)
 }
 
-pub fn append_command(command: Command, file_path: &PathBuf) -> Result<()> {
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(file_path)
+fn append_record(
+    fs: &dyn FileSystem,
+    record: &Record,
+    keys: &Keyring,
+    file_path: &Path,
+) -> Result<()> {
+    append_line(fs, &serialize_line(record, keys)?, file_path)
+}
+
+fn append_line(fs: &dyn FileSystem, line: &str, file_path: &Path) -> Result<()> {
+    let size = fs
+        .size(file_path)
         .with_whatever_context(|_| format!("Couldn't open file at {}", file_path.display()))?;
-    let line = serialize_command(&command)?;
+    let appended = fs.append(file_path, format!("{}\n", line).as_bytes());
+    if appended.is_err() {
+        // Part of the line may have made it, which the next line would be appended to, making
+        // the log unreadable
+        let _ = fs.truncate(file_path, size);
+    }
+    appended.with_whatever_context(|_| format!("Couldn't write command as new line: {}", line))
+}
 
-    writeln!(file, "{}", line)
-        .with_whatever_context(|_| format!("Couldn't write command as new line: {}", line))
+/// The log without its last line when that line was cut short by a crash, and whether the log
+/// should be written again for it. Every line is written with its newline, so an unterminated
+/// last line never got written completely, and its write never succeeded. It is kept if it reads
+/// nonetheless, rewritten with its newline so that the next line doesn't end up on it. Anything
+/// else that doesn't read still fails the opening.
+fn without_torn_line<'a>(text: &'a str, keys: &Keyring) -> (&'a str, bool) {
+    let start = text.rfind('\n').map_or(0, |index| index + 1);
+    let last = text[start..].trim();
+    // Records, encrypted or not, are JSON objects
+    if !last.starts_with('{') {
+        return (text, false);
+    }
+    match open_line(last, keys).and_then(|(line, _)| parse_line(&line)) {
+        Ok(_) => (text, true),
+        Err(_) => (&text[..start], true),
+    }
 }
 
 pub fn convert_map_to_commands(store_underlying: &HashMap<String, String>) -> Vec<Command> {
@@ -119,7 +427,18 @@ pub struct KvStoreV2 {
     // We can use `Arc<RwLock<HashMap<String, String>>>` here, but it should be fine as it will be
     // handled by the server and its state-sharing scheme.
     map: HashMap<String, String>,
+    /// The stored form of the values that are stored compressed, so that compacting the log
+    /// doesn't compress every value again.
+    compressed: HashMap<String, (Compression, String)>,
+    /// How the values written from now on are compressed.
+    compression: Compression,
+    /// The keys the log is encrypted with, if it is.
+    keys: Keyring,
+    fs: Arc<dyn FileSystem>,
     log_count: usize,
+    compactions: u64,
+    compaction_time: Duration,
+    feed: ChangeFeed,
 }
 
 impl KvStoreV2 {
@@ -127,41 +446,136 @@ impl KvStoreV2 {
         Self {
             file_path: None,
             map: HashMap::new(),
+            compressed: HashMap::new(),
+            compression: Compression::None,
+            keys: Keyring::default(),
+            fs: Arc::new(OsFileSystem),
             log_count: 0,
+            compactions: 0,
+            compaction_time: Duration::ZERO,
+            feed: ChangeFeed::new(),
         }
     }
 
+    /// Compresses the values written from now on with `compression`, when that makes them
+    /// smaller. Values already stored are read whatever they were compressed with, and keep it.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
     pub fn open(working_dir: &Path) -> Result<Self> {
+        Self::open_with_keys(working_dir, Keyring::default())
+    }
+
+    /// Opens the store with its log encrypted with the current key of `keys`. Records encrypted
+    /// with one of the old keys, or not encrypted at all, are read and encrypted again with the
+    /// current key straight away, by compacting the log. Records encrypted with any other key fail
+    /// the opening with [`crate::Error::WrongEncryptionKey`].
+    pub fn open_with_keys(working_dir: &Path, keys: Keyring) -> Result<Self> {
+        Self::open_with_file_system(working_dir, keys, Arc::new(OsFileSystem))
+    }
+
+    /// Opens the store as [`KvStoreV2::open_with_keys`] does, with its log kept on `fs`.
+    pub fn open_with_file_system(
+        working_dir: &Path,
+        keys: Keyring,
+        fs: Arc<dyn FileSystem>,
+    ) -> Result<Self> {
         let file_path = working_dir.join(DEFAULT_FILE_NAME);
-        initialize(&file_path)?;
+        if !fs.exists(&file_path) {
+            fs.create(&file_path).with_whatever_context(|_| {
+                format!("Couldn't open file at {}", file_path.display())
+            })?;
+        }
 
         let mut store = KvStoreV2::new();
         store.file_path = Some(file_path.clone());
 
-        let commands =
-            deserialize_commands(&read_to_string(&file_path).with_whatever_context(|_| {
-                format!("Couldn't read content of file at {}", file_path.display())
-            })?)?;
-        store.log_count = commands.len();
-        for command in commands {
-            apply_command(&command, &mut store.map)?;
+        let content = fs.read(&file_path).with_whatever_context(|_| {
+            format!("Couldn't read content of file at {}", file_path.display())
+        })?;
+        let content = String::from_utf8(content).with_whatever_context(|_| {
+            format!("Content of file at {} isn't valid UTF-8", file_path.display())
+        })?;
+        let (content, torn) = without_torn_line(&content, &keys);
+        let (records, stale) = deserialize_records(content, &keys)?;
+        store.keys = keys;
+        store.fs = fs;
+        store.log_count = records.len();
+        for record in records {
+            store.track(&record);
+            apply_command(&record.decode()?, &mut store.map)?;
+        }
+        if stale || torn {
+            store.compact()?;
         }
 
         Ok(store)
     }
 
+    /// Remembers how the value written by `record` is stored.
+    fn track(&mut self, record: &Record) {
+        match &record.command {
+            Command::Set { key, value } if !record.compression.is_none() => {
+                self.compressed
+                    .insert(key.clone(), (record.compression, value.clone()));
+            }
+            Command::Set { key, .. } | Command::Rm { key } => {
+                self.compressed.remove(key);
+            }
+            _ => {}
+        }
+    }
+
+    /// A `Set` record for every live key, as the values are stored.
+    fn live_records(&self) -> Vec<Record> {
+        convert_map_to_commands(&self.map)
+            .into_iter()
+            .map(|command| match &command {
+                Command::Set { key, .. } if self.compressed.contains_key(key) => {
+                    let (compression, stored) = &self.compressed[key];
+                    Record {
+                        command: Command::Set {
+                            key: key.clone(),
+                            value: stored.clone(),
+                        },
+                        compression: *compression,
+                    }
+                }
+                _ => Record {
+                    command,
+                    compression: Compression::None,
+                },
+            })
+            .collect()
+    }
+
+    /// Rewrites the log with only the live keys. The new log is written next to the old one, which
+    /// it only replaces once on the disk, so that a failed or interrupted compaction loses nothing.
     pub fn compact(&mut self) -> Result<()> {
+        let started = Instant::now();
         let log_path = self.file_path.as_ref().expect("file path not initialized");
-        let map = &self.map;
-        let commands = convert_map_to_commands(map);
-        let logs_content = serialize_commands(&commands)?;
-        fs::write(log_path.as_path(), logs_content).with_whatever_context(|_| {
-            format!("Couldn't write to file at {}", log_path.display())
-        })?;
-        self.log_count = map.len();
+        let logs_content = serialize_records(&self.live_records(), &self.keys)?;
+        let temp_path = log_path.with_extension("db.tmp");
+        self.fs
+            .write(&temp_path, logs_content.as_bytes())
+            .and_then(|_| self.fs.sync(&temp_path))
+            .and_then(|_| self.fs.rename(&temp_path, log_path))
+            .with_whatever_context(|_| {
+                format!("Couldn't write to file at {}", log_path.display())
+            })?;
+        self.log_count = self.map.len();
+        self.compactions += 1;
+        self.compaction_time += started.elapsed();
         Ok(())
     }
 
+    /// Whether the log should be compacted after a write, which as of now is always the case.
+    fn should_compact(&self) -> bool {
+        self.log_count >= self.map.len()
+    }
+
     pub fn clone(&self) -> Self {
         todo!()
     }
@@ -173,11 +587,14 @@ impl KvsEngine for KvStoreV2 {
             key: key.clone(),
             value: value.clone(),
         };
+        let record = Record::encode(command, self.compression)?;
         let file_path = self.file_path.as_ref().expect("file path not initialized");
         self.log_count += 1;
-        append_command(command, file_path)?;
-        self.map.insert(key, value);
-        if self.log_count >= self.map.len() {
+        append_record(&*self.fs, &record, &self.keys, file_path)?;
+        self.track(&record);
+        self.map.insert(key.clone(), value.clone());
+        self.feed.publish(key, Change::Set { value });
+        if self.should_compact() {
             self.compact()?;
         }
         Ok(())
@@ -189,20 +606,105 @@ impl KvsEngine for KvStoreV2 {
     }
 
     fn remove(&mut self, key: String) -> Result<Option<String>> {
-        let value_opt = self.map.remove(&key);
-        match value_opt {
-            Some(value) => {
-                let command = Command::Rm { key: key.clone() };
-                let file_path = self.file_path.as_ref().expect("file path not initialized");
-                append_command(command, file_path)?;
-                self.log_count += 1;
-                if self.log_count >= self.map.len() {
-                    self.compact()?;
+        if !self.map.contains_key(&key) {
+            return Ok(None);
+        }
+        // Logged first, so that a failed write leaves the key as it was
+        let record = Record::encode(Command::Rm { key: key.clone() }, Compression::None)?;
+        let file_path = self.file_path.as_ref().expect("file path not initialized");
+        append_record(&*self.fs, &record, &self.keys, file_path)?;
+        let value = self.map.remove(&key);
+        self.compressed.remove(&key);
+        self.log_count += 1;
+        self.feed.publish(key, Change::Rm);
+        if self.should_compact() {
+            self.compact()?;
+        }
+        Ok(value)
+    }
+
+    fn write_batch(&mut self, batch: Vec<WriteOp>) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let records = batch
+            .iter()
+            .map(|op| {
+                let command = match op.clone() {
+                    WriteOp::Set { key, value } => Command::Set { key, value },
+                    WriteOp::Rm { key } => Command::Rm { key },
+                };
+                Record::encode(command, self.compression)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let file_path = self.file_path.as_ref().expect("file path not initialized");
+        append_line(&*self.fs, &serialize_batch(&records, &self.keys)?, file_path)?;
+        self.log_count += records.len();
+        for (op, record) in batch.into_iter().zip(&records) {
+            self.track(record);
+            match op {
+                WriteOp::Set { key, value } => {
+                    self.map.insert(key.clone(), value.clone());
+                    self.feed.publish(key, Change::Set { value });
+                }
+                WriteOp::Rm { key } => {
+                    if self.map.remove(&key).is_some() {
+                        self.feed.publish(key, Change::Rm);
+                    }
                 }
-                Ok(Some(value))
             }
-            None => Ok(None),
         }
+        if self.should_compact() {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn scan(&self, prefix: String) -> Result<ScanIter<'_>> {
+        let mut keys: Vec<&String> = self
+            .map
+            .keys()
+            .filter(|key| key.starts_with(&prefix))
+            .collect();
+        keys.sort();
+        Ok(Box::new(
+            keys.into_iter()
+                .map(|key| Ok((key.clone(), self.map[key].clone()))),
+        ))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let disk_size = match self.file_path.as_ref() {
+            Some(file_path) => self.fs.size(file_path).with_whatever_context(|_| {
+                format!("Couldn't read metadata of file at {}", file_path.display())
+            })?,
+            None => 0,
+        };
+        let records = self.live_records();
+        let compacted_size = serialize_records(&records, &self.keys)?.len();
+        let value_size = self.map.values().map(|value| value.len() as u64).sum();
+        let stored_value_size = records
+            .iter()
+            .map(|record| match &record.command {
+                Command::Set { value, .. } => value.len() as u64,
+                _ => 0,
+            })
+            .sum();
+
+        Ok(EngineStats {
+            live_keys: self.map.len(),
+            stale_records: Some(self.log_count - self.map.len()),
+            disk_size,
+            compacted_size: Some(compacted_size as u64),
+            compactions: Some(self.compactions),
+            compaction_time: Some(self.compaction_time),
+            value_size: Some(value_size),
+            stored_value_size: Some(stored_value_size),
+        })
+    }
+
+    fn watch(&self, pattern: KeyPattern, after: Option<Position>) -> Result<Watcher> {
+        self.feed.subscribe(pattern, after)
     }
 
     fn name(&self) -> &'static str {
@@ -213,17 +715,30 @@ impl KvsEngine for KvStoreV2 {
 #[cfg(test)]
 mod tests_pure_fns {
     use super::*;
+    use std::fs::read_to_string;
+
+    pub(super) fn serialize_commands(commands: &[Command]) -> Result<String> {
+        let records: Vec<Record> = commands
+            .iter()
+            .map(|command| Record {
+                command: command.clone(),
+                compression: Compression::None,
+            })
+            .collect();
+        serialize_records(&records, &Keyring::default())
+    }
 
-    mod initialize {
+    mod create {
         use super::*;
-        use std::fs::File;
 
         #[test]
         fn success_file_new() {
             let temp_dir =
                 tempfile::tempdir().expect("unable to create temporary working directory");
             let file_path = temp_dir.path().join(DEFAULT_FILE_NAME);
-            initialize(&file_path).expect("unable to initialize file");
+            OsFileSystem
+                .create(&file_path)
+                .expect("unable to create file");
             assert!(file_path.exists());
         }
 
@@ -232,13 +747,16 @@ mod tests_pure_fns {
             let temp_dir =
                 tempfile::tempdir().expect("unable to create temporary working directory");
             let file_path = temp_dir.path().join(DEFAULT_FILE_NAME);
-            File::create(&file_path).expect("unable to create file");
-            initialize(&file_path).expect("unable to initialize file");
-            assert!(file_path.exists());
+            std::fs::write(&file_path, "content").expect("unable to create file");
+            OsFileSystem
+                .create(&file_path)
+                .expect("unable to create file");
+            let file_content = read_to_string(file_path).expect("unable to read file content");
+            assert_eq!(file_content, "content");
         }
     }
 
-    mod deserialize_commands {
+    mod deserialize_records {
         use super::*;
 
         #[test]
@@ -259,11 +777,65 @@ mod tests_pure_fns {
                     key: "key1".to_owned(),
                 },
             ];
-            let commands = deserialize_commands(&deserialized).unwrap();
+            let commands = deserialize_commands(&deserialized, &Keyring::default()).unwrap();
 
             assert_eq!(expected, commands);
         }
 
+        #[test]
+        fn success_batch() {
+            let deserialized = [
+                "{\"type\":\"Set\",\"key\":\"key1\",\"value\":\"value1\"}",
+                "{\"batch\":[{\"type\":\"Set\",\"key\":\"key2\",\"value\":\"value2\"},{\"type\":\"Rm\",\"key\":\"key1\"}]}",
+            ]
+            .join("\n")
+                + "\n";
+
+            let expected = vec![
+                Command::Set {
+                    key: "key1".to_owned(),
+                    value: "value1".to_owned(),
+                },
+                Command::Set {
+                    key: "key2".to_owned(),
+                    value: "value2".to_owned(),
+                },
+                Command::Rm {
+                    key: "key1".to_owned(),
+                },
+            ];
+            assert_eq!(deserialize_commands(&deserialized, &Keyring::default()).unwrap(), expected);
+            let serialized = serialize_batch(
+                &[
+                    Record::encode(expected[1].clone(), Compression::None).unwrap(),
+                    Record::encode(expected[2].clone(), Compression::None).unwrap(),
+                ],
+                &Keyring::default(),
+            )
+            .unwrap();
+            assert_eq!(serialized, deserialized.lines().nth(1).unwrap());
+        }
+
+        #[test]
+        fn success_compressed() {
+            let value = "{\"name\":\"value1\"}".repeat(100);
+            let stored = BASE64.encode(Compression::Lz4.compress(value.as_bytes()).unwrap());
+            let deserialized = format!(
+                "{{\"type\":\"Set\",\"key\":\"key1\",\"value\":\"{}\",\"compression\":\"lz4\"}}\n",
+                stored
+            );
+
+            let (records, _) = deserialize_records(&deserialized, &Keyring::default()).unwrap();
+            assert_eq!(records[0].compression, Compression::Lz4);
+            assert_eq!(
+                deserialize_commands(&deserialized, &Keyring::default()).unwrap(),
+                vec![Command::Set {
+                    key: "key1".to_owned(),
+                    value,
+                }]
+            );
+        }
+
         #[test]
         fn fail() {
             let deserialized = vec![
@@ -274,7 +846,7 @@ mod tests_pure_fns {
             .join("\n")
                 + "\n";
 
-            let result = deserialize_commands(&deserialized);
+            let result = deserialize_commands(&deserialized, &Keyring::default());
 
             assert!(result.is_err());
             assert!(result
@@ -357,7 +929,7 @@ mod tests_pure_fns {
         }
     }
 
-    mod append_command {
+    mod append_record {
         use super::*;
 
         #[test]
@@ -365,13 +937,17 @@ mod tests_pure_fns {
             let temp_dir =
                 tempfile::tempdir().expect("unable to create temporary working directory");
             let file_path = temp_dir.path().join(DEFAULT_FILE_NAME);
-            initialize(&file_path).expect("unable to initialize file");
+            OsFileSystem
+                .create(&file_path)
+                .expect("unable to create file");
 
             let command = Command::Set {
                 key: "key1".to_owned(),
                 value: "value1".to_owned(),
             };
-            append_command(command, &file_path).expect("unable to append command");
+            let record = Record::encode(command, Compression::None).expect("unable to encode");
+            append_record(&OsFileSystem, &record, &Keyring::default(), &file_path)
+                .expect("unable to append record");
 
             let file_content = read_to_string(file_path).expect("unable to read file content");
             assert_eq!(
@@ -461,7 +1037,176 @@ mod tests_pure_fns {
 
 #[cfg(test)]
 mod tests_kv_store {
+    use super::tests_pure_fns::serialize_commands;
     use super::*;
+    use std::fs::read_to_string;
+
+    mod stats {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let temp_dir =
+                tempfile::tempdir().expect("unable to create temporary working directory");
+            let mut store = KvStoreV2::open(temp_dir.path()).expect("unable to initialize file");
+
+            for key in ["key1", "key2", "key3"] {
+                store
+                    .set(key.to_owned(), "value1".to_owned())
+                    .expect("unable to set key");
+            }
+            // Writes compact the log, so the stale record is added behind the store's back
+            let command = Command::Set {
+                key: "key1".to_owned(),
+                value: "value2".to_owned(),
+            };
+            let record = Record::encode(command, Compression::None).expect("unable to encode");
+            append_record(
+                &OsFileSystem,
+                &record,
+                &Keyring::default(),
+                store.file_path.as_ref().unwrap(),
+            )
+            .expect("unable to append record");
+            let mut store = KvStoreV2::open(temp_dir.path()).expect("unable to reopen file");
+
+            let stats = store.stats().expect("unable to get stats");
+            assert_eq!(stats.live_keys, 3);
+            assert_eq!(stats.stale_records, Some(1));
+            assert_eq!(stats.stale_ratio(), Some(0.25));
+            assert!(stats.compacted_size.unwrap() < stats.disk_size);
+            assert_eq!(stats.compactions, Some(0));
+
+            store.compact().expect("unable to compact");
+            let stats = store.stats().expect("unable to get stats");
+            assert_eq!(stats.stale_records, Some(0));
+            assert_eq!(stats.compactions, Some(1));
+            assert_eq!(stats.compacted_size, Some(stats.disk_size));
+        }
+    }
+
+    mod compression {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let temp_dir =
+                tempfile::tempdir().expect("unable to create temporary working directory");
+            let large = "{\"name\":\"value1\",\"tags\":[\"a\",\"b\"]}".repeat(100);
+            {
+                let mut store = KvStoreV2::open(temp_dir.path())
+                    .expect("unable to initialize file")
+                    .with_compression(Compression::Zstd);
+                store
+                    .set("large".to_owned(), large.clone())
+                    .expect("unable to set key");
+                store
+                    .set("small".to_owned(), "value1".to_owned())
+                    .expect("unable to set key");
+            }
+            let log = read_to_string(temp_dir.path().join(DEFAULT_FILE_NAME)).unwrap();
+            assert_eq!(log.matches("\"compression\":\"zstd\"").count(), 1);
+            assert!(log.len() < large.len());
+
+            // Values keep their compression when the store compresses differently, or not at all
+            let mut store = KvStoreV2::open(temp_dir.path()).expect("unable to reopen file");
+            assert_eq!(store.get("large".to_owned()).unwrap(), Some(large.clone()));
+            assert_eq!(
+                store.get("small".to_owned()).unwrap(),
+                Some("value1".to_owned())
+            );
+            store
+                .set("other".to_owned(), large.clone())
+                .expect("unable to set key");
+            let log = read_to_string(temp_dir.path().join(DEFAULT_FILE_NAME)).unwrap();
+            assert_eq!(log.matches("\"compression\":\"zstd\"").count(), 1);
+
+            let stats = store.stats().expect("unable to get stats");
+            assert_eq!(stats.value_size, Some(2 * large.len() as u64 + 6));
+            assert!(stats.compression_ratio().unwrap() > 1.5);
+
+            store
+                .remove("large".to_owned())
+                .expect("unable to remove key");
+            let stats = store.stats().expect("unable to get stats");
+            assert_eq!(stats.compression_ratio(), Some(1.0));
+        }
+    }
+
+    mod encryption {
+        use super::*;
+        use crate::encryption::EncryptionKey;
+        use crate::Error;
+
+        fn key() -> EncryptionKey {
+            EncryptionKey::from_hex(&EncryptionKey::generate()).unwrap()
+        }
+
+        #[test]
+        fn success() {
+            let temp_dir =
+                tempfile::tempdir().expect("unable to create temporary working directory");
+            let log_path = temp_dir.path().join(DEFAULT_FILE_NAME);
+            {
+                let mut store = KvStoreV2::open(temp_dir.path()).expect("unable to open store");
+                store
+                    .set("key1".to_owned(), "value1".to_owned())
+                    .expect("unable to set key");
+            }
+
+            // Records written before encryption was turned on are encrypted at once
+            let (old, new) = (key(), key());
+            {
+                let mut store =
+                    KvStoreV2::open_with_keys(temp_dir.path(), Keyring::new(old.clone(), vec![]))
+                        .expect("unable to open store");
+                assert!(!read_to_string(&log_path).unwrap().contains("value1"));
+                store
+                    .set("key2".to_owned(), "value2".to_owned())
+                    .expect("unable to set key");
+            }
+            let log = read_to_string(&log_path).unwrap();
+            assert!(!log.contains("value2"));
+            assert!(log.lines().all(|line| line.starts_with("{\"encrypted\":")));
+
+            // Rotating keys encrypts the records again with the new one
+            let store =
+                KvStoreV2::open_with_keys(temp_dir.path(), Keyring::new(new.clone(), vec![old]))
+                    .expect("unable to open store");
+            assert_eq!(
+                store.get("key1".to_owned()).unwrap(),
+                Some("value1".to_owned())
+            );
+            assert_ne!(read_to_string(&log_path).unwrap(), log);
+            let store = KvStoreV2::open_with_keys(temp_dir.path(), Keyring::new(new, vec![]))
+                .expect("unable to open store");
+            assert_eq!(
+                store.get("key2".to_owned()).unwrap(),
+                Some("value2".to_owned())
+            );
+        }
+
+        #[test]
+        fn fail_wrong_key() {
+            let temp_dir =
+                tempfile::tempdir().expect("unable to create temporary working directory");
+            {
+                let mut store =
+                    KvStoreV2::open_with_keys(temp_dir.path(), Keyring::new(key(), vec![]))
+                        .expect("unable to open store");
+                store
+                    .set("key1".to_owned(), "value1".to_owned())
+                    .expect("unable to set key");
+            }
+
+            for result in [
+                KvStoreV2::open_with_keys(temp_dir.path(), Keyring::new(key(), vec![])),
+                KvStoreV2::open(temp_dir.path()),
+            ] {
+                assert!(matches!(result, Err(Error::WrongEncryptionKey { .. })));
+            }
+        }
+    }
 
     mod open {
         use super::*;
@@ -515,5 +1260,48 @@ mod tests_kv_store {
             assert_eq!(map.get("key2").unwrap(), "value2");
             assert_eq!(map.get("key3").unwrap(), "value3");
         }
+
+        #[test]
+        fn success_torn_last_line() {
+            let temp_dir =
+                tempfile::tempdir().expect("unable to create temporary working directory");
+            let file_path = temp_dir.path().join(DEFAULT_FILE_NAME);
+            let commands = vec![Command::Set {
+                key: "key1".to_owned(),
+                value: "value1".to_owned(),
+            }];
+            let commands_str = serialize_commands(&commands).expect("unable to serialize commands");
+            // A crash cut the second record short
+            write!(
+                File::create(&file_path).expect("unable to create file"),
+                "{}\n{{\"type\":\"Set\",\"key\":\"ke",
+                commands_str,
+            )
+            .expect("unable to write to file");
+
+            let mut store = KvStoreV2::open(temp_dir.path()).expect("unable to open file");
+            assert_eq!(store.map.len(), 1);
+            store
+                .set("key2".to_owned(), "value2".to_owned())
+                .expect("unable to set key");
+            let store = KvStoreV2::open(temp_dir.path()).expect("unable to reopen file");
+            assert_eq!(store.map.get("key1").unwrap(), "value1");
+            assert_eq!(store.map.get("key2").unwrap(), "value2");
+        }
+
+        #[test]
+        fn failure_damaged_line() {
+            let temp_dir =
+                tempfile::tempdir().expect("unable to create temporary working directory");
+            let file_path = temp_dir.path().join(DEFAULT_FILE_NAME);
+            // Only the last line may be torn
+            writeln!(
+                File::create(&file_path).expect("unable to create file"),
+                "{{\"type\":\"Set\",\"key\":\"ke\n{{\"type\":\"Rm\",\"key\":\"key1\"}}",
+            )
+            .expect("unable to write to file");
+
+            assert!(KvStoreV2::open(temp_dir.path()).is_err());
+        }
     }
 }
@@ -1,12 +1,35 @@
+pub mod auth;
+pub mod backup;
+pub mod client;
+pub mod clock;
+pub mod compression;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+pub mod encryption;
 mod engine;
 pub mod err;
+pub mod eviction;
+pub mod file_system;
+pub mod index;
 mod kv_store;
+pub mod limits;
 mod sled_store;
 mod mem_store;
+pub mod metrics;
+pub mod namespace;
+pub mod protocol;
+pub mod routing;
+pub mod script;
+#[cfg(feature = "simulation")]
+pub mod simulation;
+pub mod snapshot;
 pub mod thread_pool;
+pub mod tls;
+pub mod value;
+pub mod watch;
 
-pub use engine::{KvsEngine, evaluate_command};
+pub use engine::{EngineStats, KvsEngine, ScanIter, WriteOp, evaluate_command, evaluate_read_command};
 pub use err::{Error, Result};
-pub use kv_store::{KvStoreV2, DEFAULT_FILE_NAME as DEFAULT_FILE_NAME_KVS, Command, CommandResponse};
+pub use kv_store::{KvStoreV2, DEFAULT_FILE_NAME as DEFAULT_FILE_NAME_KVS, Command, CommandResponse, decode_log_line, decrypt_log_line, deserialize_commands};
 pub use mem_store::MemStore;
 pub use sled_store::{SledStore, DEFAULT_FILE_NAME as DEFAULT_FILE_NAME_SLED};
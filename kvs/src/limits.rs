@@ -0,0 +1,378 @@
+use crate::clock::{Clock, SystemClock};
+use crate::err::{RateLimitedSnafu, Result, TooLargeSnafu, TooManyConnectionsSnafu};
+use crate::Command;
+use serde::Deserialize;
+use snafu::{ensure, whatever};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Past this many clients, the buckets that are full again are forgotten, so that clients coming
+/// and going don't make the rate limiter grow forever.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// The `[limits]` section of the server config, e.g.
+///
+/// ```toml
+/// [limits]
+/// max_connections = 256
+/// rate_limit = 100
+/// max_value_size = 4096
+/// ```
+///
+/// Sizes are in bytes and `timeout` is in seconds.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Connections open at the same time, watches included.
+    pub max_connections: usize,
+    /// Requests per second allowed to every client IP address. Unlimited when `None`.
+    pub rate_limit: Option<f64>,
+    /// Requests a client may send in a row before `rate_limit` kicks in. Defaults to one second
+    /// worth of requests.
+    pub rate_burst: Option<u32>,
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    /// Size of a whole request, e.g. of the archive sent by `RESTORE`.
+    pub max_request_size: usize,
+    /// How long reading a request or writing a response may stall before it is given up on.
+    pub timeout: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            rate_limit: None,
+            rate_burst: None,
+            max_key_size: 64 * 1024,
+            max_value_size: 1024 * 1024,
+            max_request_size: 64 * 1024 * 1024,
+            timeout: 30,
+        }
+    }
+}
+
+impl Limits {
+    /// Catches the limits that would refuse every request.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_connections == 0 {
+            whatever!("max_connections must be at least 1");
+        }
+        if let Some(rate_limit) = self.rate_limit {
+            if !(rate_limit.is_finite() && rate_limit > 0.0) {
+                whatever!("rate_limit must be a positive number, not {}", rate_limit);
+            }
+        }
+        if self.rate_burst == Some(0) {
+            whatever!("rate_burst must be at least 1");
+        }
+        if self.timeout == 0 {
+            whatever!("timeout must be at least 1 second");
+        }
+        Ok(())
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+
+    /// The rate limiter to check every request against, or `None` without a `rate_limit`.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        let rate = self.rate_limit?;
+        let burst = self.rate_burst.unwrap_or(rate.ceil() as u32).max(1);
+        Some(RateLimiter::new(rate, burst))
+    }
+
+    /// Checks the size of a whole request.
+    pub fn check_request_size(&self, size: usize) -> Result<()> {
+        ensure!(
+            size <= self.max_request_size,
+            TooLargeSnafu {
+                what: "Request",
+                size,
+                max: self.max_request_size,
+            }
+        );
+        Ok(())
+    }
+
+    /// Checks the size of the keys and values of `command`.
+    pub fn check_command(&self, command: &Command) -> Result<()> {
+        match command {
+            Command::Get { key }
+            | Command::Rm { key }
+            | Command::LRange { key, .. }
+            | Command::SMembers { key }
+            | Command::IncrBy { key, .. } => self.check_key(key),
+            Command::Scan { prefix } => self.check_key(prefix),
+            Command::Set { key, value } => {
+                self.check_key(key)?;
+                self.check_value(value)
+            }
+            // Fields are checked as keys, elements as values
+            Command::HGet { key, field } => {
+                self.check_key(key)?;
+                self.check_key(field)
+            }
+            Command::HSet { key, field, value } => {
+                self.check_key(key)?;
+                self.check_key(field)?;
+                self.check_value(value)
+            }
+            Command::LPush {
+                key,
+                values: elements,
+            }
+            | Command::SAdd {
+                key,
+                members: elements,
+            } => {
+                self.check_key(key)?;
+                elements
+                    .iter()
+                    .try_for_each(|element| self.check_value(element))
+            }
+            // Indexed values are found in values, so they are checked as values
+            Command::Find { index, value } => {
+                self.check_key(index)?;
+                self.check_value(value)
+            }
+            Command::Eval { script, keys, args } => {
+                self.check_value(script)?;
+                self.check_script_words(keys, args)
+            }
+            Command::EvalSha { hash, keys, args } => {
+                self.check_key(hash)?;
+                self.check_script_words(keys, args)
+            }
+            Command::Backup => Ok(()),
+            Command::Restore { pairs } => pairs.iter().try_for_each(|(key, value)| {
+                self.check_key(key)?;
+                self.check_value(value)
+            }),
+        }
+    }
+
+    /// Checks the keys a script declares as keys, and its arguments as values.
+    fn check_script_words(&self, keys: &[String], args: &[String]) -> Result<()> {
+        keys.iter().try_for_each(|key| self.check_key(key))?;
+        args.iter().try_for_each(|arg| self.check_value(arg))
+    }
+
+    fn check_key(&self, key: &str) -> Result<()> {
+        ensure!(
+            key.len() <= self.max_key_size,
+            TooLargeSnafu {
+                what: "Key",
+                size: key.len(),
+                max: self.max_key_size,
+            }
+        );
+        Ok(())
+    }
+
+    fn check_value(&self, value: &str) -> Result<()> {
+        ensure!(
+            value.len() <= self.max_value_size,
+            TooLargeSnafu {
+                what: "Value",
+                size: value.len(),
+                max: self.max_value_size,
+            }
+        );
+        Ok(())
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A token bucket per client IP address, holding up to `burst` tokens and refilled at `rate`
+/// tokens per second. Every request takes a token.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            rate,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Refills the buckets by the time of `clock` rather than of the system.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Takes a token from the bucket of `client`, failing when it is empty.
+    pub fn check(&self, client: IpAddr) -> Result<()> {
+        self.check_at(client, self.clock.now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<()> {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        self.refill(bucket, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let retry_after = (1.0 - bucket.tokens) / self.rate;
+        RateLimitedSnafu {
+            retry_after_ms: (retry_after * 1000.0).ceil() as u64,
+        }
+        .fail()
+    }
+
+    /// Adds the tokens earned since the last update, returning how many the bucket now holds.
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+/// Counts the open connections, refusing new ones past the maximum.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max: usize) -> Self {
+        Self {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Counts a new connection until the returned permit is dropped.
+    pub fn acquire(&self) -> Result<ConnectionPermit> {
+        let acquired = self
+            .open
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .is_ok();
+        ensure!(acquired, TooManyConnectionsSnafu { max: self.max });
+        Ok(ConnectionPermit {
+            open: self.open.clone(),
+        })
+    }
+}
+
+/// A connection counted by a [`ConnectionLimiter`].
+pub struct ConnectionPermit {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Error;
+
+    #[test]
+    fn check_command() {
+        let limits = Limits {
+            max_key_size: 4,
+            max_value_size: 8,
+            ..Limits::default()
+        };
+        let set = |key: &str, value: &str| Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+        assert!(limits.check_command(&set("key1", "value1")).is_ok());
+
+        let err = limits.check_command(&set("key12", "value1")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Key is 5 bytes long, more than the 4 allowed"
+        );
+        let err = limits
+            .check_command(&Command::Restore {
+                pairs: vec![("key1".to_owned(), "value1234".to_owned())],
+            })
+            .unwrap_err();
+        assert!(matches!(err, Error::TooLarge { size: 9, .. }));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let limiter = RateLimiter::new(2.0, 3);
+        let alice = IpAddr::from([10, 0, 0, 1]);
+        let bob = IpAddr::from([10, 0, 0, 2]);
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(alice, start).is_ok());
+        }
+        let err = limiter.check_at(alice, start).unwrap_err();
+        assert_eq!(err.to_string(), "Rate limit exceeded, retry in 500 ms");
+        // Every client has a bucket of its own
+        assert!(limiter.check_at(bob, start).is_ok());
+        // Two tokens a second come back
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.check_at(alice, later).is_ok());
+        assert!(limiter.check_at(alice, later).is_err());
+        let much_later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.check_at(alice, much_later).is_ok());
+        }
+        assert!(limiter.check_at(alice, much_later).is_err());
+    }
+
+    #[test]
+    fn connection_limiter() {
+        let limiter = ConnectionLimiter::new(2);
+        let first = limiter.acquire().unwrap();
+        let _second = limiter.acquire().unwrap();
+        assert!(matches!(
+            limiter.acquire(),
+            Err(Error::TooManyConnections { max: 2 })
+        ));
+        drop(first);
+        assert!(limiter.acquire().is_ok());
+    }
+
+    #[test]
+    fn invalid_limits() {
+        for (config, message) in [
+            ("max_connections = 0", "max_connections"),
+            ("rate_limit = -1.0", "rate_limit"),
+            ("rate_limit = 1.0\nrate_burst = 0", "rate_burst"),
+            ("timeout = 0", "timeout"),
+        ] {
+            let limits: Limits = toml::from_str(config).unwrap();
+            let err = limits.validate().unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+}
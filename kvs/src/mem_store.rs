@@ -1,22 +1,145 @@
+use crate::encryption::Keyring;
 use crate::err::Result;
-use crate::KvsEngine;
+use crate::snapshot::{Snapshot, SnapshotFile};
+use crate::watch::{Change, ChangeFeed, KeyPattern, Position, Watcher};
+use crate::{EngineStats, KvsEngine, ScanIter, WriteOp};
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The pairs of a [`MemStore`], shared with its snapshots without being copied. Writes made while
+/// a snapshot holds them are kept aside in `changes`, and folded into them by the first write
+/// after no snapshot holds them anymore, so that no write copies all the pairs.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedMap {
+    base: Arc<HashMap<String, String>>,
+    /// Writes made while `base` was shared, `None` for the removed keys.
+    changes: HashMap<String, Option<String>>,
+    len: usize,
+}
+
+impl SharedMap {
+    pub(crate) fn get(&self, key: &str) -> Option<&String> {
+        match self.changes.get(key) {
+            Some(value) => value.as_ref(),
+            None => self.base.get(key),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Every pair, in no particular order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        let base = self
+            .base
+            .iter()
+            .filter(|(key, _)| !self.changes.contains_key(*key));
+        let changes = self
+            .changes
+            .iter()
+            .filter_map(|(key, value)| Some((key, value.as_ref()?)));
+        base.chain(changes)
+    }
+
+    fn insert(&mut self, key: String, value: String) -> Option<String> {
+        self.fold();
+        let previous = match Arc::get_mut(&mut self.base) {
+            Some(base) => base.insert(key, value),
+            None => {
+                let previous = self.get(&key).cloned();
+                self.changes.insert(key, Some(value));
+                previous
+            }
+        };
+        self.len += usize::from(previous.is_none());
+        previous
+    }
+
+    fn remove(&mut self, key: &str) -> Option<String> {
+        self.fold();
+        let previous = match Arc::get_mut(&mut self.base) {
+            Some(base) => base.remove(key),
+            None => {
+                let previous = self.get(key).cloned();
+                if self.base.contains_key(key) {
+                    self.changes.insert(key.to_owned(), None);
+                } else {
+                    self.changes.remove(key);
+                }
+                previous
+            }
+        };
+        self.len -= usize::from(previous.is_some());
+        previous
+    }
+
+    /// Applies the changes kept aside to `base`, once no snapshot holds it.
+    fn fold(&mut self) {
+        if self.changes.is_empty() {
+            return;
+        }
+        let Some(base) = Arc::get_mut(&mut self.base) else {
+            return;
+        };
+        for (key, value) in self.changes.drain() {
+            match value {
+                Some(value) => base.insert(key, value),
+                None => base.remove(&key),
+            };
+        }
+    }
+}
+
+impl From<HashMap<String, String>> for SharedMap {
+    fn from(map: HashMap<String, String>) -> Self {
+        Self {
+            len: map.len(),
+            base: Arc::new(map),
+            changes: HashMap::new(),
+        }
+    }
+}
 
 pub struct MemStore {
-    map: HashMap<String, String>,
+    /// Shared with the snapshots still being written.
+    map: SharedMap,
+    feed: ChangeFeed,
+    /// Writes made since the store was opened.
+    version: u64,
+    /// `None` when the store keeps no snapshot.
+    snapshots: Option<Arc<SnapshotFile>>,
 }
 
 impl MemStore {
     pub fn new() -> Self {
         Self {
-            map: HashMap::new(),
+            map: SharedMap::default(),
+            feed: ChangeFeed::new(),
+            version: 0,
+            snapshots: None,
         }
     }
+
+    /// Opens a store holding the pairs of the snapshot in `dir`, if there is one, and taking
+    /// snapshots to write there, sealed with `keys`. See [`crate::snapshot`].
+    pub fn open_with_snapshots(dir: &Path, keys: Keyring) -> Result<Self> {
+        let file = SnapshotFile::new(dir, keys);
+        let map: HashMap<_, _> = file.load()?.into_iter().collect();
+        Ok(Self {
+            map: map.into(),
+            snapshots: Some(Arc::new(file)),
+            ..Self::new()
+        })
+    }
 }
 
 impl KvsEngine for MemStore {
     fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.map.insert(key, value);
+        self.map.insert(key.clone(), value.clone());
+        self.version += 1;
+        self.feed.publish(key, Change::Set { value });
         Ok(())
     }
 
@@ -29,13 +152,119 @@ impl KvsEngine for MemStore {
         let value_opt = self.map.remove(&key);
         match value_opt {
             Some(value) => {
+                self.version += 1;
+                self.feed.publish(key, Change::Rm);
                 Ok(Some(value))
             }
             None => Ok(None),
         }
     }
 
+    fn write_batch(&mut self, batch: Vec<WriteOp>) -> Result<()> {
+        for op in batch {
+            match op {
+                WriteOp::Set { key, value } => self.set(key, value)?,
+                WriteOp::Rm { key } => {
+                    self.remove(key)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn scan(&self, prefix: String) -> Result<ScanIter<'_>> {
+        let mut pairs: Vec<(&String, &String)> = self
+            .map
+            .iter()
+            .filter(|(key, _)| key.starts_with(&prefix))
+            .collect();
+        pairs.sort();
+        Ok(Box::new(
+            pairs
+                .into_iter()
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        Ok(EngineStats {
+            live_keys: self.map.len(),
+            disk_size: self.snapshots.as_ref().map_or(0, |file| file.size()),
+            ..EngineStats::default()
+        })
+    }
+
+    fn snapshot(&self) -> Option<Snapshot> {
+        let file = self.snapshots.as_ref()?;
+        if file.is_saved(self.version) {
+            return None;
+        }
+        Some(Snapshot {
+            pairs: self.map.clone(),
+            version: self.version,
+            file: file.clone(),
+        })
+    }
+
+    fn watch(&self, pattern: KeyPattern, after: Option<Position>) -> Result<Watcher> {
+        self.feed.subscribe(pattern, after)
+    }
+
     fn name(&self) -> &'static str {
         "MemStore"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_map() {
+        let pairs = (0..1000).map(|index| (format!("key{}", index), "value".to_owned()));
+        let mut map = SharedMap::from(pairs.collect::<HashMap<_, _>>());
+        // As a snapshot holds it
+        let shared = map.clone();
+        assert_eq!(
+            map.insert("key0".to_owned(), "value0".to_owned())
+                .as_deref(),
+            Some("value")
+        );
+        assert_eq!(map.insert("new".to_owned(), "value".to_owned()), None);
+        assert_eq!(map.remove("key1").as_deref(), Some("value"));
+        assert_eq!(map.remove("missing"), None);
+        // The writes only went aside
+        assert!(Arc::ptr_eq(&map.base, &shared.base));
+        assert_eq!(map.changes.len(), 3);
+        assert_eq!(map.get("key0").map(String::as_str), Some("value0"));
+        assert_eq!(map.get("key1"), None);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.iter().count(), 1000);
+        assert_eq!(shared.get("key0").map(String::as_str), Some("value"));
+        assert_eq!(shared.get("new"), None);
+
+        // The first write once the snapshot is done folds them in
+        drop(shared);
+        map.remove("new");
+        assert!(map.changes.is_empty());
+        assert_eq!(map.base.len(), 999);
+        assert_eq!(map.len(), 999);
+    }
+
+    #[test]
+    fn write_during_snapshot() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let mut store = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        for index in 0..1000 {
+            store
+                .set(format!("key{}", index), "value".to_owned())
+                .unwrap();
+        }
+        let snapshot = store.snapshot().unwrap();
+        store.set("key0".to_owned(), "value0".to_owned()).unwrap();
+        // Neither the pairs nor the changes made before the snapshot were copied
+        assert!(Arc::ptr_eq(&store.map.base, &snapshot.pairs.base));
+        assert_eq!(store.map.changes.len(), 1);
+        assert_eq!(snapshot.write().unwrap(), 1000);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::err::Result;
+use crate::namespace::NamespaceInfo;
+use crate::{CommandResponse, EngineStats};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
+
+/// How a command ended, as far as the metrics are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Ok,
+    /// The command ran but its key doesn't exist, e.g. `GET` of a missing key.
+    NotFound,
+    Error,
+}
+
+impl Outcome {
+    pub fn of(response: &Result<CommandResponse>) -> Self {
+        match response {
+            Ok(CommandResponse::Get { value: None }) | Ok(CommandResponse::Rm { value: None }) => {
+                Outcome::NotFound
+            }
+            Ok(CommandResponse::WrongType(_)) => Outcome::Error,
+            Ok(_) => Outcome::Ok,
+            Err(_) => Outcome::Error,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::NotFound => "not_found",
+            Outcome::Error => "error",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, the last one being `+Inf`. Made cumulative when rendered.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct MetricsState {
+    commands: BTreeMap<(&'static str, Outcome), u64>,
+    latencies: BTreeMap<&'static str, Histogram>,
+}
+
+/// Counters and latency histograms of the commands handled by a server, rendered in the
+/// Prometheus text format together with the bookkeeping of its engine and its namespaces.
+#[derive(Default)]
+pub struct Metrics {
+    state: Mutex<MetricsState>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&self, command: &'static str, outcome: Outcome, elapsed: Duration) {
+        // Metrics aren't worth failing a request over, so a poisoned lock is used as is
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        *state.commands.entry((command, outcome)).or_default() += 1;
+        state
+            .latencies
+            .entry(command)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// Renders the metrics, `stats` being those of the engine of the default namespace.
+    pub fn render(&self, stats: &EngineStats, namespaces: &[NamespaceInfo]) -> String {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "kvs_commands_total",
+            "counter",
+            "Commands handled, by command and outcome.",
+        );
+        for ((command, outcome), count) in &state.commands {
+            let _ = writeln!(
+                out,
+                "kvs_commands_total{{command=\"{}\",outcome=\"{}\"}} {}",
+                command,
+                outcome.as_str(),
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "kvs_command_duration_seconds",
+            "histogram",
+            "Time taken to handle a command, by command.",
+        );
+        for (command, histogram) in &state.latencies {
+            let mut cumulative = 0;
+            for (index, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count;
+                let bound = match LATENCY_BUCKETS.get(index) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_owned(),
+                };
+                let _ = writeln!(
+                    out,
+                    "kvs_command_duration_seconds_bucket{{command=\"{}\",le=\"{}\"}} {}",
+                    command, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "kvs_command_duration_seconds_sum{{command=\"{}\"}} {}",
+                command, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "kvs_command_duration_seconds_count{{command=\"{}\"}} {}",
+                command, histogram.count
+            );
+        }
+
+        single_value(
+            &mut out,
+            "kvs_engine_keys",
+            "gauge",
+            "Number of keys holding a value.",
+            Some(stats.live_keys as f64),
+        );
+        single_value(
+            &mut out,
+            "kvs_engine_disk_bytes",
+            "gauge",
+            "Bytes taken on disk by the database files.",
+            Some(stats.disk_size as f64),
+        );
+        single_value(
+            &mut out,
+            "kvs_engine_stale_ratio",
+            "gauge",
+            "Share of the records on disk that are stale.",
+            stats.stale_ratio(),
+        );
+        single_value(
+            &mut out,
+            "kvs_engine_compactions_total",
+            "counter",
+            "Compactions run since the server started.",
+            stats.compactions.map(|compactions| compactions as f64),
+        );
+        single_value(
+            &mut out,
+            "kvs_engine_compaction_seconds_total",
+            "counter",
+            "Time spent compacting since the server started.",
+            stats.compaction_time.map(|time| time.as_secs_f64()),
+        );
+        single_value(
+            &mut out,
+            "kvs_engine_compression_ratio",
+            "gauge",
+            "How many times smaller the values are once stored.",
+            stats.compression_ratio(),
+        );
+
+        header(
+            &mut out,
+            "kvs_namespace_keys",
+            "gauge",
+            "Number of keys holding a value, by namespace.",
+        );
+        for namespace in namespaces {
+            let _ = writeln!(
+                out,
+                "kvs_namespace_keys{{namespace=\"{}\"}} {}",
+                namespace.name, namespace.usage.keys
+            );
+        }
+        header(
+            &mut out,
+            "kvs_namespace_bytes",
+            "gauge",
+            "Bytes taken by the keys and values, by namespace.",
+        );
+        for namespace in namespaces {
+            let _ = writeln!(
+                out,
+                "kvs_namespace_bytes{{namespace=\"{}\"}} {}",
+                namespace.name, namespace.usage.bytes
+            );
+        }
+        header(
+            &mut out,
+            "kvs_namespace_evictions_total",
+            "counter",
+            "Keys evicted to make room for writes, by namespace.",
+        );
+        for namespace in namespaces {
+            let _ = writeln!(
+                out,
+                "kvs_namespace_evictions_total{{namespace=\"{}\"}} {}",
+                namespace.name, namespace.evictions
+            );
+        }
+        header(
+            &mut out,
+            "kvs_namespace_rejected_writes_total",
+            "counter",
+            "Writes refused for lack of memory or disk, by namespace.",
+        );
+        for namespace in namespaces {
+            let _ = writeln!(
+                out,
+                "kvs_namespace_rejected_writes_total{{namespace=\"{}\"}} {}",
+                namespace.name, namespace.rejected_writes
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Writes a metric holding a single value. Engines without the value don't get the metric.
+fn single_value(out: &mut String, name: &str, kind: &str, help: &str, value: Option<f64>) {
+    if let Some(value) = value {
+        header(out, name, kind, help);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::{NamespaceConfig, Usage};
+    use snafu::FromString;
+
+    #[test]
+    fn outcome() {
+        let missing = Ok(CommandResponse::Get { value: None });
+        let found = Ok(CommandResponse::Get {
+            value: Some("value".to_owned()),
+        });
+        let failed = Err(crate::Error::without_source("failed".to_owned()));
+        assert_eq!(Outcome::of(&missing), Outcome::NotFound);
+        assert_eq!(Outcome::of(&found), Outcome::Ok);
+        assert_eq!(Outcome::of(&Ok(CommandResponse::Set)), Outcome::Ok);
+        assert_eq!(Outcome::of(&failed), Outcome::Error);
+    }
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::new();
+        metrics.observe("get", Outcome::Ok, Duration::from_micros(200));
+        metrics.observe("get", Outcome::NotFound, Duration::from_millis(2));
+        metrics.observe("set", Outcome::Ok, Duration::from_secs(10));
+        let stats = EngineStats {
+            live_keys: 3,
+            stale_records: Some(1),
+            disk_size: 120,
+            compactions: Some(2),
+            value_size: Some(300),
+            stored_value_size: Some(100),
+            ..EngineStats::default()
+        };
+
+        let namespaces = [NamespaceInfo {
+            name: "team1".to_owned(),
+            config: NamespaceConfig::default(),
+            usage: Usage { keys: 2, bytes: 40 },
+            evictions: 3,
+            rejected_writes: 1,
+        }];
+
+        let rendered = metrics.render(&stats, &namespaces);
+        for line in [
+            "# TYPE kvs_commands_total counter",
+            "kvs_commands_total{command=\"get\",outcome=\"ok\"} 1",
+            "kvs_commands_total{command=\"get\",outcome=\"not_found\"} 1",
+            "kvs_command_duration_seconds_bucket{command=\"get\",le=\"0.0001\"} 0",
+            "kvs_command_duration_seconds_bucket{command=\"get\",le=\"0.00025\"} 1",
+            "kvs_command_duration_seconds_bucket{command=\"get\",le=\"0.0025\"} 2",
+            "kvs_command_duration_seconds_bucket{command=\"set\",le=\"5\"} 0",
+            "kvs_command_duration_seconds_bucket{command=\"set\",le=\"+Inf\"} 1",
+            "kvs_command_duration_seconds_count{command=\"get\"} 2",
+            "kvs_engine_keys 3",
+            "kvs_engine_disk_bytes 120",
+            "kvs_engine_stale_ratio 0.25",
+            "kvs_engine_compactions_total 2",
+            "kvs_engine_compression_ratio 3",
+            "kvs_namespace_keys{namespace=\"team1\"} 2",
+            "kvs_namespace_bytes{namespace=\"team1\"} 40",
+            "kvs_namespace_evictions_total{namespace=\"team1\"} 3",
+            "kvs_namespace_rejected_writes_total{namespace=\"team1\"} 1",
+        ] {
+            assert!(
+                rendered.lines().any(|rendered| rendered == line),
+                "{} missing from\n{}",
+                line,
+                rendered
+            );
+        }
+        // Engines without a log don't report compactions
+        assert!(!rendered.contains("kvs_engine_compaction_seconds_total"));
+    }
+}
@@ -0,0 +1,894 @@
+//! Namespaces: separate keyspaces served by one server, each with an engine of its own.
+//!
+//! The `default` namespace is the engine kept in the data directory itself, and is where
+//! requests naming no namespace go. Other namespaces are created and dropped while the server
+//! runs, and are listed in [`MANIFEST_FILE_NAME`] within the data directory so that they are
+//! opened again when it restarts. Their engine is kept in `ns/<name>` within the data directory,
+//! unless they were given a directory of their own.
+//!
+//! Every namespace keeps track of its keys and of the bytes they take, keys and values as the
+//! engine is given them, which [`NamespaceConfig`] quotas are checked against. The bytes of all
+//! of them count against the [`Storage`] of the server, which a write going past its limit evicts
+//! keys of its namespace for, see [`crate::eviction`].
+
+use crate::engine::{evaluate_command, evaluate_read_command, WriteOp};
+use crate::err::{NamespaceNotFoundSnafu, OutOfMemorySnafu, QuotaExceededSnafu, Result, ResultExt};
+use crate::eviction::{KeyTracker, Storage};
+use crate::script::{evaluate_script, Outcome};
+use crate::{Command, CommandResponse, KvsEngine};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, whatever};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+/// The namespace of the requests naming none.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// The file listing the namespaces besides the default one, within the data directory.
+pub const MANIFEST_FILE_NAME: &str = "namespaces.json";
+
+/// The directory keeping the namespaces that weren't given a directory of their own, within the
+/// data directory.
+pub const NAMESPACES_DIR_NAME: &str = "ns";
+
+/// Longest name a namespace may have.
+const MAX_NAME_LEN: usize = 64;
+
+/// The bytes an `INCRBY` may add at most: a key holding the longest 64-bit integer.
+const MAX_INTEGER_LEN: u64 = "-9223372036854775808".len() as u64;
+
+/// Opens the engine of a namespace kept in the given directory, which may not exist yet. What
+/// comes with the engine is kept alive as long as the namespace is, e.g. a lock on the directory.
+pub type Opener = Box<
+    dyn Fn(&Path) -> Result<(Arc<RwLock<dyn KvsEngine>>, Box<dyn Any + Send + Sync>)> + Send + Sync,
+>;
+
+/// How a namespace is set up when it is created.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NamespaceConfig {
+    /// The directory to keep the engine in, instead of `ns/<name>` within the data directory.
+    /// It is left in place when the namespace is dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dir: Option<PathBuf>,
+    /// Keys the namespace may hold.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_keys: Option<u64>,
+    /// Bytes its keys and values may take.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+/// What a namespace holds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub keys: u64,
+    /// Bytes of the keys and values, as the engine is given them.
+    pub bytes: u64,
+}
+
+/// A namespace as listed by [`Namespaces::list`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub config: NamespaceConfig,
+    #[serde(flatten)]
+    pub usage: Usage,
+    /// Keys evicted to make room for writes since the server started.
+    #[serde(default)]
+    pub evictions: u64,
+    /// Writes refused for lack of room since the server started.
+    #[serde(default)]
+    pub rejected_writes: u64,
+}
+
+/// A command managing the namespaces of a server rather than the keys of one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NamespaceCommand {
+    Create {
+        name: String,
+        config: NamespaceConfig,
+    },
+    Drop {
+        name: String,
+    },
+    List,
+}
+
+/// A keyspace and the engine keeping it.
+pub struct Namespace {
+    name: String,
+    config: NamespaceConfig,
+    /// Where the engine is kept.
+    dir: PathBuf,
+    store: Arc<RwLock<dyn KvsEngine>>,
+    /// Only changed under the write lock of the store.
+    usage: Mutex<Usage>,
+    storage: Arc<Storage>,
+    /// The keys to pick evictions from, only kept when the storage evicts any.
+    tracker: Mutex<KeyTracker>,
+    evictions: AtomicU64,
+    rejected_writes: AtomicU64,
+    _guard: Box<dyn Any + Send + Sync>,
+}
+
+impl Namespace {
+    fn open(
+        name: &str,
+        config: NamespaceConfig,
+        dir: PathBuf,
+        (store, guard): (Arc<RwLock<dyn KvsEngine>>, Box<dyn Any + Send + Sync>),
+        storage: Arc<Storage>,
+    ) -> Result<Self> {
+        let mut tracker = KeyTracker::default();
+        let usage = match store.read() {
+            Ok(store) => measure(store.deref(), storage.evicts().then_some(&mut tracker))?,
+            Err(_) => whatever!("Unable to acquire read lock on state"),
+        };
+        storage.update(0, usage.bytes);
+        Ok(Self {
+            name: name.to_owned(),
+            config,
+            dir,
+            store,
+            usage: Mutex::new(usage),
+            storage,
+            tracker: Mutex::new(tracker),
+            evictions: AtomicU64::new(0),
+            rejected_writes: AtomicU64::new(0),
+            _guard: guard,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &NamespaceConfig {
+        &self.config
+    }
+
+    /// The engine of the namespace. Writing to it directly skips the quotas and leaves
+    /// [`Namespace::usage`] behind, so writes are better run through [`Namespace::evaluate`].
+    pub fn store(&self) -> &Arc<RwLock<dyn KvsEngine>> {
+        &self.store
+    }
+
+    pub fn usage(&self) -> Usage {
+        *self.usage.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub fn info(&self) -> NamespaceInfo {
+        NamespaceInfo {
+            name: self.name.clone(),
+            config: self.config.clone(),
+            usage: self.usage(),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            rejected_writes: self.rejected_writes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Runs any command under the write lock, refusing the writes that would take the namespace
+    /// over its quotas.
+    pub fn evaluate(&self, command: &Command) -> Result<CommandResponse> {
+        let Ok(mut store) = self.store.write() else {
+            whatever!("Unable to acquire write lock on state");
+        };
+        let store = store.deref_mut();
+        if let Command::Restore { pairs } = command {
+            let restored = pairs
+                .iter()
+                .fold(Usage::default(), |usage, (key, value)| Usage {
+                    keys: usage.keys + 1,
+                    bytes: usage.bytes + (key.len() + value.len()) as u64,
+                });
+            self.check_quotas(restored)?;
+            self.make_room(store, restored.bytes, &[])?;
+            let response = evaluate_command(command, store)?;
+            let mut tracker = self.tracker();
+            tracker.clear();
+            let usage = measure(store, self.storage.evicts().then_some(&mut tracker))?;
+            self.set_usage(usage);
+            return Ok(response);
+        }
+        if let Command::Eval { .. } | Command::EvalSha { .. } = command {
+            return self.evaluate_script(command, store);
+        }
+        let Some(key) = written_key(command) else {
+            if let Some(key) = read_key(command) {
+                self.touch(key);
+            }
+            return evaluate_command(command, store);
+        };
+
+        let before = size_of(store, key)?;
+        let after = match command {
+            Command::Rm { .. } => None,
+            Command::Set { key, value } => Some((key.len() + value.len()) as u64),
+            // Typed values are only known once written, so the bytes they bring are checked
+            _ => Some(before.unwrap_or(key.len() as u64) + brought_bytes(command)),
+        };
+        if let Some(after) = after {
+            let usage = self.usage();
+            let expected = Usage {
+                keys: usage.keys + u64::from(before.is_none()),
+                bytes: (usage.bytes + after).saturating_sub(before.unwrap_or(0)),
+            };
+            // Writes that don't grow the namespace are let through even when it is over quota,
+            // e.g. after its quota was lowered
+            if expected.keys > usage.keys || expected.bytes > usage.bytes {
+                self.check_quotas(expected)?;
+            }
+            self.make_room(store, expected.bytes.saturating_sub(usage.bytes), &[key])?;
+        }
+
+        let response = evaluate_command(command, store)?;
+        let after = size_of(store, key)?;
+        let mut usage = self.usage();
+        usage.keys =
+            (usage.keys + u64::from(after.is_some())).saturating_sub(u64::from(before.is_some()));
+        usage.bytes = (usage.bytes + after.unwrap_or(0)).saturating_sub(before.unwrap_or(0));
+        self.set_usage(usage);
+        match after {
+            Some(_) => self.touch(key),
+            None => self.tracker().forget(key),
+        }
+        Ok(response)
+    }
+
+    /// Runs a script, checking the quotas against its writes before applying them.
+    fn evaluate_script(
+        &self,
+        command: &Command,
+        store: &mut dyn KvsEngine,
+    ) -> Result<CommandResponse> {
+        let Outcome { result, writes } = evaluate_script(command, store)?;
+        let usage = self.usage();
+        let mut expected = usage;
+        for write in &writes {
+            let (key, after) = match write {
+                WriteOp::Set { key, value } => (key, Some((key.len() + value.len()) as u64)),
+                WriteOp::Rm { key } => (key, None),
+            };
+            let before = size_of(store, key)?;
+            expected.keys = (expected.keys + u64::from(after.is_some()))
+                .saturating_sub(u64::from(before.is_some()));
+            expected.bytes =
+                (expected.bytes + after.unwrap_or(0)).saturating_sub(before.unwrap_or(0));
+        }
+        if expected.keys > usage.keys || expected.bytes > usage.bytes {
+            self.check_quotas(expected)?;
+        }
+        let keys: Vec<_> = writes.iter().map(WriteOp::key).collect();
+        self.make_room(store, expected.bytes.saturating_sub(usage.bytes), &keys)?;
+        let written: Vec<_> = writes
+            .iter()
+            .map(|write| (write.key().to_owned(), matches!(write, WriteOp::Set { .. })))
+            .collect();
+        store.write_batch(writes)?;
+        // Evictions made room meanwhile, so the writes are applied to what is left
+        let current = self.usage();
+        self.set_usage(Usage {
+            keys: (current.keys + expected.keys).saturating_sub(usage.keys),
+            bytes: (current.bytes + expected.bytes).saturating_sub(usage.bytes),
+        });
+        for (key, set) in written {
+            if set {
+                self.touch(&key);
+            } else {
+                self.tracker().forget(&key);
+            }
+        }
+        Ok(CommandResponse::Eval { result })
+    }
+
+    /// Runs a command that only reads, under the read lock.
+    pub fn evaluate_read(&self, command: &Command) -> Result<CommandResponse> {
+        if let Some(key) = read_key(command) {
+            self.touch(key);
+        }
+        match self.store.read() {
+            Ok(store) => evaluate_read_command(command, store.deref()),
+            Err(_) => whatever!("Unable to acquire read lock on state"),
+        }
+    }
+
+    /// Evicts keys of the namespace other than `keep` until `growth` more bytes fit in the
+    /// storage, failing when the policy finds nothing more to evict.
+    fn make_room(&self, store: &mut dyn KvsEngine, growth: u64, keep: &[&str]) -> Result<()> {
+        if !self.storage.would_exceed(growth) {
+            return Ok(());
+        }
+        // Nothing is evicted for a write that wouldn't fit even with the namespace emptied
+        let mut kept = 0;
+        for key in keep {
+            kept += size_of(store, key)?.unwrap_or(0);
+        }
+        let evictable = self.usage().bytes.saturating_sub(kept);
+        let fits = self.storage.limit().is_none_or(|(_, max)| {
+            let left = self.storage.used().saturating_sub(evictable);
+            left.saturating_add(growth) <= max
+        });
+        while self.storage.would_exceed(growth) {
+            let victim = self.tracker().victim(self.storage.policy(), keep);
+            let (Some(key), true) = (victim, fits) else {
+                self.rejected_writes.fetch_add(1, Ordering::Relaxed);
+                let (what, max) = self.storage.limit().unwrap_or_default();
+                return OutOfMemorySnafu { what, max }.fail();
+            };
+            self.tracker().forget(&key);
+            // Removed through the engine, so that its index entries go and watchers hear of it
+            if let Some(size) = size_of(store, &key)? {
+                store.remove(key)?;
+                let usage = self.usage();
+                self.set_usage(Usage {
+                    keys: usage.keys.saturating_sub(1),
+                    bytes: usage.bytes.saturating_sub(size),
+                });
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_usage(&self, usage: Usage) {
+        let mut current = self.usage.lock().unwrap_or_else(|err| err.into_inner());
+        self.storage.update(current.bytes, usage.bytes);
+        *current = usage;
+    }
+
+    fn tracker(&self) -> MutexGuard<'_, KeyTracker> {
+        self.tracker.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Marks `key` as just used, for evicting the least recently used keys first.
+    fn touch(&self, key: &str) {
+        if self.storage.evicts() {
+            self.tracker().touch(key);
+        }
+    }
+
+    fn check_quotas(&self, usage: Usage) -> Result<()> {
+        if let Some(max) = self.config.max_keys {
+            ensure!(
+                usage.keys <= max,
+                QuotaExceededSnafu {
+                    namespace: &self.name,
+                    what: "keys",
+                    max
+                }
+            );
+        }
+        if let Some(max) = self.config.max_bytes {
+            ensure!(
+                usage.bytes <= max,
+                QuotaExceededSnafu {
+                    namespace: &self.name,
+                    what: "bytes",
+                    max
+                }
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        self.storage.update(self.usage().bytes, 0);
+    }
+}
+
+/// The key a command writes, for the commands writing a single one.
+fn written_key(command: &Command) -> Option<&str> {
+    match command {
+        Command::Set { key, .. }
+        | Command::Rm { key }
+        | Command::LPush { key, .. }
+        | Command::HSet { key, .. }
+        | Command::SAdd { key, .. }
+        | Command::IncrBy { key, .. } => Some(key),
+        _ => None,
+    }
+}
+
+/// The key a command reads, for the commands reading a single one.
+fn read_key(command: &Command) -> Option<&str> {
+    match command {
+        Command::Get { key }
+        | Command::LRange { key, .. }
+        | Command::HGet { key, .. }
+        | Command::SMembers { key } => Some(key),
+        _ => None,
+    }
+}
+
+/// The bytes a command on a typed value adds to it at most, besides the key.
+fn brought_bytes(command: &Command) -> u64 {
+    let total = |words: &[String]| words.iter().map(|word| word.len() as u64).sum();
+    match command {
+        Command::LPush { values, .. } => total(values),
+        Command::SAdd { members, .. } => total(members),
+        Command::HSet { field, value, .. } => (field.len() + value.len()) as u64,
+        Command::IncrBy { .. } => MAX_INTEGER_LEN,
+        _ => 0,
+    }
+}
+
+/// The bytes `key` and its value take, or `None` when it holds no value.
+fn size_of(store: &dyn KvsEngine, key: &str) -> Result<Option<u64>> {
+    let value = store.get(key.to_owned())?;
+    Ok(value.map(|value| (key.len() + value.len()) as u64))
+}
+
+/// Counts the keys of `store` and the bytes they take, going through all of them, and tracks
+/// them with `tracker` if given one.
+fn measure(store: &dyn KvsEngine, mut tracker: Option<&mut KeyTracker>) -> Result<Usage> {
+    let mut usage = Usage::default();
+    for pair in store.scan(String::new())? {
+        let (key, value) = pair?;
+        usage.keys += 1;
+        usage.bytes += (key.len() + value.len()) as u64;
+        if let Some(tracker) = tracker.as_deref_mut() {
+            tracker.touch(&key);
+        }
+    }
+    Ok(usage)
+}
+
+/// The namespaces of a server.
+pub struct Namespaces {
+    data_dir: PathBuf,
+    opener: Opener,
+    storage: Arc<Storage>,
+    /// The default namespace included. Creating and dropping namespaces holds the write lock
+    /// until the manifest is written.
+    namespaces: RwLock<BTreeMap<String, Arc<Namespace>>>,
+}
+
+impl Namespaces {
+    /// Opens the namespaces listed in the manifest of `data_dir` with `opener`, `default` being
+    /// the engine of the default namespace.
+    pub fn open(
+        data_dir: impl Into<PathBuf>,
+        default: Arc<RwLock<dyn KvsEngine>>,
+        opener: Opener,
+    ) -> Result<Self> {
+        Self::open_with_storage(data_dir, default, opener, Storage::unbounded())
+    }
+
+    /// Same as [`Namespaces::open`], the data of every namespace counting against `storage`.
+    pub fn open_with_storage(
+        data_dir: impl Into<PathBuf>,
+        default: Arc<RwLock<dyn KvsEngine>>,
+        opener: Opener,
+        storage: Storage,
+    ) -> Result<Self> {
+        let data_dir = data_dir.into();
+        let storage = Arc::new(storage);
+        let mut namespaces = BTreeMap::new();
+        let default = Namespace::open(
+            DEFAULT_NAMESPACE,
+            NamespaceConfig::default(),
+            data_dir.clone(),
+            (default, Box::new(())),
+            storage.clone(),
+        )?;
+        namespaces.insert(DEFAULT_NAMESPACE.to_owned(), Arc::new(default));
+
+        let manifest_path = data_dir.join(MANIFEST_FILE_NAME);
+        let manifest: BTreeMap<String, NamespaceConfig> = match fs::read(&manifest_path) {
+            Ok(manifest) => serde_json::from_slice(&manifest).with_whatever_context(|_| {
+                format!("Invalid namespace manifest {}", manifest_path.display())
+            })?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => whatever!(
+                "Couldn't read namespace manifest {}: {}",
+                manifest_path.display(),
+                err
+            ),
+        };
+        for (name, config) in manifest {
+            let dir = namespace_dir(&data_dir, &name, &config);
+            let engine = opener(&dir)
+                .with_whatever_context(|_| format!("Couldn't open namespace {}", name))?;
+            let namespace = Namespace::open(&name, config, dir, engine, storage.clone())?;
+            namespaces.insert(name, Arc::new(namespace));
+        }
+
+        Ok(Self {
+            data_dir,
+            opener,
+            storage,
+            namespaces: RwLock::new(namespaces),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Namespace>> {
+        let Ok(namespaces) = self.namespaces.read() else {
+            whatever!("Unable to acquire read lock on namespaces");
+        };
+        match namespaces.get(name) {
+            Some(namespace) => Ok(namespace.clone()),
+            None => NamespaceNotFoundSnafu { name }.fail(),
+        }
+    }
+
+    pub fn default_namespace(&self) -> Result<Arc<Namespace>> {
+        self.get(DEFAULT_NAMESPACE)
+    }
+
+    /// Creates a namespace and opens its engine. A directory of its own may already hold data,
+    /// which the namespace then starts with.
+    pub fn create(&self, name: &str, config: NamespaceConfig) -> Result<Arc<Namespace>> {
+        validate_name(name)?;
+        let Ok(mut namespaces) = self.namespaces.write() else {
+            whatever!("Unable to acquire write lock on namespaces");
+        };
+        if namespaces.contains_key(name) {
+            whatever!("Namespace {} already exists", name);
+        }
+        let dir = namespace_dir(&self.data_dir, name, &config);
+        if let Some(other) = namespaces.values().find(|namespace| namespace.dir == dir) {
+            whatever!(
+                "Directory {} is already used by namespace {}",
+                dir.display(),
+                other.name
+            );
+        }
+        let engine = (self.opener)(&dir)?;
+        let namespace = Arc::new(Namespace::open(
+            name,
+            config,
+            dir,
+            engine,
+            self.storage.clone(),
+        )?);
+        namespaces.insert(name.to_owned(), namespace.clone());
+        if let Err(err) = self.write_manifest(&namespaces) {
+            namespaces.remove(name);
+            return Err(err);
+        }
+        Ok(namespace)
+    }
+
+    /// Drops a namespace, deleting its data unless it was given a directory of its own.
+    /// Requests already running against it still complete.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_NAMESPACE {
+            whatever!("The {} namespace can't be dropped", DEFAULT_NAMESPACE);
+        }
+        let Ok(mut namespaces) = self.namespaces.write() else {
+            whatever!("Unable to acquire write lock on namespaces");
+        };
+        let Some(namespace) = namespaces.remove(name) else {
+            return NamespaceNotFoundSnafu { name }.fail();
+        };
+        if let Err(err) = self.write_manifest(&namespaces) {
+            namespaces.insert(name.to_owned(), namespace);
+            return Err(err);
+        }
+        if namespace.config.dir.is_none() && namespace.dir.exists() {
+            fs::remove_dir_all(&namespace.dir).with_whatever_context(|_| {
+                format!("Couldn't delete directory {}", namespace.dir.display())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// What the namespaces hold together, and the limit they are held to.
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    /// Every namespace, the default one included, in name order.
+    pub fn list(&self) -> Result<Vec<Arc<Namespace>>> {
+        let Ok(namespaces) = self.namespaces.read() else {
+            whatever!("Unable to acquire read lock on namespaces");
+        };
+        Ok(namespaces.values().cloned().collect())
+    }
+
+    /// Writes the manifest to a temporary file first, so that a crash leaves either the old or
+    /// the new one.
+    fn write_manifest(&self, namespaces: &BTreeMap<String, Arc<Namespace>>) -> Result<()> {
+        let manifest: BTreeMap<_, _> = namespaces
+            .values()
+            .filter(|namespace| namespace.name != DEFAULT_NAMESPACE)
+            .map(|namespace| (&namespace.name, &namespace.config))
+            .collect();
+        let manifest = serde_json::to_vec_pretty(&manifest)
+            .with_whatever_context(|_| "Couldn't serialize the namespace manifest")?;
+        let path = self.data_dir.join(MANIFEST_FILE_NAME);
+        let temp_path = path.with_extension("json.tmp");
+        fs::write(&temp_path, manifest)
+            .and_then(|_| fs::rename(&temp_path, &path))
+            .with_whatever_context(|_| {
+                format!("Couldn't write namespace manifest {}", path.display())
+            })
+    }
+}
+
+fn namespace_dir(data_dir: &Path, name: &str, config: &NamespaceConfig) -> PathBuf {
+    match &config.dir {
+        Some(dir) => dir.clone(),
+        None => data_dir.join(NAMESPACES_DIR_NAME).join(name),
+    }
+}
+
+/// Names are used as directory names, URL segments and words of the TCP protocol, so they are
+/// kept to ASCII letters, digits, `-` and `_`.
+fn validate_name(name: &str) -> Result<()> {
+    let valid = name
+        .bytes()
+        .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !valid {
+        whatever!(
+            "Invalid namespace name {:?}: it must be 1 to {} letters, digits, - or _",
+            name,
+            MAX_NAME_LEN
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eviction::{EvictionPolicy, StorageLimits};
+    use crate::{Error, KvStoreV2, MemStore};
+    use tempfile::TempDir;
+
+    fn kvs_opener() -> Opener {
+        Box::new(|dir: &Path| {
+            fs::create_dir_all(dir).unwrap();
+            let store: Arc<RwLock<dyn KvsEngine>> = Arc::new(RwLock::new(KvStoreV2::open(dir)?));
+            Ok((store, Box::new(()) as Box<dyn Any + Send + Sync>))
+        })
+    }
+
+    fn open(data_dir: &Path) -> Namespaces {
+        let default = Arc::new(RwLock::new(KvStoreV2::open(data_dir).unwrap()));
+        Namespaces::open(data_dir, default, kvs_opener()).unwrap()
+    }
+
+    fn set(key: &str, value: &str) -> Command {
+        Command::Set {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    fn get(namespace: &Namespace, key: &str) -> Option<String> {
+        let command = Command::Get {
+            key: key.to_owned(),
+        };
+        match namespace.evaluate_read(&command).unwrap() {
+            CommandResponse::Get { value } => value,
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn isolation() {
+        let temp_dir = TempDir::new().unwrap();
+        let namespaces = open(temp_dir.path());
+        let team1 = namespaces
+            .create("team1", NamespaceConfig::default())
+            .unwrap();
+        let default = namespaces.default_namespace().unwrap();
+
+        team1.evaluate(&set("key1", "value1")).unwrap();
+        default.evaluate(&set("key1", "value2")).unwrap();
+        assert_eq!(get(&team1, "key1"), Some("value1".to_owned()));
+        assert_eq!(get(&default, "key1"), Some("value2".to_owned()));
+        assert!(temp_dir.path().join("ns").join("team1").is_dir());
+
+        let names: Vec<_> = namespaces
+            .list()
+            .unwrap()
+            .iter()
+            .map(|namespace| namespace.name().to_owned())
+            .collect();
+        assert_eq!(names, ["default", "team1"]);
+    }
+
+    #[test]
+    fn reopen() {
+        let temp_dir = TempDir::new().unwrap();
+        let own_dir = TempDir::new().unwrap();
+        let config = NamespaceConfig {
+            dir: Some(own_dir.path().to_owned()),
+            max_keys: Some(10),
+            max_bytes: None,
+        };
+        {
+            let namespaces = open(temp_dir.path());
+            let team1 = namespaces.create("team1", config.clone()).unwrap();
+            team1.evaluate(&set("key1", "value1")).unwrap();
+            namespaces
+                .create("team2", NamespaceConfig::default())
+                .unwrap();
+            namespaces.remove("team2").unwrap();
+        }
+
+        let namespaces = open(temp_dir.path());
+        let team1 = namespaces.get("team1").unwrap();
+        assert_eq!(team1.config(), &config);
+        assert_eq!(get(&team1, "key1"), Some("value1".to_owned()));
+        assert_eq!(team1.usage(), Usage { keys: 1, bytes: 10 });
+        assert!(matches!(
+            namespaces.get("team2"),
+            Err(Error::NamespaceNotFound { .. })
+        ));
+        assert!(!temp_dir.path().join("ns").join("team2").exists());
+
+        // Dropping a namespace with a directory of its own keeps its data
+        namespaces.remove("team1").unwrap();
+        assert!(own_dir.path().join(crate::DEFAULT_FILE_NAME_KVS).exists());
+    }
+
+    #[test]
+    fn quotas() {
+        let temp_dir = TempDir::new().unwrap();
+        let namespaces = open(temp_dir.path());
+        let config = NamespaceConfig {
+            dir: None,
+            max_keys: Some(2),
+            max_bytes: Some(20),
+        };
+        let team1 = namespaces.create("team1", config).unwrap();
+        let quota_exceeded =
+            |result: Result<CommandResponse>| matches!(result, Err(Error::QuotaExceeded { .. }));
+
+        team1.evaluate(&set("key1", "value1")).unwrap();
+        team1.evaluate(&set("key2", "value2")).unwrap();
+        assert_eq!(team1.usage(), Usage { keys: 2, bytes: 20 });
+        assert!(quota_exceeded(team1.evaluate(&set("key3", "v"))));
+        assert!(quota_exceeded(team1.evaluate(&set("key1", "value10"))));
+        let lpush = Command::LPush {
+            key: "key1".to_owned(),
+            values: vec!["a".to_owned()],
+        };
+        assert!(quota_exceeded(team1.evaluate(&lpush)));
+
+        // Shrinking or removing is always allowed
+        team1.evaluate(&set("key1", "v")).unwrap();
+        let rm = Command::Rm {
+            key: "key2".to_owned(),
+        };
+        team1.evaluate(&rm).unwrap();
+        assert_eq!(team1.usage(), Usage { keys: 1, bytes: 5 });
+        team1.evaluate(&set("key3", "v")).unwrap();
+        assert_eq!(team1.usage(), Usage { keys: 2, bytes: 10 });
+    }
+
+    fn open_bounded(data_dir: &Path, max_memory: u64, policy: EvictionPolicy) -> Namespaces {
+        let limits = StorageLimits {
+            max_memory: Some(max_memory),
+            max_disk: None,
+            eviction_policy: policy,
+        };
+        let default = Arc::new(RwLock::new(MemStore::new()));
+        let opener: Opener = Box::new(|_: &Path| {
+            let store: Arc<RwLock<dyn KvsEngine>> = Arc::new(RwLock::new(MemStore::new()));
+            Ok((store, Box::new(()) as Box<dyn Any + Send + Sync>))
+        });
+        let storage = Storage::new(&limits, true, false);
+        Namespaces::open_with_storage(data_dir, default, opener, storage).unwrap()
+    }
+
+    #[test]
+    fn eviction() {
+        let temp_dir = TempDir::new().unwrap();
+        let namespaces = open_bounded(temp_dir.path(), 30, EvictionPolicy::AllKeysLru);
+        let default = namespaces.default_namespace().unwrap();
+
+        for key in ["key1", "key2", "key3"] {
+            default.evaluate(&set(key, "value1")).unwrap();
+        }
+        // Reading key1 makes key2 the least recently used
+        assert_eq!(get(&default, "key1"), Some("value1".to_owned()));
+        default.evaluate(&set("key4", "value1")).unwrap();
+        assert_eq!(get(&default, "key2"), None);
+        assert_eq!(get(&default, "key3"), Some("value1".to_owned()));
+        assert_eq!(default.usage(), Usage { keys: 3, bytes: 30 });
+        assert_eq!(default.info().evictions, 1);
+        // A write that wouldn't fit anyway evicts nothing
+        assert!(matches!(
+            default.evaluate(&set("key5", &"v".repeat(30))),
+            Err(Error::OutOfMemory { .. })
+        ));
+        assert_eq!(default.usage().keys, 3);
+
+        // Other namespaces count too, but only evict their own keys
+        let team1 = namespaces
+            .create("team1", NamespaceConfig::default())
+            .unwrap();
+        assert!(matches!(
+            team1.evaluate(&set("key1", "v")),
+            Err(Error::OutOfMemory {
+                what: "memory",
+                max: 30
+            })
+        ));
+        assert_eq!(team1.info().rejected_writes, 1);
+        assert_eq!(default.info().rejected_writes, 1);
+        let rm = Command::Rm {
+            key: "key3".to_owned(),
+        };
+        default.evaluate(&rm).unwrap();
+        team1.evaluate(&set("key1", "v")).unwrap();
+        team1.evaluate(&set("key2", "v")).unwrap();
+        assert_eq!(namespaces.storage().used(), 30);
+        team1.evaluate(&set("key3", "v")).unwrap();
+        assert_eq!(get(&team1, "key1"), None);
+        assert_eq!(default.usage(), Usage { keys: 2, bytes: 20 });
+        namespaces.remove("team1").unwrap();
+        drop(team1);
+        assert_eq!(namespaces.storage().used(), 20);
+    }
+
+    #[test]
+    fn no_eviction() {
+        let temp_dir = TempDir::new().unwrap();
+        let namespaces = open_bounded(temp_dir.path(), 20, EvictionPolicy::NoEviction);
+        let default = namespaces.default_namespace().unwrap();
+        default.evaluate(&set("key1", "value1")).unwrap();
+        default.evaluate(&set("key2", "value2")).unwrap();
+        assert!(matches!(
+            default.evaluate(&set("key3", "v")),
+            Err(Error::OutOfMemory { .. })
+        ));
+        // Writes that don't grow the data go through
+        default.evaluate(&set("key1", "value0")).unwrap();
+        assert_eq!(default.info().evictions, 0);
+        assert_eq!(default.info().rejected_writes, 1);
+    }
+
+    #[test]
+    fn random_eviction() {
+        let temp_dir = TempDir::new().unwrap();
+        let namespaces = open_bounded(temp_dir.path(), 50, EvictionPolicy::Random);
+        let default = namespaces.default_namespace().unwrap();
+        for index in 0..20 {
+            default
+                .evaluate(&set(&format!("key{:02}", index), "value"))
+                .unwrap();
+        }
+        assert_eq!(default.usage().keys, 5);
+        assert_eq!(default.info().evictions, 15);
+        assert_eq!(get(&default, "key19"), Some("value".to_owned()));
+    }
+
+    #[test]
+    fn names() {
+        let temp_dir = TempDir::new().unwrap();
+        let default = Arc::new(RwLock::new(MemStore::new()));
+        let opener: Opener = Box::new(|_: &Path| {
+            let store: Arc<RwLock<dyn KvsEngine>> = Arc::new(RwLock::new(MemStore::new()));
+            Ok((store, Box::new(()) as Box<dyn Any + Send + Sync>))
+        });
+        let namespaces = Namespaces::open(temp_dir.path(), default, opener).unwrap();
+
+        for name in ["", "a/b", "..", "a b", &"a".repeat(65)] {
+            assert!(namespaces.create(name, NamespaceConfig::default()).is_err());
+        }
+        assert!(namespaces
+            .create("default", NamespaceConfig::default())
+            .is_err());
+        assert!(namespaces.remove("default").is_err());
+        namespaces
+            .create("team-1_a", NamespaceConfig::default())
+            .unwrap();
+        assert!(namespaces
+            .create("team-1_a", NamespaceConfig::default())
+            .is_err());
+    }
+}
@@ -0,0 +1,788 @@
+//! The line protocol of the TCP server: how clients write requests, and how the server reads
+//! them back into commands.
+//!
+//! A request is an optional `AUTH <token>` line, an optional `COMPRESS <algorithm>,...` line, an
+//! optional `SELECT <namespace>` line, the line of the command, split into words on whitespace,
+//! then the payload following that line. As words can't hold whitespace, [`encode`] refuses keys
+//! and values holding any.
+//!
+//! ```
+//! use kvs::limits::Limits;
+//! use kvs::protocol::{encode, parse_request, read_request};
+//! use kvs::Command;
+//!
+//! let command = Command::Set {
+//!     key: "key1".to_owned(),
+//!     value: "value1".to_owned(),
+//! };
+//! let request = read_request(encode(&command)?.as_slice(), &Limits::default())?;
+//! assert_eq!(parse_request(request.words, &request.payload)?, command);
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::backup::{read_backup, write_pairs};
+use crate::compression::Compression;
+use crate::err::Result;
+use crate::limits::Limits;
+use crate::namespace::{NamespaceCommand, NamespaceConfig};
+use crate::watch::{KeyPattern, Position};
+use crate::{Command, Error};
+use snafu::{whatever, ResultExt};
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::time::Instant;
+use tracing::instrument;
+
+/// Turns the incoming stream into readable words represented as a vector of
+/// strings.
+#[instrument(skip_all)]
+pub fn tokenize<T: Read>(stream: T) -> Result<Vec<String>> {
+    let buf_reader = BufReader::new(stream);
+    let words = buf_reader
+        .split(b' ')
+        .map(|vec_result| {
+            let vec: Vec<u8> = vec_result.with_whatever_context::<_, &str, Error>(|_| {
+                "Failed to parse stream to u8 vector"
+            })?;
+            let word: String =
+                String::from_utf8(vec).with_whatever_context::<_, &str, Error>(|_| {
+                    "Failed to parse u8 vector to UTF-8"
+                })?;
+            Ok::<String, Error>(word.trim().to_owned())
+        })
+        .filter(|result| match result {
+            Ok(word) => !word.is_empty(),
+            Err(_) => true,
+        })
+        .collect::<Result<Vec<String>>>()?;
+
+    Ok(words)
+}
+
+pub fn parse(words: Vec<String>) -> Result<Command> {
+    match &words[..] {
+        [command_str, key] if command_str.to_uppercase() == "GET" => {
+            Ok(Command::Get { key: key.clone() })
+        }
+        [command_str, key, value] if command_str.to_uppercase() == "SET" => Ok(Command::Set {
+            key: key.clone(),
+            value: value.clone(),
+        }),
+        [command_str, key] if command_str.to_uppercase() == "RM" => {
+            Ok(Command::Rm { key: key.clone() })
+        }
+        [command_str] if command_str.to_uppercase() == "SCAN" => Ok(Command::Scan {
+            prefix: String::new(),
+        }),
+        [command_str, prefix] if command_str.to_uppercase() == "SCAN" => Ok(Command::Scan {
+            prefix: prefix.clone(),
+        }),
+        [command_str] if command_str.to_uppercase() == "BACKUP" => Ok(Command::Backup),
+        [command_str, key, values @ ..]
+            if command_str.to_uppercase() == "LPUSH" && !values.is_empty() =>
+        {
+            Ok(Command::LPush {
+                key: key.clone(),
+                values: values.to_vec(),
+            })
+        }
+        [command_str, key, start, stop] if command_str.to_uppercase() == "LRANGE" => {
+            Ok(Command::LRange {
+                key: key.clone(),
+                start: parse_integer(start)?,
+                stop: parse_integer(stop)?,
+            })
+        }
+        [command_str, key, field, value] if command_str.to_uppercase() == "HSET" => {
+            Ok(Command::HSet {
+                key: key.clone(),
+                field: field.clone(),
+                value: value.clone(),
+            })
+        }
+        [command_str, key, field] if command_str.to_uppercase() == "HGET" => Ok(Command::HGet {
+            key: key.clone(),
+            field: field.clone(),
+        }),
+        [command_str, key, members @ ..]
+            if command_str.to_uppercase() == "SADD" && !members.is_empty() =>
+        {
+            Ok(Command::SAdd {
+                key: key.clone(),
+                members: members.to_vec(),
+            })
+        }
+        [command_str, key] if command_str.to_uppercase() == "SMEMBERS" => {
+            Ok(Command::SMembers { key: key.clone() })
+        }
+        [command_str, key, delta] if command_str.to_uppercase() == "INCRBY" => {
+            Ok(Command::IncrBy {
+                key: key.clone(),
+                delta: parse_integer(delta)?,
+            })
+        }
+        [command_str, index, value] if command_str.to_uppercase() == "FIND" => Ok(Command::Find {
+            index: index.clone(),
+            value: value.clone(),
+        }),
+        [command_str, hash, count, words @ ..] if command_str.to_uppercase() == "EVALSHA" => {
+            let (keys, args) = split_keys(count, words)?;
+            Ok(Command::EvalSha {
+                hash: hash.clone(),
+                keys,
+                args,
+            })
+        }
+        _ => whatever!("Invalid command"),
+    }
+}
+
+/// Splits the words following the number of keys of `EVAL` and `EVALSHA` into the keys and the
+/// arguments of the script.
+fn split_keys(count: &str, words: &[String]) -> Result<(Vec<String>, Vec<String>)> {
+    match count.parse::<usize>() {
+        Ok(count) if count <= words.len() => {
+            let (keys, args) = words.split_at(count);
+            Ok((keys.to_vec(), args.to_vec()))
+        }
+        _ => whatever!("{} isn't a number of keys, up to {}", count, words.len()),
+    }
+}
+
+fn parse_integer(word: &str) -> Result<i64> {
+    match word.parse() {
+        Ok(integer) => Ok(integer),
+        Err(_) => whatever!("{} isn't an integer", word),
+    }
+}
+
+/// A request as sent by a client: an optional `AUTH <token>` line, an optional
+/// `COMPRESS <algorithm>,...` line listing the compressions the client can read responses in, an
+/// optional `SELECT <namespace>` line naming the namespace to run the command in, the line of the
+/// command, then the payload following that line. Only `RESTORE` and `EVAL` carry a payload: the
+/// backup archive to load and the script to run.
+#[derive(Debug, Default, PartialEq)]
+pub struct Request {
+    pub token: Option<String>,
+    pub accepts: Vec<Compression>,
+    pub namespace: Option<String>,
+    pub words: Vec<String>,
+    pub payload: Vec<u8>,
+}
+
+fn split_line(bytes: &[u8]) -> (&[u8], &[u8]) {
+    match bytes.iter().position(|byte| *byte == b'\n') {
+        Some(index) => (&bytes[..index], &bytes[index + 1..]),
+        None => (bytes, &[]),
+    }
+}
+
+/// Reads the whole request, then splits it into its parts. A request larger than
+/// `max_request_size` is drained until the client is done sending it or `timeout` passes, so
+/// that the client gets to read the error instead of a reset connection.
+pub fn read_request<T: Read>(mut stream: T, limits: &Limits) -> Result<Request> {
+    let mut request = Vec::new();
+    // One byte more than allowed tells a request right at the limit from a larger one
+    let read = (&mut stream)
+        .take(limits.max_request_size as u64 + 1)
+        .read_to_end(&mut request);
+    if let Err(err) = read {
+        match err.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                whatever!("Timed out reading the request")
+            }
+            _ => whatever!("Error happened reading from stream {}", err),
+        }
+    }
+    if request.len() > limits.max_request_size {
+        let mut size = request.len();
+        let deadline = Instant::now() + limits.timeout();
+        let mut buf = [0; 8192];
+        while Instant::now() < deadline {
+            match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => size += read,
+            }
+        }
+        limits.check_request_size(size)?;
+    }
+    let mut parsed = Request::default();
+    let mut rest = &request[..];
+    loop {
+        let (line, after) = split_line(rest);
+        let words = tokenize(line)?;
+        match &words[..] {
+            [auth_str, token] if auth_str.to_uppercase() == "AUTH" && parsed.token.is_none() => {
+                parsed.token = Some(token.clone());
+            }
+            [compress_str, algorithms]
+                if compress_str.to_uppercase() == "COMPRESS" && parsed.accepts.is_empty() =>
+            {
+                // Algorithms unknown to this server are skipped, so that newer clients still get
+                // the ones it knows
+                parsed.accepts = algorithms
+                    .split(',')
+                    .filter_map(|algorithm| algorithm.parse().ok())
+                    .collect();
+            }
+            [select_str, namespace]
+                if select_str.to_uppercase() == "SELECT" && parsed.namespace.is_none() =>
+            {
+                parsed.namespace = Some(namespace.clone());
+            }
+            _ => {
+                parsed.words = words;
+                parsed.payload = after.to_vec();
+                return Ok(parsed);
+            }
+        }
+        rest = after;
+    }
+}
+
+#[instrument(name = "parse", skip_all)]
+pub fn parse_request(words: Vec<String>, payload: &[u8]) -> Result<Command> {
+    match &words[..] {
+        [command_str] if command_str.to_uppercase() == "RESTORE" => Ok(Command::Restore {
+            pairs: read_backup(payload)?,
+        }),
+        [command_str, count, words @ ..] if command_str.to_uppercase() == "EVAL" => {
+            let (keys, args) = split_keys(count, words)?;
+            let script = std::str::from_utf8(payload)
+                .with_whatever_context(|_| "Scripts have to be UTF-8")?;
+            Ok(Command::Eval {
+                script: script.to_owned(),
+                keys,
+                args,
+            })
+        }
+        _ => parse(words),
+    }
+}
+
+/// Parses `WATCH <key>` and `WATCH <prefix>*`, optionally followed by `FROM <epoch>-<seq>` to
+/// resume after the change at that position. Returns `None` for any other command.
+pub fn parse_watch(words: &[String]) -> Result<Option<(KeyPattern, Option<Position>)>> {
+    match words {
+        [command_str, pattern] if command_str.to_uppercase() == "WATCH" => {
+            Ok(Some((KeyPattern::parse(pattern), None)))
+        }
+        [command_str, pattern, from_str, position]
+            if command_str.to_uppercase() == "WATCH" && from_str.to_uppercase() == "FROM" =>
+        {
+            Ok(Some((KeyPattern::parse(pattern), Some(position.parse()?))))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Parses `NAMESPACE CREATE <name>`, optionally followed by `MAX_KEYS <n>`, `MAX_BYTES <n>` and
+/// `DIR <path>` in any order, `NAMESPACE DROP <name>` and `NAMESPACE LIST`. Returns `None` for any
+/// other command.
+pub fn parse_namespace_command(words: &[String]) -> Result<Option<NamespaceCommand>> {
+    let [command_str, action, rest @ ..] = words else {
+        return Ok(None);
+    };
+    if command_str.to_uppercase() != "NAMESPACE" {
+        return Ok(None);
+    }
+    match (action.to_uppercase().as_str(), rest) {
+        ("CREATE", [name, options @ ..]) => {
+            let mut config = NamespaceConfig::default();
+            for option in options.chunks(2) {
+                match option {
+                    [option_str, max] if option_str.to_uppercase() == "MAX_KEYS" => {
+                        config.max_keys = Some(parse_quota(max)?);
+                    }
+                    [option_str, max] if option_str.to_uppercase() == "MAX_BYTES" => {
+                        config.max_bytes = Some(parse_quota(max)?);
+                    }
+                    [option_str, dir] if option_str.to_uppercase() == "DIR" => {
+                        config.dir = Some(dir.into());
+                    }
+                    _ => whatever!("Invalid namespace option {}", option.join(" ")),
+                }
+            }
+            Ok(Some(NamespaceCommand::Create {
+                name: name.clone(),
+                config,
+            }))
+        }
+        ("DROP", [name]) => Ok(Some(NamespaceCommand::Drop { name: name.clone() })),
+        ("LIST", []) => Ok(Some(NamespaceCommand::List)),
+        _ => whatever!("Invalid namespace command"),
+    }
+}
+
+fn parse_quota(word: &str) -> Result<u64> {
+    match word.parse() {
+        Ok(quota) => Ok(quota),
+        Err(_) => whatever!("{} isn't a valid quota", word),
+    }
+}
+
+/// The server splits requests on spaces and trims the words, so keys and values must be words.
+pub fn check_word(what: &str, word: &str) -> Result<()> {
+    if word.is_empty() || word.contains(char::is_whitespace) {
+        whatever!(
+            "{} {:?} can't be empty or contain whitespace over TCP",
+            what,
+            word
+        );
+    }
+    Ok(())
+}
+
+pub fn encode(command: &Command) -> Result<Vec<u8>> {
+    match command {
+        Command::Get { key } => {
+            check_word("Key", key)?;
+            Ok(format!("GET {}", key).into_bytes())
+        }
+        Command::Set { key, value } => {
+            check_word("Key", key)?;
+            check_word("Value", value)?;
+            Ok(format!("SET {} {}", key, value).into_bytes())
+        }
+        Command::Rm { key } => {
+            check_word("Key", key)?;
+            Ok(format!("RM {}", key).into_bytes())
+        }
+        Command::Scan { prefix } if prefix.is_empty() => Ok(b"SCAN".to_vec()),
+        Command::Scan { prefix } => {
+            check_word("Prefix", prefix)?;
+            Ok(format!("SCAN {}", prefix).into_bytes())
+        }
+        Command::Backup => Ok(b"BACKUP".to_vec()),
+        Command::Restore { pairs } => {
+            let mut request = b"RESTORE\n".to_vec();
+            write_pairs(pairs.iter().cloned().map(Ok), &mut request)?;
+            Ok(request)
+        }
+        Command::LPush { key, values } => encode_words("LPUSH", key, values),
+        Command::LRange { key, start, stop } => {
+            check_word("Key", key)?;
+            Ok(format!("LRANGE {} {} {}", key, start, stop).into_bytes())
+        }
+        Command::HSet { key, field, value } => {
+            check_word("Key", key)?;
+            check_word("Field", field)?;
+            check_word("Value", value)?;
+            Ok(format!("HSET {} {} {}", key, field, value).into_bytes())
+        }
+        Command::HGet { key, field } => {
+            check_word("Key", key)?;
+            check_word("Field", field)?;
+            Ok(format!("HGET {} {}", key, field).into_bytes())
+        }
+        Command::SAdd { key, members } => encode_words("SADD", key, members),
+        Command::SMembers { key } => {
+            check_word("Key", key)?;
+            Ok(format!("SMEMBERS {}", key).into_bytes())
+        }
+        Command::IncrBy { key, delta } => {
+            check_word("Key", key)?;
+            Ok(format!("INCRBY {} {}", key, delta).into_bytes())
+        }
+        Command::Find { index, value } => {
+            check_word("Index", index)?;
+            check_word("Value", value)?;
+            Ok(format!("FIND {} {}", index, value).into_bytes())
+        }
+        // The script may hold any character, so it follows the line of the command
+        Command::Eval { script, keys, args } => {
+            let mut request = script_words("EVAL", keys, args)?;
+            request.push('\n');
+            request.push_str(script);
+            Ok(request.into_bytes())
+        }
+        Command::EvalSha { hash, keys, args } => {
+            check_word("Hash", hash)?;
+            let words = script_words(&format!("EVALSHA {}", hash), keys, args)?;
+            Ok(words.into_bytes())
+        }
+    }
+}
+
+/// `<name> <number of keys> <key>... <arg>...`, the line of the commands running scripts.
+fn script_words(name: &str, keys: &[String], args: &[String]) -> Result<String> {
+    let mut line = format!("{} {}", name, keys.len());
+    for key in keys {
+        check_word("Key", key)?;
+        line.push(' ');
+        line.push_str(key);
+    }
+    for arg in args {
+        check_word("Argument", arg)?;
+        line.push(' ');
+        line.push_str(arg);
+    }
+    Ok(line)
+}
+
+/// `<name> <key> <value>...`, for the commands taking at least one value.
+fn encode_words(name: &str, key: &str, values: &[String]) -> Result<Vec<u8>> {
+    check_word("Key", key)?;
+    if values.is_empty() {
+        whatever!("{} needs at least one value", name);
+    }
+    let mut request = format!("{} {}", name, key);
+    for value in values {
+        check_word("Value", value)?;
+        request.push(' ');
+        request.push_str(value);
+    }
+    Ok(request.into_bytes())
+}
+
+pub fn encode_namespace_command(command: &NamespaceCommand) -> Result<String> {
+    match command {
+        NamespaceCommand::Create { name, config } => {
+            check_word("Namespace", name)?;
+            let mut request = format!("NAMESPACE CREATE {}", name);
+            if let Some(max_keys) = config.max_keys {
+                request.push_str(&format!(" MAX_KEYS {}", max_keys));
+            }
+            if let Some(max_bytes) = config.max_bytes {
+                request.push_str(&format!(" MAX_BYTES {}", max_bytes));
+            }
+            if let Some(dir) = &config.dir {
+                let Some(dir) = dir.to_str() else {
+                    whatever!("Directory {} isn't valid UTF-8", dir.display());
+                };
+                check_word("Directory", dir)?;
+                request.push_str(&format!(" DIR {}", dir));
+            }
+            Ok(request)
+        }
+        NamespaceCommand::Drop { name } => {
+            check_word("Namespace", name)?;
+            Ok(format!("NAMESPACE DROP {}", name))
+        }
+        NamespaceCommand::List => Ok("NAMESPACE LIST".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    mod tokenize {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let test_table = vec![
+                (
+                    "word1 word2 word3",
+                    vec![
+                        "word1".to_string(),
+                        "word2".to_string(),
+                        "word3".to_string(),
+                    ],
+                ),
+                (
+                    "  word1   word2  word3    ",
+                    vec![
+                        "word1".to_string(),
+                        "word2".to_string(),
+                        "word3".to_string(),
+                    ],
+                ),
+                (
+                    "word1\nword2 word3",
+                    vec!["word1\nword2".to_string(), "word3".to_string()],
+                ),
+            ];
+
+            for (input, expected) in test_table {
+                let stream = Cursor::new(input.as_bytes());
+                let got = tokenize(stream).unwrap();
+                assert_eq!(got, expected);
+            }
+        }
+    }
+
+    mod parse {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let test_table = vec![
+                (
+                    "GET key1".to_string(),
+                    Command::Get {
+                        key: "key1".to_string(),
+                    },
+                ),
+                (
+                    "SET key1 value1".to_string(),
+                    Command::Set {
+                        key: "key1".to_string(),
+                        value: "value1".to_string(),
+                    },
+                ),
+                (
+                    "RM key1".to_string(),
+                    Command::Rm {
+                        key: "key1".to_string(),
+                    },
+                ),
+                (
+                    "   GET   spaced-key-command    ".to_string(),
+                    Command::Get {
+                        key: "spaced-key-command".to_string(),
+                    },
+                ),
+                (
+                    "SCAN user:".to_string(),
+                    Command::Scan {
+                        prefix: "user:".to_string(),
+                    },
+                ),
+                (
+                    "SCAN".to_string(),
+                    Command::Scan {
+                        prefix: String::new(),
+                    },
+                ),
+                (
+                    "FIND users.by_email a@b.c".to_string(),
+                    Command::Find {
+                        index: "users.by_email".to_string(),
+                        value: "a@b.c".to_string(),
+                    },
+                ),
+                ("BACKUP".to_string(), Command::Backup),
+                (
+                    "LPUSH key1 a b".to_string(),
+                    Command::LPush {
+                        key: "key1".to_string(),
+                        values: vec!["a".to_string(), "b".to_string()],
+                    },
+                ),
+                (
+                    "lrange key1 0 -1".to_string(),
+                    Command::LRange {
+                        key: "key1".to_string(),
+                        start: 0,
+                        stop: -1,
+                    },
+                ),
+                (
+                    "HSET key1 field1 value1".to_string(),
+                    Command::HSet {
+                        key: "key1".to_string(),
+                        field: "field1".to_string(),
+                        value: "value1".to_string(),
+                    },
+                ),
+                (
+                    "SMEMBERS key1".to_string(),
+                    Command::SMembers {
+                        key: "key1".to_string(),
+                    },
+                ),
+                (
+                    "INCRBY key1 -3".to_string(),
+                    Command::IncrBy {
+                        key: "key1".to_string(),
+                        delta: -3,
+                    },
+                ),
+                (
+                    "EVALSHA 0a1b 2 key1 key2 arg1".to_string(),
+                    Command::EvalSha {
+                        hash: "0a1b".to_string(),
+                        keys: vec!["key1".to_string(), "key2".to_string()],
+                        args: vec!["arg1".to_string()],
+                    },
+                ),
+            ];
+
+            for (input, expected) in test_table {
+                let input_stream = Cursor::new(input.as_bytes());
+                let words = tokenize(input_stream).unwrap();
+                let got = parse(words).unwrap();
+                assert_eq!(got, expected);
+            }
+        }
+
+        #[test]
+        fn fail() {
+            let invalid = [
+                "LPUSH key1",
+                "INCRBY key1 one",
+                "LRANGE key1 0",
+                "SADD",
+                "EVALSHA 0a1b 2 key1",
+                "EVALSHA 0a1b -1",
+            ];
+            for input in invalid {
+                let words = tokenize(Cursor::new(input.as_bytes())).unwrap();
+                assert!(parse(words).is_err(), "{}", input);
+            }
+        }
+
+        #[test]
+        fn eval() {
+            let words = tokenize(Cursor::new("EVAL 1 key1 arg1".as_bytes())).unwrap();
+            let script = "local value = kvs.get(KEYS[1])\nreturn value";
+            assert_eq!(
+                parse_request(words, script.as_bytes()).unwrap(),
+                Command::Eval {
+                    script: script.to_string(),
+                    keys: vec!["key1".to_string()],
+                    args: vec!["arg1".to_string()],
+                }
+            );
+            let words = tokenize(Cursor::new("EVAL 0".as_bytes())).unwrap();
+            assert!(parse_request(words, b"\xff").is_err());
+        }
+    }
+
+    mod read_request {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let limits = Limits::default();
+            let request = read_request(Cursor::new("GET key1".as_bytes()), &limits).unwrap();
+            assert_eq!(request.token, None);
+            assert_eq!(request.words, vec!["GET", "key1"]);
+
+            let request = read_request(
+                Cursor::new("AUTH token1\nRESTORE\narchive".as_bytes()),
+                &limits,
+            )
+            .unwrap();
+            assert_eq!(request.token.as_deref(), Some("token1"));
+            assert_eq!(request.words, vec!["RESTORE"]);
+            assert_eq!(request.payload, b"archive");
+
+            let request = read_request(
+                Cursor::new("AUTH token1\nCOMPRESS brotli,lz4,zstd\nSCAN".as_bytes()),
+                &limits,
+            )
+            .unwrap();
+            assert_eq!(request.token.as_deref(), Some("token1"));
+            assert_eq!(request.accepts, vec![Compression::Lz4, Compression::Zstd]);
+            assert_eq!(request.words, vec!["SCAN"]);
+
+            let request = read_request(
+                Cursor::new("AUTH token1\nSELECT team1\nGET key1".as_bytes()),
+                &limits,
+            )
+            .unwrap();
+            assert_eq!(request.namespace.as_deref(), Some("team1"));
+            assert_eq!(request.words, vec!["GET", "key1"]);
+        }
+
+        #[test]
+        fn fail() {
+            let limits = Limits {
+                max_request_size: 4,
+                ..Limits::default()
+            };
+            let Err(err) = read_request(Cursor::new("GET key1".as_bytes()), &limits) else {
+                panic!("request larger than the limit was read");
+            };
+            assert!(matches!(err, Error::TooLarge { size: 8, .. }), "{}", err);
+        }
+    }
+
+    mod parse_watch {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let test_table = vec![
+                (
+                    "WATCH key1",
+                    Some((KeyPattern::Exact("key1".to_string()), None)),
+                ),
+                (
+                    "watch user:* from 7-42",
+                    Some((
+                        KeyPattern::Prefix("user:".to_string()),
+                        Some(Position { epoch: 7, seq: 42 }),
+                    )),
+                ),
+                ("GET key1", None),
+                ("WATCH key1 SINCE 42", None),
+            ];
+
+            for (input, expected) in test_table {
+                let words = tokenize(Cursor::new(input.as_bytes())).unwrap();
+                let got = parse_watch(&words).unwrap();
+                assert_eq!(got, expected);
+            }
+        }
+
+        #[test]
+        fn fail() {
+            for input in ["WATCH key1 FROM latest", "WATCH key1 FROM 42"] {
+                let words = tokenize(Cursor::new(input.as_bytes())).unwrap();
+                assert!(parse_watch(&words).is_err());
+            }
+        }
+    }
+
+    mod parse_namespace_command {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let test_table = vec![
+                (
+                    "NAMESPACE CREATE team1",
+                    Some(NamespaceCommand::Create {
+                        name: "team1".to_string(),
+                        config: NamespaceConfig::default(),
+                    }),
+                ),
+                (
+                    "namespace create team1 max_bytes 1000 DIR /data/team1 MAX_KEYS 10",
+                    Some(NamespaceCommand::Create {
+                        name: "team1".to_string(),
+                        config: NamespaceConfig {
+                            dir: Some("/data/team1".into()),
+                            max_keys: Some(10),
+                            max_bytes: Some(1000),
+                        },
+                    }),
+                ),
+                (
+                    "NAMESPACE DROP team1",
+                    Some(NamespaceCommand::Drop {
+                        name: "team1".to_string(),
+                    }),
+                ),
+                ("NAMESPACE LIST", Some(NamespaceCommand::List)),
+                ("GET namespace", None),
+            ];
+
+            for (input, expected) in test_table {
+                let words = tokenize(Cursor::new(input.as_bytes())).unwrap();
+                let got = parse_namespace_command(&words).unwrap();
+                assert_eq!(got, expected);
+            }
+        }
+
+        #[test]
+        fn fail() {
+            for input in [
+                "NAMESPACE CREATE",
+                "NAMESPACE CREATE team1 MAX_KEYS",
+                "NAMESPACE CREATE team1 MAX_KEYS -1",
+                "NAMESPACE CREATE team1 QUOTA 10",
+                "NAMESPACE DROP",
+                "NAMESPACE LIST team1",
+                "NAMESPACE RENAME team1 team2",
+            ] {
+                let words = tokenize(Cursor::new(input.as_bytes())).unwrap();
+                assert!(parse_namespace_command(&words).is_err(), "{}", input);
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Which servers hold a key when the keys are spread over several of them.
+//!
+//! Keys are placed with rendezvous hashing: every node scores every key, and a key belongs to
+//! the nodes with the best scores. Adding or removing a node only moves the keys it gains or
+//! loses, and the scores depend on nothing but the key and the node addresses, so every client,
+//! and the servers themselves, agree on where a key lives without talking to each other.
+//!
+//! ```
+//! use kvs::routing::route;
+//!
+//! let nodes = ["10.0.0.1:4004", "10.0.0.2:4004", "10.0.0.3:4004"];
+//! // The node to ask first, then the ones to fall back on
+//! let order = route("user:1", &nodes);
+//! assert_eq!(order.len(), 3);
+//! assert_eq!(order, route("user:1", &nodes));
+//! ```
+
+/// The indexes of `nodes`, from the node that `key` belongs to first to the one it belongs to
+/// last. With `n` replicas, a key lives on the first `n` of them.
+pub fn route(key: &str, nodes: &[impl AsRef<str>]) -> Vec<usize> {
+    let mut scores: Vec<_> = nodes
+        .iter()
+        .enumerate()
+        .map(|(index, node)| (score(key, node.as_ref()), index))
+        .collect();
+    // Ties, which take identical addresses, are broken by position to stay deterministic
+    scores.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    scores.into_iter().map(|(_, index)| index).collect()
+}
+
+/// The score of `node` for `key`: FNV-1a over both, mixed so that similar addresses don't get
+/// similar scores. It must never change, as it decides where the existing keys are.
+fn score(key: &str, node: &str) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = OFFSET;
+    // The separator can't be in UTF-8, so that ("ab", "c") and ("a", "bc") differ
+    for byte in node.bytes().chain([0xff]).chain(key.bytes()) {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(PRIME);
+    }
+    // The finalizer of SplitMix64
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODES: [&str; 3] = ["10.0.0.1:4004", "10.0.0.2:4004", "10.0.0.3:4004"];
+
+    #[test]
+    fn stable() {
+        // Changing these moves every key of every deployment
+        assert_eq!(score("user:1", "10.0.0.1:4004"), 0xb9bf_42db_8346_4288);
+        assert_eq!(route("user:1", &NODES), vec![2, 0, 1]);
+        assert_eq!(route("user:2", &NODES), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn balanced() {
+        let mut counts = [0; 3];
+        for i in 0..30_000 {
+            counts[route(&format!("key{}", i), &NODES)[0]] += 1;
+        }
+        assert!(
+            counts.iter().all(|count| (9_000..11_000).contains(count)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let more = [NODES[0], NODES[1], NODES[2], "10.0.0.4:4004"];
+        let mut moved = 0;
+        for i in 0..10_000 {
+            let key = format!("key{}", i);
+            let before = route(&key, &NODES)[0];
+            let after = route(&key, &more)[0];
+            if before != after {
+                assert_eq!(after, 3);
+                moved += 1;
+            }
+        }
+        assert!((2_000..3_000).contains(&moved), "{}", moved);
+    }
+
+    #[test]
+    fn edge_cases() {
+        assert!(route("key", &[] as &[&str]).is_empty());
+        assert_eq!(route("key", &["a", "a"]), vec![0, 1]);
+    }
+}
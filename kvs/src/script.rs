@@ -0,0 +1,465 @@
+//! Server-side scripts: Lua 5.4 run by `EVAL`, so that logic spanning several keys takes a single
+//! round trip.
+//!
+//! A script is given the keys it works on and some arguments, as the `KEYS` and `ARGV` tables,
+//! and reads and writes those keys, and no others, through the `kvs` table:
+//!
+//! - `kvs.get(key)` returns the string held by `key`, or `nil`, and fails for typed values;
+//! - `kvs.set(key, value)` sets `key` to a string;
+//! - `kvs.remove(key)` removes `key`, returning whether it held a value.
+//!
+//! What the script returns is sent back as JSON: tables with keys 1 to n as arrays, tables with
+//! string keys as objects. Its writes are only applied once it returned, in a single
+//! [`KvsEngine::write_batch`], so that a script failing midway writes nothing. Scripts run
+//! without access to files, the network or the clock, can't catch errors, and are stopped past
+//! [`MAX_INSTRUCTIONS`], [`TIME_LIMIT`] or [`MEMORY_LIMIT`].
+//!
+//! Every script that ran is cached under the hex SHA-256 of its source, which `EVALSHA` runs it
+//! by.
+//!
+//! ```
+//! use kvs::{evaluate_command, Command, CommandResponse, MemStore};
+//!
+//! let mut store = MemStore::new();
+//! let eval = Command::Eval {
+//!     script: "kvs.set(KEYS[1], ARGV[1]) return kvs.get(KEYS[1])".to_owned(),
+//!     keys: vec!["key1".to_owned()],
+//!     args: vec!["value1".to_owned()],
+//! };
+//! let response = evaluate_command(&eval, &mut store)?;
+//! assert_eq!(response, CommandResponse::Eval { result: "value1".into() });
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::engine::WriteOp;
+use crate::err::{Error, NoScriptSnafu, Result};
+use crate::value::{Value, ValueType, WrongType};
+use crate::{Command, KvsEngine};
+use mlua::{HookTriggers, Lua, LuaOptions, StdLib};
+use sha2::{Digest, Sha256};
+use snafu::whatever;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Lua instructions a script may run.
+pub const MAX_INSTRUCTIONS: u64 = 100_000_000;
+
+/// How long a script may run. The namespace it runs in is locked meanwhile.
+pub const TIME_LIMIT: Duration = Duration::from_secs(1);
+
+/// Bytes of memory a script may allocate.
+pub const MEMORY_LIMIT: usize = 32 * 1024 * 1024;
+
+/// Instructions run between two checks of the limits.
+const CHECK_INTERVAL: u32 = 10_000;
+
+/// Scripts kept in the cache at most. Past it, caching a script drops another one.
+const MAX_CACHED_SCRIPTS: usize = 1024;
+
+/// Levels of nested tables a result may have.
+const MAX_RESULT_DEPTH: usize = 32;
+
+/// Globals of the base library left out of the sandbox: access to files, loading code, printing
+/// to the server's output, and catching errors, which would let a script outlive its limits.
+const REMOVED_GLOBALS: [&str; 7] = [
+    "dofile",
+    "loadfile",
+    "load",
+    "print",
+    "collectgarbage",
+    "pcall",
+    "xpcall",
+];
+
+/// The scripts that ran, by hash.
+static SCRIPTS: Mutex<BTreeMap<String, Arc<str>>> = Mutex::new(BTreeMap::new());
+
+/// The hash `EVALSHA` runs a script by: the hex SHA-256 of its source.
+pub fn script_hash(script: &str) -> String {
+    hex::encode(Sha256::digest(script.as_bytes()))
+}
+
+fn cache(script: &str) {
+    let mut scripts = SCRIPTS.lock().unwrap_or_else(|err| err.into_inner());
+    let hash = script_hash(script);
+    if !scripts.contains_key(&hash) && scripts.len() >= MAX_CACHED_SCRIPTS {
+        scripts.pop_first();
+    }
+    scripts.insert(hash, Arc::from(script));
+}
+
+fn cached(hash: &str) -> Result<Arc<str>> {
+    let scripts = SCRIPTS.lock().unwrap_or_else(|err| err.into_inner());
+    match scripts.get(&hash.to_lowercase()) {
+        Some(script) => Ok(script.clone()),
+        None => NoScriptSnafu { hash }.fail(),
+    }
+}
+
+/// What a script returned, and the writes it made, not applied yet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub result: serde_json::Value,
+    pub writes: Vec<WriteOp>,
+}
+
+/// Runs the script of an `Eval` or `EvalSha` against `store`, caching the script of an `Eval`
+/// once it ran. Fails for any other command.
+pub fn evaluate_script(command: &Command, store: &dyn KvsEngine) -> Result<Outcome> {
+    match command {
+        Command::Eval { script, keys, args } => {
+            let outcome = run(script, keys, args, store)?;
+            cache(script);
+            Ok(outcome)
+        }
+        Command::EvalSha { hash, keys, args } => run(&cached(hash)?, keys, args, store),
+        _ => whatever!("{} isn't a script", command.name()),
+    }
+}
+
+fn run(script: &str, keys: &[String], args: &[String], store: &dyn KvsEngine) -> Result<Outcome> {
+    let libs = StdLib::TABLE | StdLib::STRING | StdLib::MATH | StdLib::UTF8;
+    let lua = Lua::new_with(libs, LuaOptions::default()).map_err(script_error)?;
+    lua.set_memory_limit(MEMORY_LIMIT).map_err(script_error)?;
+    let started = Instant::now();
+    let instructions = Cell::new(0);
+    let triggers = HookTriggers::new().every_nth_instruction(CHECK_INTERVAL);
+    lua.set_hook(triggers, move |_, _| {
+        instructions.set(instructions.get() + u64::from(CHECK_INTERVAL));
+        if instructions.get() > MAX_INSTRUCTIONS {
+            return Err(mlua::Error::runtime(format!(
+                "Script ran more than {} instructions",
+                MAX_INSTRUCTIONS
+            )));
+        }
+        if started.elapsed() > TIME_LIMIT {
+            return Err(mlua::Error::runtime(format!(
+                "Script ran longer than {} ms",
+                TIME_LIMIT.as_millis()
+            )));
+        }
+        Ok(())
+    });
+
+    let declared: HashSet<&str> = keys.iter().map(String::as_str).collect();
+    // The values the script wrote, as stored, `None` for removals
+    let written: RefCell<BTreeMap<String, Option<String>>> = RefCell::new(BTreeMap::new());
+    // An error of the engine, which is reported as it is rather than as a script error
+    let failure: RefCell<Option<Error>> = RefCell::new(None);
+    let fail = |err: Error| {
+        let message = err.to_string();
+        failure.borrow_mut().get_or_insert(err);
+        mlua::Error::runtime(message)
+    };
+    let check = |key: &str| {
+        if declared.contains(key) {
+            Ok(())
+        } else {
+            Err(mlua::Error::runtime(format!(
+                "Key {} isn't one of the KEYS of the script",
+                key
+            )))
+        }
+    };
+    let read = |key: &str| -> mlua::Result<Option<String>> {
+        check(key)?;
+        if let Some(value) = written.borrow().get(key) {
+            return Ok(value.clone());
+        }
+        store.get(key.to_owned()).map_err(fail)
+    };
+
+    let result = lua.scope(|scope| {
+        let kvs = lua.create_table()?;
+        let get = scope.create_function(|_, key: String| match read(&key)? {
+            None => Ok(None),
+            Some(stored) => match Value::decode(stored).map_err(fail)? {
+                Value::String(value) => Ok(Some(value)),
+                value => Err(mlua::Error::runtime(WrongType {
+                    key,
+                    expected: ValueType::String,
+                    found: value.value_type(),
+                })),
+            },
+        })?;
+        kvs.set("get", get)?;
+        let set = scope.create_function(|_, (key, value): (String, String)| {
+            check(&key)?;
+            let stored = Value::String(value).encode().map_err(fail)?;
+            written.borrow_mut().insert(key, Some(stored));
+            Ok(())
+        })?;
+        kvs.set("set", set)?;
+        let remove = scope.create_function(|_, key: String| {
+            let existed = read(&key)?.is_some();
+            written.borrow_mut().insert(key, None);
+            Ok(existed)
+        })?;
+        kvs.set("remove", remove)?;
+
+        let globals = lua.globals();
+        for name in REMOVED_GLOBALS {
+            globals.raw_set(name, mlua::Nil)?;
+        }
+        globals.set("kvs", kvs)?;
+        globals.set("KEYS", keys)?;
+        globals.set("ARGV", args)?;
+        let value = lua.load(script).set_name("=script").eval()?;
+        to_json(value, 0)
+    });
+    if let Some(err) = failure.take() {
+        return Err(err);
+    }
+    let result = result.map_err(script_error)?;
+    let writes = written
+        .into_inner()
+        .into_iter()
+        .map(|(key, value)| match value {
+            Some(value) => WriteOp::Set { key, value },
+            None => WriteOp::Rm { key },
+        })
+        .collect();
+    Ok(Outcome { result, writes })
+}
+
+fn to_json(value: mlua::Value, depth: usize) -> mlua::Result<serde_json::Value> {
+    if depth > MAX_RESULT_DEPTH {
+        return Err(mlua::Error::runtime(format!(
+            "Results can't nest more than {} tables",
+            MAX_RESULT_DEPTH
+        )));
+    }
+    match value {
+        mlua::Value::Nil => Ok(serde_json::Value::Null),
+        mlua::Value::Boolean(value) => Ok(value.into()),
+        mlua::Value::Integer(value) => Ok(value.into()),
+        mlua::Value::Number(value) => match serde_json::Number::from_f64(value) {
+            Some(number) => Ok(number.into()),
+            None => Err(mlua::Error::runtime(format!("Can't return {}", value))),
+        },
+        mlua::Value::String(value) => Ok(value.to_str()?.into()),
+        mlua::Value::Table(table) => {
+            let is_empty = table
+                .clone()
+                .pairs::<mlua::Value, mlua::Value>()
+                .next()
+                .is_none();
+            if table.raw_len() > 0 || is_empty {
+                let elements = table
+                    .sequence_values::<mlua::Value>()
+                    .map(|element| to_json(element?, depth + 1))
+                    .collect::<mlua::Result<_>>()?;
+                return Ok(serde_json::Value::Array(elements));
+            }
+            let mut fields = serde_json::Map::new();
+            for pair in table.pairs::<mlua::Value, mlua::Value>() {
+                let (key, value) = pair?;
+                let mlua::Value::String(key) = key else {
+                    return Err(mlua::Error::runtime(
+                        "Can't return a table with keys other than strings or 1 to n",
+                    ));
+                };
+                fields.insert(key.to_str()?.to_owned(), to_json(value, depth + 1)?);
+            }
+            Ok(serde_json::Value::Object(fields))
+        }
+        value => Err(mlua::Error::runtime(format!(
+            "Can't return a {}",
+            value.type_name()
+        ))),
+    }
+}
+
+/// The message of a failing script, without the tracebacks of the Lua functions it went through.
+fn script_error(err: mlua::Error) -> Error {
+    let message = match err {
+        mlua::Error::CallbackError { cause, .. } => return script_error((*cause).clone()),
+        mlua::Error::RuntimeError(message)
+        | mlua::Error::MemoryError(message)
+        | mlua::Error::SyntaxError { message, .. } => message,
+        err => err.to_string(),
+    };
+    let message = match message.split_once("\nstack traceback:") {
+        Some((message, _)) => message.to_owned(),
+        None => message,
+    };
+    Error::Script { message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::evaluate_command;
+    use crate::MemStore;
+    use serde_json::json;
+
+    fn eval(script: &str, keys: &[&str], args: &[&str]) -> Command {
+        Command::Eval {
+            script: script.to_owned(),
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    fn message(result: Result<Outcome>) -> String {
+        match result {
+            Err(Error::Script { message }) => message,
+            result => panic!("Expected a script error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let mut store = MemStore::new();
+        store
+            .set(
+                "from".to_owned(),
+                Value::String("10".to_owned()).encode().unwrap(),
+            )
+            .unwrap();
+        let script = "
+            local moved = kvs.get(KEYS[1])
+            kvs.set(KEYS[2], moved .. ARGV[1])
+            return {kvs.remove(KEYS[1]), kvs.get(KEYS[1]) == nil, kvs.get(KEYS[2])}
+        ";
+        let outcome = evaluate_script(&eval(script, &["from", "to"], &["!"]), &store).unwrap();
+        assert_eq!(outcome.result, json!([true, true, "10!"]));
+        assert_eq!(
+            outcome.writes,
+            vec![
+                WriteOp::Rm {
+                    key: "from".to_owned()
+                },
+                WriteOp::Set {
+                    key: "to".to_owned(),
+                    value: Value::String("10!".to_owned()).encode().unwrap()
+                },
+            ]
+        );
+        // Nothing is written until the outcome is applied
+        assert!(store.get("to".to_owned()).unwrap().is_none());
+    }
+
+    #[test]
+    fn results() {
+        let store = MemStore::new();
+        let cases = [
+            ("return nil", json!(null)),
+            ("return 1 + 1", json!(2)),
+            ("return 1.5", json!(1.5)),
+            ("return 'text'", json!("text")),
+            ("return {}", json!([])),
+            ("return {a = {1, 2}}", json!({"a": [1, 2]})),
+            ("return #KEYS + #ARGV", json!(0)),
+        ];
+        for (script, expected) in cases {
+            let outcome = evaluate_script(&eval(script, &[], &[]), &store).unwrap();
+            assert_eq!(outcome.result, expected, "{}", script);
+        }
+        for script in ["return {[true] = 1}", "return string.len", "return 0/0"] {
+            assert!(evaluate_script(&eval(script, &[], &[]), &store).is_err());
+        }
+    }
+
+    #[test]
+    fn undeclared_keys() {
+        let store = MemStore::new();
+        let message = message(evaluate_script(
+            &eval("kvs.set('other', 'value')", &["key1"], &[]),
+            &store,
+        ));
+        assert!(
+            message.contains("other isn't one of the KEYS"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn typed_values() {
+        let mut store = MemStore::new();
+        let push = Command::LPush {
+            key: "list".to_owned(),
+            values: vec!["value1".to_owned()],
+        };
+        evaluate_command(&push, &mut store).unwrap();
+        let message = message(evaluate_script(
+            &eval("return kvs.get(KEYS[1])", &["list"], &[]),
+            &store,
+        ));
+        assert!(
+            message.contains("holds a list, not a string"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn failing_scripts_write_nothing() {
+        let mut store = MemStore::new();
+        let command = eval("kvs.set(KEYS[1], 'value') error('failed')", &["key1"], &[]);
+        let message = message(evaluate_script(&command, &store));
+        assert_eq!(message, "script:1: failed");
+        assert!(evaluate_command(&command, &mut store).is_err());
+        assert!(store.get("key1".to_owned()).unwrap().is_none());
+    }
+
+    #[test]
+    fn limits() {
+        let store = MemStore::new();
+        let message = message(evaluate_script(
+            &eval("while true do end", &[], &[]),
+            &store,
+        ));
+        assert!(
+            message.contains("instructions") || message.contains("longer"),
+            "{}",
+            message
+        );
+        let script = "local parts = {} for i = 1, 1e9 do parts[i] = string.rep('x', 1024) end";
+        assert!(evaluate_script(&eval(script, &[], &[]), &store).is_err());
+    }
+
+    #[test]
+    fn sandbox() {
+        let store = MemStore::new();
+        for global in [
+            "io",
+            "os",
+            "require",
+            "load",
+            "pcall",
+            "xpcall",
+            "coroutine",
+            "debug",
+        ] {
+            let script = format!("return {} == nil", global);
+            let outcome = evaluate_script(&eval(&script, &[], &[]), &store).unwrap();
+            assert_eq!(outcome.result, json!(true), "{}", global);
+        }
+    }
+
+    #[test]
+    fn cache() {
+        let store = MemStore::new();
+        let script = "return ARGV[1] .. ' cached'";
+        let by_hash = Command::EvalSha {
+            hash: script_hash(script),
+            keys: Vec::new(),
+            args: vec!["script".to_owned()],
+        };
+        assert!(matches!(
+            evaluate_script(&by_hash, &store),
+            Err(Error::NoScript { .. })
+        ));
+        evaluate_script(&eval(script, &[], &["x"]), &store).unwrap();
+        let outcome = evaluate_script(&by_hash, &store).unwrap();
+        assert_eq!(outcome.result, json!("script cached"));
+        // Scripts that fail to parse aren't cached
+        let invalid = "return (";
+        assert!(evaluate_script(&eval(invalid, &[], &[]), &store).is_err());
+        assert!(cached(&script_hash(invalid)).is_err());
+    }
+}
@@ -0,0 +1,571 @@
+//! Deterministic simulation of a server and its clients, to find the bugs that only show under
+//! some interleaving of requests, crash or failing disk, and to replay them at will.
+//!
+//! A [`Simulation`] runs entirely in-process and on a single thread. Clients send random gets,
+//! sets and removes over a simulated [`Network`], which delays messages and drops connections,
+//! to a server running the engine on a [`SimFileSystem`], whose writes fill up the disk, fail to
+//! sync, stop half way, or crash the server, which is then restarted on the files it left. Time
+//! is a [`SimClock`] that jumps from one event to the next, and the order of events that happen
+//! at the same time is drawn from the seed as well, so that a run only depends on its
+//! [`SimulationConfig`]. Once done, the history of the operations is checked to be linearizable,
+//! see [`check`].
+//!
+//! ```
+//! use kvs::encryption::Keyring;
+//! use kvs::simulation::{Simulation, SimulationConfig};
+//! use kvs::{KvStoreV2, KvsEngine};
+//!
+//! let report = Simulation::new(SimulationConfig::new(42)).run(|dir, fs| {
+//!     let store = KvStoreV2::open_with_file_system(dir, Keyring::default(), fs)?;
+//!     Ok(Box::new(store) as Box<dyn KvsEngine>)
+//! })?;
+//! assert!(!report.history.is_empty());
+//! # Ok::<(), kvs::Error>(())
+//! ```
+//!
+//! A run that fails says which seed it ran with, which is enough to run it again.
+
+use crate::clock::Clock;
+use crate::err::Result;
+use crate::file_system::FileSystem;
+use crate::limits::{Limits, RateLimiter};
+use crate::protocol::{encode, parse_request, read_request};
+use crate::{evaluate_command, Command, CommandResponse, KvsEngine};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use snafu::whatever;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+mod file_system;
+mod linearizability;
+mod network;
+
+pub use file_system::SimFileSystem;
+pub use linearizability::{check, Entry, Operation, Outcome};
+pub use network::{Message, Network, SimNetwork};
+
+/// Restarts in a row a server may fail, e.g. for a disk fault while it opens its engine, before
+/// the engine is deemed unable to open what it left behind.
+const MAX_FAILED_RESTARTS: usize = 10;
+
+/// How often every fault happens, as the chance of one for every write, sync, request or message.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Faults {
+    /// A write finds the disk full, and writes nothing.
+    pub disk_full: f64,
+    /// A sync fails, as fsync does when the disk fails to write.
+    pub fsync_failure: f64,
+    /// A write only gets part of its bytes written before failing.
+    pub partial_write: f64,
+    /// The server crashes in the middle of a write.
+    pub crash_during_write: f64,
+    /// The server crashes once it ran a request, before responding to it.
+    pub crash: f64,
+    /// A request or a response is lost with its connection.
+    pub dropped_connection: f64,
+}
+
+impl Faults {
+    pub const NONE: Faults = Faults {
+        disk_full: 0.0,
+        fsync_failure: 0.0,
+        partial_write: 0.0,
+        crash_during_write: 0.0,
+        crash: 0.0,
+        dropped_connection: 0.0,
+    };
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            disk_full: 0.02,
+            fsync_failure: 0.02,
+            partial_write: 0.02,
+            crash_during_write: 0.01,
+            crash: 0.01,
+            dropped_connection: 0.05,
+        }
+    }
+}
+
+/// How a simulation runs. Runs with the same config go the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationConfig {
+    /// Where all the randomness of the run comes from.
+    pub seed: u64,
+    pub clients: usize,
+    /// Operations run by all the clients together.
+    pub operations: usize,
+    /// Keys the operations pick from: the fewer, the more the clients contend for them.
+    pub keys: usize,
+    pub faults: Faults,
+    /// How long a message takes to arrive, unless [`Simulation::with_network`] says otherwise.
+    pub latency: (Duration, Duration),
+    /// How long a client waits for its response before giving up on it.
+    pub timeout: Duration,
+    /// The limits the server applies, the rate limit counting in simulated time.
+    pub limits: Limits,
+}
+
+impl SimulationConfig {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            clients: 4,
+            operations: 200,
+            keys: 3,
+            faults: Faults::default(),
+            latency: (Duration::from_micros(100), Duration::from_millis(5)),
+            timeout: Duration::from_millis(20),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Simulated time, which only passes when the simulation says so.
+#[derive(Debug)]
+pub struct SimClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Time simulated so far.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Moves the time forward to `elapsed` since the start, if it isn't there already.
+    pub fn advance_to(&self, elapsed: Duration) {
+        let mut current = self.elapsed.lock().unwrap_or_else(|err| err.into_inner());
+        *current = (*current).max(elapsed);
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+/// What came of a run whose history is linearizable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Every operation, those the server was asked for as it was opened again at the end
+    /// included.
+    pub history: Vec<Entry>,
+    /// Requests the server answered with an error.
+    pub failed: usize,
+    /// Messages lost with their connection, or sent to a server that was down.
+    pub dropped: usize,
+    /// Times the server crashed.
+    pub crashes: usize,
+    /// Disk faults injected, crashes during writes included.
+    pub disk_faults: usize,
+    /// Time simulated.
+    pub elapsed: Duration,
+}
+
+/// Opens the engine under test in a directory of the simulated file system.
+type Open<'a> = dyn Fn(&Path, Arc<dyn FileSystem>) -> Result<Box<dyn KvsEngine>> + 'a;
+
+/// A simulated server, its clients and the network between them. See the module documentation.
+pub struct Simulation {
+    config: SimulationConfig,
+    rng: StdRng,
+    clock: Arc<SimClock>,
+    fs: Arc<SimFileSystem>,
+    network: Box<dyn Network>,
+    events: BinaryHeap<Reverse<Scheduled>>,
+    /// The number of events handled so far, which orders the history.
+    step: u64,
+    /// `None` while the server is down.
+    engine: Option<Box<dyn KvsEngine>>,
+    rate_limiter: Option<RateLimiter>,
+    failed_restarts: usize,
+    /// The operation every client waits for, as its index in the history.
+    pending: Vec<Option<usize>>,
+    history: Vec<Entry>,
+    report: Report,
+}
+
+impl Simulation {
+    pub fn new(config: SimulationConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(config.seed);
+        let clock = Arc::new(SimClock::new());
+        let fs = Arc::new(SimFileSystem::new(rng.gen(), config.faults));
+        let (min, max) = config.latency;
+        let network = SimNetwork::new(rng.gen(), min..=max, config.faults.dropped_connection);
+        let rate_limiter = config
+            .limits
+            .rate_limiter()
+            .map(|rate_limiter| rate_limiter.with_clock(clock.clone()));
+        Self {
+            rng,
+            clock,
+            fs,
+            network: Box::new(network),
+            events: BinaryHeap::new(),
+            step: 0,
+            engine: None,
+            rate_limiter,
+            failed_restarts: 0,
+            pending: vec![None; config.clients],
+            history: Vec::new(),
+            report: Report {
+                history: Vec::new(),
+                failed: 0,
+                dropped: 0,
+                crashes: 0,
+                disk_faults: 0,
+                elapsed: Duration::ZERO,
+            },
+            config,
+        }
+    }
+
+    /// Carries the messages over `network` instead of the one made from the config.
+    pub fn with_network(mut self, network: impl Network + 'static) -> Self {
+        self.network = Box::new(network);
+        self
+    }
+
+    /// Runs the workload against the engine opened by `open`, then crashes the server one last
+    /// time and reads every key, so that writes lost by a crash show. Fails when the engine
+    /// can't be opened again after a crash, or the history isn't linearizable.
+    pub fn run(
+        mut self,
+        open: impl Fn(&Path, Arc<dyn FileSystem>) -> Result<Box<dyn KvsEngine>>,
+    ) -> Result<Report> {
+        if let Err(err) = self.simulate(&open) {
+            whatever!("Run with seed {} failed: {}", self.config.seed, err);
+        }
+        self.report.history = self.history;
+        self.report.disk_faults = self.fs.injected();
+        self.report.elapsed = self.clock.elapsed();
+        Ok(self.report)
+    }
+
+    fn simulate(&mut self, open: &Open) -> Result<()> {
+        self.start(open)?;
+        for client in 0..self.config.clients {
+            let delay = self.rng.gen_range(Duration::ZERO..=self.config.timeout);
+            self.schedule(delay, Event::Invoke { client });
+        }
+        self.drain(open)?;
+        self.crash();
+        self.drain(open)?;
+        self.read_every_key()?;
+        check(&self.history)
+    }
+
+    /// Handles events until there are none left, which happens once every operation was sent
+    /// and answered or given up on, and the server is up.
+    fn drain(&mut self, open: &Open) -> Result<()> {
+        while let Some(Reverse(scheduled)) = self.events.pop() {
+            self.clock.advance_to(scheduled.time);
+            self.step += 1;
+            self.handle(scheduled.event, open)?;
+        }
+        Ok(())
+    }
+
+    fn start(&mut self, open: &Open) -> Result<()> {
+        self.engine = Some(open(Path::new("data"), self.fs.clone())?);
+        Ok(())
+    }
+
+    fn schedule(&mut self, delay: Duration, event: Event) {
+        self.events.push(Reverse(Scheduled {
+            time: self.clock.elapsed() + delay,
+            // Events due at the same time happen in an order drawn from the seed
+            order: self.rng.gen(),
+            event,
+        }));
+    }
+
+    fn send(&mut self, message: Message) {
+        match self.network.latency(&message) {
+            Some(latency) => self.schedule(latency, Event::Arrive(message)),
+            None => self.report.dropped += 1,
+        }
+    }
+
+    fn handle(&mut self, event: Event, open: &Open) -> Result<()> {
+        match event {
+            Event::Invoke { client } => self.invoke(client),
+            Event::Arrive(Message::Request {
+                client,
+                operation,
+                bytes,
+            }) => {
+                self.serve(client, operation, &bytes);
+                Ok(())
+            }
+            Event::Arrive(Message::Response {
+                client,
+                operation,
+                response,
+            }) => {
+                self.complete(client, operation, response);
+                Ok(())
+            }
+            Event::Timeout { client, operation } => {
+                if self.pending[client] == Some(operation as usize) {
+                    self.pending[client] = None;
+                    self.schedule(Duration::ZERO, Event::Invoke { client });
+                }
+                Ok(())
+            }
+            Event::Restart => self.restart(open),
+        }
+    }
+
+    /// Sends the next operation of `client`, unless every operation was sent.
+    fn invoke(&mut self, client: usize) -> Result<()> {
+        if self.history.len() >= self.config.operations {
+            return Ok(());
+        }
+        let index = self.history.len();
+        let key = format!("key{}", self.rng.gen_range(0..self.config.keys));
+        let operation = match self.rng.gen_range(0..10) {
+            0..=3 => Operation::Get,
+            4..=7 => Operation::Set {
+                // Unique, so that every read tells which write it read
+                value: format!("value{}", index),
+            },
+            _ => Operation::Rm,
+        };
+        let bytes = encode(&command(&key, &operation))?;
+        self.history.push(Entry {
+            client,
+            key,
+            operation,
+            outcome: Outcome::Unknown,
+            invoked: self.step,
+            returned: None,
+        });
+        self.pending[client] = Some(index);
+        self.send(Message::Request {
+            client,
+            operation: index as u64,
+            bytes,
+        });
+        self.schedule(
+            self.config.timeout,
+            Event::Timeout {
+                client,
+                operation: index as u64,
+            },
+        );
+        Ok(())
+    }
+
+    /// Runs a request, as the TCP server does, and sends its response back unless the server
+    /// crashed meanwhile.
+    fn serve(&mut self, client: usize, operation: u64, bytes: &[u8]) {
+        let Some(engine) = self.engine.as_deref_mut() else {
+            // Refused by a server that is down, which the client can't tell from a drop
+            self.report.dropped += 1;
+            return;
+        };
+        let response = serve_request(
+            engine,
+            bytes,
+            &self.config.limits,
+            &self.rate_limiter,
+            client,
+        );
+        if self.fs.has_crashed() || self.rng.gen_bool(self.config.faults.crash) {
+            self.crash();
+            return;
+        }
+        self.report.failed += usize::from(response.is_err());
+        self.send(Message::Response {
+            client,
+            operation,
+            response: response.map_err(|err| err.to_string()),
+        });
+    }
+
+    /// Records the outcome of an operation its client still waits for, then sends its next one.
+    fn complete(
+        &mut self,
+        client: usize,
+        operation: u64,
+        response: std::result::Result<CommandResponse, String>,
+    ) {
+        let index = operation as usize;
+        if self.pending[client] != Some(index) {
+            return;
+        }
+        self.pending[client] = None;
+        let entry = &mut self.history[index];
+        entry.outcome = outcome(&entry.operation, response);
+        if entry.outcome != Outcome::Unknown {
+            entry.returned = Some(self.step);
+        }
+        let think = self.rng.gen_range(Duration::ZERO..=self.config.latency.1);
+        self.schedule(think, Event::Invoke { client });
+    }
+
+    fn crash(&mut self) {
+        if self.engine.take().is_none() {
+            return;
+        }
+        self.report.crashes += 1;
+        let delay = self
+            .rng
+            .gen_range(self.config.latency.0..=self.config.timeout);
+        self.schedule(delay, Event::Restart);
+    }
+
+    /// Opens the engine again on the files the crash left, trying again later when that fails.
+    fn restart(&mut self, open: &Open) -> Result<()> {
+        if self.engine.is_some() {
+            return Ok(());
+        }
+        self.fs.restart();
+        match self.start(open) {
+            Ok(()) => self.failed_restarts = 0,
+            Err(err) => {
+                self.failed_restarts += 1;
+                if self.failed_restarts >= MAX_FAILED_RESTARTS {
+                    whatever!("Couldn't open the engine again after a crash: {}", err);
+                }
+                let delay = self
+                    .rng
+                    .gen_range(self.config.latency.0..=self.config.timeout);
+                self.schedule(delay, Event::Restart);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads every key from the engine opened again at the end.
+    fn read_every_key(&mut self) -> Result<()> {
+        let client = self.config.clients;
+        for key in 0..self.config.keys {
+            let key = format!("key{}", key);
+            let bytes = encode(&command(&key, &Operation::Get))?;
+            let Some(engine) = self.engine.as_deref_mut() else {
+                whatever!("The server didn't restart");
+            };
+            let response = serve_request(engine, &bytes, &self.config.limits, &None, client);
+            let outcome = outcome(&Operation::Get, response.map_err(|err| err.to_string()));
+            self.history.push(Entry {
+                client,
+                key,
+                operation: Operation::Get,
+                returned: Some(self.step + 2).filter(|_| outcome != Outcome::Unknown),
+                outcome,
+                invoked: self.step + 1,
+            });
+            self.step += 2;
+        }
+        Ok(())
+    }
+}
+
+fn command(key: &str, operation: &Operation) -> Command {
+    let key = key.to_owned();
+    match operation {
+        Operation::Get => Command::Get { key },
+        Operation::Set { value } => Command::Set {
+            key,
+            value: value.clone(),
+        },
+        Operation::Rm => Command::Rm { key },
+    }
+}
+
+/// Runs the request the way the TCP server does, from its bytes to the response.
+fn serve_request(
+    engine: &mut dyn KvsEngine,
+    bytes: &[u8],
+    limits: &Limits,
+    rate_limiter: &Option<RateLimiter>,
+    client: usize,
+) -> Result<CommandResponse> {
+    let request = read_request(bytes, limits)?;
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.check(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + client as u32)))?;
+    }
+    let command = parse_request(request.words, &request.payload)?;
+    limits.check_command(&command)?;
+    evaluate_command(&command, engine)
+}
+
+/// What the client learns of `operation` from its response.
+fn outcome(
+    operation: &Operation,
+    response: std::result::Result<CommandResponse, String>,
+) -> Outcome {
+    match (operation, response) {
+        (Operation::Get, Ok(CommandResponse::Get { value })) => Outcome::Value(value),
+        (Operation::Set { .. }, Ok(CommandResponse::Set)) => Outcome::Done,
+        (Operation::Rm, Ok(CommandResponse::Rm { value })) => Outcome::Value(value),
+        // Whatever failed may have taken effect before failing
+        _ => Outcome::Unknown,
+    }
+}
+
+enum Event {
+    /// The client sends its next operation.
+    Invoke {
+        client: usize,
+    },
+    Arrive(Message),
+    /// The client gives up waiting for the response to `operation`.
+    Timeout {
+        client: usize,
+        operation: u64,
+    },
+    /// The server opens its engine again after a crash.
+    Restart,
+}
+
+struct Scheduled {
+    time: Duration,
+    order: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.time, self.order).cmp(&(other.time, other.order))
+    }
+}
@@ -0,0 +1,220 @@
+use super::Faults;
+use crate::file_system::FileSystem;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Files kept in memory, whose writes and syncs fail as often as [`Faults`] says. A crash in the
+/// middle of a write leaves part of it behind, and fails everything after it until
+/// [`SimFileSystem::restart`], as the process that was writing is gone.
+#[derive(Debug)]
+pub struct SimFileSystem {
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    files: HashMap<PathBuf, Vec<u8>>,
+    faults: Faults,
+    rng: StdRng,
+    crashed: bool,
+    injected: usize,
+}
+
+impl SimFileSystem {
+    pub fn new(seed: u64, faults: Faults) -> Self {
+        Self {
+            state: Mutex::new(State {
+                files: HashMap::new(),
+                faults,
+                rng: StdRng::seed_from_u64(seed),
+                crashed: false,
+                injected: 0,
+            }),
+        }
+    }
+
+    /// Whether a write crashed since the last restart.
+    pub fn has_crashed(&self) -> bool {
+        self.lock().crashed
+    }
+
+    /// Lets the files be used again after a crash.
+    pub fn restart(&self) {
+        self.lock().crashed = false;
+    }
+
+    /// Faults injected so far, crashes included.
+    pub fn injected(&self) -> usize {
+        self.lock().injected
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl State {
+    fn check_alive(&self) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("simulated crash"));
+        }
+        Ok(())
+    }
+
+    fn roll(&mut self, chance: f64) -> bool {
+        let hit = self.rng.gen_bool(chance);
+        self.injected += usize::from(hit);
+        hit
+    }
+
+    /// Writes `bytes` at the end of `file`, or the part of them that the faults let through.
+    fn write(&mut self, path: &Path, bytes: &[u8], append: bool) -> io::Result<()> {
+        self.check_alive()?;
+        if !append {
+            self.files.insert(path.to_owned(), Vec::new());
+        }
+        let Some(file) = self.files.get(path) else {
+            return Err(ErrorKind::NotFound.into());
+        };
+        let written = file.len();
+        let faults = self.faults;
+        let (part, result) = if self.roll(faults.crash_during_write) {
+            self.crashed = true;
+            (
+                self.rng.gen_range(0..=bytes.len()),
+                Err(io::Error::other("simulated crash")),
+            )
+        } else if self.roll(faults.disk_full) {
+            (0, Err(ErrorKind::StorageFull.into()))
+        } else if self.roll(faults.partial_write) {
+            let part = self.rng.gen_range(0..bytes.len().max(1));
+            (part, Err(io::Error::other("simulated partial write")))
+        } else {
+            (bytes.len(), Ok(()))
+        };
+        let file = self.files.get_mut(path).expect("file checked above");
+        file.truncate(written);
+        file.extend_from_slice(&bytes[..part]);
+        result
+    }
+
+    fn file(&self, path: &Path) -> io::Result<&Vec<u8>> {
+        self.check_alive()?;
+        self.files
+            .get(path)
+            .ok_or_else(|| ErrorKind::NotFound.into())
+    }
+}
+
+impl FileSystem for SimFileSystem {
+    fn exists(&self, path: &Path) -> bool {
+        self.lock().files.contains_key(path)
+    }
+
+    fn create(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check_alive()?;
+        state.files.entry(path.to_owned()).or_default();
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.lock().file(path).cloned()
+    }
+
+    fn append(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.lock().write(path, bytes, true)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        self.lock().write(path, bytes, false)
+    }
+
+    fn sync(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.file(path)?;
+        let chance = state.faults.fsync_failure;
+        if state.roll(chance) {
+            return Err(io::Error::other("simulated fsync failure"));
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, path: &Path, size: u64) -> io::Result<()> {
+        let mut state = self.lock();
+        state.file(path)?;
+        let file = state.files.get_mut(path).expect("file checked above");
+        file.truncate(size as usize);
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.lock();
+        state.check_alive()?;
+        let file = state.files.remove(from).ok_or(ErrorKind::NotFound)?;
+        state.files.insert(to.to_owned(), file);
+        Ok(())
+    }
+
+    fn size(&self, path: &Path) -> io::Result<u64> {
+        Ok(self.lock().file(path)?.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files() {
+        let fs = SimFileSystem::new(0, Faults::NONE);
+        let path = Path::new("dir/file");
+        assert!(!fs.exists(path));
+        fs.create(path).unwrap();
+        fs.append(path, b"hello").unwrap();
+        fs.append(path, b" world").unwrap();
+        assert_eq!(fs.read(path).unwrap(), b"hello world");
+        fs.truncate(path, 5).unwrap();
+        assert_eq!(fs.size(path).unwrap(), 5);
+        fs.write(Path::new("dir/other"), b"other").unwrap();
+        fs.rename(Path::new("dir/other"), path).unwrap();
+        assert_eq!(fs.read(path).unwrap(), b"other");
+        assert!(!fs.exists(Path::new("dir/other")));
+    }
+
+    #[test]
+    fn crash() {
+        let faults = Faults {
+            crash_during_write: 1.0,
+            ..Faults::NONE
+        };
+        let fs = SimFileSystem::new(0, faults);
+        let path = Path::new("file");
+        fs.create(path).unwrap();
+        assert!(fs.append(path, b"hello").is_err());
+        assert!(fs.has_crashed());
+        assert!(fs.read(path).is_err());
+        fs.restart();
+        // Some of the write may be left
+        assert!(b"hello".starts_with(&fs.read(path).unwrap()));
+        assert_eq!(fs.injected(), 1);
+    }
+
+    #[test]
+    fn disk_full() {
+        let faults = Faults {
+            disk_full: 1.0,
+            ..Faults::NONE
+        };
+        let fs = SimFileSystem::new(0, faults);
+        let path = Path::new("file");
+        fs.create(path).unwrap();
+        let err = fs.append(path, b"hello").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::StorageFull);
+        assert_eq!(fs.read(path).unwrap(), b"");
+    }
+}
@@ -0,0 +1,239 @@
+//! Checks that a history of gets, sets and removes is linearizable: that every operation can be
+//! given a point in time between its invocation and its return such that, taken in that order,
+//! the operations read what a single copy of the store would have given them.
+//!
+//! Keys don't affect each other, so the history of every key is checked on its own, which keeps
+//! the search small. The search is that of Wing and Gong, remembering the sets of operations
+//! already tried together with the value they led to, as Lowe suggests.
+
+use crate::err::Result;
+use snafu::whatever;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+/// What a client asked the store to do with a key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Get,
+    Set { value: String },
+    Rm,
+}
+
+/// What the client learned of an operation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The value read by a get, or removed by a remove.
+    Value(Option<String>),
+    /// A set that succeeded.
+    Done,
+    /// The client never learned what came of the operation: its connection was dropped, the
+    /// server failed, or crashed. The operation may have taken effect, at any point after its
+    /// invocation, or not at all.
+    Unknown,
+}
+
+/// An operation of a client on a key, and when it was invoked and returned, in steps of the
+/// simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub client: usize,
+    pub key: String,
+    pub operation: Operation,
+    pub outcome: Outcome,
+    pub invoked: u64,
+    /// `None` for operations of unknown outcome, which may take effect long after the client
+    /// gave up on them.
+    pub returned: Option<u64>,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let returned = match self.returned {
+            Some(returned) => returned.to_string(),
+            None => "?".to_owned(),
+        };
+        write!(
+            f,
+            "[{}, {}] client {}: {:?} {} -> {:?}",
+            self.invoked, returned, self.client, self.operation, self.key, self.outcome
+        )
+    }
+}
+
+impl Entry {
+    /// The value of the key once the operation took effect on `value`, or `None` when the
+    /// operation couldn't have seen `value`.
+    fn apply(&self, value: &Option<String>) -> Option<Option<String>> {
+        match (&self.operation, &self.outcome) {
+            (Operation::Get, Outcome::Value(read)) if read == value => Some(value.clone()),
+            (Operation::Set { value }, Outcome::Done | Outcome::Unknown) => {
+                Some(Some(value.clone()))
+            }
+            (Operation::Rm, Outcome::Value(removed)) if removed == value => Some(None),
+            (Operation::Rm, Outcome::Unknown) => Some(None),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that `history` is linearizable, starting from an empty store. The error lists the
+/// history of the first key that isn't.
+pub fn check(history: &[Entry]) -> Result<()> {
+    let mut keys: BTreeMap<&str, Vec<&Entry>> = BTreeMap::new();
+    for entry in history {
+        // Reads that never returned don't tell anything
+        if entry.operation == Operation::Get && entry.outcome == Outcome::Unknown {
+            continue;
+        }
+        keys.entry(&entry.key).or_default().push(entry);
+    }
+    for (key, mut entries) in keys {
+        entries.sort_by_key(|entry| entry.invoked);
+        let mut search = Search {
+            entries: &entries,
+            linearized: vec![false; entries.len()],
+            tried: HashSet::new(),
+        };
+        if !search.run(&None) {
+            let entries: Vec<_> = entries.iter().map(ToString::to_string).collect();
+            whatever!(
+                "History of key {} isn't linearizable:\n{}",
+                key,
+                entries.join("\n")
+            );
+        }
+    }
+    Ok(())
+}
+
+struct Search<'a> {
+    /// Sorted by invocation.
+    entries: &'a [&'a Entry],
+    linearized: Vec<bool>,
+    /// The sets of linearized entries already tried, with the value they left.
+    tried: HashSet<(Vec<bool>, Option<String>)>,
+}
+
+impl Search<'_> {
+    fn run(&mut self, value: &Option<String>) -> bool {
+        let pending = || (0..self.entries.len()).filter(|&index| !self.linearized[index]);
+        // Entries of unknown outcome left over can always take effect last
+        let Some(horizon) = pending()
+            .filter_map(|index| self.entries[index].returned)
+            .min()
+        else {
+            return true;
+        };
+        // Any entry invoked before the first pending one returned may go next
+        let candidates: Vec<_> = pending()
+            .filter(|&index| self.entries[index].invoked < horizon)
+            .collect();
+        for index in candidates {
+            let Some(next) = self.entries[index].apply(value) else {
+                continue;
+            };
+            self.linearized[index] = true;
+            if self.tried.insert((self.linearized.clone(), next.clone())) && self.run(&next) {
+                return true;
+            }
+            self.linearized[index] = false;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(operation: Operation, outcome: Outcome, invoked: u64, returned: u64) -> Entry {
+        let returned = Some(returned).filter(|_| outcome != Outcome::Unknown);
+        Entry {
+            client: 0,
+            key: "key1".to_owned(),
+            operation,
+            outcome,
+            invoked,
+            returned,
+        }
+    }
+
+    fn set(value: &str) -> Operation {
+        Operation::Set {
+            value: value.to_owned(),
+        }
+    }
+
+    fn value(value: &str) -> Outcome {
+        Outcome::Value(Some(value.to_owned()))
+    }
+
+    #[test]
+    fn sequential() {
+        let history = [
+            entry(Operation::Get, Outcome::Value(None), 0, 1),
+            entry(set("value1"), Outcome::Done, 2, 3),
+            entry(Operation::Get, value("value1"), 4, 5),
+            entry(Operation::Rm, value("value1"), 6, 7),
+            entry(Operation::Rm, Outcome::Value(None), 8, 9),
+        ];
+        assert!(check(&history).is_ok());
+    }
+
+    #[test]
+    fn concurrent() {
+        // The get overlaps both sets, so it may read either
+        let history = [
+            entry(set("value1"), Outcome::Done, 0, 3),
+            entry(set("value2"), Outcome::Done, 1, 4),
+            entry(Operation::Get, value("value1"), 2, 5),
+            entry(Operation::Get, value("value2"), 6, 7),
+        ];
+        assert!(check(&history).is_ok());
+    }
+
+    #[test]
+    fn stale_read() {
+        let history = [
+            entry(set("value1"), Outcome::Done, 0, 1),
+            entry(set("value2"), Outcome::Done, 2, 3),
+            entry(Operation::Get, value("value1"), 4, 5),
+        ];
+        let err = check(&history).unwrap_err();
+        assert!(err.to_string().contains("History of key key1"));
+    }
+
+    #[test]
+    fn unknown_outcomes() {
+        // A set of unknown outcome may take effect late, or not at all
+        let history = [
+            entry(set("value1"), Outcome::Unknown, 0, 0),
+            entry(Operation::Get, Outcome::Value(None), 1, 2),
+            entry(Operation::Get, value("value1"), 3, 4),
+        ];
+        assert!(check(&history).is_ok());
+        let history = [
+            entry(set("value1"), Outcome::Unknown, 0, 0),
+            entry(Operation::Get, Outcome::Value(None), 1, 2),
+        ];
+        assert!(check(&history).is_ok());
+        // But not before it was invoked
+        let history = [
+            entry(Operation::Get, value("value1"), 0, 1),
+            entry(set("value1"), Outcome::Unknown, 2, 2),
+        ];
+        assert!(check(&history).is_err());
+    }
+
+    #[test]
+    fn lost_write() {
+        // What a crash must not do: a removed key coming back
+        let history = [
+            entry(set("value1"), Outcome::Done, 0, 1),
+            entry(Operation::Rm, Outcome::Unknown, 2, 2),
+            entry(Operation::Get, Outcome::Value(None), 3, 4),
+            entry(Operation::Get, value("value1"), 5, 6),
+        ];
+        assert!(check(&history).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use crate::CommandResponse;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+/// A message between a client and the server of a simulation.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    /// A request, as the client writes it on its connection.
+    Request {
+        client: usize,
+        operation: u64,
+        bytes: Vec<u8>,
+    },
+    /// The response to a request, or the error the server answered it with.
+    Response {
+        client: usize,
+        operation: u64,
+        response: Result<CommandResponse, String>,
+    },
+}
+
+/// Decides when the messages of a simulation arrive, if ever. Messages are delivered by the time
+/// they arrive, whatever the order they were sent in.
+pub trait Network {
+    /// How long `message` takes to arrive, or `None` when it is lost with its connection.
+    fn latency(&mut self, message: &Message) -> Option<Duration>;
+}
+
+/// A network taking any time within a range to deliver a message, and dropping a share of the
+/// connections.
+#[derive(Debug)]
+pub struct SimNetwork {
+    rng: StdRng,
+    latency: RangeInclusive<Duration>,
+    dropped_connection: f64,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, latency: RangeInclusive<Duration>, dropped_connection: f64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            latency,
+            dropped_connection,
+        }
+    }
+}
+
+impl Network for SimNetwork {
+    fn latency(&mut self, _message: &Message) -> Option<Duration> {
+        if self.rng.gen_bool(self.dropped_connection) {
+            return None;
+        }
+        Some(self.rng.gen_range(self.latency.clone()))
+    }
+}
@@ -1,14 +1,26 @@
+use crate::encryption::Keyring;
 use crate::err::Result;
-use crate::KvsEngine;
+use crate::watch::{Change, ChangeFeed, KeyPattern, Position, Watcher};
+use crate::{EngineStats, KvsEngine, ScanIter, WriteOp};
 use sled;
 use snafu::{whatever, ResultExt};
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 pub const DEFAULT_FILE_NAME: &str = "sled.db";
 
+/// The first byte of encrypted values, which never starts a UTF-8 string, and so tells them apart
+/// from values stored as they are.
+const SEALED: u8 = 0xff;
+
 pub struct SledStore {
     file_path: Option<PathBuf>,
     db: Option<sled::Db>,
+    // sled's own `watch_prefix` has no sequence numbers to resume from, and reports removals of
+    // missing keys too, so changes are published from the write path like the other engines.
+    feed: ChangeFeed,
+    /// The keys the values are encrypted with, if they are.
+    keys: Keyring,
 }
 
 impl SledStore {
@@ -16,10 +28,20 @@ impl SledStore {
         Self {
             file_path: None,
             db: None,
+            feed: ChangeFeed::new(),
+            keys: Keyring::default(),
         }
     }
 
     pub fn open(working_dir: &Path) -> Result<Self> {
+        Self::open_with_keys(working_dir, Keyring::default())
+    }
+
+    /// Opens the store with its values encrypted with the current key of `keys`. sled has no
+    /// compaction of its own to hook into, so values encrypted with one of the old keys, or not
+    /// encrypted at all, are encrypted again with the current key straight away. Values encrypted
+    /// with any other key fail the opening with [`crate::Error::WrongEncryptionKey`].
+    pub fn open_with_keys(working_dir: &Path, keys: Keyring) -> Result<Self> {
         let file_path = working_dir.join(DEFAULT_FILE_NAME);
         let db = sled::open(file_path.clone()).with_whatever_context(|_| {
             format!(
@@ -31,10 +53,63 @@ impl SledStore {
 
         let mut store = SledStore::new();
         store.file_path = Some(file_path.clone());
+        store.keys = keys;
+        store.reseal(&db)?;
         store.db = Some(db);
 
         Ok(store)
     }
+
+    /// Stores every value the way the current key would, which takes a pass over the values but
+    /// decrypts only those that need it. Without keys, values are either all encrypted or none
+    /// of them are, so the first one tells whether the store can be read.
+    fn reseal(&self, db: &sled::Db) -> Result<()> {
+        let entries = db.iter();
+        let entries: Box<dyn Iterator<Item = _>> = if self.keys.is_enabled() {
+            Box::new(entries)
+        } else {
+            Box::new(entries.take(1))
+        };
+        for entry in entries {
+            let (key, stored) =
+                entry.with_whatever_context(|_| "Couldn't scan keys of sled store")?;
+            let up_to_date = match stored.split_first() {
+                Some((&SEALED, sealed)) => self.keys.is_current(sealed),
+                _ => !self.keys.is_enabled(),
+            };
+            if up_to_date {
+                continue;
+            }
+            let key = String::from_utf8(key.to_vec())
+                .with_whatever_context(|_| "Couldn't convert scanned key to UTF-8")?;
+            let value = self.unseal(&key, &stored)?;
+            db.insert(key.as_str(), self.seal(&value)?)
+                .with_whatever_context(|_| format!("Couldn't re-encrypt key {}", key))?;
+        }
+        db.flush()
+            .with_whatever_context(|_| "Couldn't flush sled store")?;
+        Ok(())
+    }
+
+    /// The bytes `value` is stored as.
+    fn seal(&self, value: &str) -> Result<Vec<u8>> {
+        if !self.keys.is_enabled() {
+            return Ok(value.as_bytes().to_vec());
+        }
+        let mut stored = vec![SEALED];
+        stored.extend(self.keys.encrypt(value.as_bytes())?);
+        Ok(stored)
+    }
+
+    /// The value stored for `key` as `stored`.
+    fn unseal(&self, key: &str, stored: &[u8]) -> Result<String> {
+        let value = match stored.split_first() {
+            Some((&SEALED, sealed)) => self.keys.decrypt(sealed)?,
+            _ => stored.to_vec(),
+        };
+        String::from_utf8(value)
+            .with_whatever_context(|_| format!("Couldn't convert value for key {} to UTF-8", key))
+    }
 }
 
 impl KvsEngine for SledStore {
@@ -42,8 +117,9 @@ impl KvsEngine for SledStore {
         let Some(db) = self.db.as_ref() else {
             whatever!("Sled store not initialized");
         };
-        db.insert(key.clone(), value.as_bytes())
+        db.insert(key.clone(), self.seal(&value)?)
             .with_whatever_context(|_| format!("Couldn't insert key {} into sled store", key))?;
+        self.feed.publish(key, Change::Set { value });
 
         Ok(())
     }
@@ -57,10 +133,7 @@ impl KvsEngine for SledStore {
             .get(key.clone())
             .with_whatever_context(|_| format!("Couldn't get key {} from sled store", key))?;
         if let Some(value) = value_option {
-            let value = String::from_utf8(value.to_vec()).with_whatever_context(|_| {
-                format!("Couldn't convert value for key {} to UTF-8", key)
-            })?;
-            Ok(Some(value))
+            Ok(Some(self.unseal(&key, &value)?))
         } else {
             Ok(None)
         }
@@ -75,16 +148,159 @@ impl KvsEngine for SledStore {
             .remove(key.clone())
             .with_whatever_context(|_| format!("Couldn't remove key {} from sled store", key))?;
         if let Some(value) = value_option {
-            let value = String::from_utf8(value.to_vec()).with_whatever_context(|_| {
-                format!("Couldn't convert value for key {} to UTF-8", key)
-            })?;
+            let value = self.unseal(&key, &value)?;
+            self.feed.publish(key, Change::Rm);
             Ok(Some(value))
         } else {
             Ok(None)
         }
     }
 
+    fn write_batch(&mut self, batch: Vec<WriteOp>) -> Result<()> {
+        let Some(db) = self.db.as_ref() else {
+            whatever!("Sled store not initialized");
+        };
+
+        let mut sled_batch = sled::Batch::default();
+        let mut changes = Vec::new();
+        // Whether the keys written earlier in the batch hold a value, to publish only the
+        // removals of keys that were there
+        let mut present = HashMap::new();
+        for op in batch {
+            match op {
+                WriteOp::Set { key, value } => {
+                    sled_batch.insert(key.as_str(), self.seal(&value)?);
+                    present.insert(key.clone(), true);
+                    changes.push((key, Change::Set { value }));
+                }
+                WriteOp::Rm { key } => {
+                    let existed = match present.get(&key) {
+                        Some(existed) => *existed,
+                        None => db.contains_key(key.as_str()).with_whatever_context(|_| {
+                            format!("Couldn't get key {} from sled store", key)
+                        })?,
+                    };
+                    sled_batch.remove(key.as_str());
+                    present.insert(key.clone(), false);
+                    if existed {
+                        changes.push((key, Change::Rm));
+                    }
+                }
+            }
+        }
+        db.apply_batch(sled_batch)
+            .with_whatever_context(|_| "Couldn't apply batch to sled store")?;
+        for (key, change) in changes {
+            self.feed.publish(key, change);
+        }
+
+        Ok(())
+    }
+
+    fn scan(&self, prefix: String) -> Result<ScanIter<'_>> {
+        let Some(db) = self.db.as_ref() else {
+            whatever!("Sled store not initialized");
+        };
+
+        Ok(Box::new(db.scan_prefix(prefix).map(|entry| {
+            let (key, value) =
+                entry.with_whatever_context(|_| "Couldn't scan keys of sled store")?;
+            let key = String::from_utf8(key.to_vec())
+                .with_whatever_context(|_| "Couldn't convert scanned key to UTF-8")?;
+            let value = self.unseal(&key, &value)?;
+            Ok((key, value))
+        })))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let Some(db) = self.db.as_ref() else {
+            whatever!("Sled store not initialized");
+        };
+
+        Ok(EngineStats {
+            live_keys: db.len(),
+            disk_size: db
+                .size_on_disk()
+                .with_whatever_context(|_| "Couldn't get size on disk of sled store")?,
+            ..EngineStats::default()
+        })
+    }
+
+    fn watch(&self, pattern: KeyPattern, after: Option<Position>) -> Result<Watcher> {
+        self.feed.subscribe(pattern, after)
+    }
+
     fn name(&self) -> &'static str {
         "SledStore"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionKey;
+    use crate::Error;
+
+    fn key() -> EncryptionKey {
+        EncryptionKey::from_hex(&EncryptionKey::generate()).unwrap()
+    }
+
+    fn stored(store: &SledStore, key: &str) -> Vec<u8> {
+        store
+            .db
+            .as_ref()
+            .unwrap()
+            .get(key)
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    #[test]
+    fn encryption() {
+        let temp_dir = tempfile::tempdir().expect("unable to create temporary working directory");
+        {
+            let mut store = SledStore::open(temp_dir.path()).expect("unable to open store");
+            store
+                .set("key1".to_owned(), "value1".to_owned())
+                .expect("unable to set key");
+        }
+
+        let (old, new) = (key(), key());
+        let mut store =
+            SledStore::open_with_keys(temp_dir.path(), Keyring::new(old.clone(), vec![]))
+                .expect("unable to open store");
+        store
+            .set("key2".to_owned(), "value2".to_owned())
+            .expect("unable to set key");
+        for key in ["key1", "key2"] {
+            assert_eq!(stored(&store, key)[0], SEALED);
+        }
+        let sealed = stored(&store, "key1");
+        drop(store);
+
+        let store = SledStore::open_with_keys(temp_dir.path(), Keyring::new(new, vec![old]))
+            .expect("unable to open store");
+        assert_ne!(stored(&store, "key1"), sealed);
+        let pairs: Vec<(String, String)> = store
+            .scan("key".to_owned())
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("key1".to_owned(), "value1".to_owned()),
+                ("key2".to_owned(), "value2".to_owned())
+            ]
+        );
+        drop(store);
+
+        for result in [
+            SledStore::open_with_keys(temp_dir.path(), Keyring::new(key(), vec![])),
+            SledStore::open(temp_dir.path()),
+        ] {
+            assert!(matches!(result, Err(Error::WrongEncryptionKey { .. })));
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! Snapshots of the in-memory engine, to keep its pairs across restarts.
+//!
+//! A [`MemStore`](crate::MemStore) opened with
+//! [`MemStore::open_with_snapshots`](crate::MemStore::open_with_snapshots) loads the snapshot
+//! kept in its directory, if there is one, and hands out [`Snapshot`]s of its pairs through
+//! [`KvsEngine::snapshot`](crate::KvsEngine::snapshot). Taking one only shares the pairs: the
+//! store keeps the writes made while a snapshot still holds them aside, and folds them in once it
+//! is done, so that writing the snapshot, which takes a while, needs no lock on the store, and
+//! no write has to copy the pairs meanwhile.
+//!
+//! A snapshot is a backup archive, see [`crate::backup`], sealed with the keys of the store when
+//! they encrypt anything. It is written next to the previous one, which it only replaces once
+//! complete, so that a crash while writing leaves the previous one as it was.
+//!
+//! ```
+//! use kvs::encryption::Keyring;
+//! use kvs::{KvsEngine, MemStore};
+//!
+//! let dir = tempfile::TempDir::new().unwrap();
+//! let mut store = MemStore::open_with_snapshots(dir.path(), Keyring::default())?;
+//! store.set("key1".to_owned(), "value1".to_owned())?;
+//! store.snapshot().unwrap().write()?;
+//!
+//! let store = MemStore::open_with_snapshots(dir.path(), Keyring::default())?;
+//! assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::backup::{read_backup, write_pairs, BACKUP_HEADER};
+use crate::encryption::Keyring;
+use crate::err::Result;
+use crate::mem_store::SharedMap;
+use snafu::ResultExt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Name of the file the in-memory engine keeps its snapshot in.
+pub const SNAPSHOT_FILE_NAME: &str = "mem.snapshot";
+
+/// Where the snapshots of a store go, shared by the store and its snapshots.
+#[derive(Debug)]
+pub(crate) struct SnapshotFile {
+    path: PathBuf,
+    keys: Keyring,
+    /// The version of the store the last snapshot written was taken at.
+    saved: AtomicU64,
+    /// Held while writing a snapshot, so that two of them don't write the same file.
+    writing: Mutex<()>,
+}
+
+impl SnapshotFile {
+    /// The snapshot file in `dir`.
+    pub(crate) fn new(dir: &Path, keys: Keyring) -> Self {
+        Self {
+            path: dir.join(SNAPSHOT_FILE_NAME),
+            keys,
+            saved: AtomicU64::new(0),
+            writing: Mutex::new(()),
+        }
+    }
+
+    /// The pairs of the snapshot, none if there is no snapshot yet.
+    pub(crate) fn load(&self) -> Result<Vec<(String, String)>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let content = fs::read(&self.path)
+            .with_whatever_context(|_| format!("Couldn't read snapshot {}", self.path.display()))?;
+        let archive = if content.starts_with(BACKUP_HEADER.as_bytes()) {
+            content
+        } else {
+            self.keys.decrypt(&content)?
+        };
+        read_backup(archive.as_slice())
+            .with_whatever_context(|_| format!("Couldn't load snapshot {}", self.path.display()))
+    }
+
+    /// Whether the snapshot taken at `version` is already written.
+    pub(crate) fn is_saved(&self, version: u64) -> bool {
+        self.saved.load(Ordering::Relaxed) >= version
+    }
+
+    /// Bytes the snapshot takes on disk.
+    pub(crate) fn size(&self) -> u64 {
+        fs::metadata(&self.path).map_or(0, |metadata| metadata.len())
+    }
+}
+
+/// The pairs of a store at some point, to be written to its snapshot file.
+#[derive(Debug)]
+pub struct Snapshot {
+    pub(crate) pairs: SharedMap,
+    /// The number of writes the store had gone through when the snapshot was taken.
+    pub(crate) version: u64,
+    pub(crate) file: Arc<SnapshotFile>,
+}
+
+impl Snapshot {
+    /// Where the snapshot gets written.
+    pub fn path(&self) -> &Path {
+        &self.file.path
+    }
+
+    /// Writes the snapshot in place of the previous one, unless a later one was written
+    /// meanwhile. Returns the number of pairs written, none when it was skipped.
+    pub fn write(&self) -> Result<usize> {
+        let _writing = self
+            .file
+            .writing
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if self.file.is_saved(self.version) {
+            return Ok(0);
+        }
+
+        // Sorted, so that snapshots of the same pairs are the same
+        let mut pairs: Vec<_> = self.pairs.iter().collect();
+        pairs.sort_unstable();
+        let pairs = pairs
+            .into_iter()
+            .map(|(key, value)| Ok((key.clone(), value.clone())));
+        let path = &self.file.path;
+        let temp_path = path.with_extension("snapshot.tmp");
+        let file = File::create(&temp_path).with_whatever_context(|_| {
+            format!("Couldn't create snapshot {}", temp_path.display())
+        })?;
+        let mut writer = BufWriter::new(file);
+        let count = if self.file.keys.is_enabled() {
+            let mut archive = Vec::new();
+            let count = write_pairs(pairs, &mut archive)?;
+            writer
+                .write_all(&self.file.keys.encrypt(&archive)?)
+                .with_whatever_context(|_| "Couldn't write snapshot")?;
+            count
+        } else {
+            write_pairs(pairs, &mut writer)?
+        };
+        let file = writer
+            .into_inner()
+            .with_whatever_context(|_| "Couldn't write snapshot")?;
+        // On disk before it replaces the previous one, so that a crash leaves either of them
+        file.sync_all()
+            .with_whatever_context(|_| "Couldn't write snapshot")?;
+        fs::rename(&temp_path, path)
+            .with_whatever_context(|_| format!("Couldn't replace snapshot {}", path.display()))?;
+
+        self.file.saved.fetch_max(self.version, Ordering::Relaxed);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::EncryptionKey;
+    use crate::{Error, KvsEngine, MemStore};
+    use tempfile::TempDir;
+
+    fn set(store: &mut MemStore, key: &str, value: &str) {
+        store.set(key.to_owned(), value.to_owned()).unwrap();
+    }
+
+    fn get(store: &MemStore, key: &str) -> Option<String> {
+        store.get(key.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        assert!(store.snapshot().is_none());
+        set(&mut store, "key1", "value1");
+        set(&mut store, "key2", "value\nwith newline");
+        store.remove("key1".to_owned()).unwrap();
+        let snapshot = store.snapshot().unwrap();
+        assert_eq!(snapshot.write().unwrap(), 1);
+        // Nothing changed since
+        assert!(store.snapshot().is_none());
+        assert_eq!(snapshot.write().unwrap(), 0);
+
+        let store = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        assert_eq!(get(&store, "key1"), None);
+        assert_eq!(get(&store, "key2"), Some("value\nwith newline".to_owned()));
+        assert!(store.stats().unwrap().disk_size > 0);
+        assert!(store.snapshot().is_none());
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        set(&mut store, "key1", "value1");
+        let snapshot = store.snapshot().unwrap();
+        // Writes made while the snapshot is held are left out of it
+        set(&mut store, "key1", "value2");
+        set(&mut store, "key2", "value2");
+        let later = store.snapshot().unwrap();
+        assert_eq!(snapshot.write().unwrap(), 1);
+        assert_eq!(get(&store, "key1"), Some("value2".to_owned()));
+
+        let reopened = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        assert_eq!(get(&reopened, "key1"), Some("value1".to_owned()));
+        assert_eq!(get(&reopened, "key2"), None);
+
+        // An earlier snapshot written last doesn't replace a later one
+        assert_eq!(later.write().unwrap(), 2);
+        assert_eq!(snapshot.write().unwrap(), 0);
+        let reopened = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        assert_eq!(get(&reopened, "key1"), Some("value2".to_owned()));
+        assert!(!temp_dir.path().join("mem.snapshot.tmp").exists());
+    }
+
+    #[test]
+    fn encrypted() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = EncryptionKey::from_hex(&EncryptionKey::generate()).unwrap();
+        let keys = Keyring::new(key.clone(), Vec::new());
+        let mut store = MemStore::open_with_snapshots(temp_dir.path(), keys.clone()).unwrap();
+        set(&mut store, "key1", "secret value");
+        store.snapshot().unwrap().write().unwrap();
+        let content = fs::read(temp_dir.path().join(SNAPSHOT_FILE_NAME)).unwrap();
+        assert!(!String::from_utf8_lossy(&content).contains("secret value"));
+
+        let store = MemStore::open_with_snapshots(temp_dir.path(), keys).unwrap();
+        assert_eq!(get(&store, "key1"), Some("secret value".to_owned()));
+        let err = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default());
+        assert!(matches!(err, Err(Error::WrongEncryptionKey { .. })));
+        // Readable after the key is rotated
+        let new_key = EncryptionKey::from_hex(&EncryptionKey::generate()).unwrap();
+        let rotated = Keyring::new(new_key, vec![key]);
+        let store = MemStore::open_with_snapshots(temp_dir.path(), rotated).unwrap();
+        assert_eq!(get(&store, "key1"), Some("secret value".to_owned()));
+    }
+
+    #[test]
+    fn corrupted() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).unwrap();
+        set(&mut store, "key1", "value1");
+        store.snapshot().unwrap().write().unwrap();
+        let path = temp_dir.path().join(SNAPSHOT_FILE_NAME);
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replace("value1", "value2")).unwrap();
+        assert!(MemStore::open_with_snapshots(temp_dir.path(), Keyring::default()).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::err::Result;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use snafu::{whatever, ResultExt};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Reads every certificate of a PEM file, e.g. a certificate followed by its intermediates.
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = File::open(path)
+        .with_whatever_context(|_| format!("Couldn't open certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .with_whatever_context(|_| format!("Couldn't parse certificate file {}", path.display()))?;
+    if certs.is_empty() {
+        whatever!("No certificate found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// Reads the first private key of a PEM file, in PKCS#1, PKCS#8 or SEC1 format.
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = File::open(path)
+        .with_whatever_context(|_| format!("Couldn't open key file {}", path.display()))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(file))
+        .with_whatever_context(|_| format!("Couldn't parse key file {}", path.display()))?;
+    match key {
+        Some(key) => Ok(key),
+        None => whatever!("No private key found in {}", path.display()),
+    }
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots
+            .add(cert)
+            .with_whatever_context(|_| format!("Invalid CA certificate in {}", path.display()))?;
+    }
+    Ok(roots)
+}
+
+/// TLS settings of a server. With `client_ca`, clients must present a certificate signed by it.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerConfig> {
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .with_whatever_context(|_| "Couldn't set up TLS protocol versions")?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(load_roots(client_ca)?),
+                provider(),
+            )
+            .build()
+            .with_whatever_context(|_| "Couldn't set up client certificate verification")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(load_certs(cert)?, load_key(key)?)
+        .with_whatever_context(|_| {
+            format!(
+                "Certificate {} doesn't match key {}",
+                cert.display(),
+                key.display()
+            )
+        })
+}
+
+/// TLS settings of a client trusting the certificates signed by `ca`. With `identity`, the
+/// client presents that certificate and key to servers requiring one.
+pub fn client_config(ca: &Path, identity: Option<(&Path, &Path)>) -> Result<ClientConfig> {
+    let builder = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .with_whatever_context(|_| "Couldn't set up TLS protocol versions")?
+        .with_root_certificates(load_roots(ca)?);
+
+    match identity {
+        Some((cert, key)) => builder
+            .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+            .with_whatever_context(|_| {
+                format!(
+                    "Certificate {} doesn't match key {}",
+                    cert.display(),
+                    key.display()
+                )
+            }),
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
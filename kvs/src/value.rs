@@ -0,0 +1,178 @@
+//! Typed values: lists, hashes and sets, kept by every [`crate::KvsEngine`] next to plain
+//! strings. Counters are strings holding an integer.
+//!
+//! Engines only store strings, so a typed value is stored as [`TYPE_TAG`] followed by the JSON
+//! of the value and its type. Strings are stored as they are, unless they start with the tag
+//! themselves, in which case they are encoded the same way so that they read back unchanged.
+//!
+//! ```
+//! use kvs::value::{Value, ValueType};
+//!
+//! let list = Value::List(["b".to_owned(), "a".to_owned()].into());
+//! let stored = list.encode()?;
+//! assert_eq!(Value::decode(stored)?, list);
+//! assert_eq!(list.value_type(), ValueType::List);
+//! assert_eq!(Value::String("a".to_owned()).encode()?, "a");
+//! # Ok::<(), kvs::Error>(())
+//! ```
+
+use crate::err::{Result, ResultExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt;
+use std::ops::Range;
+
+/// The character starting the stored form of every typed value.
+pub const TYPE_TAG: char = '\0';
+
+/// A value, as commands see it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Value {
+    String(String),
+    /// Pushed at the front, so that the last value pushed comes first.
+    List(VecDeque<String>),
+    Hash(BTreeMap<String, String>),
+    Set(BTreeSet<String>),
+}
+
+/// The type of a [`Value`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    String,
+    List,
+    Hash,
+    Set,
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueType::String => "string",
+            ValueType::List => "list",
+            ValueType::Hash => "hash",
+            ValueType::Set => "set",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl Value {
+    /// Reads a value as stored by an engine.
+    pub fn decode(stored: String) -> Result<Self> {
+        match stored.strip_prefix(TYPE_TAG) {
+            Some(json) => serde_json::from_str(json)
+                .with_whatever_context(|_| format!("Invalid typed value {:?}", json)),
+            None => Ok(Value::String(stored)),
+        }
+    }
+
+    /// The value as an engine stores it.
+    pub fn encode(&self) -> Result<String> {
+        match self {
+            Value::String(value) if !value.starts_with(TYPE_TAG) => Ok(value.clone()),
+            _ => {
+                let json = serde_json::to_string(self)
+                    .with_whatever_context(|_| format!("Couldn't serialize value {:?}", self))?;
+                Ok(format!("{}{}", TYPE_TAG, json))
+            }
+        }
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::String(_) => ValueType::String,
+            Value::List(_) => ValueType::List,
+            Value::Hash(_) => ValueType::Hash,
+            Value::Set(_) => ValueType::Set,
+        }
+    }
+
+    /// The value as listed by scans: strings as they are, typed values as the JSON of their
+    /// contents, e.g. `["b","a"]` for a list.
+    pub fn into_text(self) -> Result<String> {
+        let json = match self {
+            Value::String(value) => return Ok(value),
+            Value::List(values) => serde_json::to_string(&values),
+            Value::Hash(fields) => serde_json::to_string(&fields),
+            Value::Set(members) => serde_json::to_string(&members),
+        };
+        json.with_whatever_context(|_| "Couldn't serialize typed value")
+    }
+}
+
+/// A command expecting a value of one type found one of another under its key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrongType {
+    pub key: String,
+    pub expected: ValueType,
+    pub found: ValueType,
+}
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Key {} holds a {}, not a {}",
+            self.key, self.found, self.expected
+        )
+    }
+}
+
+/// The indexes of the elements from `start` to `stop` included, out of `len`. Negative indexes
+/// count from the end, -1 being the last element, and indexes out of bounds are clamped.
+pub fn list_range(len: usize, start: i64, stop: i64) -> Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.clamp(0, len);
+    let stop = if stop < 0 { len + stop } else { stop }.clamp(-1, len - 1);
+    if start > stop {
+        0..0
+    } else {
+        start as usize..stop as usize + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoding() {
+        let values = [
+            Value::String("value1".to_owned()),
+            Value::String(String::new()),
+            // Read back as a string, not as a typed value
+            Value::String(format!("{}{{\"type\":\"set\",\"value\":[]}}", TYPE_TAG)),
+            Value::List(["a".to_owned(), "a".to_owned()].into()),
+            Value::Hash([("field1".to_owned(), "value1".to_owned())].into()),
+            Value::Set(["a".to_owned(), "b".to_owned()].into()),
+        ];
+        for value in values {
+            let stored = value.encode().unwrap();
+            assert_eq!(Value::decode(stored).unwrap(), value);
+        }
+        assert!(Value::decode(format!("{}not json", TYPE_TAG)).is_err());
+    }
+
+    #[test]
+    fn text() {
+        let list = Value::List(["b".to_owned(), "a".to_owned()].into());
+        assert_eq!(list.into_text().unwrap(), "[\"b\",\"a\"]");
+        let hash = Value::Hash([("field1".to_owned(), "value1".to_owned())].into());
+        assert_eq!(hash.into_text().unwrap(), "{\"field1\":\"value1\"}");
+        let string = Value::String("[1]".to_owned());
+        assert_eq!(string.into_text().unwrap(), "[1]");
+    }
+
+    #[test]
+    fn range() {
+        assert_eq!(list_range(5, 0, -1), 0..5);
+        assert_eq!(list_range(5, 1, 2), 1..3);
+        assert_eq!(list_range(5, -2, -1), 3..5);
+        assert_eq!(list_range(5, -10, 10), 0..5);
+        assert_eq!(list_range(5, 3, 1), 0..0);
+        assert_eq!(list_range(5, 5, 10), 0..0);
+        assert_eq!(list_range(0, 0, -1), 0..0);
+    }
+}
@@ -0,0 +1,555 @@
+use crate::err::Result;
+use crate::index::is_reserved;
+use serde::{Deserialize, Serialize};
+use snafu::{whatever, ResultExt};
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+
+/// Number of past events kept in memory, so that a watcher can reconnect and resume from the
+/// last sequence number it saw.
+pub const HISTORY_LEN: usize = 4096;
+
+/// Bytes of keys and values the history may hold, so that large values don't keep hundreds of
+/// megabytes alive. Whichever of this and [`HISTORY_LEN`] is reached first bounds the history.
+pub const HISTORY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Number of live events a watcher may have pending. A watcher falling further behind is
+/// dropped rather than buffered without end; it can resume from the last position it got.
+pub const WATCHER_BUFFER: usize = 1024;
+
+/// What happened to a key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Change {
+    Set { value: String },
+    Rm,
+}
+
+/// A change of a single key. Sequence numbers start at 1 and grow by one with every change of
+/// the store, whatever the key. They start over whenever the store is opened again, so they come
+/// with the epoch of the feed that numbered them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub epoch: u32,
+    pub seq: u64,
+    pub key: String,
+    #[serde(flatten)]
+    pub change: Change,
+}
+
+impl Event {
+    /// Roughly how much memory the event holds.
+    fn size(&self) -> usize {
+        let value_len = match &self.change {
+            Change::Set { value } => value.len(),
+            Change::Rm => 0,
+        };
+        size_of::<Event>() + self.key.len() + value_len
+    }
+
+    /// Where a watcher that saw this event resumes from.
+    pub fn position(&self) -> Position {
+        Position {
+            epoch: self.epoch,
+            seq: self.seq,
+        }
+    }
+}
+
+/// A point in the changes of a store: a sequence number, and the epoch of the feed it belongs
+/// to. Written `<epoch>-<seq>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub epoch: u32,
+    pub seq: u64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.seq)
+    }
+}
+
+impl FromStr for Position {
+    type Err = crate::Error;
+
+    fn from_str(position: &str) -> Result<Self> {
+        let parsed = position
+            .split_once('-')
+            .and_then(|(epoch, seq)| Some((epoch.parse().ok()?, seq.parse().ok()?)));
+        match parsed {
+            Some((epoch, seq)) => Ok(Position { epoch, seq }),
+            None => whatever!("Invalid position {}: expected <epoch>-<seq>", position),
+        }
+    }
+}
+
+/// The keys a watcher is interested in: `key` matches a single key, `prefix*` every key starting
+/// with `prefix`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeyPattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl KeyPattern {
+    pub fn parse(pattern: &str) -> Self {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => KeyPattern::Prefix(prefix.to_owned()),
+            None => KeyPattern::Exact(pattern.to_owned()),
+        }
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            KeyPattern::Exact(exact) => key == exact,
+            KeyPattern::Prefix(prefix) => key.starts_with(prefix),
+        }
+    }
+
+    /// Whether every key matched by `other` is also matched by this pattern.
+    pub fn covers(&self, other: &KeyPattern) -> bool {
+        match other {
+            KeyPattern::Exact(key) => self.matches(key),
+            KeyPattern::Prefix(prefix) => match self {
+                KeyPattern::Exact(_) => false,
+                KeyPattern::Prefix(own_prefix) => prefix.starts_with(own_prefix),
+            },
+        }
+    }
+}
+
+impl fmt::Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyPattern::Exact(key) => write!(f, "{}", key),
+            KeyPattern::Prefix(prefix) => write!(f, "{}*", prefix),
+        }
+    }
+}
+
+struct Subscriber {
+    pattern: KeyPattern,
+    sender: Sender<Event>,
+    /// Set when the subscriber is dropped for falling behind, so that its watcher can tell it
+    /// apart from the feed going away.
+    lagged: Arc<AtomicBool>,
+}
+
+struct FeedState {
+    next_seq: u64,
+    history: VecDeque<Event>,
+    /// Sum of the sizes of the events in `history`.
+    history_bytes: usize,
+    subscribers: Vec<Subscriber>,
+}
+
+/// Hands out sequence numbers to the changes of an engine and forwards them to its watchers.
+/// Engines publish from their write path, after the change has been applied.
+pub struct ChangeFeed {
+    /// Drawn at random, so that positions of an earlier run of the store (or of another store)
+    /// aren't mistaken for positions of this one.
+    epoch: u32,
+    state: Mutex<FeedState>,
+}
+
+impl ChangeFeed {
+    pub fn new() -> Self {
+        Self {
+            epoch: RandomState::new().build_hasher().finish() as u32,
+            state: Mutex::new(FeedState {
+                next_seq: 1,
+                history: VecDeque::new(),
+                history_bytes: 0,
+                subscribers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Records a change and sends it to every matching watcher. Never blocks on a watcher: one
+    /// with [`WATCHER_BUFFER`] events pending is dropped instead. Returns the sequence number,
+    /// or `None` for the keys kept for indexes, which aren't published.
+    pub fn publish(&self, key: String, change: Change) -> Option<u64> {
+        if is_reserved(&key) {
+            return None;
+        }
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let event = Event {
+            epoch: self.epoch,
+            seq: state.next_seq,
+            key,
+            change,
+        };
+        state.next_seq += 1;
+        // Watchers that hung up or fell behind are dropped here
+        state.subscribers.retain(|subscriber| {
+            if !subscriber.pattern.matches(&event.key) {
+                return !subscriber.sender.is_closed();
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    subscriber.lagged.store(true, Ordering::Relaxed);
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            }
+        });
+        state.history_bytes += event.size();
+        state.history.push_back(event.clone());
+        while state.history.len() > HISTORY_LEN || state.history_bytes > HISTORY_BYTES {
+            let Some(dropped) = state.history.pop_front() else {
+                break;
+            };
+            state.history_bytes -= dropped.size();
+        }
+
+        Some(event.seq)
+    }
+
+    /// The position of the latest change, with sequence number 0 if nothing changed yet.
+    pub fn last_position(&self) -> Position {
+        let state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        Position {
+            epoch: self.epoch,
+            seq: state.next_seq - 1,
+        }
+    }
+
+    /// Starts watching the keys matching `pattern`. With `after`, the kept changes following that
+    /// position are replayed first, so a watcher that reconnects misses nothing; it is an error if
+    /// the position is from another epoch, or if some of those changes were already dropped from
+    /// the history.
+    pub fn subscribe(&self, pattern: KeyPattern, after: Option<Position>) -> Result<Watcher> {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        let last_seq = state.next_seq - 1;
+        let mut replayed = Vec::new();
+        if let Some(Position { epoch, seq: after }) = after {
+            if epoch != self.epoch {
+                whatever!(
+                    "Position {} is from epoch {}, but the store is now at epoch {}: it was \
+                     reopened since, and the changes in between are unknown",
+                    Position { epoch, seq: after },
+                    epoch,
+                    self.epoch
+                );
+            }
+            if after > last_seq {
+                whatever!(
+                    "Unknown sequence number {}: the latest change is {}",
+                    after,
+                    last_seq
+                );
+            }
+            let first_kept = state
+                .history
+                .front()
+                .map_or(state.next_seq, |event| event.seq);
+            if after + 1 < first_kept {
+                whatever!(
+                    "Sequence number {} is too old: the oldest kept change is {}",
+                    after,
+                    first_kept
+                );
+            }
+            replayed.extend(
+                state
+                    .history
+                    .iter()
+                    .filter(|event| event.seq > after && pattern.matches(&event.key))
+                    .cloned(),
+            );
+        }
+        // The replayed events don't count against the buffer of live ones
+        let (sender, receiver) = channel(WATCHER_BUFFER + replayed.len());
+        for event in replayed {
+            // The receiver is still in our hands and there is room, so this can't fail
+            let _ = sender.try_send(event);
+        }
+        let lagged = Arc::new(AtomicBool::new(false));
+        state.subscribers.push(Subscriber {
+            pattern,
+            sender,
+            lagged: lagged.clone(),
+        });
+
+        Ok(Watcher {
+            receiver,
+            lagged,
+            runtime: None,
+            started_at: Position {
+                epoch: self.epoch,
+                seq: last_seq,
+            },
+        })
+    }
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The receiving end of a subscription. Dropping it unsubscribes.
+pub struct Watcher {
+    receiver: Receiver<Event>,
+    lagged: Arc<AtomicBool>,
+    /// Only built for [`Watcher::recv_timeout`], which needs a timer.
+    runtime: Option<Runtime>,
+    started_at: Position,
+}
+
+impl Watcher {
+    /// The position of the latest change when the subscription started. Every live change comes
+    /// after it; only replayed ones may come before.
+    pub fn started_at(&self) -> Position {
+        self.started_at
+    }
+
+    /// Waits for the next change without blocking a thread. Returns `None` once the engine is
+    /// dropped, and an error if the watcher fell behind and was dropped by the feed.
+    pub async fn recv(&mut self) -> Result<Option<Event>> {
+        match self.receiver.recv().await {
+            Some(event) => Ok(Some(event)),
+            None => self.closed().map(|_| None),
+        }
+    }
+
+    /// Waits for the next change, giving up after `timeout`. Returns `None` on timeout. Must not
+    /// be called from an async context; use [`Watcher::recv`] there.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Event>> {
+        if self.runtime.is_none() {
+            let runtime = Builder::new_current_thread()
+                .enable_time()
+                .build()
+                .with_whatever_context(|_| "Unable to start the timer of the watcher")?;
+            self.runtime = Some(runtime);
+        }
+        let runtime = self.runtime.as_ref().expect("the runtime was just built");
+        let receiver = &mut self.receiver;
+        let received =
+            runtime.block_on(async { tokio::time::timeout(timeout, receiver.recv()).await });
+        match received {
+            Ok(Some(event)) => Ok(Some(event)),
+            Ok(None) => {
+                self.closed()?;
+                whatever!("The change feed was closed")
+            }
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Why the channel is closed: an error if the feed dropped the watcher for lagging.
+    fn closed(&self) -> Result<()> {
+        if self.lagged.load(Ordering::Relaxed) {
+            whatever!(
+                "The watcher fell more than {} changes behind and was dropped; resume from the \
+                 position of the last change it got",
+                WATCHER_BUFFER
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    /// Blocks until the next change. Ends when the engine is dropped, or when the watcher fell
+    /// behind and was dropped by the feed.
+    fn next(&mut self) -> Option<Event> {
+        self.receiver.blocking_recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(value: &str) -> Change {
+        Change::Set {
+            value: value.to_owned(),
+        }
+    }
+
+    #[test]
+    fn pattern() {
+        assert!(KeyPattern::parse("key1").matches("key1"));
+        assert!(!KeyPattern::parse("key1").matches("key10"));
+        assert!(KeyPattern::parse("key*").matches("key10"));
+        assert!(KeyPattern::parse("*").matches("anything"));
+        assert!(!KeyPattern::parse("key*").matches("ke"));
+
+        assert!(KeyPattern::parse("key*").covers(&KeyPattern::parse("key1")));
+        assert!(KeyPattern::parse("key*").covers(&KeyPattern::parse("key1*")));
+        assert!(!KeyPattern::parse("key1*").covers(&KeyPattern::parse("key*")));
+        assert!(!KeyPattern::parse("key1").covers(&KeyPattern::parse("key1*")));
+        assert_eq!(KeyPattern::parse("key*").to_string(), "key*");
+    }
+
+    fn at(feed: &ChangeFeed, seq: u64) -> Option<Position> {
+        Some(Position {
+            epoch: feed.epoch,
+            seq,
+        })
+    }
+
+    #[test]
+    fn position() {
+        let position: Position = "12-345".parse().unwrap();
+        assert_eq!(
+            position,
+            Position {
+                epoch: 12,
+                seq: 345
+            }
+        );
+        assert_eq!(position.to_string(), "12-345");
+        assert!("345".parse::<Position>().is_err());
+        assert!("12-".parse::<Position>().is_err());
+        assert!("-345".parse::<Position>().is_err());
+    }
+
+    #[test]
+    fn publish_subscribe() {
+        let feed = ChangeFeed::new();
+        feed.publish("key0".to_owned(), set("value0"));
+        let mut watcher = feed.subscribe(KeyPattern::parse("key1*"), None).unwrap();
+        feed.publish("key1".to_owned(), set("value1"));
+        feed.publish("key2".to_owned(), set("value2"));
+        feed.publish("key10".to_owned(), Change::Rm);
+
+        assert_eq!(
+            watcher.next(),
+            Some(Event {
+                epoch: feed.epoch,
+                seq: 2,
+                key: "key1".to_owned(),
+                change: set("value1"),
+            })
+        );
+        assert_eq!(
+            watcher.next(),
+            Some(Event {
+                epoch: feed.epoch,
+                seq: 4,
+                key: "key10".to_owned(),
+                change: Change::Rm,
+            })
+        );
+        assert_eq!(watcher.recv_timeout(Duration::ZERO).unwrap(), None);
+        assert_eq!(watcher.started_at(), at(&feed, 1).unwrap());
+        assert_eq!(feed.last_position(), at(&feed, 4).unwrap());
+    }
+
+    #[test]
+    fn resume() {
+        let feed = ChangeFeed::new();
+        for i in 0..5 {
+            feed.publish(format!("key{}", i), set("value"));
+        }
+        let watcher = feed
+            .subscribe(KeyPattern::parse("*"), at(&feed, 3))
+            .unwrap();
+        feed.publish("key5".to_owned(), Change::Rm);
+        let seqs: Vec<u64> = watcher.take(3).map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![4, 5, 6]);
+
+        // Nothing to replay, but still a valid position
+        assert!(feed.subscribe(KeyPattern::parse("*"), at(&feed, 6)).is_ok());
+        assert!(feed.subscribe(KeyPattern::parse("*"), at(&feed, 0)).is_ok());
+        let err = feed
+            .subscribe(KeyPattern::parse("*"), at(&feed, 7))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Unknown sequence number"));
+    }
+
+    #[test]
+    fn resume_other_epoch() {
+        let feed = ChangeFeed::new();
+        feed.publish("key1".to_owned(), set("value1"));
+        let other = ChangeFeed::new();
+        other.publish("key1".to_owned(), set("value1"));
+        assert_ne!(feed.epoch, other.epoch);
+
+        let err = feed
+            .subscribe(KeyPattern::parse("*"), Some(other.last_position()))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("reopened"), "{}", err);
+    }
+
+    #[test]
+    fn resume_too_old() {
+        let feed = ChangeFeed::new();
+        for i in 0..HISTORY_LEN + 10 {
+            feed.publish(format!("key{}", i), set("value"));
+        }
+        let err = feed
+            .subscribe(KeyPattern::parse("*"), at(&feed, 5))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("too old"));
+        assert!(feed
+            .subscribe(KeyPattern::parse("*"), at(&feed, 10))
+            .is_ok());
+    }
+
+    #[test]
+    fn history_bytes() {
+        let feed = ChangeFeed::new();
+        let value = "v".repeat(1024 * 1024);
+        for i in 0..20 {
+            feed.publish(format!("key{}", i), set(&value));
+        }
+        {
+            let state = feed.state.lock().unwrap();
+            assert!(state.history_bytes <= HISTORY_BYTES);
+            assert_eq!(state.history.len(), 15);
+        }
+        let err = feed
+            .subscribe(KeyPattern::parse("*"), at(&feed, 1))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("too old"));
+        assert!(feed.subscribe(KeyPattern::parse("*"), at(&feed, 5)).is_ok());
+    }
+
+    #[test]
+    fn lagging_watcher() {
+        let feed = ChangeFeed::new();
+        let mut watcher = feed.subscribe(KeyPattern::parse("*"), None).unwrap();
+        for i in 0..=WATCHER_BUFFER {
+            feed.publish(format!("key{}", i), set("value"));
+        }
+        assert_eq!(feed.state.lock().unwrap().subscribers.len(), 0);
+
+        // What was buffered is still delivered, then the watcher learns it was dropped
+        for _ in 0..WATCHER_BUFFER {
+            assert!(watcher.recv_timeout(Duration::ZERO).unwrap().is_some());
+        }
+        let err = watcher.recv_timeout(Duration::ZERO).unwrap_err();
+        assert!(err.to_string().contains("behind"), "{}", err);
+    }
+
+    #[test]
+    fn drop_unsubscribes() {
+        let feed = ChangeFeed::new();
+        let watcher = feed.subscribe(KeyPattern::parse("*"), None).unwrap();
+        drop(watcher);
+        feed.publish("key1".to_owned(), set("value1"));
+        assert_eq!(feed.state.lock().unwrap().subscribers.len(), 0);
+    }
+}
@@ -0,0 +1,385 @@
+use assert_cmd::prelude::*;
+use kvs::compression::Compression;
+use kvs::encryption::{EncryptionKey, Keyring};
+use kvs::{KvStoreV2, KvsEngine, SledStore, DEFAULT_FILE_NAME_KVS, DEFAULT_FILE_NAME_SLED};
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::process::Command;
+use tempfile::TempDir;
+
+const KEY_COUNT: usize = 100;
+
+fn fill(store: &mut dyn KvsEngine) {
+    for i in 0..KEY_COUNT {
+        store
+            .set(format!("key{}", i), format!("value{}", i))
+            .unwrap();
+    }
+}
+
+fn check(store: &dyn KvsEngine) {
+    for i in 0..KEY_COUNT {
+        assert_eq!(
+            store.get(format!("key{}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    assert_eq!(store.scan(String::new()).unwrap().count(), KEY_COUNT);
+}
+
+#[test]
+fn migrate_kvs_to_sled() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(&mut KvStoreV2::open(temp_dir.path()).unwrap());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains(format!("Migrated {} keys", KEY_COUNT)));
+
+    assert!(!temp_dir.path().join(DEFAULT_FILE_NAME_KVS).exists());
+    assert!(temp_dir
+        .path()
+        .join(".kvs-migrate-backup")
+        .join(DEFAULT_FILE_NAME_KVS)
+        .exists());
+    assert!(!temp_dir.path().join(".kvs-migrate").exists());
+    check(&SledStore::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn migrate_sled_to_kvs() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(&mut SledStore::open(temp_dir.path()).unwrap());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "sled", "--to", "kvs", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success();
+
+    assert!(!temp_dir.path().join(DEFAULT_FILE_NAME_SLED).exists());
+    check(&KvStoreV2::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn migrate_invalid() {
+    let temp_dir = TempDir::new().unwrap();
+
+    // Nothing to migrate yet
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure();
+
+    fill(&mut KvStoreV2::open(temp_dir.path()).unwrap());
+
+    for (from, to) in [("kvs", "kvs"), ("kvs", "mem"), ("mem", "kvs")] {
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(["migrate", "--from", from, "--to", to, "--data-dir"])
+            .arg(temp_dir.path())
+            .assert()
+            .failure();
+    }
+
+    // The destination already has data
+    SledStore::open(temp_dir.path()).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("database file for engine sled existed"));
+
+    check(&KvStoreV2::open(temp_dir.path()).unwrap());
+}
+
+#[test]
+fn dump_restore() {
+    for (format, file_name) in [("jsonl", "dump.jsonl"), ("csv", "dump.csv")] {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("source");
+        let destination_dir = temp_dir.path().join("destination");
+        let dump_path = temp_dir.path().join(file_name);
+        std::fs::create_dir(&source_dir).unwrap();
+        {
+            let mut store = KvStoreV2::open(&source_dir).unwrap();
+            fill(&mut store);
+            store
+                .set(
+                    "key, with \"quotes\"".to_owned(),
+                    "value\nwith newline".to_owned(),
+                )
+                .unwrap();
+        }
+
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(["dump", "--format", format, "--out"])
+            .arg(&dump_path)
+            .arg("--data-dir")
+            .arg(&source_dir)
+            .assert()
+            .success()
+            .stderr(contains(format!("Dumped {} records", KEY_COUNT + 1)));
+
+        // The format is guessed from the extension
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(["restore", "--engine", "sled", "--in"])
+            .arg(&dump_path)
+            .arg("--data-dir")
+            .arg(&destination_dir)
+            .assert()
+            .success()
+            .stderr(contains(format!("Restored {} records", KEY_COUNT + 1)));
+
+        let store = SledStore::open(&destination_dir).unwrap();
+        assert_eq!(
+            store.get("key, with \"quotes\"".to_owned()).unwrap(),
+            Some("value\nwith newline".to_owned())
+        );
+        for i in 0..KEY_COUNT {
+            assert_eq!(
+                store.get(format!("key{}", i)).unwrap(),
+                Some(format!("value{}", i))
+            );
+        }
+    }
+}
+
+#[test]
+fn dump_stdout() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStoreV2::open(temp_dir.path()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["dump", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(
+            "{\"key\":\"key1\",\"value\":\"value1\"}\n{\"key\":\"key2\",\"value\":\"value2\"}\n",
+        );
+}
+
+#[test]
+fn verify() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(&mut KvStoreV2::open(temp_dir.path()).unwrap());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("No problems found"));
+
+    // Simulate a write cut off halfway through
+    let log_path = temp_dir.path().join(DEFAULT_FILE_NAME_KVS);
+    let mut content = std::fs::read_to_string(&log_path).unwrap();
+    content.push_str("{\"type\":\"Set\",\"key\":\"key1\",\"val");
+    std::fs::write(&log_path, content).unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("the last record is incomplete"))
+        .stdout(contains(format!(
+            "line {} is not a valid command",
+            KEY_COUNT + 1
+        )));
+}
+
+#[test]
+fn verify_foreign_command() {
+    let temp_dir = TempDir::new().unwrap();
+    fill(&mut KvStoreV2::open(temp_dir.path()).unwrap());
+    // Valid JSON of a command, but not one the engine accepts in its log
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(DEFAULT_FILE_NAME_KVS))
+        .unwrap();
+    writeln!(
+        log,
+        "{}",
+        serde_json::to_string(&kvs::Command::Backup).unwrap()
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains(format!(
+            "line {} is a Backup command",
+            KEY_COUNT + 1
+        )));
+}
+
+#[test]
+fn verify_compressed() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut store = KvStoreV2::open(temp_dir.path())
+        .unwrap()
+        .with_compression(Compression::Zstd);
+    store.set("key1".to_owned(), "value1".repeat(100)).unwrap();
+    drop(store);
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("No problems found"));
+
+    // The base64 of something that isn't zstd
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(DEFAULT_FILE_NAME_KVS))
+        .unwrap();
+    writeln!(
+        log,
+        "{{\"type\":\"Set\",\"key\":\"key2\",\"value\":\"bm90IHpzdGQ=\",\"compression\":\"zstd\"}}"
+    )
+    .unwrap();
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("line 2 has an unreadable value"));
+}
+
+#[test]
+fn encryption() {
+    let temp_dir = TempDir::new().unwrap();
+    let key_file = temp_dir.path().join("kvs.key");
+    let output = Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .arg("gen-key")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    fs::write(&key_file, &output.stdout).unwrap();
+    let keys = Keyring::new(EncryptionKey::read(&key_file).unwrap(), Vec::new());
+    let data_dir = temp_dir.path().join("data");
+    fs::create_dir(&data_dir).unwrap();
+    fill(&mut KvStoreV2::open_with_keys(&data_dir, keys.clone()).unwrap());
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(&data_dir)
+        .arg("--encryption-key-file")
+        .arg(&key_file)
+        .assert()
+        .success()
+        .stdout(contains(format!("Checked {} records", KEY_COUNT)));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(&data_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEncryptionKey"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled", "--data-dir"])
+        .arg(&data_dir)
+        .arg("--encryption-key-file")
+        .arg(&key_file)
+        .assert()
+        .success();
+    check(&SledStore::open_with_keys(&data_dir, keys).unwrap());
+    assert!(SledStore::open(&data_dir).is_err());
+}
+
+#[test]
+fn stats() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStoreV2::open(temp_dir.path()).unwrap();
+        fill(&mut store);
+    }
+    // Writes compact the log, so the stale records are appended behind the engine's back
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(temp_dir.path().join(DEFAULT_FILE_NAME_KVS))
+        .unwrap();
+    for i in 0..10 {
+        let command = kvs::Command::Set {
+            key: format!("key{}", i),
+            value: "overwritten".to_owned(),
+        };
+        writeln!(log, "{}", serde_json::to_string(&command).unwrap()).unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains(format!("Live keys: {}", KEY_COUNT)))
+        .stdout(contains("Stale records: 10"))
+        .stdout(contains("Compaction savings"));
+
+    let temp_dir = TempDir::new().unwrap();
+    fill(&mut SledStore::open(temp_dir.path()).unwrap());
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Engine: sled"))
+        .stdout(contains(format!("Live keys: {}", KEY_COUNT)));
+}
+
+#[test]
+fn stats_compression() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let mut store = KvStoreV2::open(temp_dir.path())
+            .unwrap()
+            .with_compression(Compression::Lz4);
+        for i in 0..10 {
+            let value = format!("{{\"id\":{},\"name\":\"value\"}}", i).repeat(50);
+            store.set(format!("key{}", i), value).unwrap();
+        }
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats", "--data-dir"])
+        .arg(temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("Values: "))
+        .stdout(contains("compression ratio"));
+}
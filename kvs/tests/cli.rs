@@ -1,10 +1,15 @@
 use assert_cmd::prelude::*;
+use kvs::encryption::EncryptionKey;
 use predicates::str::{contains, is_empty};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
 // `kvs-client` with no args should exit with a non-zero code.
@@ -224,6 +229,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // The data directory stays locked until the server is gone
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +312,8 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        // The data directory stays locked until the server is gone
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +344,1671 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let addr = "127.0.0.1:4006";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    assert!(data_dir.join("kvs.db").exists());
+    assert!(!temp_dir.path().join("kvs.db").exists());
+    let content = fs::read_to_string(data_dir.join("kvs.db")).unwrap();
+    assert!(content.contains("value1"));
+}
+
+#[test]
+fn cli_data_dir_locked() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already in use"));
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["migrate", "--from", "kvs", "--to", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already in use"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    // The lock is gone together with the process
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        "addr = \"127.0.0.1:4009\"\nengine = \"kvs\"\ndata_dir = \"from-config\"\n",
+    )
+    .unwrap();
+
+    // Environment variables override the config file
+    let stderr_path = temp_dir.path().join("stderr");
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .env("KVS_ENGINE", "sled")
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("127.0.0.1:4009"));
+    assert!(temp_dir.path().join("from-config").join("sled.db").exists());
+    assert!(!temp_dir.path().join("from-config").join("kvs.db").exists());
+
+    // Arguments override both
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--data-dir", "from-args"])
+        .env("KVS_ENGINE", "sled")
+        .env("KVS_DATA_DIR", "from-env")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(temp_dir.path().join("from-args").join("sled.db").exists());
+    assert!(!temp_dir.path().join("from-env").exists());
+
+    // Unknown settings are rejected
+    fs::write(&config_path, "port = 4009\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+fn cli_backup_restore(engine: &str, source_addr: &str, destination_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let backup_path = temp_dir.path().join("backup.kvs");
+    let mut source = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            engine,
+            "--addr",
+            source_addr,
+            "--data-dir",
+            "source",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let mut destination = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", destination_addr])
+        .args(["--data-dir", "destination"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for i in 0..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", &format!("key{}", i), &format!("value{}", i)])
+            .args(["--addr", source_addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "--out"])
+        .arg(&backup_path)
+        .args(["--addr", source_addr])
+        .assert()
+        .success()
+        .stdout(contains("Backed up 10 keys"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", "--in"])
+        .arg(&backup_path)
+        .args(["--addr", destination_addr])
+        .assert()
+        .success()
+        .stdout(contains("Restored 10 keys"));
+    for i in 0..10 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", &format!("key{}", i), "--addr", destination_addr])
+            .assert()
+            .success()
+            .stdout(format!("value{}\n", i));
+    }
+
+    // Only empty servers can be restored into
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", "--in"])
+        .arg(&backup_path)
+        .args(["--addr", destination_addr])
+        .assert()
+        .failure()
+        .stderr(contains("empty store"));
+
+    // Tampered archives are rejected
+    let archive = fs::read_to_string(&backup_path).unwrap();
+    fs::write(&backup_path, archive.replace("value3", "value4")).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", "--in"])
+        .arg(&backup_path)
+        .args(["--addr", destination_addr])
+        .assert()
+        .failure()
+        .stderr(contains("checksum mismatch"));
+
+    // The server survives bad requests
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key3", "--addr", destination_addr])
+        .assert()
+        .success()
+        .stdout("value3\n");
+
+    source.kill().expect("server exited before killed");
+    destination.kill().expect("server exited before killed");
+    source.wait().unwrap();
+    destination.wait().unwrap();
+}
+
+#[test]
+fn cli_backup_restore_kvs_engine() {
+    cli_backup_restore("kvs", "127.0.0.1:4010", "127.0.0.1:4011");
+}
+
+#[test]
+fn cli_backup_restore_sled_engine() {
+    cli_backup_restore("sled", "127.0.0.1:4012", "127.0.0.1:4013");
+}
+
+/// Reads lines from `reader` until one carrying a change event shows up, and parses it.
+fn next_event(lines: &mut impl Iterator<Item = std::io::Result<String>>) -> serde_json::Value {
+    loop {
+        let line = lines.next().expect("stream ended").unwrap();
+        let data = line.strip_prefix("data:").unwrap_or(&line).trim();
+        if data.starts_with('{') {
+            return serde_json::from_str(data).unwrap();
+        }
+    }
+}
+
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "key*", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for args in [
+        ["set", "key1", "value1"],
+        ["set", "other", "value2"],
+        ["set", "key2", "value3"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
+        .assert()
+        .success();
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    let event = next_event(&mut lines);
+    let epoch = event["epoch"].as_u64().unwrap();
+    assert_eq!(event["seq"], 1);
+    assert_eq!(event["key"], "key1");
+    assert_eq!(event["type"], "Set");
+    assert_eq!(event["value"], "value1");
+    let event = next_event(&mut lines);
+    assert_eq!(
+        (event["seq"].as_u64(), event["key"].as_str()),
+        (Some(3), Some("key2"))
+    );
+    let event = next_event(&mut lines);
+    assert_eq!(event["seq"], 4);
+    assert_eq!(event["type"], "Rm");
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().unwrap();
+
+    // Resuming replays what came after the given position
+    let from = format!("{}-1", epoch);
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "key1", "--from", &from, "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    let event = next_event(&mut lines);
+    assert_eq!(
+        (event["seq"].as_u64(), event["key"].as_str()),
+        (Some(4), Some("key1"))
+    );
+    watcher.kill().expect("watcher exited before killed");
+    watcher.wait().unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "key*", "--from", &format!("{}-100", epoch)])
+        .args(["--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("Unknown sequence number 100"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    // After a restart the sequence numbers start over, so old positions are refused
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "key*", "--from", &from, "--addr", addr])
+        .assert()
+        .failure()
+        .stderr(contains("reopened"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_watch_sse() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let mut server = Command::cargo_bin("server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let watch = |headers: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "GET /v1/watch/key* HTTP/1.1\r\nHost: {}\r\n{}\r\n",
+            addr, headers
+        )
+        .unwrap();
+        BufReader::new(stream).lines()
+    };
+    let set = |key: &str, value: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /v1/set/{}/{} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            key, value, addr
+        )
+        .unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        assert!(status.contains("200"), "{}", status);
+    };
+
+    let mut lines = watch("");
+    thread::sleep(Duration::from_millis(500));
+    set("key1", "value1");
+    set("other", "value2");
+    set("key2", "value3");
+    let event = next_event(&mut lines);
+    let epoch = event["epoch"].as_u64().unwrap();
+    assert_eq!(
+        (event["seq"].as_u64(), event["key"].as_str()),
+        (Some(1), Some("key1"))
+    );
+    let event = next_event(&mut lines);
+    assert_eq!(
+        (event["seq"].as_u64(), event["key"].as_str()),
+        (Some(3), Some("key2"))
+    );
+
+    // A reconnecting `EventSource` sends the id of the last event it got
+    let mut lines = watch(&format!("Last-Event-ID: {}-1\r\n", epoch));
+    let event = next_event(&mut lines);
+    assert_eq!(event["seq"], 3);
+
+    let mut lines = watch("Last-Event-ID: 1\r\n");
+    let status = lines.next().unwrap().unwrap();
+    assert!(status.contains("400"), "{}", status);
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+const AUTH_CONFIG: &str = r#"
+[auth.roles.admin]
+read = ["*"]
+write = ["*"]
+
+[auth.roles.users-admin]
+read = ["user:*"]
+write = ["user:*"]
+
+[[auth.users]]
+name = "root"
+token = "root-token"
+roles = ["admin"]
+
+[[auth.users]]
+name = "bob"
+token = "bob-token"
+roles = ["users-admin"]
+"#;
+
+/// Runs `bin` with the given token against `addr`.
+fn client_as(
+    bin: &str,
+    addr: &str,
+    token: Option<&str>,
+    args: &[&str],
+) -> assert_cmd::assert::Assert {
+    let mut command = Command::cargo_bin(bin).unwrap();
+    command
+        .args(args)
+        .args(["--addr", addr])
+        .env_remove("KVS_TOKEN");
+    if let Some(token) = token {
+        command.env("KVS_TOKEN", token);
+    }
+    command.assert()
+}
+
+fn cli_auth(server_bin: &str, client_bin: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.toml"), AUTH_CONFIG).unwrap();
+    let mut server = Command::cargo_bin(server_bin)
+        .unwrap()
+        .args(["--config", "kvs.toml", "--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    client_as(client_bin, addr, None, &["set", "motd", "hello"])
+        .failure()
+        .stderr(contains("Authentication failed"));
+    client_as(client_bin, addr, Some("wrong"), &["get", "motd"])
+        .failure()
+        .stderr(contains("invalid token"));
+    client_as(
+        client_bin,
+        addr,
+        Some("root-token"),
+        &["set", "motd", "hello"],
+    )
+    .success();
+    client_as(
+        client_bin,
+        addr,
+        Some("bob-token"),
+        &["set", "user:1", "bob"],
+    )
+    .success();
+    client_as(client_bin, addr, Some("bob-token"), &["get", "user:1"])
+        .success()
+        .stdout("bob\n");
+    client_as(client_bin, addr, Some("bob-token"), &["get", "motd"])
+        .failure()
+        .stderr(contains("User bob isn't allowed to read motd"));
+    client_as(client_bin, addr, Some("bob-token"), &["rm", "motd"])
+        .failure()
+        .stderr(contains("User bob isn't allowed to write motd"));
+    client_as(client_bin, addr, Some("root-token"), &["get", "motd"])
+        .success()
+        .stdout("hello\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_auth_tcp() {
+    cli_auth("kvs-server", "kvs-client", "127.0.0.1:4016");
+}
+
+#[test]
+fn cli_auth_http() {
+    cli_auth("server", "client", "127.0.0.1:4017");
+}
+
+#[test]
+fn cli_auth_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        AUTH_CONFIG.replace("roles = [\"admin\"]", "roles = [\"admni\"]"),
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--addr", "127.0.0.1:4018"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("User root has undefined role admni"));
+}
+
+/// Writes a CA, a server certificate for 127.0.0.1 and a client certificate, all signed by that
+/// CA, plus a second unrelated CA, as PEM files into `dir`.
+fn generate_certs(dir: &Path) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "kvs test CA");
+    let ca = ca_params.clone().self_signed(&ca_key).unwrap();
+    fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+    for (name, subject) in [("server", "127.0.0.1"), ("client", "kvs-client")] {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![subject.to_owned()])
+            .unwrap()
+            .signed_by(&key, &ca, &ca_key)
+            .unwrap();
+        fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+        fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+    }
+
+    let other_key = KeyPair::generate().unwrap();
+    let other_ca = ca_params.self_signed(&other_key).unwrap();
+    fs::write(dir.join("other-ca.pem"), other_ca.pem()).unwrap();
+}
+
+fn cli_tls(server_bin: &str, client_bin: &str, addr: &str, mtls_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    generate_certs(temp_dir.path());
+    let client = |addr: &str, args: &[&str]| {
+        let mut command = Command::cargo_bin(client_bin).unwrap();
+        command
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir);
+        command.assert()
+    };
+
+    let mut server = Command::cargo_bin(server_bin)
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--data-dir", "tls"])
+        .args(["--tls-cert", "server.pem", "--tls-key", "server.key"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let mut mtls_server = Command::cargo_bin(server_bin)
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", mtls_addr, "--data-dir", "mtls"])
+        .args(["--tls-cert", "server.pem", "--tls-key", "server.key"])
+        .args(["--tls-client-ca", "ca.pem"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    client(addr, &["set", "key1", "value1", "--ca", "ca.pem"]).success();
+    client(addr, &["get", "key1", "--ca", "ca.pem"])
+        .success()
+        .stdout("value1\n");
+    client(addr, &["rm", "key1", "--ca", "ca.pem"]).success();
+    client(addr, &["get", "key1", "--ca", "ca.pem"])
+        .success()
+        .stdout("Key not found\n");
+    // Plain text and untrusted servers are refused
+    client(addr, &["get", "key1"]).failure();
+    client(addr, &["get", "key1", "--ca", "other-ca.pem"]).failure();
+
+    // Mutual TLS
+    let identity = ["--cert", "client.pem", "--cert-key", "client.key"];
+    client(
+        mtls_addr,
+        &[&["set", "key1", "value1", "--ca", "ca.pem"], &identity[..]].concat(),
+    )
+    .success();
+    client(
+        mtls_addr,
+        &[&["get", "key1", "--ca", "ca.pem"], &identity[..]].concat(),
+    )
+    .success()
+    .stdout("value1\n");
+    client(mtls_addr, &["get", "key1", "--ca", "ca.pem"]).failure();
+
+    server.kill().expect("server exited before killed");
+    mtls_server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    mtls_server.wait().unwrap();
+}
+
+#[test]
+fn cli_tls_tcp() {
+    cli_tls(
+        "kvs-server",
+        "kvs-client",
+        "127.0.0.1:4019",
+        "127.0.0.1:4020",
+    );
+}
+
+#[test]
+fn cli_tls_http() {
+    cli_tls("server", "client", "127.0.0.1:4021", "127.0.0.1:4022");
+}
+
+/// Fetches `path` from the HTTP server at `addr`, returning the response with its headers.
+fn http_get(addr: &str, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, addr
+    )
+    .unwrap();
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_to_string(&mut response)
+        .unwrap();
+    response
+}
+
+fn cli_metrics(server_bin: &str, client_bin: &str, addr: &str, metrics_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin(server_bin)
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(["--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        Command::cargo_bin(client_bin)
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+    client(&["set", "key1", "value1"]).success();
+    client(&["set", "key1", "value2"]).success();
+    client(&["get", "key1"]).success();
+    client(&["get", "key2"]).success();
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    for line in [
+        "kvs_commands_total{command=\"set\",outcome=\"ok\"} 2",
+        "kvs_commands_total{command=\"get\",outcome=\"ok\"} 1",
+        "kvs_commands_total{command=\"get\",outcome=\"not_found\"} 1",
+        "kvs_command_duration_seconds_count{command=\"set\"} 2",
+        "kvs_engine_keys 1",
+        // Every write compacts the log
+        "kvs_engine_stale_ratio 0",
+        "kvs_engine_compactions_total 2",
+    ] {
+        assert!(
+            response.lines().any(|response| response == line),
+            "{} missing from\n{}",
+            line,
+            response
+        );
+    }
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_metrics_tcp() {
+    cli_metrics(
+        "kvs-server",
+        "kvs-client",
+        "127.0.0.1:4023",
+        "127.0.0.1:4024",
+    );
+}
+
+#[test]
+fn cli_metrics_http() {
+    cli_metrics("server", "client", "127.0.0.1:4025", "127.0.0.1:4026");
+
+    // The HTTP server also serves them next to the API
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4027";
+    let mut server = Command::cargo_bin("server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let response = http_get(addr, "/v1/get/key1");
+    assert!(response.contains("x-request-id: "), "{}", response);
+    let response = http_get(addr, "/metrics");
+    assert!(response.contains("kvs_commands_total{command=\"get\",outcome=\"not_found\"} 1"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_log_format_json() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let addr = "127.0.0.1:4028";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr, "--log-format", "json"])
+        .env("RUST_LOG", "info")
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .assert()
+        .success();
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    let content = fs::read_to_string(&stderr_path).unwrap();
+    let lines = content
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    assert!(lines
+        .iter()
+        .any(|line| line["fields"]["message"].as_str() == Some("Logger initialized!")));
+    // Every line about the request carries its ID, down to the engine
+    let evaluated = lines
+        .iter()
+        .find(|line| line["span"]["name"] == "evaluate_command")
+        .unwrap_or_else(|| panic!("no line about evaluate_command in\n{}", content));
+    assert_eq!(evaluated["span"]["command"], "set");
+    assert_eq!(evaluated["spans"][0]["name"], "request");
+    assert_eq!(evaluated["spans"][0]["id"], 1);
+}
+
+#[test]
+fn cli_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4029";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .args([
+            "--max-value-size",
+            "8",
+            "--max-connections",
+            "2",
+            "--timeout",
+            "1",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    client(&["set", "key1", "123456789"])
+        .failure()
+        .stderr(contains("Value is 9 bytes long, more than the 8 allowed"));
+    client(&["set", "key1", "12345678"]).success();
+
+    // A client that never sends its request doesn't hold the server up, neither while it is
+    // waited for nor after
+    let mut idle = TcpStream::connect(addr).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let started = Instant::now();
+    client(&["get", "key1"]).success().stdout("12345678\n");
+    assert!(started.elapsed() < Duration::from_millis(500));
+    let mut response = String::new();
+    idle.read_to_string(&mut response).unwrap();
+    assert_eq!(response, "ERR ERROR Timed out reading the request");
+    client(&["get", "key1"]).success().stdout("12345678\n");
+
+    // Watches keep counting as open connections
+    let watch = || {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "WATCH key*").unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert!(lines.next().unwrap().unwrap().starts_with("OK"));
+        lines
+    };
+    let _first = watch();
+    let _second = watch();
+    client(&["get", "key1"]).failure().stderr(contains(
+        "Too many connections, the server allows 2 at a time",
+    ));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_rate_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4030";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .args(["--rate-limit", "0.5", "--rate-burst", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    client(&["set", "key1", "value1"]).success();
+    client(&["get", "key1"]).success().stdout("value1\n");
+    client(&["get", "key1"])
+        .failure()
+        .stderr(contains("Rate limit exceeded, retry in"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_repl_script() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4036";
+    let mut server = Command::cargo_bin("server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let repl = |script: &str| {
+        let path = temp_dir.path().join("script.kvs");
+        fs::write(&path, script).unwrap();
+        Command::cargo_bin("client")
+            .unwrap()
+            .args(["repl", "--script"])
+            .arg(&path)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    let output = repl(
+        "# a comment\n\
+         set key1 value1\n\
+         set key2 \"line1\\nline2\"\n\
+         \\timing\n\
+         get key1\n\
+         \\timing\n\
+         scan key\n\
+         \n\
+         batch\n\
+         set key3 value  3\n\
+         get key1\n\
+         rm missing\n\
+         end\n\
+         get key3\n\
+         exit\n\
+         get never\n",
+    )
+    .success()
+    .get_output()
+    .stdout
+    .clone();
+    let output = String::from_utf8(output).unwrap();
+    let (timings, lines): (Vec<_>, Vec<_>) =
+        output.lines().partition(|line| line.starts_with("Time: "));
+    assert_eq!(timings.len(), 1);
+    assert_eq!(
+        lines,
+        [
+            "OK",
+            "OK",
+            "Timing is on",
+            "value1",
+            "Timing is off",
+            "key1 = value1",
+            "key2 = (2 lines)",
+            "| line1",
+            "| line2",
+            "(2 keys)",
+            "OK",
+            "value1",
+            "Key not found",
+            "value 3",
+        ]
+    );
+
+    repl("get key1\nfrobnicate\nget key1\n")
+        .failure()
+        .stdout("value1\n")
+        .stderr(contains(
+            "script.kvs:2: Unknown command frobnicate, see help",
+        ));
+    repl("batch\nget key1\n")
+        .failure()
+        .stderr(contains("batch without end"));
+    repl("set key4 \"unterminated\n")
+        .failure()
+        .stderr(contains("script.kvs:1: Unterminated quote"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4037";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    client(&["set", "user:2", "bob"]).success();
+    client(&["set", "user:1", "alice"]).success();
+    client(&["set", "group:1", "admins"]).success();
+    client(&["scan", "user:"])
+        .success()
+        .stdout("user:1 = alice\nuser:2 = bob\n");
+    client(&["scan"])
+        .success()
+        .stdout("group:1 = admins\nuser:1 = alice\nuser:2 = bob\n");
+    client(&["scan", "nobody"]).success().stdout(is_empty());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_typed_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4050";
+    let spawn = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut server = spawn();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    client(&["lpush", "list1", "a", "b"])
+        .success()
+        .stdout("2\n");
+    client(&["hset", "hash1", "field1", "value1"]).success();
+    client(&["sadd", "set1", "b", "a"]).success().stdout("2\n");
+    client(&["incrby", "counter1", "-4"])
+        .success()
+        .stdout("-4\n");
+    client(&["get", "list1"])
+        .failure()
+        .stderr(contains("Key list1 holds a list, not a string"));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    // Typed values are kept in the log like any other value
+    let mut server = spawn();
+    thread::sleep(Duration::from_secs(1));
+    client(&["lrange", "list1"]).success().stdout("b\na\n");
+    client(&["lrange", "list1", "-1", "-1"])
+        .success()
+        .stdout("a\n");
+    client(&["hget", "hash1", "field1"])
+        .success()
+        .stdout("value1\n");
+    client(&["hget", "hash1", "field2"])
+        .success()
+        .stdout("Field not found\n");
+    client(&["smembers", "set1"]).success().stdout("a\nb\n");
+    client(&["incrby", "counter1", "5"]).success().stdout("1\n");
+    client(&["sadd", "hash1", "a"])
+        .failure()
+        .stderr(contains("holds a hash"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_import_export() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4038";
+    let mut server = Command::cargo_bin("server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    let mut jsonl = String::new();
+    for i in 0..250 {
+        jsonl.push_str(&format!(
+            "{{\"key\":\"user:{:03}\",\"value\":\"name {}\"}}\n",
+            i, i
+        ));
+    }
+    fs::write(temp_dir.path().join("users.jsonl"), &jsonl).unwrap();
+    fs::write(
+        temp_dir.path().join("groups.csv"),
+        "key,value\ngroup:1,\"admins, all of them\"\ngroup:2,users\n",
+    )
+    .unwrap();
+    client(&["import", "users.jsonl", "--batch-size", "100"])
+        .success()
+        .stderr(contains("Imported 250 records"));
+    client(&["import", "groups.csv"])
+        .success()
+        .stderr(contains("Imported 2 records"));
+    client(&["get", "user:042"]).success().stdout("name 42\n");
+
+    client(&["export", "--prefix", "user:"])
+        .success()
+        .stdout(jsonl)
+        .stderr(contains("Exported 250 records"));
+    client(&["export", "--prefix", "group:", "--out", "groups.out.csv"]).success();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("groups.out.csv")).unwrap(),
+        "key,value\ngroup:1,\"admins, all of them\"\ngroup:2,users\n"
+    );
+
+    // A failing import tells where to resume from
+    fs::write(
+        temp_dir.path().join("broken.jsonl"),
+        "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\nnot json\n{\"key\":\"c\",\"value\":\"3\"}\n",
+    )
+    .unwrap();
+    client(&["import", "broken.jsonl", "--batch-size", "2"])
+        .failure()
+        .stderr(contains(
+            "Imported 2 records before failing, resume with --skip 2",
+        ))
+        .stderr(contains("Couldn't parse JSONL record at line 3"));
+    client(&["get", "b"]).success().stdout("2\n");
+    client(&["import", "broken.jsonl", "--skip", "3"])
+        .success()
+        .stderr(contains("Imported 4 records"));
+    client(&["get", "c"]).success().stdout("3\n");
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_bench() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4039";
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--duration", "1", "--keys", "100"])
+        .args(["--distribution", "zipf", "--value-size", "1-64"])
+        .args(["--read-ratio", "0.5"])
+        .assert()
+        .success()
+        .stderr(contains("Preloaded 100 keys"))
+        .stdout(contains(" 0 errors"))
+        .stdout(contains("p999"))
+        .stdout(contains("get "))
+        .stdout(contains("set "));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key00000042", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(predicates::str::is_match("^[A-Za-z0-9]{1,64}\n$").unwrap());
+    Command::cargo_bin("kvs-bench")
+        .unwrap()
+        .args(["--addr", addr, "--read-ratio", "2"])
+        .assert()
+        .failure()
+        .stderr(contains("--read-ratio must be between 0 and 1"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4043", "127.0.0.1:4044"];
+    let temp_dirs: Vec<_> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<_> = addrs
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, temp_dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--engine", "mem", "--addr", addr])
+                .current_dir(temp_dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+    let config = temp_dirs[0].path().join("client.toml");
+    fs::write(
+        &config,
+        format!(
+            "nodes = [\"{}\", \"{}\"]\nreplicas = 2\n",
+            addrs[0], addrs[1]
+        ),
+    )
+    .unwrap();
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .arg("--config")
+            .arg(&config)
+            .assert()
+    };
+
+    client(&["set", "key1", "value1"]).success();
+    // Both servers hold every key
+    for addr in addrs {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+    client(&["health"])
+        .success()
+        .stdout(format!("{} up\n{} up\n", addrs[0], addrs[1]));
+
+    servers[0].kill().unwrap();
+    servers[0].wait().unwrap();
+    client(&["get", "key1"]).success().stdout("value1\n");
+    client(&["health"])
+        .failure()
+        .stdout(contains(format!("{} down: ", addrs[0])))
+        .stdout(contains(format!("{} up", addrs[1])));
+    // --addr takes over the config file
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["health", "--addr", addrs[1]])
+        .arg("--config")
+        .arg(&config)
+        .assert()
+        .success()
+        .stdout(format!("{} up\n", addrs[1]));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &format!("{},nowhere", addrs[1])])
+        .assert()
+        .failure()
+        .stderr(contains("Invalid binding address"));
+
+    servers[1].kill().unwrap();
+    servers[1].wait().unwrap();
+}
+
+#[test]
+fn cli_compression() {
+    let value = "{\"id\":1,\"name\":\"value1\",\"tags\":[\"a\",\"b\"]}".repeat(20);
+    let tcp_dir = TempDir::new().unwrap();
+    let http_dir = TempDir::new().unwrap();
+    let mut tcp_server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--compression", "zstd", "--addr", "127.0.0.1:4045"])
+        .current_dir(&tcp_dir)
+        .spawn()
+        .unwrap();
+    let mut http_server = Command::cargo_bin("server")
+        .unwrap()
+        .args([
+            "--engine",
+            "mem",
+            "--compression",
+            "lz4",
+            "--addr",
+            "127.0.0.1:4046",
+        ])
+        .current_dir(&http_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    for (client, addr) in [
+        ("kvs-client", "127.0.0.1:4045"),
+        ("client", "127.0.0.1:4046"),
+    ] {
+        Command::cargo_bin(client)
+            .unwrap()
+            .args(["set", "key1", &value, "--addr", addr])
+            .assert()
+            .success();
+        for compress in [true, false] {
+            let mut command = Command::cargo_bin(client).unwrap();
+            command.args(["get", "key1", "--addr", addr]);
+            if compress {
+                command.arg("--compress");
+            }
+            command.assert().success().stdout(format!("{}\n", value));
+        }
+    }
+
+    // Only the clients asking for it get compressed responses. The TCP server answers once the
+    // request ends, while hyper drops connections closed halfway and relies on `Connection: close`
+    let get = |request: &str, addr: &str| {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        if !request.contains("HTTP/1.1") {
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+        }
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        response
+    };
+    assert!(get("COMPRESS gzip,zstd\nGET key1", "127.0.0.1:4045").starts_with(b"Z zstd "));
+    assert!(get("GET key1", "127.0.0.1:4045").starts_with(b"OK {"));
+    // No content coding exists for lz4, so HTTP gets zstd
+    let request = "GET /v1/get/key1 HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: zstd\r\n\
+        Connection: close\r\n\r\n";
+    let response = String::from_utf8_lossy(&get(request, "127.0.0.1:4046")).to_lowercase();
+    assert!(response.contains("content-encoding: zstd"), "{}", response);
+
+    tcp_server.kill().unwrap();
+    http_server.kill().unwrap();
+    tcp_server.wait().unwrap();
+    http_server.wait().unwrap();
+    let log = fs::read_to_string(tcp_dir.path().join("kvs.db")).unwrap();
+    assert!(log.contains("\"compression\":\"zstd\""), "{}", log);
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["stats", "--data-dir"])
+        .arg(tcp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("compression ratio"));
+}
+
+#[test]
+fn cli_encryption() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let (old_key, new_key) = (EncryptionKey::generate(), EncryptionKey::generate());
+    let old_key_file = temp_dir.path().join("old.key");
+    let new_key_file = temp_dir.path().join("new.key");
+    fs::write(&old_key_file, &old_key).unwrap();
+    fs::write(&new_key_file, &new_key).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4047", "--data-dir"])
+        .arg(&data_dir)
+        .env("KVS_ENCRYPTION_KEY", &old_key)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "secret1", "--addr", "127.0.0.1:4047"])
+        .assert()
+        .success();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    let log = fs::read_to_string(data_dir.join("kvs.db")).unwrap();
+    assert!(!log.contains("secret1"), "{}", log);
+
+    // Without the key, or with another one, the server refuses to start
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4047", "--data-dir"])
+        .arg(&data_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEncryptionKey"));
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4047", "--data-dir"])
+        .arg(&data_dir)
+        .arg("--encryption-key-file")
+        .arg(&new_key_file)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEncryptionKey"));
+
+    // Rotating keys encrypts the data again with the new one
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4047", "--data-dir"])
+        .arg(&data_dir)
+        .arg("--encryption-key-file")
+        .arg(&new_key_file)
+        .arg("--old-encryption-key-file")
+        .arg(&old_key_file)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4047"])
+        .assert()
+        .success()
+        .stdout("secret1\n");
+    server.kill().unwrap();
+    server.wait().unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["verify", "--data-dir"])
+        .arg(&data_dir)
+        .arg("--encryption-key-file")
+        .arg(&new_key_file)
+        .assert()
+        .success();
+}
+
+#[test]
+fn cli_namespaces() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4053";
+    let custom_dir = temp_dir.path().join("custom");
+    let spawn = || {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap()
+    };
+    let mut server = spawn();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    client(&["namespace", "create", "tenant1", "--max-keys", "1"]).success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["namespace", "create", "tenant2", "--addr", addr, "--dir"])
+        .arg(&custom_dir)
+        .assert()
+        .success();
+    client(&["namespace", "create", "tenant1"])
+        .failure()
+        .stderr(contains("already exists"));
+    client(&["set", "key1", "default"]).success();
+    client(&["set", "key1", "tenant1", "--namespace", "tenant1"]).success();
+    client(&["set", "key2", "tenant1", "--namespace", "tenant1"])
+        .failure()
+        .stderr(contains("quota of 1 keys"));
+    client(&["set", "key1", "tenant2", "--namespace", "tenant2"]).success();
+    client(&["get", "key1", "--namespace", "missing"])
+        .failure()
+        .stderr(contains("Namespace missing doesn't exist"));
+    assert!(temp_dir.path().join("ns").join("tenant1").is_dir());
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+
+    // The namespaces and their keys are there again after a restart
+    let mut server = spawn();
+    thread::sleep(Duration::from_secs(1));
+    client(&["get", "key1"]).success().stdout("default\n");
+    client(&["get", "key1", "--namespace", "tenant1"])
+        .success()
+        .stdout("tenant1\n");
+    client(&["get", "key1", "--namespace", "tenant2"])
+        .success()
+        .stdout("tenant2\n");
+    let output = client(&["namespace", "list"])
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let names: Vec<_> = String::from_utf8(output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["name"].clone())
+        .collect();
+    assert_eq!(names, ["default", "tenant1", "tenant2"]);
+
+    // Dropping a namespace deletes its directory, unless it was given one
+    client(&["namespace", "drop", "tenant1"]).success();
+    client(&["namespace", "drop", "tenant2"]).success();
+    client(&["get", "key1", "--namespace", "tenant1"])
+        .failure()
+        .stderr(contains("doesn't exist"));
+    assert!(!temp_dir.path().join("ns").join("tenant1").exists());
+    assert!(custom_dir.join("kvs.db").exists());
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_find() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4056";
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "[indexes]\nusers.by_age = \"$.age\"\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml", "--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    client(&["set", "users:1", r#"{"age":30}"#]).success();
+    client(&["set", "users:2", r#"{"age":"30"}"#]).success();
+    client(&["find", "users.by_age", "30"])
+        .success()
+        .stdout("users:1 = {\"age\":30}\nusers:2 = {\"age\":\"30\"}\n");
+    client(&["find", "users.by_age", "31"]).success().stdout("");
+    client(&["find", "users.by_name", "30"])
+        .failure()
+        .stderr(contains("Index users.by_name doesn't exist"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_eval() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4059";
+    fs::write(
+        temp_dir.path().join("swap.lua"),
+        "local first = kvs.get(KEYS[1])\n\
+         kvs.set(KEYS[1], kvs.get(KEYS[2]))\n\
+         kvs.set(KEYS[2], first)\n\
+         return {KEYS[1], KEYS[2]}\n",
+    )
+    .unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    client(&["set", "key1", "value1"]).success();
+    client(&["set", "key2", "value2"]).success();
+    client(&[
+        "eval", "--file", "swap.lua", "--key", "key1", "--key", "key2",
+    ])
+    .success()
+    .stdout("[\"key1\",\"key2\"]\n");
+    client(&["get", "key1"]).success().stdout("value2\n");
+    client(&["eval", "return ARGV[1] + 1", "--arg", "-2"])
+        .success()
+        .stdout("-1\n");
+    client(&["eval", "error('failed')"])
+        .failure()
+        .stderr(contains("Script failed: script:1: failed"));
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+fn cli_max_memory(server_bin: &str, client_bin: &str, addr: &str, metrics_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin(server_bin)
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .args(["--max-memory", "30", "--eviction-policy", "allkeys-lru"])
+        .args(["--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin(client_bin)
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    for key in ["key1", "key2", "key3", "key4"] {
+        client(&["set", key, "value1"]).success();
+    }
+    client(&["get", "key1"]).success().stdout("Key not found\n");
+    client(&["get", "key4"]).success().stdout("value1\n");
+    client(&["set", "key5", &"v".repeat(30)])
+        .failure()
+        .stderr(contains("Out of memory"));
+
+    let response = http_get(metrics_addr, "/metrics");
+    for line in [
+        "kvs_namespace_bytes{namespace=\"default\"} 30",
+        "kvs_namespace_evictions_total{namespace=\"default\"} 1",
+        "kvs_namespace_rejected_writes_total{namespace=\"default\"} 1",
+    ] {
+        assert!(
+            response.lines().any(|response| response == line),
+            "{} missing from\n{}",
+            line,
+            response
+        );
+    }
+
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_max_memory_tcp() {
+    cli_max_memory(
+        "kvs-server",
+        "kvs-client",
+        "127.0.0.1:4060",
+        "127.0.0.1:4061",
+    );
+}
+
+#[test]
+fn cli_max_memory_http() {
+    cli_max_memory("server", "client", "127.0.0.1:4062", "127.0.0.1:4063");
+}
+
+fn cli_snapshots(server_bin: &str, client_bin: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let start = |interval: &str| {
+        let server = Command::cargo_bin(server_bin)
+            .unwrap()
+            .args(["--engine", "mem", "--addr", addr])
+            .args(["--snapshot-interval", interval])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        server
+    };
+    let client = |args: &[&str]| {
+        Command::cargo_bin(client_bin)
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+    };
+
+    // Saved on the interval, so even a killed server keeps what was written before
+    let mut server = start("1");
+    client(&["set", "key1", "value1"]).success();
+    thread::sleep(Duration::from_millis(2500));
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    assert!(temp_dir.path().join("mem.snapshot").exists());
+
+    // Saved on shutdown
+    let mut server = start("0");
+    client(&["get", "key1"]).success().stdout("value1\n");
+    client(&["set", "key2", "value2"]).success();
+    let terminated = std::process::Command::new("kill")
+        .args(["-TERM", &server.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(terminated.success());
+    assert!(server.wait().unwrap().success());
+
+    let mut server = start("0");
+    client(&["get", "key1"]).success().stdout("value1\n");
+    client(&["get", "key2"]).success().stdout("value2\n");
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn cli_snapshots_tcp() {
+    cli_snapshots("kvs-server", "kvs-client", "127.0.0.1:4064");
+}
+
+#[test]
+fn cli_snapshots_http() {
+    cli_snapshots("server", "client", "127.0.0.1:4065");
+}
@@ -0,0 +1,524 @@
+use assert_cmd::prelude::*;
+use kvs::client::{AsyncKvsClient, ClientConfig, KvsClient, Protocol, ServerErrorKind};
+use kvs::namespace::NamespaceConfig;
+use kvs::routing::route;
+use kvs::watch::Change;
+use kvs::{Command as KvsCommand, CommandResponse, Error};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// A server killed when dropped, so that a failing test doesn't leave it running on its port.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn spawn_server(bin: &str, addr: &str, temp_dir: &TempDir) -> Server {
+    let server = Command::cargo_bin(bin)
+        .unwrap()
+        .args(["--engine", "mem", "--addr", addr])
+        .current_dir(temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Server(server)
+}
+
+fn config(addr: &str, protocol: Protocol) -> ClientConfig {
+    ClientConfig {
+        protocol,
+        ..ClientConfig::new(addr)
+    }
+}
+
+fn client_access_server(server_bin: &str, addr: &str, protocol: Protocol) {
+    let temp_dir = TempDir::new().unwrap();
+    let server = spawn_server(server_bin, addr, &temp_dir);
+    let client = KvsClient::new(config(addr, protocol)).unwrap();
+
+    assert_eq!(client.get("key1").unwrap(), None);
+    client.set("key1", "value1").unwrap();
+    assert_eq!(client.get("key1").unwrap(), Some("value1".to_owned()));
+    client.remove("key1").unwrap();
+    assert!(matches!(
+        client.remove("key1"),
+        Err(Error::KeyNotFound { key }) if key == "key1"
+    ));
+
+    let commands: Vec<_> = (0..20)
+        .map(|i| KvsCommand::Set {
+            key: format!("key{}", i),
+            value: format!("value{}", i),
+        })
+        .collect();
+    for response in client.pipeline(&commands) {
+        assert!(matches!(response, Ok(CommandResponse::Set)));
+    }
+    let archive = client.backup().unwrap();
+
+    let mut watch = client.watch("key*", None).unwrap();
+    client.set("key1", "value1b").unwrap();
+    let event = watch.next().unwrap().unwrap();
+    assert_eq!(event.key, "key1");
+    assert_eq!(
+        event.change,
+        Change::Set {
+            value: "value1b".to_owned()
+        }
+    );
+    drop(watch);
+
+    client.set("key2", "value2b").unwrap();
+    let mut watch = client.watch("key*", Some(event.position())).unwrap();
+    assert_eq!(watch.next().unwrap().unwrap().key, "key2");
+    drop(watch);
+    drop(server);
+
+    // Restoring needs an empty store
+    let _server = spawn_server(server_bin, addr, &temp_dir);
+    assert_eq!(client.restore(&archive).unwrap(), 20);
+    assert_eq!(client.get("key7").unwrap(), Some("value7".to_owned()));
+    let err = client.restore(&archive).unwrap_err();
+    assert!(
+        matches!(
+            err,
+            Error::Server {
+                kind: ServerErrorKind::Other,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn client_access_server_tcp() {
+    client_access_server("kvs-server", "127.0.0.1:4031", Protocol::Tcp);
+}
+
+#[test]
+fn client_access_server_http() {
+    client_access_server("server", "127.0.0.1:4032", Protocol::Http);
+}
+
+fn client_typed_values(server_bin: &str, addr: &str, protocol: Protocol) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = spawn_server(server_bin, addr, &temp_dir);
+    let client = KvsClient::new(config(addr, protocol)).unwrap();
+
+    assert_eq!(client.lpush("list1", &["a", "b"]).unwrap(), 2);
+    assert_eq!(client.lpush("list1", &["c"]).unwrap(), 3);
+    assert_eq!(client.lrange("list1", 0, -1).unwrap(), ["c", "b", "a"]);
+    assert_eq!(client.lrange("list1", -2, -1).unwrap(), ["b", "a"]);
+    assert!(client.lrange("missing", 0, -1).unwrap().is_empty());
+
+    assert!(client.hset("hash1", "field1", "value1").unwrap());
+    assert!(!client.hset("hash1", "field1", "value2").unwrap());
+    assert_eq!(
+        client.hget("hash1", "field1").unwrap(),
+        Some("value2".to_owned())
+    );
+    assert_eq!(client.hget("hash1", "field2").unwrap(), None);
+
+    assert_eq!(client.sadd("set1", &["b", "a"]).unwrap(), 2);
+    assert_eq!(client.sadd("set1", &["a", "c"]).unwrap(), 1);
+    assert_eq!(client.smembers("set1").unwrap(), ["a", "b", "c"]);
+
+    assert_eq!(client.incr_by("counter1", 10).unwrap(), 10);
+    assert_eq!(client.incr_by("counter1", -3).unwrap(), 7);
+    assert_eq!(client.get("counter1").unwrap(), Some("7".to_owned()));
+
+    for result in [
+        client.get("list1").map(|_| ()),
+        client.lpush("hash1", &["a"]).map(|_| ()),
+        client.hget("set1", "a").map(|_| ()),
+        client.incr_by("list1", 1).map(|_| ()),
+    ] {
+        let err = result.unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                Error::Server {
+                    kind: ServerErrorKind::WrongType,
+                    ..
+                }
+            ),
+            "{}",
+            err
+        );
+    }
+    // A string can be overwritten by any value, and the other way around
+    client.set("list1", "value1").unwrap();
+    assert_eq!(
+        client.sadd("list1", &["a"]).unwrap_err().to_string(),
+        "Key list1 holds a string, not a set"
+    );
+
+    let pairs = client.scan("set").unwrap();
+    assert_eq!(
+        pairs,
+        [("set1".to_owned(), "[\"a\",\"b\",\"c\"]".to_owned())]
+    );
+}
+
+#[test]
+fn client_typed_values_tcp() {
+    client_typed_values("kvs-server", "127.0.0.1:4048", Protocol::Tcp);
+}
+
+#[test]
+fn client_typed_values_http() {
+    client_typed_values("server", "127.0.0.1:4049", Protocol::Http);
+}
+
+fn client_namespaces(server_bin: &str, addr: &str, protocol: Protocol) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = spawn_server(server_bin, addr, &temp_dir);
+    let client = KvsClient::new(config(addr, protocol)).unwrap();
+    let in_namespace = |namespace: &str| {
+        KvsClient::new(ClientConfig {
+            namespace: Some(namespace.to_owned()),
+            ..config(addr, protocol)
+        })
+        .unwrap()
+    };
+
+    client
+        .create_namespace(
+            "tenant1",
+            &NamespaceConfig {
+                max_keys: Some(2),
+                ..NamespaceConfig::default()
+            },
+        )
+        .unwrap();
+    client
+        .create_namespace("tenant2", &NamespaceConfig::default())
+        .unwrap();
+    let tenant1 = in_namespace("tenant1");
+    let tenant2 = in_namespace("tenant2");
+
+    // The same key holds a different value in every namespace
+    client.set("key1", "default").unwrap();
+    tenant1.set("key1", "tenant1").unwrap();
+    assert_eq!(client.get("key1").unwrap(), Some("default".to_owned()));
+    assert_eq!(tenant1.get("key1").unwrap(), Some("tenant1".to_owned()));
+    assert_eq!(tenant2.get("key1").unwrap(), None);
+    assert!(matches!(
+        tenant2.remove("key1"),
+        Err(Error::KeyNotFound { .. })
+    ));
+    assert_eq!(tenant1.scan("").unwrap().len(), 1);
+
+    tenant1.set("key2", "value2").unwrap();
+    let err = tenant1.set("key3", "value3").unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            Error::Server {
+                kind: ServerErrorKind::QuotaExceeded,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+    // Overwriting a key doesn't take more of the quota
+    tenant1.set("key2", "value4").unwrap();
+
+    let infos = client.namespaces().unwrap();
+    let names: Vec<_> = infos.iter().map(|info| info.name.as_str()).collect();
+    assert_eq!(names, ["default", "tenant1", "tenant2"]);
+    assert_eq!(infos[1].config.max_keys, Some(2));
+    assert_eq!(infos[1].usage.keys, 2);
+
+    client.drop_namespace("tenant1").unwrap();
+    let err = tenant1.get("key1").unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            Error::Server {
+                kind: ServerErrorKind::NoSuchNamespace,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+    assert!(client.drop_namespace("default").is_err());
+    assert_eq!(client.get("key1").unwrap(), Some("default".to_owned()));
+}
+
+#[test]
+fn client_namespaces_tcp() {
+    client_namespaces("kvs-server", "127.0.0.1:4051", Protocol::Tcp);
+}
+
+#[test]
+fn client_namespaces_http() {
+    client_namespaces("server", "127.0.0.1:4052", Protocol::Http);
+}
+
+fn client_indexes(server_bin: &str, addr: &str, protocol: Protocol) {
+    let temp_dir = TempDir::new().unwrap();
+    let start = |config: &[&str]| {
+        let server = Command::cargo_bin(server_bin)
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr])
+            .args(config)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Server(server)
+    };
+    let client = KvsClient::new(config(addr, protocol)).unwrap();
+
+    // Written before the index is declared, so left to the backfill
+    let server = start(&[]);
+    client.set("users:1", r#"{"email":"a@b.c"}"#).unwrap();
+    client.set("users:2", r#"{"email":"d@e.f"}"#).unwrap();
+    client.set("orders:1", r#"{"email":"a@b.c"}"#).unwrap();
+    assert!(client.find("users.by_email", "a@b.c").is_err());
+    drop(server);
+
+    std::fs::write(
+        temp_dir.path().join("kvs.toml"),
+        "[indexes]\nusers.by_email = \"$.email\"\n",
+    )
+    .unwrap();
+    let _server = start(&["--config", "kvs.toml"]);
+    let mut attempts = 0;
+    let found = loop {
+        match client.find("users.by_email", "a@b.c") {
+            Err(err) if attempts < 50 && err.to_string().contains("still being built") => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+            result => break result.unwrap(),
+        }
+    };
+    assert_eq!(
+        found,
+        vec![("users:1".to_owned(), r#"{"email":"a@b.c"}"#.to_owned())]
+    );
+
+    client.set("users:3", r#"{"email":"a@b.c"}"#).unwrap();
+    client.set("users:1", r#"{"email":"g@h.i"}"#).unwrap();
+    client.remove("users:2").unwrap();
+    let keys = |value: &str| -> Vec<String> {
+        let pairs = client.find("users.by_email", value).unwrap();
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(keys("a@b.c"), ["users:3"]);
+    assert_eq!(keys("g@h.i"), ["users:1"]);
+    assert!(keys("d@e.f").is_empty());
+    assert!(client.find("users.by_name", "a").is_err());
+    // The entries stay out of sight
+    assert_eq!(client.scan("").unwrap().len(), 3);
+}
+
+#[test]
+fn client_indexes_tcp() {
+    client_indexes("kvs-server", "127.0.0.1:4054", Protocol::Tcp);
+}
+
+#[test]
+fn client_indexes_http() {
+    client_indexes("server", "127.0.0.1:4055", Protocol::Http);
+}
+
+fn client_scripts(server_bin: &str, addr: &str, protocol: Protocol) {
+    let temp_dir = TempDir::new().unwrap();
+    let _server = spawn_server(server_bin, addr, &temp_dir);
+    let client = KvsClient::new(config(addr, protocol)).unwrap();
+
+    let transfer = "
+        local from = tonumber(kvs.get(KEYS[1]) or '0')
+        local amount = tonumber(ARGV[1])
+        if from < amount then error('not enough') end
+        kvs.set(KEYS[1], tostring(from - amount))
+        kvs.set(KEYS[2], tostring(tonumber(kvs.get(KEYS[2]) or '0') + amount))
+        return {from = from - amount}
+    ";
+    client.set("alice", "10").unwrap();
+    // The first run sends the script, the second one only its hash
+    for left in [7, 4] {
+        let result = client.eval(transfer, &["alice", "bob"], &["3"]).unwrap();
+        assert_eq!(result, serde_json::json!({ "from": left }));
+    }
+    assert_eq!(client.get("bob").unwrap(), Some("6".to_owned()));
+
+    // A failing script writes nothing
+    let err = client
+        .eval(transfer, &["alice", "bob"], &["5"])
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            Error::Server {
+                kind: ServerErrorKind::Script,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+    assert!(err.to_string().contains("not enough"), "{}", err);
+    assert_eq!(client.get("alice").unwrap(), Some("4".to_owned()));
+    assert_eq!(client.get("bob").unwrap(), Some("6".to_owned()));
+
+    let err = client
+        .eval("return kvs.get('carol')", &["alice"], &[])
+        .unwrap_err();
+    assert!(err.to_string().contains("carol"), "{}", err);
+    let err = client.eval("while true do end", &[], &[]).unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            Error::Server {
+                kind: ServerErrorKind::Script,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn client_scripts_tcp() {
+    client_scripts("kvs-server", "127.0.0.1:4057", Protocol::Tcp);
+}
+
+#[test]
+fn client_scripts_http() {
+    client_scripts("server", "127.0.0.1:4058", Protocol::Http);
+}
+
+#[test]
+fn client_connect_error() {
+    // Nothing listens there
+    let client = KvsClient::new(ClientConfig {
+        retries: 1,
+        backoff: Duration::from_millis(10),
+        ..ClientConfig::new("127.0.0.1:4033")
+    })
+    .unwrap();
+    assert!(matches!(client.get("key1"), Err(Error::Connect { .. })));
+}
+
+#[tokio::test]
+async fn async_client_concurrent() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4034";
+    let _server = spawn_server("kvs-server", addr, &temp_dir);
+    let client = AsyncKvsClient::new(ClientConfig {
+        max_connections: 4,
+        ..ClientConfig::new(addr)
+    })
+    .unwrap();
+
+    let tasks: Vec<_> = (0..16)
+        .map(|i| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let key = format!("key{}", i);
+                client.set(&key, "value").await.unwrap();
+                client.get(&key).await.unwrap()
+            })
+        })
+        .collect();
+    for task in tasks {
+        assert_eq!(task.await.unwrap(), Some("value".to_owned()));
+    }
+}
+
+#[test]
+fn client_restore_large_http() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4035";
+    let _server = spawn_server("server", addr, &temp_dir);
+    let client = KvsClient::new(config(addr, Protocol::Http)).unwrap();
+
+    // Bigger than the 2 MiB axum accepts by default
+    let value = "v".repeat(1024 * 1024);
+    let pairs = (0..3).map(|i| Ok((format!("key{}", i), value.clone())));
+    let mut archive = Vec::new();
+    kvs::backup::write_pairs(pairs, &mut archive).unwrap();
+    assert_eq!(client.restore(&archive).unwrap(), 3);
+    assert_eq!(client.get("key2").unwrap(), Some(value));
+}
+
+#[test]
+fn client_sharding_failover() {
+    let addrs = ["127.0.0.1:4040", "127.0.0.1:4041", "127.0.0.1:4042"];
+    // Every server locks its data directory
+    let temp_dirs: Vec<_> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<_> = addrs
+        .iter()
+        .zip(&temp_dirs)
+        .map(|(addr, temp_dir)| Some(spawn_server("kvs-server", addr, temp_dir)))
+        .collect();
+    let client = KvsClient::new(ClientConfig {
+        replicas: 2,
+        ..ClientConfig::new(addrs.join(","))
+    })
+    .unwrap();
+    let nodes: Vec<_> = addrs
+        .iter()
+        .map(|addr| KvsClient::new(ClientConfig::new(*addr)).unwrap())
+        .collect();
+
+    for i in 0..30 {
+        client
+            .set(&format!("key{:02}", i), &format!("value{}", i))
+            .unwrap();
+    }
+    // Every key is on its first 2 servers, and only there
+    for i in 0..30 {
+        let key = format!("key{:02}", i);
+        let order = route(&key, &addrs);
+        for (rank, node) in order.iter().enumerate() {
+            let expected = (rank < 2).then(|| format!("value{}", i));
+            assert_eq!(nodes[*node].get(&key).unwrap(), expected, "{}", key);
+        }
+    }
+    let pairs = client.scan("key").unwrap();
+    assert_eq!(pairs.len(), 30);
+    assert_eq!(pairs[7], ("key07".to_owned(), "value7".to_owned()));
+    client.remove("key00").unwrap();
+    assert!(matches!(
+        client.remove("key00"),
+        Err(Error::KeyNotFound { .. })
+    ));
+    assert!(client.backup().is_err());
+    assert!(client.watch("key*", None).is_err());
+
+    // With a server down, every key is still on another one
+    servers[1] = None;
+    for i in 1..30 {
+        assert_eq!(
+            client.get(&format!("key{:02}", i)).unwrap(),
+            Some(format!("value{}", i))
+        );
+    }
+    client.set("key01", "value1b").unwrap();
+    assert_eq!(client.get("key01").unwrap(), Some("value1b".to_owned()));
+    assert_eq!(client.scan("key").unwrap().len(), 29);
+    let health = client.health_check();
+    assert!(health[0].1.is_ok() && health[2].1.is_ok());
+    assert!(matches!(health[1].1, Err(Error::Connect { .. })));
+
+    // Not with two of them
+    servers[2] = None;
+    assert!(matches!(client.scan("key"), Err(Error::Connect { .. })));
+    drop(servers);
+}
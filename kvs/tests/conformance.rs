@@ -0,0 +1,39 @@
+use kvs::conformance::Durability;
+use kvs::index::{IndexDef, IndexedStore};
+use kvs::{KvStoreV2, MemStore, Result, SledStore};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+kvs::engine_conformance_tests!(kv_store, Durability::Persistent, |path: &Path| {
+    KvStoreV2::open(path)
+});
+
+kvs::engine_conformance_tests!(sled_store, Durability::Persistent, open_sled);
+
+kvs::engine_conformance_tests!(mem_store, Durability::Volatile, |_: &Path| Ok(
+    MemStore::new()
+));
+
+kvs::engine_conformance_tests!(indexed_kv_store, Durability::Persistent, open_indexed);
+
+/// An index on keys the checks write keeps its entries alongside them, which they shouldn't see.
+fn open_indexed(path: &Path) -> Result<IndexedStore<KvStoreV2>> {
+    let index = IndexDef::new("user", "by_name", "$.name")?;
+    IndexedStore::open(KvStoreV2::open(path)?, vec![index])
+}
+
+/// sled's background threads can hold on to the directory lock for a moment after the store is
+/// dropped, so reopening within the same process has to wait for them.
+fn open_sled(path: &Path) -> Result<SledStore> {
+    let mut attempts = 0;
+    loop {
+        match SledStore::open(path) {
+            Err(_) if attempts < 50 => {
+                attempts += 1;
+                thread::sleep(Duration::from_millis(20));
+            }
+            result => return result,
+        }
+    }
+}
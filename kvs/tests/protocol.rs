@@ -0,0 +1,173 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use kvs::compression::Compression;
+use kvs::encryption::Keyring;
+use kvs::limits::Limits;
+use kvs::namespace::{NamespaceCommand, NamespaceConfig};
+use kvs::protocol::{
+    encode, encode_namespace_command, parse_namespace_command, parse_request, parse_watch,
+    read_request, tokenize,
+};
+use kvs::{deserialize_commands, Command};
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+/// Keys, values and the like, as the TCP protocol can carry them.
+fn word() -> impl Strategy<Value = String> {
+    "\\S{1,12}"
+}
+
+fn words() -> impl Strategy<Value = Vec<String>> {
+    vec(word(), 0..4)
+}
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        word().prop_map(|key| Command::Get { key }),
+        (word(), word()).prop_map(|(key, value)| Command::Set { key, value }),
+        word().prop_map(|key| Command::Rm { key }),
+        "\\S{0,12}".prop_map(|prefix| Command::Scan { prefix }),
+        Just(Command::Backup),
+        vec((any::<String>(), any::<String>()), 0..4).prop_map(|pairs| Command::Restore { pairs }),
+        (word(), vec(word(), 1..4)).prop_map(|(key, values)| Command::LPush { key, values }),
+        (word(), any::<i64>(), any::<i64>()).prop_map(|(key, start, stop)| Command::LRange {
+            key,
+            start,
+            stop
+        }),
+        (word(), word(), word()).prop_map(|(key, field, value)| Command::HSet {
+            key,
+            field,
+            value
+        }),
+        (word(), word()).prop_map(|(key, field)| Command::HGet { key, field }),
+        (word(), vec(word(), 1..4)).prop_map(|(key, members)| Command::SAdd { key, members }),
+        word().prop_map(|key| Command::SMembers { key }),
+        (word(), any::<i64>()).prop_map(|(key, delta)| Command::IncrBy { key, delta }),
+        (word(), word()).prop_map(|(index, value)| Command::Find { index, value }),
+        (any::<String>(), words(), words()).prop_map(|(script, keys, args)| Command::Eval {
+            script,
+            keys,
+            args
+        }),
+        (word(), words(), words()).prop_map(|(hash, keys, args)| Command::EvalSha {
+            hash,
+            keys,
+            args
+        }),
+    ]
+}
+
+fn namespace_command() -> impl Strategy<Value = NamespaceCommand> {
+    let config = (
+        proptest::option::of(word()),
+        proptest::option::of(any::<u64>()),
+        proptest::option::of(any::<u64>()),
+    )
+        .prop_map(|(dir, max_keys, max_bytes)| NamespaceConfig {
+            dir: dir.map(Into::into),
+            max_keys,
+            max_bytes,
+        });
+    prop_oneof![
+        (word(), config).prop_map(|(name, config)| NamespaceCommand::Create { name, config }),
+        word().prop_map(|name| NamespaceCommand::Drop { name }),
+        Just(NamespaceCommand::List),
+    ]
+}
+
+proptest! {
+    // What the client encodes, the server parses back to the same command
+    #[test]
+    fn command_round_trip(command in command()) {
+        let request = read_request(encode(&command)?.as_slice(), &Limits::default())?;
+        prop_assert_eq!(request.token, None);
+        prop_assert_eq!(request.namespace, None);
+        prop_assert_eq!(parse_request(request.words, &request.payload)?, command);
+    }
+
+    #[test]
+    fn preamble_round_trip(
+        command in command(),
+        token in proptest::option::of(word()),
+        accepts in proptest::sample::subsequence(Compression::ALGORITHMS.to_vec(), 0..=2),
+        namespace in proptest::option::of(word()),
+    ) {
+        let mut bytes = Vec::new();
+        if let Some(token) = &token {
+            bytes.extend(format!("AUTH {}\n", token).into_bytes());
+        }
+        if !accepts.is_empty() {
+            let accepts: Vec<_> = accepts.iter().map(ToString::to_string).collect();
+            bytes.extend(format!("COMPRESS {}\n", accepts.join(",")).into_bytes());
+        }
+        if let Some(namespace) = &namespace {
+            bytes.extend(format!("SELECT {}\n", namespace).into_bytes());
+        }
+        bytes.extend(encode(&command)?);
+
+        let request = read_request(bytes.as_slice(), &Limits::default())?;
+        prop_assert_eq!(request.token, token);
+        prop_assert_eq!(request.accepts, accepts);
+        prop_assert_eq!(request.namespace, namespace);
+        prop_assert_eq!(parse_request(request.words, &request.payload)?, command);
+    }
+
+    #[test]
+    fn namespace_command_round_trip(command in namespace_command()) {
+        let words = tokenize(encode_namespace_command(&command)?.as_bytes())?;
+        prop_assert_eq!(parse_namespace_command(&words)?, Some(command));
+    }
+
+    // Whatever a client sends, the server answers with an error at worst
+    #[test]
+    fn any_request_parses_without_panicking(bytes in vec(any::<u8>(), 0..256)) {
+        if let Ok(request) = read_request(bytes.as_slice(), &Limits::default()) {
+            let _ = parse_watch(&request.words);
+            let _ = parse_namespace_command(&request.words);
+            let _ = parse_request(request.words, &request.payload);
+        }
+    }
+
+    #[test]
+    fn any_log_replays_without_panicking(text in any::<String>()) {
+        let _ = deserialize_commands(&text, &Keyring::default());
+    }
+
+    // Logs cut short or with a byte flipped by a crash or a bad disk
+    #[test]
+    fn damaged_log_replays_without_panicking(
+        records in vec(("\\PC{0,8}", "\\PC{0,8}"), 1..4),
+        cut in any::<proptest::sample::Index>(),
+        flip in any::<proptest::sample::Index>(),
+        byte in any::<u8>(),
+    ) {
+        let log: String = records
+            .iter()
+            .map(|(key, value)| {
+                let command = Command::Set { key: key.clone(), value: value.clone() };
+                serde_json::to_string(&command).unwrap() + "\n"
+            })
+            .collect();
+        let mut bytes = log.into_bytes();
+        let at = flip.index(bytes.len());
+        bytes[at] = byte;
+        bytes.truncate(cut.index(bytes.len() + 1));
+        let _ = deserialize_commands(&String::from_utf8_lossy(&bytes), &Keyring::default());
+    }
+
+    // Compressed values whose bytes don't decompress, or claim more than they could hold
+    #[test]
+    fn any_compressed_value_replays_without_panicking(
+        bytes in vec(any::<u8>(), 0..64),
+        compression in proptest::sample::select(Compression::ALGORITHMS.to_vec()),
+    ) {
+        let record = serde_json::json!({
+            "type": "Set",
+            "key": "key1",
+            "value": BASE64.encode(&bytes),
+            "compression": compression,
+        });
+        let _ = deserialize_commands(&record.to_string(), &Keyring::default());
+    }
+}
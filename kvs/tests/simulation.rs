@@ -0,0 +1,126 @@
+use kvs::compression::Compression;
+use kvs::encryption::{EncryptionKey, Keyring};
+use kvs::file_system::FileSystem;
+use kvs::simulation::{Faults, Outcome, Report, SimFileSystem, Simulation, SimulationConfig};
+use kvs::{KvStoreV2, KvsEngine, MemStore, Result, DEFAULT_FILE_NAME_KVS};
+use std::env;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Seeds every run of the test tries, unless `KVS_SIMULATION_SEED` picks the one to replay.
+const SEEDS: u64 = 40;
+
+fn seeds() -> Vec<u64> {
+    match env::var("KVS_SIMULATION_SEED") {
+        Ok(seed) => vec![seed
+            .parse()
+            .expect("KVS_SIMULATION_SEED should be a number")],
+        Err(_) => (0..SEEDS).collect(),
+    }
+}
+
+fn open_kv_store(dir: &Path, fs: Arc<dyn FileSystem>) -> Result<Box<dyn KvsEngine>> {
+    let store = KvStoreV2::open_with_file_system(dir, Keyring::default(), fs)?;
+    Ok(Box::new(store))
+}
+
+fn simulate(config: SimulationConfig) -> Report {
+    Simulation::new(config).run(open_kv_store).unwrap()
+}
+
+#[test]
+fn kv_store_is_linearizable_under_faults() {
+    let mut crashes = 0;
+    let mut disk_faults = 0;
+    for seed in seeds() {
+        let report = simulate(SimulationConfig::new(seed));
+        crashes += report.crashes;
+        disk_faults += report.disk_faults;
+    }
+    // The runs went through what they are meant to
+    assert!(crashes > 0);
+    assert!(disk_faults > 0);
+}
+
+#[test]
+fn kv_store_with_compression_and_encryption_is_linearizable_under_faults() {
+    let keys = Keyring::new(
+        EncryptionKey::from_hex(&EncryptionKey::generate()).unwrap(),
+        Vec::new(),
+    );
+    for seed in seeds() {
+        let keys = keys.clone();
+        Simulation::new(SimulationConfig::new(seed))
+            .run(move |dir, fs| {
+                let store = KvStoreV2::open_with_file_system(dir, keys.clone(), fs)?
+                    .with_compression(Compression::Lz4);
+                Ok(Box::new(store) as Box<dyn KvsEngine>)
+            })
+            .unwrap();
+    }
+}
+
+#[test]
+fn same_seed_same_run() {
+    let config = SimulationConfig::new(7);
+    assert_eq!(simulate(config.clone()), simulate(config));
+    assert_ne!(
+        simulate(SimulationConfig::new(7)).history,
+        simulate(SimulationConfig::new(8)).history
+    );
+}
+
+#[test]
+fn without_faults_every_operation_returns() {
+    let config = SimulationConfig {
+        faults: Faults::NONE,
+        ..SimulationConfig::new(3)
+    };
+    let report = simulate(config);
+    assert_eq!(report.history.len(), 200 + 3);
+    assert!(report
+        .history
+        .iter()
+        .all(|entry| entry.outcome != Outcome::Unknown && entry.returned.is_some()));
+    assert_eq!(report.failed, 0);
+    assert_eq!(report.dropped, 0);
+    // The one crash at the end
+    assert_eq!(report.crashes, 1);
+}
+
+#[test]
+fn rate_limit_counts_simulated_time() {
+    let mut config = SimulationConfig {
+        faults: Faults::NONE,
+        ..SimulationConfig::new(5)
+    };
+    config.limits.rate_limit = Some(50.0);
+    config.limits.rate_burst = Some(2);
+    let report = simulate(config);
+    assert!(report.failed > 0);
+    // Failed requests may have taken effect or not, which the history allows for
+    assert!(report.elapsed > std::time::Duration::ZERO);
+}
+
+#[test]
+fn volatile_engine_is_caught_losing_writes() {
+    let err = Simulation::new(SimulationConfig::new(1))
+        .run(|_, _| Ok(Box::new(MemStore::new()) as Box<dyn KvsEngine>))
+        .unwrap_err();
+    assert!(err.to_string().contains("Run with seed 1 failed"));
+    assert!(err.to_string().contains("isn't linearizable"));
+}
+
+#[test]
+fn failed_write_leaves_the_log_readable() {
+    let faults = Faults {
+        partial_write: 1.0,
+        ..Faults::NONE
+    };
+    let fs = Arc::new(SimFileSystem::new(0, faults));
+    let dir = Path::new("data");
+    let mut store = KvStoreV2::open_with_file_system(dir, Keyring::default(), fs.clone()).unwrap();
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert_eq!(fs.read(&dir.join(DEFAULT_FILE_NAME_KVS)).unwrap(), b"");
+    assert_eq!(store.get("key1".to_owned()).unwrap(), None);
+}